@@ -0,0 +1,323 @@
+//! Typed client for the Pixelflut protocol as spoken by breakwater.
+//!
+//! Commands are batched in memory and only written to the server on [`Client::flush`], so that many pixels end up in
+//! few writes. Colors are passed as written in the commands, e.g. `0xrrggbb` for [`Client::px`].
+//!
+//! ```no_run
+//! use breakwater_client::Client;
+//!
+//! let mut client = Client::connect("127.0.0.1:1234").unwrap();
+//! client.size().flush().unwrap();
+//! let (width, height) = client.read_size().unwrap();
+//!
+//! for x in 0..width {
+//!     client.px(x, height / 2, 0xff0000);
+//! }
+//! client.get_pixel(0, height / 2).flush().unwrap();
+//! assert_eq!(client.read_pixel().unwrap().rgb, 0xff0000);
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to connect to Pixelflut server"))]
+    Connect { source: io::Error },
+
+    #[snafu(display("Failed to write commands to Pixelflut server"))]
+    WriteCommands { source: io::Error },
+
+    #[snafu(display("Failed to read response from Pixelflut server"))]
+    ReadResponse { source: io::Error },
+
+    #[snafu(display("The Pixelflut server closed the connection"))]
+    ConnectionClosed,
+
+    #[snafu(display("Unexpected response {response:?}, expected {expected}"))]
+    UnexpectedResponse {
+        response: String,
+        expected: &'static str,
+    },
+}
+
+/// Color of a single pixel as read using [`Client::get_pixel`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pixel {
+    pub x: u16,
+    pub y: u16,
+    /// `0xrrggbb`
+    pub rgb: u32,
+}
+
+pub struct Client<S> {
+    stream: S,
+    /// Commands that have not been sent yet
+    commands: Vec<u8>,
+    /// Data read from the server, which does not contain a complete response (line) yet
+    responses: Vec<u8>,
+}
+
+impl Client<TcpStream> {
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self, Error> {
+        let stream = TcpStream::connect(address).context(ConnectSnafu)?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S> Client<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            commands: Vec::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// `PX x y rrggbb`: Colors the pixel with `rgb` (`0xrrggbb`)
+    pub fn px(&mut self, x: u16, y: u16, rgb: u32) -> &mut Self {
+        self.command(format_args!("PX {x} {y} {:06x}\n", rgb & 0x00ff_ffff))
+    }
+
+    /// `PX x y rrggbbaa`: Colors the pixel with `rgba` (`0xrrggbbaa`), the server might ignore the alpha channel
+    pub fn px_rgba(&mut self, x: u16, y: u16, rgba: u32) -> &mut Self {
+        self.command(format_args!("PX {x} {y} {rgba:08x}\n"))
+    }
+
+    /// `PX x y gg`: Colors the pixel gray, which is shorter than the equivalent [`Self::px`]
+    pub fn gg(&mut self, x: u16, y: u16, gray: u8) -> &mut Self {
+        self.command(format_args!("PX {x} {y} {gray:02x}\n"))
+    }
+
+    /// `OFFSET x y`: Adds the offset to all following commands of this connection
+    pub fn offset(&mut self, x: u16, y: u16) -> &mut Self {
+        self.command(format_args!("OFFSET {x} {y}\n"))
+    }
+
+    /// `SIZE`: Requests the size of the canvas, the response can be read using [`Self::read_size`]
+    pub fn size(&mut self) -> &mut Self {
+        self.command(format_args!("SIZE\n"))
+    }
+
+    /// `PX x y`: Requests the color of the pixel, the response can be read using [`Self::read_pixel`]
+    pub fn get_pixel(&mut self, x: u16, y: u16) -> &mut Self {
+        self.command(format_args!("PX {x} {y}\n"))
+    }
+
+    /// The commands batched since the last [`Self::flush`], e.g. to generate commands for benchmarks
+    pub fn buffered(&self) -> &[u8] {
+        &self.commands
+    }
+
+    fn command(&mut self, command: std::fmt::Arguments) -> &mut Self {
+        self.commands
+            .write_fmt(command)
+            .expect("writing to a Vec can not fail");
+        self
+    }
+}
+
+impl<S: Write> Client<S> {
+    /// Writes all batched commands to the server
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.stream
+            .write_all(&self.commands)
+            .and_then(|_| self.stream.flush())
+            .context(WriteCommandsSnafu)?;
+        self.commands.clear();
+        Ok(())
+    }
+}
+
+impl<S: Read> Client<S> {
+    /// Reads the response to [`Self::size`]
+    pub fn read_size(&mut self) -> Result<(u16, u16), Error> {
+        let response = self.read_line()?;
+        parse_size_response(&response)
+    }
+
+    /// Reads the response to [`Self::get_pixel`]. Please note that servers don't answer for pixels outside of the
+    /// canvas, so this would block.
+    pub fn read_pixel(&mut self) -> Result<Pixel, Error> {
+        let response = self.read_line()?;
+        parse_pixel_response(&response)
+    }
+
+    /// Reads the next response line (without the newline)
+    fn read_line(&mut self) -> Result<String, Error> {
+        loop {
+            if let Some(newline) = self.responses.iter().position(|&byte| byte == b'\n') {
+                let line = self.responses.drain(..=newline).collect::<Vec<_>>();
+                return Ok(String::from_utf8_lossy(&line[..newline]).into_owned());
+            }
+
+            let mut buffer = [0; 1024];
+            let read = self.stream.read(&mut buffer).context(ReadResponseSnafu)?;
+            ensure!(read > 0, ConnectionClosedSnafu);
+            self.responses.extend_from_slice(&buffer[..read]);
+        }
+    }
+}
+
+/// Parses a `SIZE <width> <height>` response (without the newline)
+pub fn parse_size_response(response: &str) -> Result<(u16, u16), Error> {
+    let unexpected = || UnexpectedResponseSnafu {
+        response,
+        expected: "SIZE <width> <height>",
+    };
+
+    let mut parts = response
+        .strip_prefix("SIZE ")
+        .context(unexpected())?
+        .split(' ');
+    let (Some(width), Some(height), None) = (parts.next(), parts.next(), parts.next()) else {
+        return unexpected().fail();
+    };
+
+    Ok((
+        width.parse().ok().context(unexpected())?,
+        height.parse().ok().context(unexpected())?,
+    ))
+}
+
+/// Parses a `PX <x> <y> <rrggbb>` response (without the newline)
+pub fn parse_pixel_response(response: &str) -> Result<Pixel, Error> {
+    let unexpected = || UnexpectedResponseSnafu {
+        response,
+        expected: "PX <x> <y> <rrggbb>",
+    };
+
+    let mut parts = response
+        .strip_prefix("PX ")
+        .context(unexpected())?
+        .split(' ');
+    let (Some(x), Some(y), Some(rgb), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return unexpected().fail();
+    };
+    ensure!(rgb.len() == 6, unexpected());
+
+    Ok(Pixel {
+        x: x.parse().ok().context(unexpected())?,
+        y: y.parse().ok().context(unexpected())?,
+        rgb: u32::from_str_radix(rgb, 16).ok().context(unexpected())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Collects the written commands and answers with the given responses
+    struct MockStream {
+        written: Vec<u8>,
+        responses: Cursor<Vec<u8>>,
+    }
+
+    impl MockStream {
+        fn new(responses: &str) -> Self {
+            Self {
+                written: Vec::new(),
+                responses: Cursor::new(responses.as_bytes().to_vec()),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Only hand out a few bytes at a time, so that responses are split across reads
+            let len = buf.len().min(3);
+            self.responses.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[rstest]
+    #[case::px(|c: &mut Client<MockStream>| { c.px(1, 2, 0xff8000); }, "PX 1 2 ff8000\n")]
+    #[case::px_leading_zeros(|c: &mut Client<MockStream>| { c.px(0, 0, 0x00000f); }, "PX 0 0 00000f\n")]
+    #[case::px_ignores_upper_byte(|c: &mut Client<MockStream>| { c.px(0, 0, 0xab123456); }, "PX 0 0 123456\n")]
+    #[case::px_rgba(|c: &mut Client<MockStream>| { c.px_rgba(1919, 1079, 0x12345678); }, "PX 1919 1079 12345678\n")]
+    #[case::px_rgba_leading_zeros(|c: &mut Client<MockStream>| { c.px_rgba(3, 4, 0xff); }, "PX 3 4 000000ff\n")]
+    #[case::gg(|c: &mut Client<MockStream>| { c.gg(5, 6, 0x0a); }, "PX 5 6 0a\n")]
+    #[case::offset(|c: &mut Client<MockStream>| { c.offset(100, 200); }, "OFFSET 100 200\n")]
+    #[case::size(|c: &mut Client<MockStream>| { c.size(); }, "SIZE\n")]
+    #[case::get_pixel(|c: &mut Client<MockStream>| { c.get_pixel(7, 8); }, "PX 7 8\n")]
+    #[case::chained(
+        |c: &mut Client<MockStream>| { c.offset(10, 10).px(0, 0, 0xffffff).gg(1, 0, 0).get_pixel(0, 0); },
+        "OFFSET 10 10\nPX 0 0 ffffff\nPX 1 0 00\nPX 0 0\n"
+    )]
+    fn test_commands(#[case] commands: fn(&mut Client<MockStream>), #[case] expected: &str) {
+        let mut client = Client::new(MockStream::new(""));
+        commands(&mut client);
+        assert_eq!(client.buffered(), expected.as_bytes());
+
+        client.flush().unwrap();
+        assert_eq!(client.buffered(), b"");
+        assert_eq!(client.stream.written, expected.as_bytes());
+    }
+
+    #[test]
+    fn test_read_responses() {
+        let mut client = Client::new(MockStream::new("SIZE 1920 1080\nPX 1 2 abcdef\nPX 3"));
+
+        assert_eq!(client.read_size().unwrap(), (1920, 1080));
+        assert_eq!(
+            client.read_pixel().unwrap(),
+            Pixel {
+                x: 1,
+                y: 2,
+                rgb: 0xabcdef
+            }
+        );
+        // The last response is incomplete
+        assert!(matches!(client.read_pixel(), Err(Error::ConnectionClosed)));
+    }
+
+    #[rstest]
+    #[case("SIZE 640 480", Some((640, 480)))]
+    #[case("SIZE 640", None)]
+    #[case("SIZE 640 480 1", None)]
+    #[case("SIZE -1 480", None)]
+    #[case("PX 1 2 abcdef", None)]
+    fn test_parse_size_response(#[case] response: &str, #[case] expected: Option<(u16, u16)>) {
+        assert_eq!(parse_size_response(response).ok(), expected);
+    }
+
+    #[rstest]
+    #[case("PX 0 0 000000", Some((0, 0, 0)))]
+    #[case("PX 1919 1079 ff8000", Some((1919, 1079, 0xff8000)))]
+    #[case("PX 1 2 abcd", None)]
+    #[case("PX 1 2 abcdefff", None)]
+    #[case("PX 1 2 zzzzzz", None)]
+    #[case("PX 1 2", None)]
+    #[case("SIZE 640 480", None)]
+    fn test_parse_pixel_response(
+        #[case] response: &str,
+        #[case] expected: Option<(u16, u16, u32)>,
+    ) {
+        assert_eq!(
+            parse_pixel_response(response)
+                .ok()
+                .map(|pixel| (pixel.x, pixel.y, pixel.rgb)),
+            expected
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Benchmarks the parser with a stream of commands that resembles what real flooders send, instead of a single kind of
+//! command.
+
+use std::{io, sync::Arc, time::Duration};
+
+use breakwater_client::Client;
+use breakwater_parser::{OriginalParser, Parser, SimpleFrameBuffer};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const FRAMEBUFFER_WIDTH: usize = 1920;
+const FRAMEBUFFER_HEIGHT: usize = 1080;
+
+/// Size of the generated command stream, roughly what a connection reads in a few network buffers
+const COMMANDS_SIZE: usize = 4 * 1024 * 1024;
+
+/// Fixed, so that results of different runs are comparable
+const SEED: u64 = 0x6272_6561_6b77_6174;
+
+/// Relative weights of the generated commands, they don't need to add up to anything in particular.
+#[derive(Clone, Copy, Debug)]
+struct CommandMix {
+    /// `PX x y rrggbb`
+    rgb: u32,
+    /// `PX x y rrggbbaa`
+    rgba: u32,
+    /// `PX x y gg`
+    gray: u32,
+    /// `OFFSET x y`
+    offset: u32,
+    /// `PX x y`
+    read: u32,
+}
+
+impl CommandMix {
+    fn total(&self) -> u32 {
+        self.rgb + self.rgba + self.gray + self.offset + self.read
+    }
+}
+
+/// splitmix64, good enough to generate benchmark data and keeps us free of an additional dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, upper: u64) -> u64 {
+        self.next_u64() % upper
+    }
+}
+
+/// Generates at least `size` bytes of commands. The same `seed` always results in the same commands.
+///
+/// Offsets and coordinates are chosen so that all pixels end up on the screen, as pixels outside of it take a shortcut
+/// in the parser.
+fn generate_commands(mix: CommandMix, seed: u64, size: usize) -> Vec<u8> {
+    let max_offset_x = FRAMEBUFFER_WIDTH as u64 / 2;
+    let max_offset_y = FRAMEBUFFER_HEIGHT as u64 / 2;
+
+    let mut rng = Rng(seed);
+    let mut client = Client::new(io::sink());
+    while client.buffered().len() < size {
+        let choice = rng.below(mix.total() as u64) as u32;
+        let x = rng.below(max_offset_x) as u16;
+        let y = rng.below(max_offset_y) as u16;
+        let color = rng.next_u64() as u32;
+
+        if choice < mix.rgb {
+            client.px(x, y, color)
+        } else if choice < mix.rgb + mix.rgba {
+            client.px_rgba(x, y, color)
+        } else if choice < mix.rgb + mix.rgba + mix.gray {
+            client.gg(x, y, color as u8)
+        } else if choice < mix.rgb + mix.rgba + mix.gray + mix.offset {
+            client.offset(
+                rng.below(max_offset_x) as u16,
+                rng.below(max_offset_y) as u16,
+            )
+        } else {
+            client.get_pixel(x, y)
+        };
+    }
+
+    client.buffered().to_vec()
+}
+
+fn mixed_commands(c: &mut Criterion) {
+    let fb = Arc::new(SimpleFrameBuffer::new(
+        FRAMEBUFFER_WIDTH,
+        FRAMEBUFFER_HEIGHT,
+    ));
+
+    let mut c_group = c.benchmark_group("parse_mixed_commands");
+    for (name, mix) in [
+        (
+            "rgb_only",
+            CommandMix {
+                rgb: 1,
+                rgba: 0,
+                gray: 0,
+                offset: 0,
+                read: 0,
+            },
+        ),
+        (
+            "realistic",
+            CommandMix {
+                rgb: 70,
+                rgba: 10,
+                gray: 15,
+                offset: 5,
+                read: 0,
+            },
+        ),
+        (
+            "offset_heavy",
+            CommandMix {
+                rgb: 50,
+                rgba: 0,
+                gray: 0,
+                offset: 50,
+                read: 0,
+            },
+        ),
+        (
+            // E.g. clients that only draw pixels which differ from the image they want to show
+            "read_heavy",
+            CommandMix {
+                rgb: 10,
+                rgba: 0,
+                gray: 0,
+                offset: 0,
+                read: 90,
+            },
+        ),
+    ] {
+        let commands = generate_commands(mix, SEED, COMMANDS_SIZE);
+
+        // Let criterion report MB/s
+        c_group.throughput(Throughput::Bytes(commands.len() as u64));
+        c_group.bench_with_input(name, &commands, |b, input| {
+            b.iter(|| OriginalParser::new(fb.clone()).parse(input, &mut Vec::new()));
+        });
+    }
+}
+
+criterion_group!(
+    name = parsing;
+    config = Criterion::default().warm_up_time(Duration::from_secs(1)).measurement_time(Duration::from_secs(3));
+    targets = mixed_commands
+);
+criterion_main!(parsing);
@@ -3,7 +3,8 @@ use std::{sync::Arc, time::Duration};
 #[cfg(target_arch = "x86_64")]
 use breakwater_parser::AssemblerParser;
 use breakwater_parser::{
-    MemchrParser, OriginalParser, Parser, RefactoredParser, SimpleFrameBuffer,
+    alpha_blend, alpha_blend_scalar, FrameBuffer, MemchrParser, OriginalParser, Parser,
+    RefactoredParser, SimpleFrameBuffer,
 };
 use criterion::{criterion_group, criterion_main, Criterion};
 use pixelbomber::image_handler::{self, ImageConfigBuilder};
@@ -102,10 +103,11 @@ fn invoke_benchmark(
         FRAMEBUFFER_HEIGHT,
     ));
 
-    let parser_names = vec!["original", "refactored" /*"memchr"*/];
+    #[cfg_attr(not(target_arch = "x86_64"), allow(unused_mut))]
+    let mut parser_names = vec!["original", "refactored" /*"memchr"*/];
 
-    // #[cfg(target_arch = "x86_64")]
-    // parser_names.push("assembler");
+    #[cfg(target_arch = "x86_64")]
+    parser_names.push("assembler");
 
     for parse_name in parser_names {
         c_group.bench_with_input(parse_name, &commands, |b, input| {
@@ -121,9 +123,59 @@ fn invoke_benchmark(
     }
 }
 
+fn compare_alpha_blend(c: &mut Criterion) {
+    // Some deterministic, but not too regular colors with all sorts of alpha values
+    let pixels = (0..FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT)
+        .map(|i| (i as u32).wrapping_mul(0x9e37_79b9))
+        .collect::<Vec<_>>();
+
+    let mut c_group = c.benchmark_group("alpha_blend");
+    c_group.bench_function("scalar", |b| {
+        b.iter(|| {
+            pixels
+                .windows(2)
+                .map(|pixels| alpha_blend_scalar(pixels[0] & 0x00ff_ffff, pixels[1]))
+                .fold(0, u32::wrapping_add)
+        })
+    });
+    c_group.bench_function("simd", |b| {
+        b.iter(|| {
+            pixels
+                .windows(2)
+                .map(|pixels| alpha_blend(pixels[0] & 0x00ff_ffff, pixels[1]))
+                .fold(0, u32::wrapping_add)
+        })
+    });
+}
+
+fn compare_set_batch(c: &mut Criterion) {
+    let fb = SimpleFrameBuffer::new(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT);
+    // A run of 1000 writes, spread somewhat randomly across the screen
+    let pixels = (0..1000_usize)
+        .map(|i| {
+            let hash = i.wrapping_mul(0x9e37_79b9);
+            (
+                hash % FRAMEBUFFER_WIDTH,
+                (hash >> 11) % FRAMEBUFFER_HEIGHT,
+                i as u32,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut c_group = c.benchmark_group("set_1000_pixels");
+    c_group.bench_function("set", |b| {
+        b.iter(|| {
+            for &(x, y, rgba) in &pixels {
+                fb.set(x, y, rgba);
+            }
+        })
+    });
+    c_group.bench_function("set_batch", |b| b.iter(|| fb.set_batch(&pixels)));
+}
+
 criterion_group!(
     name = parsing;
     config = Criterion::default().warm_up_time(Duration::from_secs(1)).measurement_time(Duration::from_secs(3));
-    targets = compare_implementations
+    targets = compare_implementations, compare_alpha_blend, compare_set_batch
 );
 criterion_main!(parsing);
@@ -1,37 +1,198 @@
-use std::{arch::asm, sync::Arc};
+use std::{
+    simd::{cmp::SimdPartialOrd, num::SimdUint, simd_swizzle, u16x8, u8x16},
+    sync::Arc,
+};
 
-use crate::{FrameBuffer, Parser};
+#[cfg(feature = "alpha")]
+use crate::alpha_blend;
+use crate::{
+    original::{simd_unhex, write_pixel_response, PX_PATTERN},
+    FrameBuffer, Parser,
+};
 
 const PARSER_LOOKAHEAD: usize = "PX 1234 1234 rrggbbaa\n".len(); // Longest possible command
 
+/// Maximum number of digits of a coordinate, same as in [`crate::OriginalParser`]
+const MAX_COORDINATE_DIGITS: usize = 4;
+
+/// Moves the digits of both coordinates into place, indexed by the number of digits of x and y (minus one). The digits
+/// of x end up right-aligned in the lanes 0..4, the digits of y right-aligned in the lanes 4..8. All other lanes
+/// are out of range, so that they are zeroed by the shuffle.
+const SHUFFLE_PATTERNS: [[u8x16; MAX_COORDINATE_DIGITS]; MAX_COORDINATE_DIGITS] =
+    shuffle_patterns();
+
+/// Value of every digit in the lanes produced by [`SHUFFLE_PATTERNS`]
+const DIGIT_WEIGHTS: u16x8 = u16x8::from_array([1000, 100, 10, 1, 1000, 100, 10, 1]);
+
+const fn shuffle_patterns() -> [[u8x16; MAX_COORDINATE_DIGITS]; MAX_COORDINATE_DIGITS] {
+    let mut patterns =
+        [[u8x16::from_array([0xff; 16]); MAX_COORDINATE_DIGITS]; MAX_COORDINATE_DIGITS];
+
+    let mut x_digits = 1;
+    while x_digits <= MAX_COORDINATE_DIGITS {
+        let mut y_digits = 1;
+        while y_digits <= MAX_COORDINATE_DIGITS {
+            let mut pattern = [0xff; 16];
+            let mut digit = 0;
+            while digit < x_digits {
+                pattern[MAX_COORDINATE_DIGITS - x_digits + digit] = digit as u8;
+                digit += 1;
+            }
+            // The digits of y start after the separator following x
+            digit = 0;
+            while digit < y_digits {
+                pattern[2 * MAX_COORDINATE_DIGITS - y_digits + digit] =
+                    (x_digits + 1 + digit) as u8;
+                digit += 1;
+            }
+
+            patterns[x_digits - 1][y_digits - 1] = u8x16::from_array(pattern);
+            y_digits += 1;
+        }
+        x_digits += 1;
+    }
+
+    patterns
+}
+
+/// Parses the coordinates `x y` at the start of the 16 bytes using SIMD. Returns x, y and the number of bytes the
+/// coordinates take up, or [`None`] in case one of the coordinates has no digits.
+///
+/// This behaves the same as [`crate::OriginalParser`]: Coordinates consist of up to 4 digits, the single byte
+/// following x is skipped.
+#[inline(always)]
+fn parse_coordinates(chunk: u8x16) -> Option<(usize, usize, usize)> {
+    // Non-digits wrap around, so that they are larger than 9
+    let digits = chunk - u8x16::splat(b'0');
+    let digit_mask = digits.simd_le(u8x16::splat(9)).to_bitmask();
+
+    let x_digits = (digit_mask.trailing_ones() as usize).min(MAX_COORDINATE_DIGITS);
+    let y_digits =
+        ((digit_mask >> (x_digits + 1)).trailing_ones() as usize).min(MAX_COORDINATE_DIGITS);
+    if x_digits == 0 || y_digits == 0 {
+        return None;
+    }
+
+    let shuffled = digits.swizzle_dyn(SHUFFLE_PATTERNS[x_digits - 1][y_digits - 1]);
+    let coordinates: u16x8 = simd_swizzle!(shuffled, [0, 1, 2, 3, 4, 5, 6, 7]).cast();
+    let weighted = (coordinates * DIGIT_WEIGHTS).to_array();
+
+    let x = weighted[0] + weighted[1] + weighted[2] + weighted[3];
+    let y = weighted[4] + weighted[5] + weighted[6] + weighted[7];
+    Some((x as usize, y as usize, x_digits + 1 + y_digits))
+}
+
+/// Parser only understanding `PX` commands (both setting and reading pixels), which parses the coordinates using SIMD
+/// shuffles instead of looking at every digit on its own. All other commands are ignored.
 pub struct AssemblerParser<FB: FrameBuffer> {
-    _fb: Arc<FB>,
+    fb: Arc<FB>,
+    pixels_drawn: u64,
 }
 
 impl<FB: FrameBuffer> AssemblerParser<FB> {
-    pub fn new(_fb: Arc<FB>) -> Self {
-        Self { _fb }
+    pub fn new(fb: Arc<FB>) -> Self {
+        Self {
+            fb,
+            pixels_drawn: 0,
+        }
+    }
+
+    /// Parses the coordinates and color of the `PX` command starting at `command_start` and draws (or reads) the pixel.
+    /// Returns the index of the newline terminating the command or [`None`] in case the command is invalid.
+    #[inline(always)]
+    fn parse_coords_and_rgba(
+        &mut self,
+        buffer: &[u8],
+        command_start: usize,
+        response: &mut Vec<u8>,
+    ) -> Option<usize> {
+        let coordinates_start = command_start + "PX ".len();
+        let chunk =
+            unsafe { (buffer.as_ptr().add(coordinates_start) as *const [u8; 16]).read_unaligned() };
+        let (x, y, coordinates_len) = parse_coordinates(u8x16::from_array(chunk))?;
+
+        let mut i = coordinates_start + coordinates_len;
+        let byte_at = |index: usize| unsafe { *buffer.get_unchecked(index) };
+
+        // Separator between coordinates and color
+        if byte_at(i) == b' ' {
+            i += 1;
+
+            // Must be followed by 6 bytes RGB and newline or ...
+            if byte_at(i + 6) == b'\n' {
+                let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i) });
+                self.fb.set(x, y, rgba & 0x00ff_ffff);
+                self.pixels_drawn += 1;
+                return Some(i + 6);
+            }
+
+            // ... or must be followed by 8 bytes RGBA and newline
+            if byte_at(i + 8) == b'\n' {
+                let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i) });
+                self.set_rgba(x, y, rgba);
+                self.pixels_drawn += 1;
+                return Some(i + 8);
+            }
+
+            // ... for the efficient/lazy clients
+            if byte_at(i + 2) == b'\n' {
+                let base = simd_unhex(unsafe { buffer.as_ptr().add(i) }) & 0xff;
+                self.fb.set(x, y, (base << 16) | (base << 8) | base);
+                self.pixels_drawn += 1;
+                return Some(i + 2);
+            }
+        }
+
+        // End of command to read Pixel value
+        if byte_at(i) == b'\n' {
+            if let Some(rgb) = self.fb.get(x, y) {
+                write_pixel_response(response, x, y, rgb);
+            }
+            return Some(i);
+        }
+
+        None
+    }
+
+    #[cfg(not(feature = "alpha"))]
+    #[inline(always)]
+    fn set_rgba(&self, x: usize, y: usize, rgba: u32) {
+        self.fb.set(x, y, rgba & 0x00ff_ffff);
+    }
+
+    #[cfg(feature = "alpha")]
+    #[inline(always)]
+    fn set_rgba(&self, x: usize, y: usize, rgba: u32) {
+        let alpha = (rgba >> 24) & 0xff;
+
+        if alpha == 0 || x >= self.fb.get_width() || y >= self.fb.get_height() {
+            return;
+        }
+
+        let current = unsafe { self.fb.get_unchecked(x, y) };
+        self.fb.set(x, y, alpha_blend(current, rgba));
     }
 }
 
 impl<FB: FrameBuffer> Parser for AssemblerParser<FB> {
-    fn parse(&mut self, buffer: &[u8], _response: &mut Vec<u8>) -> usize {
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
         let mut last_byte_parsed = 0;
 
-        // This loop does nothing and should be seen as a placeholder
-        unsafe {
-            asm!(
-                "mov {i}, {buffer_start}",
-                "2:",
-                "inc {last_byte_parsed}",
-                "inc {i}",
-                "cmp {i}, {buffer_end}",
-                "jl 2b",
-                buffer_start = in(reg) buffer.as_ptr(),
-                buffer_end = in(reg) buffer.as_ptr().add(buffer.len()),
-                last_byte_parsed = inout(reg) last_byte_parsed,
-                i = out(reg) _,
-            )
+        let mut i = 0;
+        let loop_end = buffer.len().saturating_sub(PARSER_LOOKAHEAD);
+
+        while i < loop_end {
+            let current_command =
+                unsafe { (buffer.as_ptr().add(i) as *const u64).read_unaligned() };
+            if current_command & 0x00ff_ffff == PX_PATTERN {
+                if let Some(command_end) = self.parse_coords_and_rgba(buffer, i, response) {
+                    last_byte_parsed = command_end;
+                    i = command_end + 1;
+                    continue;
+                }
+            }
+
+            i += 1;
         }
 
         last_byte_parsed
@@ -40,4 +201,152 @@ impl<FB: FrameBuffer> Parser for AssemblerParser<FB> {
     fn parser_lookahead(&self) -> usize {
         PARSER_LOOKAHEAD
     }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.pixels_drawn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{OriginalParser, SimpleFrameBuffer};
+
+    /// Small xorshift PRNG, so that the fuzzing is reproducible without pulling in a dependency
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, max: u64) -> u64 {
+            self.next() % max
+        }
+    }
+
+    /// Random valid `PX` command. The coordinates might have leading zeros or be outside of the canvas.
+    fn random_px_command(random: &mut Random) -> String {
+        let coordinate = |random: &mut Random| {
+            let digits = 1 + random.below(4) as usize;
+            let value = random.below(10_u64.pow(digits as u32));
+            if random.below(4) == 0 {
+                format!("{value:0digits$}")
+            } else {
+                value.to_string()
+            }
+        };
+        let x = coordinate(random);
+        let y = coordinate(random);
+
+        let color = random.next() as u32;
+        match random.below(4) {
+            0 => format!("PX {x} {y} {:06x}\n", color & 0x00ff_ffff),
+            1 => format!("PX {x} {y} {color:08x}\n"),
+            2 => format!("PX {x} {y} {:02x}\n", color & 0xff),
+            _ => format!("PX {x} {y}\n"),
+        }
+    }
+
+    /// Runs the input through the given parser, returns the framebuffer, the response, the index returned by the
+    /// parser and the number of pixels drawn
+    fn parse(
+        parser: impl FnOnce(Arc<SimpleFrameBuffer>) -> Box<dyn Parser>,
+        input: &str,
+    ) -> (Vec<u8>, Vec<u8>, usize, u64) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = parser(fb.clone());
+
+        let mut buffer = input.as_bytes().to_vec();
+        buffer.resize(buffer.len() + parser.parser_lookahead(), 0);
+        let mut response = Vec::new();
+        let last_byte_parsed = parser.parse(&buffer, &mut response);
+
+        (
+            fb.as_bytes().to_vec(),
+            response,
+            last_byte_parsed,
+            parser.pixels_drawn(),
+        )
+    }
+
+    fn assert_same_as_original_parser(input: &str) {
+        let (original_fb, original_response, original_last_byte, original_pixels) =
+            parse(|fb| Box::new(OriginalParser::new(fb)), input);
+        let (fb, response, last_byte, pixels) =
+            parse(|fb| Box::new(AssemblerParser::new(fb)), input);
+
+        assert!(fb == original_fb, "Framebuffers differ for input {input:?}");
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            String::from_utf8(original_response).unwrap(),
+            "Responses differ for input {input:?}"
+        );
+        assert_eq!(last_byte, original_last_byte, "Input {input:?}");
+        assert_eq!(pixels, original_pixels, "Input {input:?}");
+    }
+
+    #[rstest]
+    #[case(1, 1, [0xff, 0xff, 0xff, 0, 0xff, 0xff, 0xff, 2])]
+    #[case(4, 4, [0, 1, 2, 3, 5, 6, 7, 8])]
+    #[case(2, 3, [0xff, 0xff, 0, 1, 0xff, 3, 4, 5])]
+    fn test_shuffle_patterns(
+        #[case] x_digits: usize,
+        #[case] y_digits: usize,
+        #[case] expected: [u8; 8],
+    ) {
+        let pattern = SHUFFLE_PATTERNS[x_digits - 1][y_digits - 1].to_array();
+        assert_eq!(pattern[..8], expected);
+        assert_eq!(pattern[8..], [0xff; 8]);
+    }
+
+    #[rstest]
+    #[case("0 0\n", Some((0, 0, 3)))]
+    #[case("1234 5678\n", Some((1234, 5678, 9)))]
+    #[case("12 3 ffffff\n", Some((12, 3, 4)))]
+    #[case("0042 7 ff\n", Some((42, 7, 6)))]
+    // Same as the original parser, the single byte following x is skipped, no matter what it is
+    #[case("1x2\n", Some((1, 2, 3)))]
+    #[case("12345678\n", Some((1234, 678, 8)))]
+    #[case("12345 6\n", None)]
+    #[case(" 1 2\n", None)]
+    #[case("1 \n", None)]
+    #[case("a b\n", None)]
+    fn test_parse_coordinates(
+        #[case] input: &str,
+        #[case] expected: Option<(usize, usize, usize)>,
+    ) {
+        let mut chunk = [0; 16];
+        chunk[..input.len()].copy_from_slice(input.as_bytes());
+        assert_eq!(parse_coordinates(u8x16::from_array(chunk)), expected);
+    }
+
+    #[rstest]
+    #[case("PX 0 0 ff0000\nPX 1 0 00ff00\nPX 2 0 0000ff\nPX 0 0\nPX 1 0\nPX 2 0\n")]
+    #[case("PX 639 479 ffffff\nPX 640 479 ffffff\nPX 639 480\nPX 9999 9999 ab\n")]
+    #[case("PX 10 10 12345678\nPX 10 10 123456ff\nPX 10 10 12345600\nPX 10 10\n")]
+    #[case("PX 1 2 ff\nPX 1 2\n")]
+    // Invalid commands are skipped
+    #[case("PX 1 ffffff\nPX 1 2 fffff\nPX 1 2 ffffff\nPX\nPX 1 2\n")]
+    #[case("HELLO\nPX 1 2 ffffff\nPX 1 2\n")]
+    fn test_same_as_original_parser(#[case] input: &str) {
+        assert_same_as_original_parser(input);
+    }
+
+    #[test]
+    fn test_fuzz_against_original_parser() {
+        let mut random = Random(0x5eed_b4ea_4a7e_4000);
+        for _ in 0..200 {
+            let commands = 1 + random.below(100);
+            let input = (0..commands)
+                .map(|_| random_px_command(&mut random))
+                .collect::<String>();
+            assert_same_as_original_parser(&input);
+        }
+    }
 }
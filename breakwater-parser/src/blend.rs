@@ -0,0 +1,71 @@
+use std::simd::{num::SimdUint, u16x4, u8x4, Simd};
+
+const SIMD_1: Simd<u16, 4> = u16x4::from_array([1; 4]);
+const SIMD_8: Simd<u16, 4> = u16x4::from_array([8; 4]);
+
+/// Draws the color `rgba` (in the format `0xaabbggrr`) with its alpha channel on top of the pixel `current` (in the
+/// format of the framebuffer `0x00bbggrr`) and returns the resulting pixel.
+///
+/// All three color channels are blended at once using SIMD. The result is exactly the same as
+/// [`alpha_blend_scalar`] (including the rounding).
+#[inline(always)]
+pub fn alpha_blend(current: u32, rgba: u32) -> u32 {
+    let alpha = (rgba >> 24) as u16;
+
+    let current: Simd<u16, 4> = u8x4::from_array(current.to_le_bytes()).cast();
+    let new: Simd<u16, 4> = u8x4::from_array(rgba.to_le_bytes()).cast();
+
+    // Max value is 0xff * 0xff, so we don't overflow the u16
+    let blended = current * u16x4::splat(0xff - alpha) + new * u16x4::splat(alpha);
+    // This is the same as `blended / 0xff` (for all values up to 0xff * 0xff), but we don't need a division
+    let blended = (blended + SIMD_1 + (blended >> SIMD_8)) >> SIMD_8;
+
+    u32::from_le_bytes(blended.cast::<u8>().to_array()) & 0x00ff_ffff
+}
+
+/// Scalar version of [`alpha_blend`], which blends every color channel on its own.
+#[inline(always)]
+pub fn alpha_blend_scalar(current: u32, rgba: u32) -> u32 {
+    let alpha = (rgba >> 24) & 0xff;
+    let alpha_comp = 0xff - alpha;
+
+    let r = ((current & 0xff) * alpha_comp + (rgba & 0xff) * alpha) / 0xff;
+    let g = (((current >> 8) & 0xff) * alpha_comp + ((rgba >> 8) & 0xff) * alpha) / 0xff;
+    let b = (((current >> 16) & 0xff) * alpha_comp + ((rgba >> 16) & 0xff) * alpha) / 0xff;
+
+    (b << 16) | (g << 8) | r
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0x0000_0000, 0x88ef_cdab, 0x007f_6d5b)]
+    #[case(0x00ff_ffff, 0x0000_0000, 0x00ff_ffff)]
+    #[case(0x00ff_ffff, 0xff00_0000, 0x0000_0000)]
+    #[case(0x0000_00ff, 0x80ff_0000, 0x0080_007f)]
+    #[case(0x0012_3456, 0x7f65_4321, 0x003b_3b3b)]
+    fn test_alpha_blend(#[case] current: u32, #[case] rgba: u32, #[case] expected: u32) {
+        assert_eq!(alpha_blend_scalar(current, rgba), expected);
+        assert_eq!(alpha_blend(current, rgba), expected);
+    }
+
+    #[test]
+    fn test_simd_matches_scalar() {
+        for current in [0x0000_0000, 0x00ff_ffff, 0x0012_3456, 0x00ab_cdef] {
+            for color in (0..=0xff_ffff).step_by(0x01_0305) {
+                for alpha in 0..=0xff {
+                    let rgba = (alpha << 24) | color;
+                    assert_eq!(
+                        alpha_blend(current, rgba),
+                        alpha_blend_scalar(current, rgba),
+                        "current: {current:#010x}, rgba: {rgba:#010x}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Width and height of the tiles the changes are tracked in
+pub const DIRTY_TILE_SIZE: usize = 64;
+
+/// Area of the framebuffer that changed since the last call to [`DirtyTiles::take_dirty_regions`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Keeps track of the tiles of [`DIRTY_TILE_SIZE`]x[`DIRTY_TILE_SIZE`] pixels that were written to, so that consumers
+/// (such as sinks) only need to copy the changed areas.
+///
+/// Every tile is a single bit in an atomic bitmap, so marking tiles does not need any locking.
+///
+/// As [`DirtyTiles::take_dirty_regions`] resets the bitmap, it only works for a single consumer. Additional consumers
+/// can use [`DirtyTiles::advance_epoch`] and [`DirtyTiles::changed_tiles_since`] instead, which rely on the epoch every
+/// tile was last written in.
+pub struct DirtyTiles {
+    width: usize,
+    height: usize,
+    tiles_per_row: usize,
+    tile_rows: usize,
+    bitmap: Vec<AtomicU64>,
+    epoch: AtomicU64,
+    tile_epochs: Vec<AtomicU64>,
+}
+
+impl DirtyTiles {
+    pub fn new(width: usize, height: usize) -> Self {
+        let tiles_per_row = width.div_ceil(DIRTY_TILE_SIZE);
+        let tile_rows = height.div_ceil(DIRTY_TILE_SIZE);
+
+        Self {
+            width,
+            height,
+            tiles_per_row,
+            tile_rows,
+            bitmap: (0..(tiles_per_row * tile_rows).div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            // Tiles that were never written to are in epoch 0, which is never returned by `advance_epoch`
+            epoch: AtomicU64::new(1),
+            tile_epochs: (0..tiles_per_row * tile_rows)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    /// Marks the tile containing the pixel as dirty. Needs to be called *after* the pixel was written, so that
+    /// consumers never see the tile without the change.
+    #[inline(always)]
+    pub fn mark(&self, x: usize, y: usize) {
+        self.mark_tile(x / DIRTY_TILE_SIZE + (y / DIRTY_TILE_SIZE) * self.tiles_per_row);
+    }
+
+    /// Marks all tiles containing the given number of pixels starting at the index (counted row by row) as dirty, see
+    /// [`crate::FrameBuffer::set_multi_from_start_index`].
+    pub fn mark_range(&self, starting_index: usize, num_pixels: usize) {
+        if num_pixels == 0 {
+            return;
+        }
+        let end_index = starting_index + num_pixels - 1;
+        let (first_row, last_row) = (starting_index / self.width, end_index / self.width);
+
+        for tile_y in first_row / DIRTY_TILE_SIZE..=last_row / DIRTY_TILE_SIZE {
+            let rows_start = first_row.max(tile_y * DIRTY_TILE_SIZE);
+            let rows_end = last_row.min((tile_y + 1) * DIRTY_TILE_SIZE - 1);
+
+            // In case the range wraps into the next row within this tile row we simply mark the whole tile row, as
+            // the range most likely spans (nearly) the full width anyway
+            let (first_x, last_x) = if rows_start == rows_end {
+                (
+                    if rows_start == first_row {
+                        starting_index % self.width
+                    } else {
+                        0
+                    },
+                    if rows_end == last_row {
+                        end_index % self.width
+                    } else {
+                        self.width - 1
+                    },
+                )
+            } else {
+                (0, self.width - 1)
+            };
+
+            let tile_row_start = tile_y * self.tiles_per_row;
+            for tile_x in first_x / DIRTY_TILE_SIZE..=last_x / DIRTY_TILE_SIZE {
+                self.mark_tile(tile_row_start + tile_x);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn mark_tile(&self, tile: usize) {
+        self.bitmap[tile / 64].fetch_or(1 << (tile % 64), Ordering::Release);
+
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let tile_epoch = &self.tile_epochs[tile];
+        // Most writes hit a tile that was already written to in this epoch, they don't need to write the cache line
+        if tile_epoch.load(Ordering::Relaxed) < epoch {
+            tile_epoch.fetch_max(epoch, Ordering::Release);
+        }
+    }
+
+    /// Ends the current epoch and returns it. Consumers call this right before reading a frame and later pass the
+    /// returned epoch to [`Self::changed_tiles_since`] to get the tiles that changed since that frame.
+    pub fn advance_epoch(&self) -> u64 {
+        self.epoch.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Returns the tiles written to in the given epoch or later, clipped to the framebuffer. Pass the epoch returned by
+    /// [`Self::advance_epoch`] before reading the previous frame. Tiles written to right before that epoch ended are
+    /// returned again, as the pixels might not have been visible when the previous frame was read.
+    ///
+    /// Other than [`Self::take_dirty_regions`] this does not reset anything, so there can be any number of consumers.
+    pub fn changed_tiles_since(&self, epoch: u64) -> impl Iterator<Item = DirtyRegion> + '_ {
+        self.tile_epochs
+            .iter()
+            .enumerate()
+            .filter(move |(_, tile_epoch)| tile_epoch.load(Ordering::Acquire) >= epoch)
+            .map(|(tile, _)| {
+                let x = (tile % self.tiles_per_row) * DIRTY_TILE_SIZE;
+                let y = (tile / self.tiles_per_row) * DIRTY_TILE_SIZE;
+                DirtyRegion {
+                    x,
+                    y,
+                    width: DIRTY_TILE_SIZE.min(self.width - x),
+                    height: DIRTY_TILE_SIZE.min(self.height - y),
+                }
+            })
+    }
+
+    /// Returns all areas that changed since the last call and resets them. Adjacent dirty tiles in a row are merged
+    /// into a single region, regions are clipped to the framebuffer.
+    pub fn take_dirty_regions(&self) -> Vec<DirtyRegion> {
+        let bitmap = self
+            .bitmap
+            .iter()
+            .map(|word| word.swap(0, Ordering::Acquire))
+            .collect::<Vec<_>>();
+        let is_dirty = |tile: usize| bitmap[tile / 64] & (1 << (tile % 64)) != 0;
+
+        let mut regions = Vec::new();
+        for tile_y in 0..self.tile_rows {
+            let y = tile_y * DIRTY_TILE_SIZE;
+            let height = DIRTY_TILE_SIZE.min(self.height - y);
+
+            let mut tile_x = 0;
+            while tile_x < self.tiles_per_row {
+                if !is_dirty(tile_y * self.tiles_per_row + tile_x) {
+                    tile_x += 1;
+                    continue;
+                }
+
+                let run_start = tile_x;
+                while tile_x < self.tiles_per_row && is_dirty(tile_y * self.tiles_per_row + tile_x)
+                {
+                    tile_x += 1;
+                }
+
+                let x = run_start * DIRTY_TILE_SIZE;
+                regions.push(DirtyRegion {
+                    x,
+                    y,
+                    width: (tile_x * DIRTY_TILE_SIZE).min(self.width) - x,
+                    height,
+                });
+            }
+        }
+
+        regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> DirtyRegion {
+        DirtyRegion {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_nothing_dirty() {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        assert_eq!(dirty_tiles.take_dirty_regions(), []);
+    }
+
+    #[test]
+    fn test_take_resets_regions() {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        dirty_tiles.mark(0, 0);
+        assert_eq!(dirty_tiles.take_dirty_regions(), [region(0, 0, 64, 64)]);
+        assert_eq!(dirty_tiles.take_dirty_regions(), []);
+    }
+
+    #[test]
+    fn test_adjacent_tiles_are_merged() {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        dirty_tiles.mark(70, 10);
+        dirty_tiles.mark(130, 63);
+        dirty_tiles.mark(300, 0);
+        dirty_tiles.mark(70, 64);
+        assert_eq!(
+            dirty_tiles.take_dirty_regions(),
+            [
+                region(64, 0, 128, 64),
+                region(256, 0, 64, 64),
+                region(64, 64, 64, 64)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regions_are_clipped_to_the_framebuffer() {
+        let dirty_tiles = DirtyTiles::new(100, 70);
+        dirty_tiles.mark(99, 69);
+        assert_eq!(dirty_tiles.take_dirty_regions(), [region(64, 64, 36, 6)]);
+    }
+
+    #[test]
+    fn test_take_does_not_affect_epochs() {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        let epoch = dirty_tiles.advance_epoch();
+        dirty_tiles.mark(0, 0);
+        assert_eq!(dirty_tiles.take_dirty_regions(), [region(0, 0, 64, 64)]);
+        assert_eq!(
+            dirty_tiles.changed_tiles_since(epoch).collect::<Vec<_>>(),
+            [region(0, 0, 64, 64)]
+        );
+    }
+
+    #[rstest]
+    #[case::nothing(&[], &[])]
+    #[case::single_pixel(&[(0, 0)], &[region(0, 0, 64, 64)])]
+    #[case::same_tile(&[(1, 2), (63, 63), (10, 40)], &[region(0, 0, 64, 64)])]
+    #[case::adjacent_tiles(&[(63, 0), (64, 0)], &[region(0, 0, 64, 64), region(64, 0, 64, 64)])]
+    #[case::tiles_are_not_merged(&[(70, 10), (130, 63), (300, 0), (70, 64)], &[
+        region(64, 0, 64, 64),
+        region(128, 0, 64, 64),
+        region(256, 0, 64, 64),
+        region(64, 64, 64, 64),
+    ])]
+    #[case::clipped(&[(639, 479), (600, 0)], &[region(576, 0, 64, 64), region(576, 448, 64, 32)])]
+    fn test_changed_tiles_since(
+        #[case] changed_pixels: &[(usize, usize)],
+        #[case] expected: &[DirtyRegion],
+    ) {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        // Already part of the frame before the last one
+        dirty_tiles.mark(200, 200);
+        dirty_tiles.advance_epoch();
+        let last_frame = dirty_tiles.advance_epoch();
+        for &(x, y) in changed_pixels {
+            dirty_tiles.mark(x, y);
+        }
+
+        let changed_tiles = dirty_tiles
+            .changed_tiles_since(last_frame)
+            .collect::<Vec<_>>();
+        assert_eq!(changed_tiles, expected);
+        // The changed tiles contain exactly the changed pixels of the canvas
+        for x in 0..640 {
+            for y in 0..480 {
+                let changed = changed_tiles.iter().any(|tile| {
+                    (tile.x..tile.x + tile.width).contains(&x)
+                        && (tile.y..tile.y + tile.height).contains(&y)
+                });
+                let tile_of_changed_pixel = changed_pixels.iter().any(|&(changed_x, changed_y)| {
+                    changed_x / DIRTY_TILE_SIZE == x / DIRTY_TILE_SIZE
+                        && changed_y / DIRTY_TILE_SIZE == y / DIRTY_TILE_SIZE
+                });
+                assert_eq!(changed, tile_of_changed_pixel, "pixel {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_changes_are_returned_for_two_frames() {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        let changed_tiles_since =
+            |epoch| dirty_tiles.changed_tiles_since(epoch).collect::<Vec<_>>();
+
+        let first_frame = dirty_tiles.advance_epoch();
+        dirty_tiles.mark(0, 0);
+        let second_frame = dirty_tiles.advance_epoch();
+        dirty_tiles.mark(100, 100);
+        let third_frame = dirty_tiles.advance_epoch();
+
+        assert_eq!(
+            changed_tiles_since(first_frame),
+            [region(0, 0, 64, 64), region(64, 64, 64, 64)]
+        );
+        // The first tile was written to right before the second frame, so it's returned again
+        assert_eq!(
+            changed_tiles_since(second_frame),
+            [region(0, 0, 64, 64), region(64, 64, 64, 64)]
+        );
+        assert_eq!(changed_tiles_since(third_frame), [region(64, 64, 64, 64)]);
+        assert_eq!(changed_tiles_since(dirty_tiles.advance_epoch()), []);
+    }
+
+    #[rstest]
+    #[case::single_pixel(0, 1, &[region(0, 0, 64, 64)])]
+    #[case::within_row(60, 10, &[region(0, 0, 128, 64)])]
+    #[case::end_of_row(639, 1, &[region(576, 0, 64, 64)])]
+    #[case::wrapping_row(639, 2, &[region(0, 0, 640, 64)])]
+    #[case::second_tile_row(64 * 640 + 200, 10, &[region(192, 64, 64, 64)])]
+    #[case::wrapping_tile_row(63 * 640 + 600, 50, &[region(576, 0, 64, 64), region(0, 64, 64, 64)])]
+    #[case::multiple_tile_rows(63 * 640, 3 * 640, &[region(0, 0, 640, 64), region(0, 64, 640, 64)])]
+    #[case::everything(0, 640 * 480, &(0..8).map(|tile_y| region(0, tile_y * 64, 640, 64.min(480 - tile_y * 64))).collect::<Vec<_>>())]
+    fn test_mark_range(
+        #[case] starting_index: usize,
+        #[case] num_pixels: usize,
+        #[case] expected: &[DirtyRegion],
+    ) {
+        let dirty_tiles = DirtyTiles::new(640, 480);
+        dirty_tiles.mark_range(starting_index, num_pixels);
+        assert_eq!(dirty_tiles.take_dirty_regions(), expected);
+    }
+}
@@ -1,5 +1,20 @@
+pub mod dirty;
+pub mod packed24;
+pub mod recording;
+pub mod resizable;
+pub mod rgb565;
+pub mod serialized;
+#[cfg(feature = "shared-memory")]
+pub mod shared_memory;
 pub mod simple;
+pub mod tiled;
 
+use std::borrow::Cow;
+
+use dirty::DirtyRegion;
+
+/// Stores the pixels in the format `0x00bbggrr`, so in memory every pixel is `r, g, b, 0` (`rgb0`). The fourth byte is
+/// not used to store any alpha value, see [`FrameBuffer::set_multi`].
 pub trait FrameBuffer {
     fn get_width(&self) -> usize;
 
@@ -22,8 +37,38 @@ pub trait FrameBuffer {
     /// make sure x and y are in bounds
     unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32;
 
+    /// Stores `rgba` as-is, it's up to the caller to only pass pixels in the format `0x00bbggrr`.
     fn set(&self, x: usize, y: usize, rgba: u32);
 
+    /// Sets the pixel to `rgba`, but only in case it currently is `expected`. Returns whether the pixel was set.
+    ///
+    /// The default implementation is not atomic: a write happening between reading and setting the pixel is
+    /// overwritten.
+    fn compare_and_set(&self, x: usize, y: usize, expected: u32, rgba: u32) -> bool {
+        if self.get(x, y) != Some(expected) {
+            return false;
+        }
+        self.set(x, y, rgba);
+        true
+    }
+
+    /// Sets a batch of `(x, y, rgba)` pixels, which has the same effect as calling [`FrameBuffer::set`] for every
+    /// pixel in order. Framebuffers can override this to e.g. only check the bounds once per batch.
+    #[inline(always)]
+    fn set_batch(&self, pixels: &[(usize, usize, u32)]) {
+        for &(x, y, rgba) in pixels {
+            self.set(x, y, rgba);
+        }
+    }
+
+    /// The fourth (alpha) byte of every pixel is cleared, so pixels written using `PXMULTI` are stored exactly the same
+    /// as pixels drawn using `PX`. This way exporting the framebuffer (e.g. using [`FrameBuffer::as_bytes`]),
+    /// importing it on another server using `PXMULTI` and reading it back is lossless for the color channels.
+    ///
+    /// With the `alpha` feature `PXMULTI` uses [`FrameBuffer::blend_multi_from_start_index`] instead, so the exported
+    /// pixels need an alpha of `ff` (as sent by e.g. `PG` and `GETRECT`). The alpha of `00` exported by
+    /// [`FrameBuffer::as_bytes`] would not draw anything.
+    ///
     /// We can *not* take an `&[u32]` for the pixel here, as `std::slice::from_raw_parts` requires the data to be
     /// aligned. As the data already is stored in a buffer we can not guarantee it's correctly aligned, so let's just
     /// treat the pixels as raw bytes.
@@ -40,10 +85,112 @@ pub trait FrameBuffer {
         (new_x, new_y)
     }
 
-    /// Returns the number of pixels copied
+    /// Returns the number of pixels copied. Clears the alpha byte, see [`FrameBuffer::set_multi`].
     fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize;
 
+    /// Like [`FrameBuffer::set_multi_from_start_index`], but draws every pixel with its alpha channel on top of the
+    /// current pixel (see [`crate::alpha_blend`]), the same way `PX x y rrggbbaa` does. As every pixel is read and
+    /// written on its own this is way slower than copying the pixels.
+    ///
+    /// Returns the number of pixels drawn, which is 0 in case the pixels would exceed the screen
+    #[cfg(feature = "alpha")]
+    fn blend_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = pixels.len() / 4;
+        if starting_index + num_pixels > self.get_size() {
+            return 0;
+        }
+
+        let width = self.get_width();
+        for (index, pixel) in (starting_index..).zip(pixels.chunks_exact(4)) {
+            let (x, y) = (index % width, index / width);
+            let rgba = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            let current = unsafe { self.get_unchecked(x, y) };
+            self.set(x, y, crate::alpha_blend(current, rgba));
+        }
+
+        num_pixels
+    }
+
+    /// Like [`FrameBuffer::set_multi_from_start_index`], but instead of dropping writes that would exceed the screen,
+    /// it writes as many pixels as fit and ignores the rest.
+    ///
+    /// Returns the number of pixels copied
+    #[inline(always)]
+    fn set_multi_clamped(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let fitting_pixels = self.get_size().saturating_sub(starting_index);
+        let num_pixels = (pixels.len() / 4).min(fitting_pixels);
+
+        self.set_multi_from_start_index(starting_index, &pixels[..num_pixels * 4])
+    }
+
+    /// Number of bytes every pixel takes up in [`FrameBuffer::as_bytes`]
+    #[inline(always)]
+    fn bytes_per_pixel(&self) -> usize {
+        4
+    }
+
+    /// The raw pixels as stored by the framebuffer, see [`FrameBuffer::bytes_per_pixel`] for their size
     fn as_bytes(&self) -> &[u8];
 
+    /// # Panics
+    /// Framebuffers that don't store every pixel as `u32` (see [`FrameBuffer::bytes_per_pixel`]) panic
     fn as_pixels(&self) -> &[u32];
+
+    /// The pixels as `rgb0` (4 bytes per pixel), which most consumers (such as sinks) expect. Framebuffers storing the
+    /// pixels in another format (see [`FrameBuffer::bytes_per_pixel`]) are converted pixel by pixel.
+    fn as_rgb0_bytes(&self) -> Cow<'_, [u8]> {
+        if self.bytes_per_pixel() == 4 {
+            return Cow::Borrowed(self.as_bytes());
+        }
+
+        Cow::Owned(
+            (0..self.get_height())
+                .flat_map(|y| (0..self.get_width()).map(move |x| (x, y)))
+                .flat_map(|(x, y)| unsafe { self.get_unchecked(x, y) }.to_ne_bytes())
+                .collect(),
+        )
+    }
+
+    /// Returns the areas that changed since the last call, so that consumers only need to copy those. [`None`] means
+    /// the framebuffer does not track changes, so everything needs to be considered as changed.
+    ///
+    /// As the dirty regions are reset on every call, there should only be a single consumer calling this.
+    fn take_dirty_regions(&self) -> Option<Vec<DirtyRegion>> {
+        None
+    }
+
+    /// Ends the current epoch of the change tracking and returns it, see [`dirty::DirtyTiles::advance_epoch`]. Call it
+    /// right before reading a frame. [`None`] means the framebuffer does not track changes.
+    fn advance_dirty_epoch(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the tiles that changed since the frame read after the given epoch ended, see
+    /// [`dirty::DirtyTiles::changed_tiles_since`]. [`None`] means the framebuffer does not track changes, so
+    /// everything needs to be considered as changed.
+    ///
+    /// Other than [`FrameBuffer::take_dirty_regions`] this can be used by any number of consumers.
+    fn changed_tiles_since(&self, _epoch: u64) -> Option<Vec<DirtyRegion>> {
+        None
+    }
+}
+
+/// Draws the pixels of a `PXMULTI` command: Blends them in case the `alpha` feature is enabled, otherwise copies them
+/// (ignoring their alpha byte). Returns the number of pixels drawn.
+#[cfg(feature = "binary-sync-pixels")]
+#[inline(always)]
+pub(crate) fn sync_pixels<FB: FrameBuffer>(fb: &FB, starting_index: usize, pixels: &[u8]) -> usize {
+    #[cfg(feature = "alpha")]
+    return fb.blend_multi_from_start_index(starting_index, pixels);
+    #[cfg(not(feature = "alpha"))]
+    return fb.set_multi_from_start_index(starting_index, pixels);
+}
+
+/// Copies the raw `pixels` into `target`, clearing the fourth (alpha) byte of every pixel. Kept as a simple loop, so
+/// that the compiler can vectorize it.
+#[inline(always)]
+pub(crate) fn copy_pixels_without_alpha(target: &mut [u32], pixels: &[u8]) {
+    for (target, pixel) in target.iter_mut().zip(pixels.chunks_exact(4)) {
+        *target = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], 0]);
+    }
 }
@@ -0,0 +1,169 @@
+use core::slice;
+
+use super::FrameBuffer;
+
+/// Stores every pixel using only 3 bytes (`r, g, b`), which saves a quarter of the memory compared to the other
+/// framebuffers for huge canvases. The alpha byte is discarded anyway, so colors are not changed.
+///
+/// As the pixels are not stored as `rgb0`, consumers need to look at [`FrameBuffer::bytes_per_pixel`] when using
+/// [`FrameBuffer::as_bytes`] or convert them using [`FrameBuffer::as_rgb0_bytes`].
+pub struct Packed24FrameBuffer {
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+}
+
+impl Packed24FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            buffer: vec![0; width * height * 3],
+        }
+    }
+}
+
+impl FrameBuffer for Packed24FrameBuffer {
+    #[inline(always)]
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    #[inline(always)]
+    fn get_height(&self) -> usize {
+        self.height
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        let index = (x + y * self.width) * 3;
+        u32::from_le_bytes([
+            *self.buffer.get_unchecked(index),
+            *self.buffer.get_unchecked(index + 1),
+            *self.buffer.get_unchecked(index + 2),
+            0,
+        ])
+    }
+
+    #[inline(always)]
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        if x < self.width && y < self.height {
+            let [r, g, b, _] = rgba.to_le_bytes();
+            unsafe {
+                let ptr = self.buffer.as_ptr().add((x + y * self.width) * 3) as *mut u8;
+                *ptr = r;
+                *ptr.add(1) = g;
+                *ptr.add(2) = b;
+            }
+        }
+    }
+
+    /// The pixels are still passed as 4 bytes (`rgba`), the alpha byte is dropped
+    #[inline(always)]
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = pixels.len() / 4;
+
+        if starting_index + num_pixels > self.get_size() {
+            // We did not move
+            return 0;
+        }
+
+        let starting_ptr = unsafe { self.buffer.as_ptr().add(starting_index * 3) };
+        let target_slice =
+            unsafe { slice::from_raw_parts_mut(starting_ptr as *mut u8, num_pixels * 3) };
+        for (target, pixel) in target_slice.chunks_exact_mut(3).zip(pixels.chunks_exact(4)) {
+            target.copy_from_slice(&pixel[..3]);
+        }
+
+        num_pixels
+    }
+
+    #[inline(always)]
+    fn bytes_per_pixel(&self) -> usize {
+        3
+    }
+
+    /// The pixels as `rgb` (3 bytes per pixel)
+    #[inline(always)]
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// # Panics
+    /// The pixels are not stored as `u32`, use [`FrameBuffer::as_bytes`] or [`FrameBuffer::as_rgb0_bytes`] instead
+    fn as_pixels(&self) -> &[u32] {
+        panic!("The packed 24 bit framebuffer does not store pixels as u32, use as_bytes instead");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rstest::{fixture, rstest};
+
+    #[fixture]
+    fn fb() -> Packed24FrameBuffer {
+        Packed24FrameBuffer::new(640, 480)
+    }
+
+    #[rstest]
+    #[case(0x000000, 0x000000)]
+    #[case(0xffffff, 0xffffff)]
+    #[case(0x0000ff, 0x0000ff)]
+    #[case(0x00ff00, 0x00ff00)]
+    #[case(0xff0000, 0xff0000)]
+    #[case(0x563412, 0x563412)]
+    // The alpha byte is dropped
+    #[case(0x12ffffff, 0xffffff)]
+    fn test_roundtrip(fb: Packed24FrameBuffer, #[case] rgba: u32, #[case] expected: u32) {
+        fb.set(42, 13, rgba);
+        assert_eq!(fb.get(42, 13), Some(expected));
+
+        // Neighbouring pixels are not touched
+        assert_eq!(fb.get(41, 13), Some(0));
+        assert_eq!(fb.get(43, 13), Some(0));
+    }
+
+    #[rstest]
+    fn test_out_of_bounds(fb: Packed24FrameBuffer) {
+        fb.set(640, 0, 0xffffff);
+        fb.set(0, 480, 0xffffff);
+        assert_eq!(fb.get(640, 0), None);
+        assert!(fb.as_bytes().iter().all(|&byte| byte == 0));
+    }
+
+    #[rstest]
+    fn test_set_multi(fb: Packed24FrameBuffer) {
+        // The pixels are passed as rgba, the alpha byte is truncated
+        let pixels = [0xffffffff_u32, 0x1200ff00, 0x00563412];
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+
+        assert_eq!(fb.set_multi_from_start_index(638, &pixel_bytes), 3);
+        assert_eq!(fb.get(637, 0), Some(0));
+        assert_eq!(fb.get(638, 0), Some(0xffffff));
+        assert_eq!(fb.get(639, 0), Some(0x00ff00));
+        assert_eq!(fb.get(0, 1), Some(0x563412));
+        assert_eq!(fb.get(1, 1), Some(0));
+
+        // Would exceed the screen
+        assert_eq!(
+            fb.set_multi_from_start_index(fb.get_size() - 1, &pixel_bytes),
+            0
+        );
+    }
+
+    #[rstest]
+    fn test_as_bytes(fb: Packed24FrameBuffer) {
+        assert_eq!(fb.bytes_per_pixel(), 3);
+        assert_eq!(fb.as_bytes().len(), 640 * 480 * 3);
+
+        fb.set(1, 0, 0x563412);
+        assert_eq!(fb.as_bytes()[..6], [0, 0, 0, 0x12, 0x34, 0x56]);
+
+        // Sinks expecting rgb0 get the pixels converted
+        let rgb0 = fb.as_rgb0_bytes();
+        assert_eq!(rgb0.len(), 640 * 480 * 4);
+        assert_eq!(rgb0[..8], [0, 0, 0, 0, 0x12, 0x34, 0x56, 0]);
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use super::{dirty::DirtyRegion, FrameBuffer};
+
+const RECORD_TYPE_PIXEL: u8 = 0;
+const RECORD_TYPE_PIXELS: u8 = 1;
+
+/// A single write to the framebuffer as stored in the journal. All numbers are stored as little endian `u32`:
+///
+/// * [`JournalRecord::Pixel`]: `0`, `x`, `y`, `rgba` (13 bytes)
+/// * [`JournalRecord::Pixels`]: `1`, `starting_index`, number of pixels, followed by the pixels in the same format as
+///   passed to [`FrameBuffer::set_multi_from_start_index`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalRecord {
+    Pixel {
+        x: u32,
+        y: u32,
+        rgba: u32,
+    },
+    Pixels {
+        starting_index: u32,
+        pixels: Vec<u8>,
+    },
+}
+
+impl JournalRecord {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            JournalRecord::Pixel { x, y, rgba } => {
+                writer.write_all(&[RECORD_TYPE_PIXEL])?;
+                writer.write_all(&x.to_le_bytes())?;
+                writer.write_all(&y.to_le_bytes())?;
+                writer.write_all(&rgba.to_le_bytes())
+            }
+            JournalRecord::Pixels {
+                starting_index,
+                pixels,
+            } => {
+                writer.write_all(&[RECORD_TYPE_PIXELS])?;
+                writer.write_all(&starting_index.to_le_bytes())?;
+                writer.write_all(&((pixels.len() / 4) as u32).to_le_bytes())?;
+                writer.write_all(pixels)
+            }
+        }
+    }
+
+    /// Reads the next record, returns [`None`] once the end of the journal is reached
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut record_type = [0];
+        match reader.read_exact(&mut record_type) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        match record_type[0] {
+            RECORD_TYPE_PIXEL => Ok(Some(JournalRecord::Pixel {
+                x: read_u32(reader)?,
+                y: read_u32(reader)?,
+                rgba: read_u32(reader)?,
+            })),
+            RECORD_TYPE_PIXELS => {
+                let starting_index = read_u32(reader)?;
+                let mut pixels = vec![0; read_u32(reader)? as usize * 4];
+                reader.read_exact(&mut pixels)?;
+                Ok(Some(JournalRecord::Pixels {
+                    starting_index,
+                    pixels,
+                }))
+            }
+            unknown => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown journal record type {unknown}"),
+            )),
+        }
+    }
+
+    /// Applies the write to the given framebuffer, e.g. to replay a journal
+    pub fn apply<FB: FrameBuffer>(&self, fb: &FB) {
+        match self {
+            JournalRecord::Pixel { x, y, rgba } => fb.set(*x as usize, *y as usize, *rgba),
+            JournalRecord::Pixels {
+                starting_index,
+                pixels,
+            } => {
+                fb.set_multi_from_start_index(*starting_index as usize, pixels);
+            }
+        }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+enum JournalMessage {
+    Record(JournalRecord),
+    /// Answered once all records queued before have been written
+    Flush(SyncSender<io::Result<()>>),
+}
+
+/// Forwards all writes to the wrapped framebuffer and additionally appends them to a journal (see [`JournalRecord`]),
+/// e.g. to create replays. Only writes that landed on the canvas are recorded.
+///
+/// The records are handed to a writer thread through an unbounded channel, so the parsers never wait for the journal.
+/// The writer thread batches the records and only flushes the journal once there are no records queued.
+pub struct RecordingFrameBuffer<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    records_tx: Sender<JournalMessage>,
+}
+
+impl<FB: FrameBuffer> RecordingFrameBuffer<FB> {
+    /// Starts the writer thread, which stops once the returned framebuffer is dropped
+    pub fn new(fb: Arc<FB>, journal: impl Write + Send + 'static) -> Self {
+        let (records_tx, records_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("breakwater-journal".to_owned())
+            .spawn(move || write_journal(BufWriter::new(journal), records_rx))
+            .expect("failed to spawn the thread writing the journal");
+
+        Self { fb, records_tx }
+    }
+
+    /// Blocks until all records queued so far have been written to the journal. Returns the first error writing the
+    /// journal, in which case no further records have been written.
+    pub fn flush(&self) -> io::Result<()> {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        self.records_tx
+            .send(JournalMessage::Flush(done_tx))
+            .ok()
+            .and_then(|_| done_rx.recv().ok())
+            .unwrap_or_else(|| {
+                Err(io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "the thread writing the journal is gone",
+                ))
+            })
+    }
+
+    #[inline(always)]
+    fn record(&self, record: JournalRecord) {
+        // Only fails in case the writer thread is gone, in which case there is nothing we can do about it
+        let _ = self.records_tx.send(JournalMessage::Record(record));
+    }
+}
+
+fn write_journal(mut journal: impl Write, records_rx: Receiver<JournalMessage>) {
+    let mut error = None;
+    while let Ok(message) = records_rx.recv() {
+        handle_journal_message(&mut journal, &mut error, message);
+        // Write everything that's already queued before flushing
+        while let Ok(message) = records_rx.try_recv() {
+            handle_journal_message(&mut journal, &mut error, message);
+        }
+        // Errors are reported on the next flush (if any)
+        let _ = journal.flush();
+    }
+}
+
+fn handle_journal_message(
+    journal: &mut impl Write,
+    error: &mut Option<io::Error>,
+    message: JournalMessage,
+) {
+    match message {
+        JournalMessage::Record(record) => {
+            if error.is_none() {
+                *error = record.write_to(journal).err();
+            }
+        }
+        JournalMessage::Flush(done_tx) => {
+            let result = match error {
+                Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+                None => journal.flush(),
+            };
+            let _ = done_tx.send(result);
+        }
+    }
+}
+
+impl<FB: FrameBuffer> FrameBuffer for RecordingFrameBuffer<FB> {
+    #[inline(always)]
+    fn get_width(&self) -> usize {
+        self.fb.get_width()
+    }
+
+    #[inline(always)]
+    fn get_height(&self) -> usize {
+        self.fb.get_height()
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        self.fb.get_unchecked(x, y)
+    }
+
+    #[inline(always)]
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        if x < self.get_width() && y < self.get_height() {
+            self.fb.set(x, y, rgba);
+            self.record(JournalRecord::Pixel {
+                x: x as u32,
+                y: y as u32,
+                rgba,
+            });
+        }
+    }
+
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = self.fb.set_multi_from_start_index(starting_index, pixels);
+        if num_pixels > 0 {
+            self.record(JournalRecord::Pixels {
+                starting_index: starting_index as u32,
+                pixels: pixels[..num_pixels * 4].to_vec(),
+            });
+        }
+        num_pixels
+    }
+
+    #[inline(always)]
+    fn bytes_per_pixel(&self) -> usize {
+        self.fb.bytes_per_pixel()
+    }
+
+    #[inline(always)]
+    fn as_bytes(&self) -> &[u8] {
+        self.fb.as_bytes()
+    }
+
+    #[inline(always)]
+    fn as_pixels(&self) -> &[u32] {
+        self.fb.as_pixels()
+    }
+
+    #[inline(always)]
+    fn take_dirty_regions(&self) -> Option<Vec<DirtyRegion>> {
+        self.fb.take_dirty_regions()
+    }
+
+    #[inline(always)]
+    fn advance_dirty_epoch(&self) -> Option<u64> {
+        self.fb.advance_dirty_epoch()
+    }
+
+    #[inline(always)]
+    fn changed_tiles_since(&self, epoch: u64) -> Option<Vec<DirtyRegion>> {
+        self.fb.changed_tiles_since(epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{OriginalParser, Parser, SimpleFrameBuffer};
+
+    /// Collects the journal in memory, so that the test can read it afterwards
+    #[derive(Clone, Default)]
+    struct SharedJournal(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedJournal {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_journal(journal: &SharedJournal) -> Vec<JournalRecord> {
+        let journal = journal.0.lock().unwrap();
+        let mut reader = journal.as_slice();
+        let mut records = Vec::new();
+        while let Some(record) = JournalRecord::read_from(&mut reader).unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn test_journal_contains_writes() {
+        let fb = Arc::new(SimpleFrameBuffer::new(4, 2));
+        let journal = SharedJournal::default();
+        let recording = RecordingFrameBuffer::new(fb.clone(), journal.clone());
+
+        recording.set(1, 0, 0x00563412);
+        // Out of bounds, so neither drawn nor recorded
+        recording.set(4, 0, 0x00ffffff);
+        assert_eq!(
+            recording.set_multi_from_start_index(6, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            2
+        );
+        // Would exceed the screen
+        assert_eq!(recording.set_multi_from_start_index(7, &[0xff; 8]), 0);
+        recording.set(3, 1, 0x00abcdef);
+        recording.flush().unwrap();
+
+        assert_eq!(
+            read_journal(&journal),
+            [
+                JournalRecord::Pixel {
+                    x: 1,
+                    y: 0,
+                    rgba: 0x00563412
+                },
+                JournalRecord::Pixels {
+                    starting_index: 6,
+                    pixels: vec![1, 2, 3, 4, 5, 6, 7, 8]
+                },
+                JournalRecord::Pixel {
+                    x: 3,
+                    y: 1,
+                    rgba: 0x00abcdef
+                },
+            ]
+        );
+        // The writes are forwarded as well
+        assert_eq!(fb.get(1, 0), Some(0x00563412));
+        assert_eq!(fb.get(2, 1), Some(u32::from_ne_bytes([1, 2, 3, 0])));
+        assert_eq!(fb.get(3, 1), Some(0x00abcdef));
+    }
+
+    #[test]
+    fn test_replaying_journal_results_in_identical_framebuffer() {
+        let fb = Arc::new(SimpleFrameBuffer::new(16, 4));
+        let journal = SharedJournal::default();
+        let recording = Arc::new(RecordingFrameBuffer::new(fb.clone(), journal.clone()));
+
+        let mut parser = OriginalParser::new(recording.clone());
+        let mut buffer = (0..100_u32)
+            .map(|i| format!("PX {} {} {:06x}\n", i % 16, i % 4, i * 0x010203))
+            .collect::<String>()
+            .into_bytes();
+        buffer.resize(buffer.len() + parser.parser_lookahead(), 0);
+        parser.parse(&buffer, &mut Vec::new());
+        recording.flush().unwrap();
+
+        let replayed = SimpleFrameBuffer::new(16, 4);
+        let records = read_journal(&journal);
+        assert_eq!(records.len(), 100);
+        for record in records {
+            record.apply(&replayed);
+        }
+        assert_eq!(replayed.as_bytes(), fb.as_bytes());
+    }
+
+    #[test]
+    fn test_truncated_journal() {
+        let mut journal = Vec::new();
+        JournalRecord::Pixel {
+            x: 1,
+            y: 2,
+            rgba: 3,
+        }
+        .write_to(&mut journal)
+        .unwrap();
+        assert_eq!(journal.len(), 13);
+
+        let mut reader = &journal[..7];
+        assert!(JournalRecord::read_from(&mut reader).is_err());
+    }
+}
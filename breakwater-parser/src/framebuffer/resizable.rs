@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+use super::FrameBuffer;
+
+/// Framebuffers that can be copied into a framebuffer of another size
+pub trait Resize: FrameBuffer + Sized {
+    /// Returns a new framebuffer of the given size, which contains all pixels of this framebuffer that are still within
+    /// the new bounds. Pixels that were not part of this framebuffer are black.
+    fn resized(&self, width: usize, height: usize) -> Self;
+}
+
+/// Allows resizing the framebuffer at runtime, e.g. to reconfigure a wall of screens without restarting breakwater.
+///
+/// Parsers and sinks rely on the size of a framebuffer never changing (e.g. the bounds checked before calling
+/// [`FrameBuffer::get_unchecked`], or the size of their own buffers), so the framebuffer is not resized in place.
+/// Instead [`ResizableFrameBuffer::resize`] swaps in a resized copy. Consumers [`ResizableFrameBuffer::load`] the
+/// current framebuffer, keep using it for e.g. a whole frame and detect resizes by comparing it with the next one they
+/// load (using [`Arc::ptr_eq`]).
+///
+/// Draws to the previous framebuffer that happen while it is copied, or after the swap, are lost.
+///
+/// Framebuffers that can not be [`Resize`]d can be wrapped as well, so that consumers can handle both the same way.
+pub struct ResizableFrameBuffer<FB> {
+    current: ArcSwap<FB>,
+    /// Serializes resizes, so that no resize copies a framebuffer that is about to be replaced by another resize
+    resize_lock: Mutex<()>,
+}
+
+impl<FB> ResizableFrameBuffer<FB> {
+    pub fn new(fb: FB) -> Self {
+        Self::from_arc(Arc::new(fb))
+    }
+
+    /// Wraps a framebuffer that is already shared, e.g. with sinks that don't follow resizes
+    pub fn from_arc(fb: Arc<FB>) -> Self {
+        Self {
+            current: ArcSwap::new(fb),
+            resize_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the current framebuffer, which keeps its size even when [`ResizableFrameBuffer::resize`] is called in
+    /// the meantime
+    pub fn load(&self) -> Arc<FB> {
+        self.current.load_full()
+    }
+
+    /// Whether the given framebuffer (previously [`ResizableFrameBuffer::load`]ed) is still the current one. This is
+    /// cheaper than loading the current framebuffer, so consumers can check it frequently.
+    pub fn is_current(&self, fb: &Arc<FB>) -> bool {
+        Arc::ptr_eq(&self.current.load(), fb)
+    }
+}
+
+impl<FB: Resize> ResizableFrameBuffer<FB> {
+    /// Replaces the current framebuffer with a copy of the given size and returns it
+    pub fn resize(&self, width: usize, height: usize) -> Arc<FB> {
+        let _resize_guard = self.resize_lock.lock().expect("resize lock poisoned");
+
+        let resized = Arc::new(self.current.load().resized(width, height));
+        self.current.store(Arc::clone(&resized));
+        resized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::SimpleFrameBuffer;
+
+    #[rstest]
+    #[case::grow(8, 6)]
+    #[case::shrink(2, 3)]
+    #[case::wider_but_lower(10, 2)]
+    fn test_resize(#[case] width: usize, #[case] height: usize) {
+        let resizable = ResizableFrameBuffer::new(SimpleFrameBuffer::new(4, 4));
+        let before = resizable.load();
+        for (x, y) in [(0, 0), (3, 0), (1, 2), (3, 3)] {
+            before.set(x, y, (x + y * 4) as u32 + 1);
+        }
+
+        assert!(resizable.is_current(&before));
+        let resized = resizable.resize(width, height);
+        assert!(Arc::ptr_eq(&resized, &resizable.load()));
+        assert!(resizable.is_current(&resized));
+        assert!(!resizable.is_current(&before));
+        assert_eq!((resized.get_width(), resized.get_height()), (width, height));
+        assert_eq!(resized.as_pixels().len(), width * height);
+
+        for x in 0..width {
+            for y in 0..height {
+                let expected = before.get(x, y).unwrap_or(0);
+                assert_eq!(resized.get(x, y), Some(expected), "Checking pixel {x} {y}");
+            }
+        }
+        assert_eq!(resized.get(width, 0), None);
+        assert_eq!(resized.get(0, height), None);
+
+        // Writes against the new bounds work, the previous framebuffer is left untouched
+        resized.set(width - 1, height - 1, 0xabcdef);
+        assert_eq!(resized.get(width - 1, height - 1), Some(0xabcdef));
+        assert_eq!((before.get_width(), before.get_height()), (4, 4));
+        assert_eq!(before.get(3, 3), Some(16));
+    }
+
+    #[test]
+    fn test_resize_keeps_dirty_tracking() {
+        let resizable =
+            ResizableFrameBuffer::new(SimpleFrameBuffer::new(64, 64).with_dirty_tracking());
+        let resized = resizable.resize(128, 32);
+
+        resized.set(100, 10, 0xffffff);
+        let dirty_regions = resized.take_dirty_regions().unwrap();
+        assert_eq!(dirty_regions.len(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+use core::slice;
+
+use super::FrameBuffer;
+
+/// Stores every pixel using only 16 bits (5 bits red, 6 bits green, 5 bits blue), which halves the memory bandwidth
+/// compared to the other framebuffers, e.g. for embedded displays natively using RGB565.
+///
+/// Colors are quantized when they are set, so reading a pixel returns the closest color that can be represented, e.g.
+/// `0x00563412` is read back as `0x00523410`.
+pub struct Rgb565FrameBuffer {
+    width: usize,
+    height: usize,
+    buffer: Vec<u16>,
+}
+
+impl Rgb565FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            buffer: vec![0; width * height],
+        }
+    }
+}
+
+/// Converts a pixel in the format `0x00bbggrr` to RGB565 by dropping the lower bits of every channel
+#[inline(always)]
+fn to_rgb565(rgba: u32) -> u16 {
+    let r = (rgba & 0xff) as u16;
+    let g = ((rgba >> 8) & 0xff) as u16;
+    let b = ((rgba >> 16) & 0xff) as u16;
+
+    ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3)
+}
+
+/// Converts RGB565 back to `0x00bbggrr`. The upper bits of every channel are repeated in the lower bits, so that e.g.
+/// white stays white.
+#[inline(always)]
+fn from_rgb565(pixel: u16) -> u32 {
+    let r = ((pixel >> 11) & 0x1f) as u32;
+    let g = ((pixel >> 5) & 0x3f) as u32;
+    let b = (pixel & 0x1f) as u32;
+
+    let r = (r << 3) | (r >> 2);
+    let g = (g << 2) | (g >> 4);
+    let b = (b << 3) | (b >> 2);
+
+    r | (g << 8) | (b << 16)
+}
+
+impl FrameBuffer for Rgb565FrameBuffer {
+    #[inline(always)]
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    #[inline(always)]
+    fn get_height(&self) -> usize {
+        self.height
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        from_rgb565(*self.buffer.get_unchecked(x + y * self.width))
+    }
+
+    #[inline(always)]
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        if x < self.width && y < self.height {
+            unsafe {
+                let ptr = self.buffer.as_ptr().add(x + y * self.width) as *mut u16;
+                *ptr = to_rgb565(rgba);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = pixels.len() / 4;
+
+        if starting_index + num_pixels > self.buffer.len() {
+            // We did not move
+            return 0;
+        }
+
+        let starting_ptr = unsafe { self.buffer.as_ptr().add(starting_index) };
+        let target_slice =
+            unsafe { slice::from_raw_parts_mut(starting_ptr as *mut u16, num_pixels) };
+        for (target, pixel) in target_slice.iter_mut().zip(pixels.chunks_exact(4)) {
+            // The alpha byte is ignored anyway
+            *target = to_rgb565(u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], 0]));
+        }
+
+        num_pixels
+    }
+
+    #[inline(always)]
+    fn bytes_per_pixel(&self) -> usize {
+        2
+    }
+
+    /// The pixels in RGB565, using the native byte order
+    #[inline(always)]
+    fn as_bytes(&self) -> &[u8] {
+        let len = 2 * self.buffer.len();
+        let ptr = self.buffer.as_ptr() as *const u8;
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    }
+
+    /// # Panics
+    /// The pixels are not stored as `u32`, use [`FrameBuffer::as_bytes`] instead
+    fn as_pixels(&self) -> &[u32] {
+        panic!("The RGB565 framebuffer does not store pixels as u32, use as_bytes instead");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rstest::{fixture, rstest};
+
+    #[fixture]
+    fn fb() -> Rgb565FrameBuffer {
+        Rgb565FrameBuffer::new(640, 480)
+    }
+
+    #[rstest]
+    // Representable colors survive the roundtrip unchanged
+    #[case(0x000000, 0x000000)]
+    #[case(0xffffff, 0xffffff)]
+    #[case(0x0000ff, 0x0000ff)]
+    #[case(0x00ff00, 0x00ff00)]
+    #[case(0xff0000, 0xff0000)]
+    // Other colors are quantized to 5 bits red, 6 bits green and 5 bits blue
+    #[case(0x563412, 0x523410)]
+    #[case(0x070307, 0x000000)]
+    #[case(0x080408, 0x080408)]
+    // The alpha byte is dropped
+    #[case(0x12ffffff, 0xffffff)]
+    fn test_roundtrip(fb: Rgb565FrameBuffer, #[case] rgba: u32, #[case] expected: u32) {
+        fb.set(42, 13, rgba);
+        assert_eq!(fb.get(42, 13), Some(expected));
+    }
+
+    #[rstest]
+    fn test_quantization_error_is_bounded(fb: Rgb565FrameBuffer) {
+        for value in 0..=255_u32 {
+            let rgba = value | (value << 8) | (value << 16);
+            fb.set(0, 0, rgba);
+            let read = fb.get(0, 0).unwrap();
+
+            for (shift, max_error) in [(0, 7), (8, 3), (16, 7)] {
+                let expected = (rgba >> shift) & 0xff;
+                let actual = (read >> shift) & 0xff;
+                assert!(
+                    expected.abs_diff(actual) <= max_error,
+                    "Channel at bit {shift} of {rgba:06x} was read back as {read:06x}"
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_out_of_bounds(fb: Rgb565FrameBuffer) {
+        fb.set(640, 0, 0xffffff);
+        assert_eq!(fb.get(640, 0), None);
+        assert!(fb.as_bytes().iter().all(|&byte| byte == 0));
+    }
+
+    #[rstest]
+    fn test_set_multi(fb: Rgb565FrameBuffer) {
+        let pixels = [0xffffff_u32, 0x00ff00, 0x563412];
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+
+        assert_eq!(fb.set_multi_from_start_index(638, &pixel_bytes), 3);
+        assert_eq!(fb.get(638, 0), Some(0xffffff));
+        assert_eq!(fb.get(639, 0), Some(0x00ff00));
+        assert_eq!(fb.get(0, 1), Some(0x523410));
+
+        // Would exceed the screen
+        assert_eq!(
+            fb.set_multi_from_start_index(fb.get_size() - 1, &pixel_bytes),
+            0
+        );
+    }
+
+    #[rstest]
+    fn test_as_bytes(fb: Rgb565FrameBuffer) {
+        assert_eq!(fb.bytes_per_pixel(), 2);
+        assert_eq!(fb.as_bytes().len(), 640 * 480 * 2);
+
+        // Pure red
+        fb.set(1, 0, 0x0000ff);
+        assert_eq!(fb.as_bytes()[..2], [0, 0]);
+        assert_eq!(fb.as_bytes()[2..4], 0xf800_u16.to_ne_bytes());
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use super::{dirty::DirtyRegion, FrameBuffer};
+
+/// Number of draws that can be queued before the parsers have to wait for the writer thread
+const DRAW_QUEUE_SIZE: usize = 64 * 1024;
+
+enum Draw {
+    Pixel {
+        x: usize,
+        y: usize,
+        rgba: u32,
+    },
+    Pixels {
+        starting_index: usize,
+        pixels: Vec<u8>,
+    },
+    /// Answered once all draws queued before have been applied
+    Flush(SyncSender<()>),
+}
+
+/// Funnels all draws into the wrapped framebuffer through a single writer thread, which applies them in the order they
+/// were queued. This way the content of the framebuffer only depends on the order in which the draws arrive, e.g. to
+/// make recordings reproducible, at the cost of some throughput.
+///
+/// All reads are answered by the wrapped framebuffer directly, so they (including the current color used for alpha
+/// blending) might not contain the draws that are still queued.
+pub struct SerializedFrameBuffer<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    draws_tx: SyncSender<Draw>,
+}
+
+impl<FB: FrameBuffer + Send + Sync + 'static> SerializedFrameBuffer<FB> {
+    /// Starts the writer thread, which stops once the returned framebuffer is dropped
+    pub fn new(fb: Arc<FB>) -> Self {
+        let (draws_tx, draws_rx) = mpsc::sync_channel(DRAW_QUEUE_SIZE);
+        let writer_fb = Arc::clone(&fb);
+        thread::Builder::new()
+            .name("breakwater-draws".to_owned())
+            .spawn(move || apply_draws(writer_fb.as_ref(), draws_rx))
+            .expect("failed to spawn the thread applying the draws");
+
+        Self { fb, draws_tx }
+    }
+}
+
+impl<FB: FrameBuffer> SerializedFrameBuffer<FB> {
+    /// Blocks until all draws queued so far have been applied to the wrapped framebuffer
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        // Both only fail in case the writer thread is gone, in which case there is nothing to wait for
+        if self.draws_tx.send(Draw::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    #[inline(always)]
+    fn queue(&self, draw: Draw) {
+        // Only fails in case the writer thread is gone, in which case there is nothing we can do about it
+        let _ = self.draws_tx.send(draw);
+    }
+}
+
+fn apply_draws<FB: FrameBuffer>(fb: &FB, draws_rx: Receiver<Draw>) {
+    for draw in draws_rx {
+        match draw {
+            Draw::Pixel { x, y, rgba } => fb.set(x, y, rgba),
+            Draw::Pixels {
+                starting_index,
+                pixels,
+            } => {
+                fb.set_multi_from_start_index(starting_index, &pixels);
+            }
+            Draw::Flush(done_tx) => {
+                let _ = done_tx.send(());
+            }
+        }
+    }
+}
+
+impl<FB: FrameBuffer> FrameBuffer for SerializedFrameBuffer<FB> {
+    #[inline(always)]
+    fn get_width(&self) -> usize {
+        self.fb.get_width()
+    }
+
+    #[inline(always)]
+    fn get_height(&self) -> usize {
+        self.fb.get_height()
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        self.fb.get_unchecked(x, y)
+    }
+
+    #[inline(always)]
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        self.queue(Draw::Pixel { x, y, rgba });
+    }
+
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = pixels.len() / 4;
+        // Same as the other framebuffers, writes exceeding the screen are ignored. We need to check this upfront, as
+        // the caller needs to know where it landed.
+        if starting_index + num_pixels > self.get_size() {
+            return 0;
+        }
+
+        self.queue(Draw::Pixels {
+            starting_index,
+            pixels: pixels[..num_pixels * 4].to_vec(),
+        });
+        num_pixels
+    }
+
+    #[inline(always)]
+    fn bytes_per_pixel(&self) -> usize {
+        self.fb.bytes_per_pixel()
+    }
+
+    #[inline(always)]
+    fn as_bytes(&self) -> &[u8] {
+        self.fb.as_bytes()
+    }
+
+    #[inline(always)]
+    fn as_pixels(&self) -> &[u32] {
+        self.fb.as_pixels()
+    }
+
+    #[inline(always)]
+    fn take_dirty_regions(&self) -> Option<Vec<DirtyRegion>> {
+        self.fb.take_dirty_regions()
+    }
+
+    #[inline(always)]
+    fn advance_dirty_epoch(&self) -> Option<u64> {
+        self.fb.advance_dirty_epoch()
+    }
+
+    #[inline(always)]
+    fn changed_tiles_since(&self, epoch: u64) -> Option<Vec<DirtyRegion>> {
+        self.fb.changed_tiles_since(epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OriginalParser, Parser, SimpleFrameBuffer};
+
+    /// Two connections drawing on top of each other. The commands are handed to the parsers in a fixed order.
+    fn draw_overlapping<FB: FrameBuffer>(fb: Arc<FB>) {
+        let mut parsers = [OriginalParser::new(fb.clone()), OriginalParser::new(fb)];
+        for round in 0..100_u32 {
+            let mut buffer = (0..16)
+                .map(|x| format!("PX {x} {} {:06x}\n", round % 4, round * 0x010203))
+                .collect::<String>()
+                .into_bytes();
+            let parser = &mut parsers[round as usize % 2];
+            buffer.resize(buffer.len() + parser.parser_lookahead(), 0);
+            parser.parse(&buffer, &mut Vec::new());
+        }
+    }
+
+    #[test]
+    fn test_same_input_results_in_identical_framebuffer() {
+        let render = || {
+            let fb = Arc::new(SimpleFrameBuffer::new(16, 4));
+            let serialized = Arc::new(SerializedFrameBuffer::new(fb.clone()));
+            draw_overlapping(serialized.clone());
+            serialized.flush();
+            fb.as_bytes().to_vec()
+        };
+
+        let first = render();
+        assert_eq!(first, render());
+
+        // Draws are applied in the order they were queued, so it's the same as drawing directly
+        let fb = Arc::new(SimpleFrameBuffer::new(16, 4));
+        draw_overlapping(fb.clone());
+        assert_eq!(first, fb.as_bytes());
+    }
+
+    #[test]
+    fn test_set_multi() {
+        let fb = Arc::new(SimpleFrameBuffer::new(4, 2));
+        let serialized = SerializedFrameBuffer::new(fb.clone());
+
+        assert_eq!(
+            serialized.set_multi_from_start_index(6, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            2
+        );
+        // Would exceed the screen
+        assert_eq!(serialized.set_multi_from_start_index(7, &[0xff; 8]), 0);
+        serialized.flush();
+
+        assert_eq!(fb.get(2, 1), Some(u32::from_ne_bytes([1, 2, 3, 0])));
+        assert_eq!(fb.get(3, 1), Some(u32::from_ne_bytes([5, 6, 7, 0])));
+    }
+}
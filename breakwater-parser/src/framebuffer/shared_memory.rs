@@ -0,0 +1,291 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use memmap2::MmapMut;
+
+/// Named shared memory lives in this tmpfs on Linux, it's also where `shm_open` puts it. External tools can therefore
+/// either map the file in there or call `shm_open` with the name.
+pub const SHARED_MEMORY_DIR: &str = "/dev/shm";
+
+/// Identifies a shared memory region holding a breakwater canvas
+pub const SHARED_MEMORY_MAGIC: &[u8; 8] = b"BRKWATER";
+
+/// [`SHARED_MEMORY_MAGIC`], followed by the width and height as little endian `u32`. The pixels come directly after
+/// it, in the same format as [`crate::FrameBuffer::as_pixels`]. As the mapping is page aligned, so are the pixels.
+pub const SHARED_MEMORY_HEADER_SIZE: usize = 16;
+
+/// A named shared memory region holding a canvas, see [`crate::SimpleFrameBuffer::from_shared_memory`].
+///
+/// The region is not removed on drop, so that external tools don't lose the canvas while breakwater is restarting.
+pub struct SharedMemory {
+    path: PathBuf,
+    mmap: MmapMut,
+    width: usize,
+    height: usize,
+}
+
+impl SharedMemory {
+    /// Opens the region with the given name, or creates it with a black canvas if it doesn't exist yet. The pixels of
+    /// an existing region are kept, it has to have the requested size though.
+    pub fn open_or_create(name: &str, width: usize, height: usize) -> io::Result<Self> {
+        let path = shared_memory_path(name)?;
+        let header_width = u32::try_from(width).map_err(|_| invalid_size(width, height))?;
+        let header_height = u32::try_from(height).map_err(|_| invalid_size(width, height))?;
+        let expected_len = region_len(width, height);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let len = file.metadata()?.len();
+        let created = len == 0;
+        if created {
+            // tmpfs hands out zeroed pages, so the canvas starts black
+            file.set_len(expected_len)?;
+        }
+
+        // SAFETY: Other processes can modify the region while it's mapped. That's fine, as the contents are only ever
+        // interpreted as pixels (where every bit pattern is valid), which the clients write concurrently anyway.
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        if created {
+            mmap[..8].copy_from_slice(SHARED_MEMORY_MAGIC);
+            mmap[8..12].copy_from_slice(&header_width.to_le_bytes());
+            mmap[12..16].copy_from_slice(&header_height.to_le_bytes());
+        } else {
+            let found = read_header(&mmap);
+            if len != expected_len || found != Some((width, height)) {
+                return Err(size_mismatch(&path, width, height, found));
+            }
+        }
+
+        Ok(Self {
+            path,
+            mmap,
+            width,
+            height,
+        })
+    }
+
+    /// Opens an existing region, taking the size of the canvas from its header. Meant for tools that only know the name
+    /// of the region.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let path = shared_memory_path(name)?;
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+
+        // SAFETY: See `open_or_create`
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        match read_header(&mmap) {
+            Some((width, height)) if mmap.len() as u64 == region_len(width, height) => Ok(Self {
+                path,
+                mmap,
+                width,
+                height,
+            }),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("The shared memory {path:?} does not contain a breakwater canvas"),
+            )),
+        }
+    }
+
+    /// Replaces the region with the given name by a black canvas of the given size, e.g. after breakwater was
+    /// restarted with a different `--width` or `--height`. Tools that still have the old region mapped keep seeing the
+    /// old canvas until they map it again.
+    pub fn recreate(name: &str, width: usize, height: usize) -> io::Result<Self> {
+        match fs::remove_file(shared_memory_path(name)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        Self::open_or_create(name, width, height)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Points to the first of the `width * height` pixels following the header
+    pub(crate) fn pixels_ptr(&mut self) -> *mut u32 {
+        unsafe { self.mmap.as_mut_ptr().add(SHARED_MEMORY_HEADER_SIZE) as *mut u32 }
+    }
+}
+
+fn region_len(width: usize, height: usize) -> u64 {
+    (SHARED_MEMORY_HEADER_SIZE + width * height * 4) as u64
+}
+
+/// Returns the width and height stored in the header, in case the region contains a breakwater canvas
+fn read_header(region: &[u8]) -> Option<(usize, usize)> {
+    if region.len() < SHARED_MEMORY_HEADER_SIZE || &region[..8] != SHARED_MEMORY_MAGIC {
+        return None;
+    }
+    let width = u32::from_le_bytes(region[8..12].try_into().unwrap());
+    let height = u32::from_le_bytes(region[12..16].try_into().unwrap());
+    Some((width as usize, height as usize))
+}
+
+/// Accepts the name with or without the leading slash used by `shm_open`
+fn shared_memory_path(name: &str) -> io::Result<PathBuf> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    if name.is_empty() || name.contains('/') {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Invalid shared memory name {name:?}, it must neither be empty nor contain a slash"
+            ),
+        ));
+    }
+    Ok(Path::new(SHARED_MEMORY_DIR).join(name))
+}
+
+fn invalid_size(width: usize, height: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("A canvas of {width}x{height} pixels does not fit into the shared memory header"),
+    )
+}
+
+/// Uses [`ErrorKind::InvalidData`], so that callers can tell it apart from other errors and e.g. offer to
+/// [`SharedMemory::recreate`] the region
+fn size_mismatch(
+    path: &Path,
+    width: usize,
+    height: usize,
+    found: Option<(usize, usize)>,
+) -> io::Error {
+    let found = match found {
+        Some((found_width, found_height)) => format!("a canvas of {found_width}x{found_height}"),
+        None => "no breakwater canvas".to_owned(),
+    };
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("The shared memory {path:?} contains {found} instead of {width}x{height} pixels"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{FrameBuffer, OriginalParser, Parser, SimpleFrameBuffer};
+
+    /// Every test gets its own region, so that the tests can run in parallel
+    fn shared_memory_name(test: &str) -> String {
+        format!("breakwater-test-{}-{test}", std::process::id())
+    }
+
+    #[test]
+    fn test_draws_are_visible_through_second_handle() {
+        let name = shared_memory_name("draws");
+        let fb = Arc::new(SimpleFrameBuffer::from_shared_memory(
+            SharedMemory::open_or_create(&name, 640, 480).unwrap(),
+        ));
+
+        let mut parser = OriginalParser::new(fb.clone());
+        let mut buffer = b"PX 1 2 abcdef\nPX 639 479 123456\n".to_vec();
+        buffer.resize(buffer.len() + parser.parser_lookahead(), 0);
+        parser.parse(&buffer, &mut Vec::new());
+
+        let second_handle = SharedMemory::open_or_create(&name, 640, 480).unwrap();
+        let reader = SimpleFrameBuffer::from_shared_memory(second_handle);
+        assert_eq!(reader.get(1, 2), Some(0xefcdab));
+        assert_eq!(reader.get(639, 479), Some(0x563412));
+        assert_eq!(reader.as_bytes(), fb.as_bytes());
+
+        // The header tells external tools what they are looking at
+        let path = shared_memory_path(&name).unwrap();
+        assert_eq!(read_header(&fs::read(&path).unwrap()), Some((640, 480)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let name = shared_memory_name("size-mismatch");
+        let shared_memory = SharedMemory::open_or_create(&name, 64, 48).unwrap();
+
+        let err = SharedMemory::open_or_create(&name, 48, 64).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = SharedMemory::open_or_create(&name, 640, 480).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        fs::remove_file(shared_memory.path()).unwrap();
+    }
+
+    #[test]
+    fn test_recreate_with_different_size() {
+        let name = shared_memory_name("recreate");
+        let fb = SimpleFrameBuffer::from_shared_memory(
+            SharedMemory::open_or_create(&name, 64, 48).unwrap(),
+        );
+        fb.set(1, 2, 0x123456);
+
+        let err = SharedMemory::open_or_create(&name, 640, 480).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("a canvas of 64x48"), "{err}");
+
+        let recreated = SharedMemory::recreate(&name, 640, 480).unwrap();
+        let path = recreated.path().to_owned();
+        let recreated = SimpleFrameBuffer::from_shared_memory(recreated);
+        assert_eq!(recreated.get_width(), 640);
+        assert_eq!(recreated.get_height(), 480);
+        assert_eq!(recreated.as_pixels().len(), 640 * 480);
+        assert_eq!(recreated.get(1, 2), Some(0));
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            SHARED_MEMORY_HEADER_SIZE + 640 * 480 * 4
+        );
+
+        // The old mapping is still intact
+        assert_eq!(fb.get(1, 2), Some(0x123456));
+        // Now the region has the new size
+        assert!(SharedMemory::open_or_create(&name, 640, 480).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_reads_size_from_header() {
+        let name = shared_memory_name("open");
+        let shared_memory = SharedMemory::open_or_create(&name, 64, 48).unwrap();
+        let path = shared_memory.path().to_owned();
+        SimpleFrameBuffer::from_shared_memory(shared_memory).set(63, 47, 0x123456);
+
+        let fb = SimpleFrameBuffer::from_shared_memory(SharedMemory::open(&name).unwrap());
+        assert_eq!(fb.get_width(), 64);
+        assert_eq!(fb.get_height(), 48);
+        assert_eq!(fb.get(63, 47), Some(0x123456));
+
+        // Regions without a valid header are refused
+        fs::write(&path, [0; 64]).unwrap();
+        let err = SharedMemory::open(&name).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+        let err = SharedMemory::open(&name).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        for name in ["", "/", "foo/bar"] {
+            let err = SharedMemory::open_or_create(name, 64, 48).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(
+            shared_memory_path("/breakwater").unwrap(),
+            Path::new("/dev/shm/breakwater")
+        );
+    }
+}
@@ -1,23 +1,104 @@
 use core::slice;
-
-use super::FrameBuffer;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+#[cfg(feature = "shared-memory")]
+use super::shared_memory::SharedMemory;
+use super::{
+    copy_pixels_without_alpha,
+    dirty::{DirtyRegion, DirtyTiles},
+    resizable::Resize,
+    FrameBuffer,
+};
 
 pub struct SimpleFrameBuffer {
     width: usize,
     height: usize,
-    buffer: Vec<u32>,
+    buffer: Pixels,
+    dirty_tiles: Option<DirtyTiles>,
 }
 
 impl SimpleFrameBuffer {
     pub fn new(width: usize, height: usize) -> Self {
-        let mut buffer = Vec::with_capacity(width * height);
-        buffer.resize_with(width * height, || 0);
+        let mut pixels = Vec::with_capacity(width * height);
+        pixels.resize_with(width * height, || 0);
+        Self {
+            width,
+            height,
+            buffer: Pixels {
+                ptr: pixels.as_mut_ptr(),
+                len: pixels.len(),
+                _memory: PixelMemory::Heap { _pixels: pixels },
+            },
+            dirty_tiles: None,
+        }
+    }
+
+    /// Stores the pixels in the given shared memory region instead of on the heap, so that external tools can read
+    /// the canvas live. A resized framebuffer is stored on the heap again.
+    #[cfg(feature = "shared-memory")]
+    pub fn from_shared_memory(mut shared_memory: SharedMemory) -> Self {
+        let (width, height) = (shared_memory.width(), shared_memory.height());
         Self {
             width,
             height,
-            buffer,
+            buffer: Pixels {
+                ptr: shared_memory.pixels_ptr(),
+                len: width * height,
+                _memory: PixelMemory::Shared {
+                    _shared_memory: shared_memory,
+                },
+            },
+            dirty_tiles: None,
         }
     }
+
+    /// Tracks the areas written to, see [`FrameBuffer::take_dirty_regions`]. This adds an atomic operation to every
+    /// write, so only enable it when there is a consumer of the dirty regions.
+    pub fn with_dirty_tracking(mut self) -> Self {
+        self.dirty_tiles = Some(DirtyTiles::new(self.width, self.height));
+        self
+    }
+}
+
+/// The pixels are always accessed through `ptr`, so that the hot path doesn't need to check where they are stored
+struct Pixels {
+    ptr: *mut u32,
+    len: usize,
+    /// Owns the memory `ptr` points into, it's only kept so that it's freed together with the framebuffer
+    _memory: PixelMemory,
+}
+
+enum PixelMemory {
+    Heap {
+        _pixels: Vec<u32>,
+    },
+    #[cfg(feature = "shared-memory")]
+    Shared {
+        _shared_memory: SharedMemory,
+    },
+}
+
+// The pixels are shared between threads the same way the `Vec<u32>` owning them would be
+unsafe impl Send for Pixels {}
+unsafe impl Sync for Pixels {}
+
+impl Deref for Pixels {
+    type Target = [u32];
+
+    #[inline(always)]
+    fn deref(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for Pixels {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
 }
 
 impl FrameBuffer for SimpleFrameBuffer {
@@ -48,6 +129,58 @@ impl FrameBuffer for SimpleFrameBuffer {
                 let ptr = self.buffer.as_ptr().add(x + y * self.width) as *mut u32;
                 *ptr = rgba;
             }
+            if let Some(dirty_tiles) = &self.dirty_tiles {
+                dirty_tiles.mark(x, y);
+            }
+        }
+    }
+
+    /// Atomic, so that out of multiple concurrent `compare_and_set` calls for the same pixel only one succeeds. Plain
+    /// writes using [`FrameBuffer::set`] are still not synchronized with it.
+    #[inline(always)]
+    fn compare_and_set(&self, x: usize, y: usize, expected: u32, rgba: u32) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+
+        let pixel = unsafe {
+            AtomicU32::from_ptr(self.buffer.as_ptr().add(x + y * self.width) as *mut u32)
+        };
+        let swapped = pixel
+            .compare_exchange(expected, rgba, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+        if swapped {
+            if let Some(dirty_tiles) = &self.dirty_tiles {
+                dirty_tiles.mark(x, y);
+            }
+        }
+        swapped
+    }
+
+    /// Checks the bounds of the whole batch upfront, so that the common case of only valid pixels is a tight loop
+    /// without any branches. If any pixel is out of bounds, we fall back to checking every pixel.
+    #[inline(always)]
+    fn set_batch(&self, pixels: &[(usize, usize, u32)]) {
+        let (max_x, max_y) = pixels.iter().fold((0, 0), |(max_x, max_y), &(x, y, _)| {
+            (max_x.max(x), max_y.max(y))
+        });
+        if pixels.is_empty() || max_x >= self.width || max_y >= self.height {
+            for &(x, y, rgba) in pixels {
+                self.set(x, y, rgba);
+            }
+            return;
+        }
+
+        let ptr = self.buffer.as_ptr() as *mut u32;
+        for &(x, y, rgba) in pixels {
+            unsafe {
+                *ptr.add(x + y * self.width) = rgba;
+            }
+        }
+        if let Some(dirty_tiles) = &self.dirty_tiles {
+            for &(x, y, _) in pixels {
+                dirty_tiles.mark(x, y);
+            }
         }
     }
 
@@ -68,8 +201,11 @@ impl FrameBuffer for SimpleFrameBuffer {
 
         let starting_ptr = unsafe { self.buffer.as_ptr().add(starting_index) };
         let target_slice =
-            unsafe { slice::from_raw_parts_mut(starting_ptr as *mut u8, pixels.len()) };
-        target_slice.copy_from_slice(pixels);
+            unsafe { slice::from_raw_parts_mut(starting_ptr as *mut u32, num_pixels) };
+        copy_pixels_without_alpha(target_slice, pixels);
+        if let Some(dirty_tiles) = &self.dirty_tiles {
+            dirty_tiles.mark_range(starting_index, num_pixels);
+        }
 
         num_pixels
     }
@@ -85,6 +221,40 @@ impl FrameBuffer for SimpleFrameBuffer {
     fn as_pixels(&self) -> &[u32] {
         &self.buffer
     }
+
+    fn take_dirty_regions(&self) -> Option<Vec<DirtyRegion>> {
+        self.dirty_tiles
+            .as_ref()
+            .map(DirtyTiles::take_dirty_regions)
+    }
+
+    fn advance_dirty_epoch(&self) -> Option<u64> {
+        self.dirty_tiles.as_ref().map(DirtyTiles::advance_epoch)
+    }
+
+    fn changed_tiles_since(&self, epoch: u64) -> Option<Vec<DirtyRegion>> {
+        self.dirty_tiles
+            .as_ref()
+            .map(|dirty_tiles| dirty_tiles.changed_tiles_since(epoch).collect())
+    }
+}
+
+impl Resize for SimpleFrameBuffer {
+    /// Keeps tracking the dirty regions in case this framebuffer does. The first frame of the resized framebuffer
+    /// needs to be copied completely anyway, so the areas written to before are not carried over.
+    fn resized(&self, width: usize, height: usize) -> Self {
+        let mut resized = Self::new(width, height);
+        if self.dirty_tiles.is_some() {
+            resized = resized.with_dirty_tracking();
+        }
+
+        let copied_width = width.min(self.width);
+        for y in 0..height.min(self.height) {
+            resized.buffer[y * width..y * width + copied_width]
+                .copy_from_slice(&self.buffer[y * self.width..y * self.width + copied_width]);
+        }
+        resized
+    }
 }
 
 #[cfg(test)]
@@ -114,6 +284,43 @@ mod tests {
         assert_eq!(fb.get(x, y), Some(rgba));
     }
 
+    #[rstest]
+    #[case::empty(&[])]
+    #[case::single(&[(1, 2, 0x123456)])]
+    #[case::same_pixel_twice(&[(1, 2, 0x123456), (1, 2, 0xabcdef)])]
+    #[case::corners(&[(0, 0, 1), (639, 0, 2), (0, 479, 3), (639, 479, 4)])]
+    #[case::x_out_of_bounds(&[(1, 2, 0x123456), (640, 0, 0xffffff), (3, 4, 0xabcdef)])]
+    #[case::y_out_of_bounds(&[(1, 2, 0x123456), (0, 480, 0xffffff), (3, 4, 0xabcdef)])]
+    pub fn test_set_batch(#[case] pixels: &[(usize, usize, u32)]) {
+        let expected = fb();
+        for &(x, y, rgba) in pixels {
+            expected.set(x, y, rgba);
+        }
+
+        let batched = fb().with_dirty_tracking();
+        batched.set_batch(pixels);
+        assert_eq!(batched.as_pixels(), expected.as_pixels());
+
+        let dirty_regions = batched.take_dirty_regions().unwrap();
+        assert_eq!(dirty_regions.is_empty(), pixels.is_empty());
+    }
+
+    #[rstest]
+    pub fn test_compare_and_set(fb: SimpleFrameBuffer) {
+        assert!(fb.compare_and_set(1, 2, 0, 0x123456));
+        assert_eq!(fb.get(1, 2), Some(0x123456));
+
+        // The pixel is not black anymore
+        assert!(!fb.compare_and_set(1, 2, 0, 0xabcdef));
+        assert_eq!(fb.get(1, 2), Some(0x123456));
+
+        assert!(fb.compare_and_set(1, 2, 0x123456, 0xabcdef));
+        assert_eq!(fb.get(1, 2), Some(0xabcdef));
+
+        assert!(!fb.compare_and_set(640, 0, 0, 0xffffff));
+        assert!(!fb.compare_and_set(0, 480, 0, 0xffffff));
+    }
+
     #[rstest]
     pub fn test_out_of_bounds(fb: SimpleFrameBuffer) {
         assert_eq!(fb.get(usize::MAX, usize::MAX), None);
@@ -190,4 +397,64 @@ mod tests {
             }
         }
     }
+
+    #[rstest]
+    pub fn test_set_multi_clamped_near_the_end(fb: SimpleFrameBuffer) {
+        let starting_index = fb.get_size() - 3;
+        let pixels = (1..=10_u32).collect::<Vec<_>>();
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+
+        let pixels_copied = fb.set_multi_clamped(starting_index, &pixel_bytes);
+        assert_eq!(pixels_copied, 3);
+
+        // Only the pixels fitting on the screen are set, the overflow is ignored
+        let y = fb.get_height() - 1;
+        assert_eq!(fb.get(fb.get_width() - 4, y), Some(0));
+        assert_eq!(fb.get(fb.get_width() - 3, y), Some(1));
+        assert_eq!(fb.get(fb.get_width() - 2, y), Some(2));
+        assert_eq!(fb.get(fb.get_width() - 1, y), Some(3));
+
+        // Starting beyond the screen writes nothing
+        assert_eq!(fb.set_multi_clamped(fb.get_size(), &pixel_bytes), 0);
+    }
+
+    #[rstest]
+    pub fn test_dirty_tracking(fb: SimpleFrameBuffer) {
+        // Without tracking everything has to be considered dirty
+        fb.set(0, 0, 0x00ff_ffff);
+        assert_eq!(fb.take_dirty_regions(), None);
+
+        let fb = fb.with_dirty_tracking();
+        assert_eq!(fb.take_dirty_regions(), Some(vec![]));
+
+        fb.set(10, 10, 0x00ff_0000);
+        fb.set(200, 300, 0x0000_ff00);
+        // Out of bounds, so nothing is touched
+        fb.set(640, 0, 0x0000_00ff);
+        fb.set_multi(600, 100, &[0xff; 4 * 10]);
+        assert_eq!(
+            fb.take_dirty_regions(),
+            Some(vec![
+                DirtyRegion {
+                    x: 0,
+                    y: 0,
+                    width: 64,
+                    height: 64,
+                },
+                DirtyRegion {
+                    x: 576,
+                    y: 64,
+                    width: 64,
+                    height: 64,
+                },
+                DirtyRegion {
+                    x: 192,
+                    y: 256,
+                    width: 64,
+                    height: 64,
+                },
+            ])
+        );
+        assert_eq!(fb.take_dirty_regions(), Some(vec![]));
+    }
 }
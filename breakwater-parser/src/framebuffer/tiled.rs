@@ -0,0 +1,365 @@
+use core::slice;
+
+use super::{copy_pixels_without_alpha, FrameBuffer};
+
+/// Stores the pixels in square tiles instead of lines, so that pixels close to each other (e.g. from clients drawing
+/// small images) are likely in the same cache lines. This can improve performance for very large canvases.
+///
+/// Watch out: [`FrameBuffer::as_bytes`] and [`FrameBuffer::as_pixels`] return the raw memory, so the pixels are
+/// ordered tile by tile (and within a tile line by line). Tiles at the right and bottom border are padded in case the
+/// canvas size is not a multiple of the tile size. Use [`TiledFrameBuffer::copy_linear_pixels`] to get the pixels
+/// line by line, e.g. for sinks.
+pub struct TiledFrameBuffer {
+    width: usize,
+    height: usize,
+    /// log2 of the tile size, so that we can use shifts and masks instead of divisions
+    tile_shift: u32,
+    tiles_per_row: usize,
+    buffer: Vec<u32>,
+}
+
+impl TiledFrameBuffer {
+    /// # Panics
+    /// The `tile_size` must be a power of two
+    pub fn new(width: usize, height: usize, tile_size: usize) -> Self {
+        assert!(
+            tile_size.is_power_of_two(),
+            "The tile size must be a power of two, but was {tile_size}"
+        );
+
+        let tiles_per_row = width.div_ceil(tile_size);
+        let tiles_per_column = height.div_ceil(tile_size);
+        let mut buffer =
+            Vec::with_capacity(tiles_per_row * tiles_per_column * tile_size * tile_size);
+        buffer.resize_with(buffer.capacity(), || 0);
+        Self {
+            width,
+            height,
+            tile_shift: tile_size.trailing_zeros(),
+            tiles_per_row,
+            buffer,
+        }
+    }
+
+    pub fn get_tile_size(&self) -> usize {
+        1 << self.tile_shift
+    }
+
+    /// Copies all pixels line by line into `pixels`, which needs to have the size of the canvas
+    pub fn copy_linear_pixels(&self, pixels: &mut [u32]) {
+        assert_eq!(pixels.len(), self.get_size());
+
+        let tile_size = self.get_tile_size();
+        for (y, line) in pixels.chunks_exact_mut(self.width).enumerate() {
+            // Every tile contains a contiguous part of the line
+            for (tile_x, part) in line.chunks_mut(tile_size).enumerate() {
+                let start = self.index(tile_x << self.tile_shift, y);
+                part.copy_from_slice(&self.buffer[start..start + part.len()]);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn index(&self, x: usize, y: usize) -> usize {
+        let mask = (1 << self.tile_shift) - 1;
+        let tile = (y >> self.tile_shift) * self.tiles_per_row + (x >> self.tile_shift);
+        (tile << (2 * self.tile_shift)) + ((y & mask) << self.tile_shift) + (x & mask)
+    }
+}
+
+impl FrameBuffer for TiledFrameBuffer {
+    #[inline(always)]
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    #[inline(always)]
+    fn get_height(&self) -> usize {
+        self.height
+    }
+
+    #[inline(always)]
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        *self.buffer.get_unchecked(self.index(x, y))
+    }
+
+    #[inline(always)]
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        if x < self.width && y < self.height {
+            unsafe {
+                let ptr = self.buffer.as_ptr().add(self.index(x, y)) as *mut u32;
+                *ptr = rgba;
+            }
+        }
+    }
+
+    /// The `starting_index` is the index in a linear (line by line) framebuffer, so that clients don't need to know
+    /// about the tiling
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        let num_pixels = pixels.len() / 4;
+
+        if starting_index + num_pixels > self.get_size() {
+            // We did not move
+            return 0;
+        }
+
+        let tile_size = self.get_tile_size();
+        let mut index = starting_index;
+        let mut pixels = &pixels[..num_pixels * 4];
+        while !pixels.is_empty() {
+            let x = index % self.width;
+            let y = index / self.width;
+
+            // Copy everything up to the end of the current tile (or line) in one go
+            let run = (tile_size - (x & (tile_size - 1)))
+                .min(self.width - x)
+                .min(pixels.len() / 4);
+            let target = unsafe {
+                let ptr = self.buffer.as_ptr().add(self.index(x, y)) as *mut u32;
+                slice::from_raw_parts_mut(ptr, run)
+            };
+            copy_pixels_without_alpha(target, &pixels[..run * 4]);
+
+            index += run;
+            pixels = &pixels[run * 4..];
+        }
+
+        num_pixels
+    }
+
+    #[inline(always)]
+    fn as_bytes(&self) -> &[u8] {
+        let len = 4 * self.buffer.len();
+        let ptr = self.buffer.as_ptr() as *const u8;
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    }
+
+    #[inline(always)]
+    fn as_pixels(&self) -> &[u32] {
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFrameBuffer;
+
+    use rstest::{fixture, rstest};
+
+    #[fixture]
+    fn fb() -> TiledFrameBuffer {
+        // 480 is not a multiple of the tile size, so we also test the padding
+        TiledFrameBuffer::new(640, 480, 64)
+    }
+
+    #[rstest]
+    #[case(0, 0, 0)]
+    #[case(0, 0, 0xff0000)]
+    #[case(0, 0, 0x0000ff)]
+    #[case(0, 0, 0x12345678)]
+    #[case(63, 0, 0x12345678)]
+    #[case(64, 1, 0x12345678)]
+    #[case(639, 479, 0x12345678)]
+    pub fn test_roundtrip(
+        fb: TiledFrameBuffer,
+        #[case] x: usize,
+        #[case] y: usize,
+        #[case] rgba: u32,
+    ) {
+        fb.set(x, y, rgba);
+        assert_eq!(fb.get(x, y), Some(rgba));
+    }
+
+    #[rstest]
+    pub fn test_out_of_bounds(fb: TiledFrameBuffer) {
+        assert_eq!(fb.get(usize::MAX, usize::MAX), None);
+        assert_eq!(fb.get(640, 0), None);
+        assert_eq!(fb.get(0, 480), None);
+
+        // Must not end up in the padding of the tiles (or anywhere else)
+        fb.set(640, 0, 42);
+        fb.set(0, 480, 42);
+        assert!(fb.as_pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[rstest]
+    pub fn test_set_multi_from_beginning(fb: TiledFrameBuffer) {
+        let pixels = (0..100_u32).collect::<Vec<_>>();
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+
+        let (current_x, current_y) = fb.set_multi(0, 0, &pixel_bytes);
+
+        assert_eq!(current_x, 100);
+        assert_eq!(current_y, 0);
+
+        for x in 0..100 {
+            assert_eq!(fb.get(x as usize, 0), Some(x), "Checking pixel {x}");
+        }
+
+        // The next pixel must not have been colored
+        assert_eq!(fb.get(101, 0), Some(0));
+    }
+
+    #[rstest]
+    pub fn test_set_multi_in_the_middle(fb: TiledFrameBuffer) {
+        let mut x = 10;
+        let mut y = 100;
+
+        // Let's color exactly 3 lines and 42 pixels
+        let pixels = (0..3 * fb.width as u32 + 42).collect::<Vec<_>>();
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+        let (current_x, current_y) = fb.set_multi(x, y, &pixel_bytes);
+
+        assert_eq!(current_x, 52);
+        assert_eq!(current_y, 103);
+
+        // Let's check everything has been colored
+        for rgba in 0..3 * fb.width as u32 + 42 {
+            assert_eq!(fb.get(x, y), Some(rgba));
+
+            x += 1;
+            if x >= fb.width {
+                x = 0;
+                y += 1;
+            }
+        }
+
+        // Everything afterwards must have not been touched (let's check the next 10 lines)
+        for _ in 0..10 * fb.width as u32 {
+            assert_eq!(fb.get(x, y), Some(0));
+
+            x += 1;
+            if x >= fb.width {
+                x = 0;
+                y += 1;
+            }
+        }
+    }
+
+    #[rstest]
+    pub fn test_set_multi_does_nothing_when_too_long(fb: TiledFrameBuffer) {
+        let too_long = vec![42_u8; fb.width * fb.height * 4 /* bytes per pixel */];
+        let (current_x, current_y) = fb.set_multi(1, 0, &too_long);
+
+        // Should be unchanged
+        assert_eq!(current_x, 1);
+        assert_eq!(current_y, 0);
+        assert!(fb.as_pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[rstest]
+    pub fn test_set_multi_clamped_near_the_end(fb: TiledFrameBuffer) {
+        let starting_index = fb.get_size() - 3;
+        let pixels = (1..=10_u32).collect::<Vec<_>>();
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+
+        let pixels_copied = fb.set_multi_clamped(starting_index, &pixel_bytes);
+        assert_eq!(pixels_copied, 3);
+
+        // Only the pixels fitting on the screen are set, the overflow is ignored
+        let y = fb.get_height() - 1;
+        assert_eq!(fb.get(fb.get_width() - 4, y), Some(0));
+        assert_eq!(fb.get(fb.get_width() - 3, y), Some(1));
+        assert_eq!(fb.get(fb.get_width() - 2, y), Some(2));
+        assert_eq!(fb.get(fb.get_width() - 1, y), Some(3));
+
+        // Starting beyond the screen writes nothing
+        assert_eq!(fb.set_multi_clamped(fb.get_size(), &pixel_bytes), 0);
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(8)]
+    #[case(64)]
+    #[case(1024)]
+    pub fn test_copy_linear_pixels_matches_simple_framebuffer(#[case] tile_size: usize) {
+        let (width, height) = (100, 70);
+        let tiled = TiledFrameBuffer::new(width, height, tile_size);
+        let simple = SimpleFrameBuffer::new(width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let rgba = (x * 1_000 + y) as u32;
+                tiled.set(x, y, rgba);
+                simple.set(x, y, rgba);
+            }
+        }
+
+        let mut linear = vec![0; width * height];
+        tiled.copy_linear_pixels(&mut linear);
+        assert_eq!(linear, simple.as_pixels());
+    }
+
+    /// Exporting a framebuffer and importing it into another one (e.g. on another server using `PXMULTI`) must not
+    /// change any pixel, regardless of the framebuffer implementations on both sides
+    #[rstest]
+    #[case::simple_to_simple(false, false)]
+    #[case::simple_to_tiled(false, true)]
+    #[case::tiled_to_simple(true, false)]
+    #[case::tiled_to_tiled(true, true)]
+    pub fn test_export_import_roundtrip(#[case] export_tiled: bool, #[case] import_tiled: bool) {
+        let (width, height) = (100, 70);
+        let new_fb = |tiled: bool| -> Box<dyn FrameBuffer> {
+            if tiled {
+                Box::new(TiledFrameBuffer::new(width, height, 16))
+            } else {
+                Box::new(SimpleFrameBuffer::new(width, height))
+            }
+        };
+
+        let source = new_fb(export_tiled);
+        for y in 0..height {
+            for x in 0..width {
+                source.set(
+                    x,
+                    y,
+                    (x as u32 * 0x0001_0203 + y as u32 * 0x0003_0001) & 0x00ff_ffff,
+                );
+            }
+        }
+        // Exported line by line, as a client would do it. Clients sending a (meaningless) alpha value must not matter.
+        let exported_bytes: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let [r, g, b, _] = source.get(x, y).unwrap().to_ne_bytes();
+                [r, g, b, 0xff]
+            })
+            .collect();
+
+        let target = new_fb(import_tiled);
+        assert_eq!(target.set_multi(0, 0, &exported_bytes), (0, height));
+
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(
+                    target.get(x, y),
+                    source.get(x, y),
+                    "Checking pixel ({x}, {y})"
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    #[case::simple(false)]
+    #[case::tiled(true)]
+    /// The alpha byte of imported pixels is ignored, so they are stored exactly the same as pixels drawn using `PX`
+    pub fn test_set_multi_clears_alpha(#[case] tiled: bool) {
+        let fb: Box<dyn FrameBuffer> = if tiled {
+            Box::new(TiledFrameBuffer::new(640, 480, 64))
+        } else {
+            Box::new(SimpleFrameBuffer::new(640, 480))
+        };
+
+        // r, g, b, a
+        let pixel_bytes = [
+            0x12, 0x34, 0x56, 0x78, 0xff, 0xee, 0xdd, 0xff, 0x01, 0x02, 0x03, 0x00,
+        ];
+        fb.set_multi(10, 20, &pixel_bytes);
+
+        assert_eq!(fb.get(10, 20), Some(0x0056_3412));
+        assert_eq!(fb.get(11, 20), Some(0x00dd_eeff));
+        assert_eq!(fb.get(12, 20), Some(0x0003_0201));
+    }
+}
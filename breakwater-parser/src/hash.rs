@@ -0,0 +1,57 @@
+use twox_hash::XxHash3_64;
+
+use crate::FrameBuffer;
+
+/// Fast (non-cryptographic) hash of the raw pixels of the framebuffer (see [`FrameBuffer::as_bytes`]), so that e.g. the
+/// canvases of multiple servers synced using `PXMULTI` can be compared without transferring them.
+///
+/// The hash only matches across servers using the same framebuffer type, as it depends on how the pixels are stored.
+pub fn canvas_hash<FB: FrameBuffer>(fb: &FB) -> u64 {
+    XxHash3_64::oneshot(fb.as_bytes())
+}
+
+/// Writes the [`canvas_hash`] to `response` in the format `HASH <hash as 16 hexadecimal digits>\n`
+pub fn write_canvas_hash<FB: FrameBuffer>(fb: &FB, response: &mut Vec<u8>) {
+    response.extend_from_slice(format!("HASH {:016x}\n", canvas_hash(fb)).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleFrameBuffer;
+
+    fn draw_gradient(fb: &SimpleFrameBuffer) {
+        for x in 0..fb.get_width() {
+            for y in 0..fb.get_height() {
+                fb.set(x, y, (x * y) as u32 & 0x00ff_ffff);
+            }
+        }
+    }
+
+    #[test]
+    fn test_canvas_hash() {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        let other_fb = SimpleFrameBuffer::new(640, 480);
+        assert_eq!(canvas_hash(&fb), canvas_hash(&other_fb));
+
+        draw_gradient(&fb);
+        draw_gradient(&other_fb);
+        assert_eq!(canvas_hash(&fb), canvas_hash(&other_fb));
+
+        // A single differing pixel changes the hash
+        other_fb.set(639, 479, 0x0012_3456);
+        assert_ne!(canvas_hash(&fb), canvas_hash(&other_fb));
+    }
+
+    #[test]
+    fn test_write_canvas_hash() {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        let mut response = Vec::new();
+        write_canvas_hash(&fb, &mut response);
+
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            format!("HASH {:016x}\n", canvas_hash(&fb))
+        );
+    }
+}
@@ -5,17 +5,103 @@ use const_format::formatcp;
 
 #[cfg(target_arch = "x86_64")]
 mod assembler;
+mod blend;
 mod framebuffer;
+#[cfg(feature = "hash-command")]
+mod hash;
 mod memchr;
 mod original;
 mod refactored;
+mod remaining_payload;
+mod rotation;
+#[cfg(feature = "screenshot-command")]
+mod screenshot;
+#[cfg(feature = "qoi")]
+mod snapshot;
+#[cfg(feature = "sprites")]
+mod sprites;
+#[cfg(test)]
+mod test_helpers;
+#[cfg(feature = "text")]
+mod text;
+mod write_protection;
 
 #[cfg(target_arch = "x86_64")]
 pub use assembler::AssemblerParser;
-pub use framebuffer::{simple::SimpleFrameBuffer, FrameBuffer};
+pub use blend::{alpha_blend, alpha_blend_scalar};
+#[cfg(feature = "shared-memory")]
+pub use framebuffer::shared_memory::{
+    SharedMemory, SHARED_MEMORY_DIR, SHARED_MEMORY_HEADER_SIZE, SHARED_MEMORY_MAGIC,
+};
+pub use framebuffer::{
+    dirty::{DirtyRegion, DirtyTiles, DIRTY_TILE_SIZE},
+    packed24::Packed24FrameBuffer,
+    recording::{JournalRecord, RecordingFrameBuffer},
+    resizable::{ResizableFrameBuffer, Resize},
+    rgb565::Rgb565FrameBuffer,
+    serialized::SerializedFrameBuffer,
+    simple::SimpleFrameBuffer,
+    tiled::TiledFrameBuffer,
+    FrameBuffer,
+};
+#[cfg(feature = "hash-command")]
+pub use hash::{canvas_hash, write_canvas_hash};
 pub use memchr::MemchrParser;
-pub use original::OriginalParser;
+#[cfg(not(feature = "alpha"))]
+pub use original::ALPHA_NOT_SUPPORTED_TEXT;
+#[cfg(feature = "circle-command")]
+pub use original::MAX_CIRCLE_RADIUS;
+#[cfg(feature = "flip-command")]
+pub use original::MAX_FLIP_PIXELS;
+#[cfg(feature = "getrect")]
+pub use original::MAX_GETRECT_PIXELS;
+pub use original::{OriginalParser, INVALID_OFFSET_COMMAND_TEXT, INVALID_PX_COMMAND_TEXT};
+#[cfg(any(
+    feature = "hash-command",
+    feature = "qoi",
+    feature = "screenshot-command"
+))]
+pub use original::{MAX_CANVAS_COMMANDS_PER_PARSE, TOO_MANY_CANVAS_COMMANDS_TEXT};
+#[cfg(feature = "text-command")]
+pub use original::{MAX_TEXT_LENGTH, TEXT_FONT_SIZE};
 pub use refactored::RefactoredParser;
+pub use remaining_payload::{PayloadHandler, RemainingPayload};
+pub use rotation::CanvasRotation;
+#[cfg(feature = "screenshot-command")]
+pub use screenshot::{write_screenshot, ScreenshotCompression};
+#[cfg(feature = "qoi")]
+pub use snapshot::write_qoi_snapshot;
+#[cfg(feature = "sprites")]
+pub use sprites::{Sprite, Sprites, MAX_SPRITES_PER_CONNECTION, MAX_SPRITE_BYTES_PER_CONNECTION};
+#[cfg(feature = "text")]
+pub use text::{draw_text, rasterize_text};
+pub use write_protection::WriteProtectedRegion;
+
+// The limits of the following commands only exist if the command is enabled, so their help texts can't be picked using
+// `cfg!` like the others
+#[cfg(feature = "flip-command")]
+const FLIP_HELP_TEXT: &str = formatcp!("FLIP x y w h h|v: Mirror the area with the top-left corner (x,y), the width w and the height h in place, either horizontally (h) or vertically (v). Areas larger than {MAX_FLIP_PIXELS} pixels are ignored\n");
+#[cfg(not(feature = "flip-command"))]
+const FLIP_HELP_TEXT: &str = "";
+
+#[cfg(feature = "circle-command")]
+const CIRCLE_HELP_TEXT: &str = formatcp!("CIRCLE x y r rrggbb(aa): Fill the disc with the center (x,y) and the radius r with the given color. Discs with a radius larger than {MAX_CIRCLE_RADIUS} are ignored\n");
+#[cfg(not(feature = "circle-command"))]
+const CIRCLE_HELP_TEXT: &str = "";
+
+#[cfg(feature = "getrect")]
+const GETRECT_HELP_TEXT: &str = formatcp!("GETRECT x y w h: Get the pixels of the area with the top-left corner (x,y), the width w and the height h. The response is `GETRECT <width> <height> <length in bytes>\\n` followed by the pixels as rgba (4 bytes each) row by row. The area is clipped to the drawing surface and to at most {MAX_GETRECT_PIXELS} pixels, the response contains the resulting width and height\n");
+#[cfg(not(feature = "getrect"))]
+const GETRECT_HELP_TEXT: &str = "";
+
+#[cfg(feature = "sprites")]
+const SPRITES_HELP_TEXT: &str = formatcp!("\
+SPRITE define id w h <rgba bytes>: Upload a sprite with the width w and the height h, the header is followed by a single space and w * h pixels as rgba (4 bytes each) row by row without a newline. Every connection can define up to {MAX_SPRITES_PER_CONNECTION} sprites with a total of {} pixels\n\
+SPRITE blit id x y: Draw the sprite previously uploaded on this connection with its top-left corner at (x,y)\n",
+    MAX_SPRITE_BYTES_PER_CONNECTION / 4
+);
+#[cfg(not(feature = "sprites"))]
+const SPRITES_HELP_TEXT: &str = "";
 
 pub const HELP_TEXT: &[u8] = formatcp!("\
 Pixelflut server powered by breakwater https://github.com/sbernauer/breakwater
@@ -26,20 +112,56 @@ PX x y rrggbb: Color the pixel (x,y) with the given hexadecimal color rrggbb
 PX x y gg: Color the pixel (x,y) with the hexadecimal color gggggg. Basically this is the same as the other commands, but is a more efficient way of filling white, black or gray areas
 PX x y: Get the color value of the pixel (x,y)
 {}{}SIZE: Get the size of the drawing surface, e.g. `SIZE 1920 1080`
-OFFSET x y: Apply offset (x,y) to all further pixel draws on this connection. This can e.g. be used to pre-calculate an image/animation and simply use the OFFSET command to move it around the screen without the need to re-calculate it
-",
+CAPS: Get the capabilities of the server (size, enabled features and connection limits) as `key=value` pairs in a single line
+OFFSET x y: Apply offset (x,y) to all further pixel draws and reads on this connection. This can e.g. be used to pre-calculate an image/animation and simply use the OFFSET command to move it around the screen without the need to re-calculate it
+{}{}{}{}{}{}{}{}{}",
 if cfg!(feature = "alpha") {
     "PX x y rrggbbaa: Color the pixel (x,y) with the given hexadecimal color rrggbb and a transparency of aa, where ff means draw normally on top of the existing pixel and 00 means fully transparent (no change at all)"
 } else {
     "PX x y rrggbbaa: Color the pixel (x,y) with the given hexadecimal color rrggbb. The alpha part is discarded for performance reasons, as breakwater was compiled without the alpha feature"
 },
 if cfg!(feature = "binary-set-pixel") {
-    "PBxxyyrgba: Binary version of the PX command. x and y are little-endian 16 bit coordinates, r, g, b and a are a byte each. There is *no* newline after the command.\n"
+    "PBxxyyrgba: Binary version of the PX command. x and y are little-endian 16 bit coordinates, r, g, b and a are a byte each. There is *no* newline after the command.\n\
+    PGxxyy: Binary version of the `PX x y` command. x and y are little-endian 16 bit coordinates. The response is the color as rgba (a byte each, a is always ff) without a newline, pixels outside of the drawing surface are not answered. There is *no* newline after the command.\n"
+} else {
+    ""
+},
+if cfg!(feature = "binary-sync-pixels") && cfg!(feature = "alpha") {
+    "PXMULTI<startX:16><startY:16><len:32><rgba 1 of (startX, startY)><rgba 2 of (startX + 1, startY)><rgba 3 of (startX + 1, startY)>...<rgba len>: EXPERIMENTAL binary syncing of whole pixel areas. The server will take the following <len> bytes and blend every pixel onto the framebuffer using its alpha channel, pixels with an alpha of 00 are not drawn at all. This is intended for export-use, especially when syncing or combining multiple Pixelflut screens across multiple servers\n"
+} else if cfg!(feature = "binary-sync-pixels") {
+    "PXMULTI<startX:16><startY:16><len:32><rgba 1 of (startX, startY)><rgba 2 of (startX + 1, startY)><rgba 3 of (startX + 1, startY)>...<rgba len>: EXPERIMENTAL binary syncing of whole pixel areas. Please note that for performance reasons this will be copied 1:1 to the servers framebuffer. The server will just take the following <len> bytes and copy them into the framebuffer, only the alpha channel is ignored (it is not blended), so you might mess up the screen. This is intended for export-use, especially when syncing or combining multiple Pixelflut screens across multiple servers\n"
+} else {
+    ""
+},
+if cfg!(feature = "qoi") {
+    "QOI: Get a snapshot of the drawing surface as QOI image. The response is `QOI <length in bytes>\\n` followed by the image\n"
+} else {
+    ""
+},
+FLIP_HELP_TEXT,
+CIRCLE_HELP_TEXT,
+GETRECT_HELP_TEXT,
+if cfg!(feature = "cas-command") {
+    "PXCAS x y expected_rrggbb new_rrggbb: Color the pixel (x,y) with new_rrggbb, but only in case it currently has the color expected_rrggbb. This prevents overwriting pixels other clients have drawn in the meantime\n"
+} else {
+    ""
+},
+if cfg!(feature = "text-command") {
+    formatcp!(
+        "TEXT x y rrggbb text: Write the text (at most {} bytes) with the given color, the top-left corner is at (x,y)\n",
+        original::MAX_TEXT_LENGTH
+    )
 } else {
     ""
 },
-if cfg!(feature = "binary-sync-pixels") {
-    "PXMULTI<startX:16><startY:16><len:32><rgba 1 of (startX, startY)><rgba 2 of (startX + 1, startY)><rgba 3 of (startX + 1, startY)>...<rgba len>: EXPERIMENTAL binary syncing of whole pixel areas. Please note that for performance reasons this will be copied 1:1 to the servers framebuffer. The server will just take the following <len> bytes and memcpy it into the framebuffer, so the alpha channel doesn't matter and you might mess up the screen. This is intended for export-use, especially when syncing or combining multiple Pixelflut screens across multiple servers\n"
+SPRITES_HELP_TEXT,
+if cfg!(feature = "hash-command") {
+    "HASH: Get a hash of the whole drawing surface as `HASH <hash as 16 hexadecimal digits>`, e.g. to check that the canvases of multiple servers are in sync\n"
+} else {
+    ""
+},
+if cfg!(feature = "screenshot-command") {
+    "SCREENSHOT [ZSTD]: Get the pixels of the whole drawing surface. The response is `SCREENSHOT <width> <height> <compression> <length in bytes>\\n` followed by the pixels as rgba (4 bytes each) row by row. The compression is 0 for `SCREENSHOT` and 1 for `SCREENSHOT ZSTD`, in which case the pixels are zstd-compressed\n"
 } else {
     ""
 },
@@ -47,10 +169,33 @@ if cfg!(feature = "binary-sync-pixels") {
 
 pub const ALT_HELP_TEXT: &[u8] = b"Stop spamming HELP!\n";
 
+/// Once the response reaches this size, parsers may stop parsing early, so that the response can be written before
+/// continuing, see [`Parser::parse`].
+pub const RESPONSE_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Parses the Pixelflut commands of a single client connection.
+///
+/// The connection offset set by `OFFSET x y` is applied identically by all commands addressing pixels: It is added to
+/// the coordinates of every draw (`PX` with gray, rgb or rgba color, `PB`, the start coordinates of `PXMULTI`, the
+/// area of `FLIP`, the center of `CIRCLE`, `PXCAS`, `TEXT` and `SPRITE blit`) and every read (`PX x y` and the area of
+/// `GETRECT`). Reads respond with the coordinates as sent by the client, i.e. without the offset. Commands describing
+/// the whole canvas (such as `SIZE`, `CAPS`, `QOI` or `HASH`) are not affected by the offset.
 pub trait Parser {
     /// Returns the last byte parsed. The next parsing loop will again contain all data that was not parsed.
+    ///
+    /// Parsers may stop after a complete command once the response reached [`RESPONSE_FLUSH_THRESHOLD`] bytes, in which
+    /// case the caller should write the response and continue parsing after the last byte parsed.
     fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize;
 
     // Sadly this cant be const (yet?) (https://github.com/rust-lang/rust/issues/71971 and https://github.com/rust-lang/rfcs/pull/2632)
     fn parser_lookahead(&self) -> usize;
+
+    /// Returns the number of pixels this parser has drawn since it was created.
+    fn pixels_drawn(&self) -> u64;
+
+    /// Returns the number of commands that started like a known command (e.g. `PX` or `OFFSET`), but could not be
+    /// parsed, since the parser was created. Parsers not detecting them always return 0.
+    fn malformed_commands(&self) -> u64 {
+        0
+    }
 }
@@ -1,70 +1,242 @@
 use std::sync::Arc;
 
-use crate::{FrameBuffer, Parser};
+#[cfg(feature = "alpha")]
+use crate::alpha_blend;
+#[cfg(feature = "binary-sync-pixels")]
+use crate::{framebuffer::sync_pixels, original::PixelSync, RemainingPayload};
+use crate::{original::write_pixel_response, FrameBuffer, Parser, ALT_HELP_TEXT, HELP_TEXT};
+
+const PARSER_LOOKAHEAD: usize = "PX 1234 1234 rrggbbaa\n".len(); // Longest possible command
 
 pub struct MemchrParser<FB: FrameBuffer> {
+    connection_x_offset: usize,
+    connection_y_offset: usize,
     fb: Arc<FB>,
+    pixels_drawn: u64,
+    /// Number of `HELP` commands answered on this connection, so that clients can't spam them
+    help_count: usize,
+    /// Payload of a variable-length command (e.g. `PXMULTI`), which did not fit into the last buffer
+    #[cfg(feature = "binary-sync-pixels")]
+    remaining_payload: Option<RemainingPayload<FB>>,
 }
 
 impl<FB: FrameBuffer> MemchrParser<FB> {
     pub fn new(fb: Arc<FB>) -> Self {
-        Self { fb }
+        Self {
+            connection_x_offset: 0,
+            connection_y_offset: 0,
+            fb,
+            pixels_drawn: 0,
+            help_count: 0,
+            #[cfg(feature = "binary-sync-pixels")]
+            remaining_payload: None,
+        }
+    }
+
+    fn handle_line(&mut self, line: &[u8], response: &mut Vec<u8>) {
+        let mut tokens = line.split(|&b| b == b' ');
+
+        match tokens.next() {
+            Some(b"PX") => {
+                let (Some(x), Some(y)) = (
+                    tokens.next().and_then(parse_coordinate),
+                    tokens.next().and_then(parse_coordinate),
+                ) else {
+                    return;
+                };
+                // The coordinates are not limited in length, so they can be close to usize::MAX
+                let (client_x, client_y) = (x, y);
+                let x = client_x.saturating_add(self.connection_x_offset);
+                let y = client_y.saturating_add(self.connection_y_offset);
+
+                match tokens.next() {
+                    Some(color) => {
+                        if let Some(rgba) = parse_color(color) {
+                            self.set_pixel(x, y, rgba);
+                        }
+                    }
+                    None => {
+                        if let Some(rgb) = self.fb.get(x, y) {
+                            // We don't want to return the actual (absolute) coordinates, the client should also get the result offseted
+                            write_pixel_response(response, client_x, client_y, rgb);
+                        }
+                    }
+                }
+            }
+            Some(b"OFFSET") => {
+                if let (Some(x), Some(y)) = (
+                    tokens.next().and_then(parse_coordinate),
+                    tokens.next().and_then(parse_coordinate),
+                ) {
+                    // Larger offsets would only move all further draws and reads outside of the canvas as well
+                    self.connection_x_offset = x.min(self.fb.get_width());
+                    self.connection_y_offset = y.min(self.fb.get_height());
+                }
+            }
+            Some(b"SIZE") => {
+                response.extend_from_slice(
+                    format!("SIZE {} {}\n", self.fb.get_width(), self.fb.get_height()).as_bytes(),
+                );
+            }
+            Some(b"HELP") => {
+                match self.help_count {
+                    0..=2 => response.extend_from_slice(HELP_TEXT),
+                    3 => response.extend_from_slice(ALT_HELP_TEXT),
+                    // The client has requested the help to often, let's just ignore it
+                    _ => return,
+                }
+                self.help_count += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Draws the color in the format `0xaabbggrr`
+    #[cfg(not(feature = "alpha"))]
+    fn set_pixel(&mut self, x: usize, y: usize, rgba: u32) {
+        self.fb.set(x, y, rgba & 0x00ff_ffff);
+        self.pixels_drawn += 1;
+    }
+
+    /// Draws the color in the format `0xaabbggrr`
+    #[cfg(feature = "alpha")]
+    fn set_pixel(&mut self, x: usize, y: usize, rgba: u32) {
+        self.pixels_drawn += 1;
+
+        let alpha = rgba >> 24;
+        if alpha == 0 || x >= self.fb.get_width() || y >= self.fb.get_height() {
+            return;
+        }
+
+        let current = unsafe { self.fb.get_unchecked(x, y) };
+        self.fb.set(x, y, alpha_blend(current, rgba));
     }
 }
 
 impl<FB: FrameBuffer> Parser for MemchrParser<FB> {
-    fn parse(&mut self, buffer: &[u8], _response: &mut Vec<u8>) -> usize {
-        let mut last_char_after_newline = 0;
-        for newline in memchr::memchr_iter(b'\n', buffer) {
-            // TODO Use get_unchecked everywhere
-            let line = &buffer[last_char_after_newline..newline.saturating_sub(1)];
-            last_char_after_newline = newline + 1;
-
-            if line.is_empty() {
-                panic!("Line is empty, we probably should handle this");
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        // We don't need the lookahead, as we only parse complete commands
+        let buffer = &buffer[..buffer.len().saturating_sub(PARSER_LOOKAHEAD)];
+        let mut last_byte_parsed = 0;
+        let mut i = 0;
+
+        #[cfg(feature = "binary-sync-pixels")]
+        if let Some(remaining) = &mut self.remaining_payload {
+            let (consumed, pixels_drawn) = remaining.consume(self.fb.as_ref(), buffer);
+            self.pixels_drawn += pixels_drawn;
+
+            if !remaining.is_finished() {
+                // The client requested to write more bytes that are currently in the buffer, so there is nothing to
+                // do left
+                return consumed.saturating_sub(1);
             }
 
-            let mut spaces = memchr::memchr_iter(b' ', line);
-            let Some(first_space) = spaces.next() else {
+            self.remaining_payload = None;
+            i = consumed;
+            last_byte_parsed = consumed.saturating_sub(1);
+        }
+
+        while i < buffer.len() {
+            let rest = &buffer[i..];
+
+            // Binary commands can contain newlines, so we need to check for them before searching for the newline
+            #[cfg(feature = "binary-set-pixel")]
+            if rest.starts_with(b"PB") {
+                let Some(command) = rest.get(2..10) else {
+                    // The command is cut off, the next parse call will contain the rest
+                    break;
+                };
+                let x = u16::from_le_bytes([command[0], command[1]]);
+                let y = u16::from_le_bytes([command[2], command[3]]);
+                let rgba = u32::from_le_bytes([command[4], command[5], command[6], command[7]]);
+
+                // TODO: Support alpha channel (behind alpha feature flag)
+                self.fb.set(
+                    x as usize + self.connection_x_offset,
+                    y as usize + self.connection_y_offset,
+                    rgba & 0x00ff_ffff,
+                );
+                self.pixels_drawn += 1;
+
+                last_byte_parsed = i + 9;
+                i += 10;
                 continue;
-            };
+            }
+            #[cfg(feature = "binary-sync-pixels")]
+            if rest.starts_with(b"PXMULTI") {
+                let Some(header) = rest.get(7..15) else {
+                    // The command is cut off, the next parse call will contain the rest
+                    break;
+                };
+                let start_x =
+                    u16::from_le_bytes([header[0], header[1]]) as usize + self.connection_x_offset;
+                let start_y =
+                    u16::from_le_bytes([header[2], header[3]]) as usize + self.connection_y_offset;
+                let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                let len_in_bytes = len as usize * 4;
+                let current_index = start_x + start_y * self.fb.get_width();
+                let payload = &rest[15..];
 
-            match &line[0..first_space] {
-                b"PX" => {
-                    let Some(second_space) = spaces.next() else {
-                        continue;
-                    };
-                    let Some(third_space) = spaces.next() else {
-                        continue;
-                    };
-                    let Some(fourth_space) = spaces.next() else {
-                        continue;
-                    };
-                    let x: u16 = std::str::from_utf8(&line[first_space + 1..second_space])
-                        .expect("Not utf-8")
-                        .parse()
-                        .expect("x was not a number");
-                    let y: u16 = std::str::from_utf8(&line[second_space + 1..third_space])
-                        .expect("Not utf-8")
-                        .parse()
-                        .expect("y was not a number");
-                    let rgba: u32 = std::str::from_utf8(&line[third_space + 1..fourth_space])
-                        .expect("Not utf-8")
-                        .parse()
-                        .expect("rgba was not a number");
-
-                    self.fb.set(x as usize, y as usize, rgba);
-                }
-                _ => {
+                if len_in_bytes <= payload.len() {
+                    // Easy going here
+                    sync_pixels(&*self.fb, current_index, &payload[..len_in_bytes]);
+                    self.pixels_drawn += len as u64;
+
+                    i += 15 + len_in_bytes;
+                    last_byte_parsed = i - 1;
                     continue;
                 }
+
+                // The client requested to write more bytes that are currently in the buffer, we need to remember
+                // what the client is doing.
+                let mut remaining = RemainingPayload::new(
+                    Box::new(PixelSync {
+                        current_index,
+                        maintenance_mode: None,
+                    }),
+                    len_in_bytes,
+                );
+                let (consumed, pixels_drawn) = remaining.consume(self.fb.as_ref(), payload);
+                self.pixels_drawn += pixels_drawn;
+                self.remaining_payload = Some(remaining);
+
+                return i + 15 + consumed - 1;
             }
+
+            let Some(newline) = memchr::memchr(b'\n', rest) else {
+                // The command is cut off, the next parse call will contain the rest
+                break;
+            };
+            self.handle_line(&rest[..newline], response);
+
+            last_byte_parsed = i + newline;
+            i += newline + 1;
         }
 
-        last_char_after_newline.saturating_sub(1)
+        last_byte_parsed
     }
 
     fn parser_lookahead(&self) -> usize {
-        0
+        PARSER_LOOKAHEAD
+    }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.pixels_drawn
+    }
+}
+
+fn parse_coordinate(token: &[u8]) -> Option<usize> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// Parses the colors `rrggbb`, `rrggbbaa` and `gg` into the format `0xaabbggrr`
+fn parse_color(color: &[u8]) -> Option<u32> {
+    let value = u32::from_str_radix(std::str::from_utf8(color).ok()?, 16).ok()?;
+
+    match color.len() {
+        2 => Some(0xff00_0000 | (value * 0x01_0101)),
+        6 => Some(0xff00_0000 | (value.swap_bytes() >> 8)),
+        8 => Some(value.swap_bytes()),
+        _ => None,
     }
 }
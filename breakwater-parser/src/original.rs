@@ -1,107 +1,906 @@
 #[cfg(feature = "binary-sync-pixels")]
 use core::slice;
 use std::{
+    ops::Deref,
     simd::{num::SimdUint, u32x8, Simd},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
-use crate::{FrameBuffer, Parser, ALT_HELP_TEXT, HELP_TEXT};
+#[cfg(feature = "alpha")]
+use crate::alpha_blend;
+#[cfg(feature = "text-command")]
+use crate::rasterize_text;
+#[cfg(feature = "hash-command")]
+use crate::write_canvas_hash;
+#[cfg(feature = "qoi")]
+use crate::write_qoi_snapshot;
+#[cfg(feature = "sprites")]
+use crate::Sprites;
+#[cfg(feature = "binary-sync-pixels")]
+use crate::{framebuffer::sync_pixels, PayloadHandler, RemainingPayload};
+#[cfg(feature = "screenshot-command")]
+use crate::{write_screenshot, ScreenshotCompression};
+use crate::{
+    CanvasRotation, FrameBuffer, Parser, WriteProtectedRegion, ALT_HELP_TEXT, HELP_TEXT,
+    RESPONSE_FLUSH_THRESHOLD,
+};
+
+/// Length of the longest possible command of the enabled features, every command must be followed by at least this many
+/// bytes to be parsed
+pub const PARSER_LOOKAHEAD: usize = {
+    let mut lookahead = "PX 1234 1234 rrggbbaa\n".len();
+    if cfg!(feature = "flip-command") {
+        lookahead = max(lookahead, "FLIP 1234 1234 1234 1234 h\n".len());
+    }
+    if cfg!(feature = "getrect") {
+        lookahead = max(lookahead, "GETRECT 1234 1234 1234 1234\n".len());
+    }
+    if cfg!(feature = "cas-command") {
+        lookahead = max(lookahead, "PXCAS 1234 1234 rrggbb rrggbb\n".len());
+    }
+    if cfg!(feature = "circle-command") {
+        lookahead = max(lookahead, "CIRCLE 1234 1234 1234 rrggbbaa\n".len());
+    }
+    if cfg!(feature = "text-command") {
+        lookahead = max(
+            lookahead,
+            "TEXT 1234 1234 rrggbb \n".len() + MAX_TEXT_LENGTH,
+        );
+    }
+    if cfg!(feature = "sprites") {
+        lookahead = max(lookahead, "SPRITE define 1234 1234 1234 ".len());
+    }
+    lookahead
+};
+
+/// Maximum length of the text of a `TEXT` command in bytes
+pub const MAX_TEXT_LENGTH: usize = 64;
+
+/// Font size (height in pixels) of the text drawn using `TEXT`
+#[cfg(feature = "text-command")]
+pub const TEXT_FONT_SIZE: f32 = 24.0;
+
+/// Maximum number of pixels a single `FLIP` command can mirror, so that a single command can't keep the parser busy
+/// for too long
+#[cfg(feature = "flip-command")]
+pub const MAX_FLIP_PIXELS: usize = 512 * 512;
+
+/// Maximum radius of a `CIRCLE`, so that a single command can't keep the parser busy for too long
+#[cfg(feature = "circle-command")]
+pub const MAX_CIRCLE_RADIUS: usize = 256;
 
-pub const PARSER_LOOKAHEAD: usize = "PX 1234 1234 rrggbbaa\n".len(); // Longest possible command
+/// Maximum number of pixels a single `GETRECT` command returns, so that a single command can't keep the parser busy
+/// for too long
+#[cfg(feature = "getrect")]
+pub const MAX_GETRECT_PIXELS: usize = 512 * 512;
+
+/// Maximum number of commands processing the whole canvas (`HASH`, `QOI` and `SCREENSHOT`) answered per
+/// [`Parser::parse`] call, so that a single read full of them can't keep the parser busy for too long. The server only
+/// continues a read after writing the (usually large) responses, so their rate is also bounded by the client reading
+/// them.
+#[cfg(any(
+    feature = "hash-command",
+    feature = "qoi",
+    feature = "screenshot-command"
+))]
+pub const MAX_CANVAS_COMMANDS_PER_PARSE: usize = 4;
+
+/// Response sent for the commands exceeding [`MAX_CANVAS_COMMANDS_PER_PARSE`], so that the client does not wait for the
+/// response forever
+#[cfg(any(
+    feature = "hash-command",
+    feature = "qoi",
+    feature = "screenshot-command"
+))]
+pub const TOO_MANY_CANVAS_COMMANDS_TEXT: &[u8] =
+    b"ERROR: Too many HASH, QOI or SCREENSHOT commands at once, wait for the response before sending the next one\n";
+
+/// Response sent in strict mode for `PX` commands that could not be parsed
+pub const INVALID_PX_COMMAND_TEXT: &[u8] =
+    b"ERROR: Invalid PX command, expected `PX x y rrggbb`, `PX x y rrggbbaa`, `PX x y gg` or `PX x y`\n";
+/// Response sent in strict mode for `OFFSET` commands that could not be parsed
+pub const INVALID_OFFSET_COMMAND_TEXT: &[u8] =
+    b"ERROR: Invalid OFFSET command, expected `OFFSET x y`\n";
+/// Response sent for `PX x y rrggbbaa` commands in case alpha is rejected, see [`OriginalParser::with_reject_alpha`]
+#[cfg(not(feature = "alpha"))]
+pub const ALPHA_NOT_SUPPORTED_TEXT: &[u8] =
+    b"ERROR: Alpha is not supported by this server, use `PX x y rrggbb` instead\n";
 
 pub(crate) const PX_PATTERN: u64 = string_to_number(b"PX \0\0\0\0\0");
 pub(crate) const PB_PATTERN: u64 = string_to_number(b"PB\0\0\0\0\0\0");
+#[cfg(feature = "binary-set-pixel")]
+pub(crate) const PG_PATTERN: u64 = string_to_number(b"PG\0\0\0\0\0\0");
 pub(crate) const OFFSET_PATTERN: u64 = string_to_number(b"OFFSET \0\0");
 pub(crate) const SIZE_PATTERN: u64 = string_to_number(b"SIZE\0\0\0\0");
 pub(crate) const HELP_PATTERN: u64 = string_to_number(b"HELP\0\0\0\0");
+pub(crate) const CAPS_PATTERN: u64 = string_to_number(b"CAPS\0\0\0\0");
 #[cfg(feature = "binary-sync-pixels")]
 pub(crate) const PXMULTI_PATTERN: u64 = string_to_number(b"PXMULTI\0");
+#[cfg(feature = "flip-command")]
+pub(crate) const FLIP_PATTERN: u64 = string_to_number(b"FLIP \0\0\0");
+#[cfg(feature = "circle-command")]
+pub(crate) const CIRCLE_PATTERN: u64 = string_to_number(b"CIRCLE \0");
+#[cfg(feature = "cas-command")]
+pub(crate) const PXCAS_PATTERN: u64 = string_to_number(b"PXCAS \0\0");
+#[cfg(feature = "getrect")]
+pub(crate) const GETRECT_PATTERN: u64 = string_to_number(b"GETRECT ");
+#[cfg(feature = "qoi")]
+pub(crate) const QOI_PATTERN: u64 = string_to_number(b"QOI\n\0\0\0\0");
+#[cfg(feature = "text-command")]
+pub(crate) const TEXT_PATTERN: u64 = string_to_number(b"TEXT \0\0\0");
+#[cfg(feature = "sprites")]
+pub(crate) const SPRITE_PATTERN: u64 = string_to_number(b"SPRITE \0");
+#[cfg(feature = "hash-command")]
+pub(crate) const HASH_PATTERN: u64 = string_to_number(b"HASH\0\0\0\0");
+#[cfg(feature = "screenshot-command")]
+pub(crate) const SCREENSHOT_PATTERN: u64 = string_to_number(b"SCREENSH");
 
-pub struct OriginalParser<FB: FrameBuffer> {
+/// The framebuffer is usually shared with the other connections using an [`Arc`], but it can also be borrowed (`F` being
+/// `&FB`), so that the parser does not need to own it, see [`OriginalParser::new`].
+pub struct OriginalParser<FB: FrameBuffer, F: Deref<Target = FB> = Arc<FB>> {
     connection_x_offset: usize,
     connection_y_offset: usize,
-    fb: Arc<FB>,
+    fb: F,
+    pixels_drawn: u64,
+    /// Commands that looked like a known command, but could not be parsed, see [`Parser::malformed_commands`]
+    malformed_commands: u64,
+    /// Report commands that could not be parsed to the client instead of silently skipping them
+    strict: bool,
+    /// Accept runs of spaces between the tokens of `PX` commands
+    lenient_whitespace: bool,
+    /// Respond with an error to `PX x y rrggbbaa` instead of drawing the pixel opaque
+    #[cfg(not(feature = "alpha"))]
+    reject_alpha: bool,
+    /// Respond to `PX x y` with `rrggbbaa` instead of `rrggbb`
+    #[cfg(feature = "alpha")]
+    rgba_reads: bool,
+    /// Confirm every drawn pixel to the client, intended for debugging clients
+    pixel_command_echo: bool,
+    /// Areas clients are not allowed to draw into using `PX` or `PB`
+    write_protected_regions: Vec<WriteProtectedRegion>,
+    /// Rotation of the canvas clients draw onto relative to the framebuffer
+    canvas_rotation: CanvasRotation,
+    /// Size of the canvas clients draw onto (before the rotation), can be smaller than the framebuffer
+    width: usize,
+    height: usize,
+    /// Logical size requested using [`Self::with_logical_size`], so that it can be re-applied to another framebuffer
+    logical_width: Option<usize>,
+    logical_height: Option<usize>,
+    /// Additional byte terminating commands, besides the newline
+    #[cfg(feature = "custom-separators")]
+    command_separator: Option<u8>,
+    /// Only used to report them to the client in the `CAPS` response, the limits are enforced by the server
+    max_pixels_per_connection: Option<u64>,
+    max_bytes_per_connection: Option<u64>,
+    /// Set while the canvas is read-only, see [`Self::with_maintenance_mode`]
+    maintenance_mode: Arc<AtomicBool>,
+    /// Whether drawing a pixel needs more than the bounds check of the framebuffer, because of the rotation, logical
+    /// size, write-protected regions or maintenance mode. Updated once per [`Parser::parse`] call, so that the hot path
+    /// does not pay for them when none of them apply.
+    restricted: bool,
+    /// Number of `HELP` commands answered on this connection, so that clients can't spam them
+    help_count: usize,
+    /// Number of commands processing the whole canvas answered during the current [`Parser::parse`] call, see
+    /// [`MAX_CANVAS_COMMANDS_PER_PARSE`]
+    #[cfg(any(
+        feature = "hash-command",
+        feature = "qoi",
+        feature = "screenshot-command"
+    ))]
+    canvas_commands: usize,
+    /// Font used to draw `TEXT` commands, they are ignored without a font
+    #[cfg(feature = "text-command")]
+    font: Option<rusttype::Font<'static>>,
+    /// Payload of a variable-length command (e.g. `PXMULTI`), which did not fit into the last buffer
     #[cfg(feature = "binary-sync-pixels")]
-    remaining_pixel_sync: Option<RemainingPixelSync>,
+    remaining_payload: Option<RemainingPayload<FB>>,
+    /// Sprites defined using `SPRITE define`, they are not shared with other connections
+    #[cfg(feature = "sprites")]
+    sprites: Sprites,
 }
 
+/// Draws the payload of a `PXMULTI` command into the framebuffer, see [`sync_pixels`]
 #[cfg(feature = "binary-sync-pixels")]
-#[derive(Debug)]
-pub struct RemainingPixelSync {
-    current_index: usize,
-    bytes_remaining: usize,
+pub(crate) struct PixelSync {
+    pub(crate) current_index: usize,
+    /// The payload is skipped while this is set, [`None`] for parsers without a maintenance mode
+    pub(crate) maintenance_mode: Option<Arc<AtomicBool>>,
 }
 
-impl<FB: FrameBuffer> OriginalParser<FB> {
-    pub fn new(fb: Arc<FB>) -> Self {
+#[cfg(feature = "binary-sync-pixels")]
+impl<FB: FrameBuffer> PayloadHandler<FB> for PixelSync {
+    fn chunk_size(&self) -> usize {
+        // We need to stick to the 4 bytes of a pixel alignment
+        4
+    }
+
+    fn handle_payload(&mut self, fb: &FB, payload: &[u8]) -> u64 {
+        if self
+            .maintenance_mode
+            .as_ref()
+            .is_some_and(|maintenance_mode| maintenance_mode.load(Ordering::Relaxed))
+        {
+            // Keep track of the position, so that the rest of the payload ends up in the right place in case the
+            // maintenance mode ends in the meantime
+            self.current_index += payload.len() / 4;
+        } else {
+            self.current_index += sync_pixels(fb, self.current_index, payload);
+        }
+        payload.len() as u64 / 4
+    }
+}
+
+impl<FB: FrameBuffer, F: Deref<Target = FB>> OriginalParser<FB, F> {
+    /// Takes anything pointing to the framebuffer, e.g. an `Arc<FB>` or a plain `&FB`
+    pub fn new(fb: F) -> Self {
         Self {
             connection_x_offset: 0,
             connection_y_offset: 0,
+            width: fb.get_width(),
+            height: fb.get_height(),
+            logical_width: None,
+            logical_height: None,
+            #[cfg(feature = "custom-separators")]
+            command_separator: None,
             fb,
+            pixels_drawn: 0,
+            malformed_commands: 0,
+            strict: false,
+            lenient_whitespace: false,
+            #[cfg(not(feature = "alpha"))]
+            reject_alpha: false,
+            #[cfg(feature = "alpha")]
+            rgba_reads: false,
+            pixel_command_echo: false,
+            write_protected_regions: Vec::new(),
+            canvas_rotation: CanvasRotation::None,
+            max_pixels_per_connection: None,
+            max_bytes_per_connection: None,
+            maintenance_mode: Arc::default(),
+            // Until the first `parse` call takes a closer look
+            restricted: true,
+            help_count: 0,
+            #[cfg(any(
+                feature = "hash-command",
+                feature = "qoi",
+                feature = "screenshot-command"
+            ))]
+            canvas_commands: 0,
+            #[cfg(feature = "text-command")]
+            font: None,
             #[cfg(feature = "binary-sync-pixels")]
-            remaining_pixel_sync: None,
+            remaining_payload: None,
+            #[cfg(feature = "sprites")]
+            sprites: Sprites::default(),
         }
     }
-}
 
-impl<FB: FrameBuffer> Parser for OriginalParser<FB> {
-    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+    /// In strict mode a short `ERROR: ...` line is written to the response for every command that starts like a known
+    /// command, but could not be parsed. Otherwise such commands are silently skipped.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// In lenient whitespace mode `PX` commands with multiple spaces between the tokens (e.g. `PX  0   0  ffffff`) are
+    /// accepted as well. Such commands are handled in a slow path, so the normal commands are not slowed down.
+    pub fn with_lenient_whitespace(mut self, lenient_whitespace: bool) -> Self {
+        self.lenient_whitespace = lenient_whitespace;
+        self
+    }
+
+    /// Without the `alpha` feature the alpha channel of `PX x y rrggbbaa` commands is discarded, so the pixel is drawn
+    /// opaque. When rejecting alpha, such commands write [`ALPHA_NOT_SUPPORTED_TEXT`] to the response instead and the
+    /// pixel is not drawn at all, so that clients notice that alpha is not supported.
+    #[cfg(not(feature = "alpha"))]
+    pub fn with_reject_alpha(mut self, reject_alpha: bool) -> Self {
+        self.reject_alpha = reject_alpha;
+        self
+    }
+
+    /// With the `alpha` feature clients can draw using `PX x y rrggbbaa`, but reads (`PX x y`) still respond with
+    /// `rrggbb`, as most clients expect exactly that. With rgba reads enabled the response is `PX x y rrggbbaa`
+    /// instead, so that clients can treat reads and writes uniformly. As alpha is blended into the canvas when drawing,
+    /// the canvas itself is always opaque and the alpha of the response is always `ff`.
+    #[cfg(feature = "alpha")]
+    pub fn with_rgba_reads(mut self, rgba_reads: bool) -> Self {
+        self.rgba_reads = rgba_reads;
+        self
+    }
+
+    /// In pixel command echo mode a line `ECHO PX x y rrggbb` is written to the response for every pixel drawn by `PX`
+    /// or `PB`, containing the color that ended up in the framebuffer. This allows clients to compare what they sent
+    /// against what has been drawn. Pixels that were not drawn (e.g. because they are out of bounds) are not echoed.
+    /// `PXMULTI`, `FLIP` and `CIRCLE` are not echoed.
+    pub fn with_pixel_command_echo(mut self, pixel_command_echo: bool) -> Self {
+        self.pixel_command_echo = pixel_command_echo;
+        self
+    }
+
+    /// Pixels inside of the given regions are silently dropped, e.g. to stop clients from drawing on top of the
+    /// statistics. `PXMULTI` is not affected, as it is copied 1:1 into the framebuffer.
+    pub fn with_write_protected_regions(
+        mut self,
+        write_protected_regions: Vec<WriteProtectedRegion>,
+    ) -> Self {
+        self.write_protected_regions = write_protected_regions;
+        self
+    }
+
+    /// Clients draw onto a rotated canvas, e.g. because the display is mounted sideways. The coordinates of `PX`, `PB`
+    /// and `SIZE` are transformed accordingly. `PXMULTI` is not affected, as it is copied 1:1 into the framebuffer.
+    pub fn with_canvas_rotation(mut self, canvas_rotation: CanvasRotation) -> Self {
+        self.canvas_rotation = canvas_rotation;
+        self
+    }
+
+    /// Clients can only draw onto the top-left `width` x `height` pixels of the framebuffer, e.g. because the
+    /// framebuffer is larger than the visible wall. `SIZE` and `CAPS` report the logical size and the rotation is
+    /// applied within it. [`None`] (or a size larger than the framebuffer) uses the size of the framebuffer.
+    /// `PXMULTI` is not affected, as it is copied 1:1 into the framebuffer.
+    pub fn with_logical_size(mut self, width: Option<usize>, height: Option<usize>) -> Self {
+        self.logical_width = width;
+        self.logical_height = height;
+        self.apply_logical_size();
+        self
+    }
+
+    fn apply_logical_size(&mut self) {
+        self.width = self
+            .logical_width
+            .map_or(self.fb.get_width(), |width| width.min(self.fb.get_width()));
+        self.height = self.logical_height.map_or(self.fb.get_height(), |height| {
+            height.min(self.fb.get_height())
+        });
+    }
+
+    /// The framebuffer this parser currently draws on
+    pub fn framebuffer(&self) -> &F {
+        &self.fb
+    }
+
+    /// Continues drawing on the given framebuffer, e.g. after the previous one was resized (see
+    /// [`crate::ResizableFrameBuffer`]). The logical size is applied to the new framebuffer, everything else (such as
+    /// the offset, the sprites or the rest of a `PXMULTI` payload) stays with the connection.
+    pub fn set_framebuffer(&mut self, fb: F) {
+        self.fb = fb;
+        self.apply_logical_size();
+    }
+
+    /// Commands can additionally be terminated by the given byte (e.g. `;`), for clients that batch commands like
+    /// `PX 0 0 ff0000;PX 1 0 00ff00;`. The newline always terminates commands. The separator must not be part of any
+    /// command (e.g. a digit or a space).
+    #[cfg(feature = "custom-separators")]
+    pub fn with_command_separator(mut self, command_separator: Option<u8>) -> Self {
+        self.command_separator = command_separator;
+        self
+    }
+
+    /// While the shared flag is set the canvas is read-only, e.g. to take a consistent snapshot or migrate to another
+    /// host without dropping the connections. All drawing commands (including `PXMULTI`) are still parsed, but don't
+    /// change the framebuffer. Reading pixels, `SIZE` and the like keep working.
+    pub fn with_maintenance_mode(mut self, maintenance_mode: Arc<AtomicBool>) -> Self {
+        self.maintenance_mode = maintenance_mode;
+        self
+    }
+
+    /// The connection limits are only reported to clients in the `CAPS` response, enforcing them is up to the caller.
+    pub fn with_connection_limits(
+        mut self,
+        max_pixels_per_connection: Option<u64>,
+        max_bytes_per_connection: Option<u64>,
+    ) -> Self {
+        self.max_pixels_per_connection = max_pixels_per_connection;
+        self.max_bytes_per_connection = max_bytes_per_connection;
+        self
+    }
+
+    /// Font used to draw `TEXT` commands. Without a font `TEXT` commands are ignored.
+    #[cfg(feature = "text-command")]
+    pub fn with_font(mut self, font: Option<rusttype::Font<'static>>) -> Self {
+        self.font = font;
+        self
+    }
+
+    /// Writes all capabilities in a single, machine-parseable line of `key=value` pairs
+    fn write_capabilities(&self, response: &mut Vec<u8>) {
+        fn flag(enabled: bool) -> u8 {
+            enabled as u8
+        }
+        fn limit(limit: Option<u64>) -> String {
+            limit.map_or_else(|| "none".to_owned(), |limit| limit.to_string())
+        }
+
+        let (width, height) = self.canvas_size();
+        response.extend_from_slice(
+            format!(
+                "CAPS width={width} height={height} max-x={} max-y={} bit-depth=24 alpha={} binary-set-pixel={} \
+                binary-sync-pixels={} qoi={} flip-command={} circle-command={} getrect={} screenshot-command={} \
+                cas-command={} text-command={} sprites={} hash-command={} max-pixels-per-connection={} max-bytes-per-connection={}\n",
+                width.saturating_sub(1),
+                height.saturating_sub(1),
+                flag(cfg!(feature = "alpha")),
+                flag(cfg!(feature = "binary-set-pixel")),
+                flag(cfg!(feature = "binary-sync-pixels")),
+                flag(cfg!(feature = "qoi")),
+                flag(cfg!(feature = "flip-command")),
+                flag(cfg!(feature = "circle-command")),
+                flag(cfg!(feature = "getrect")),
+                flag(cfg!(feature = "screenshot-command")),
+                flag(cfg!(feature = "cas-command")),
+                flag(cfg!(feature = "text-command")),
+                flag(cfg!(feature = "sprites")),
+                flag(cfg!(feature = "hash-command")),
+                limit(self.max_pixels_per_connection),
+                limit(self.max_bytes_per_connection),
+            )
+            .as_bytes(),
+        );
+    }
+
+    /// Size of the (rotated) canvas as seen by the clients
+    #[inline(always)]
+    fn canvas_size(&self) -> (usize, usize) {
+        self.canvas_rotation.canvas_size(self.width, self.height)
+    }
+
+    #[inline(always)]
+    fn to_framebuffer(&self, x: usize, y: usize) -> (usize, usize) {
+        if !self.restricted {
+            return (x, y);
+        }
+
+        self.canvas_rotation
+            .to_framebuffer(x, y, self.width, self.height)
+    }
+
+    /// Mirrors the region of the canvas horizontally (left becomes right) or vertically (top becomes bottom) in place.
+    /// The region is clipped to the canvas, regions with more than [`MAX_FLIP_PIXELS`] pixels are ignored.
+    #[cfg(feature = "flip-command")]
+    fn flip(&mut self, x: usize, y: usize, width: usize, height: usize, vertical: bool) {
+        let (canvas_width, canvas_height) = self.canvas_size();
+        let width = width.min(canvas_width.saturating_sub(x));
+        let height = height.min(canvas_height.saturating_sub(y));
+        if width * height > MAX_FLIP_PIXELS || self.is_in_maintenance_mode() {
+            return;
+        }
+
+        if vertical {
+            for row in 0..height / 2 {
+                for column in 0..width {
+                    self.swap_pixels(x + column, y + row, x + column, y + height - 1 - row);
+                }
+            }
+        } else {
+            for row in 0..height {
+                for column in 0..width / 2 {
+                    self.swap_pixels(x + column, y + row, x + width - 1 - column, y + row);
+                }
+            }
+        }
+    }
+
+    /// Both pixels must be within the canvas
+    #[cfg(feature = "flip-command")]
+    #[inline(always)]
+    fn swap_pixels(&mut self, x1: usize, y1: usize, x2: usize, y2: usize) {
+        let (x1, y1) = self.to_framebuffer(x1, y1);
+        let (x2, y2) = self.to_framebuffer(x2, y2);
+        if self.is_write_protected(x1, y1) || self.is_write_protected(x2, y2) {
+            return;
+        }
+
+        let (rgba1, rgba2) =
+            unsafe { (self.fb.get_unchecked(x1, y1), self.fb.get_unchecked(x2, y2)) };
+        self.fb.set(x1, y1, rgba2);
+        self.fb.set(x2, y2, rgba1);
+        self.pixels_drawn += 2;
+    }
+
+    /// Fills the disc around `(center_x, center_y)` with the given radius row by row, each row spanning the pixels
+    /// within the radius. The disc is clipped to the canvas, discs with a radius larger than [`MAX_CIRCLE_RADIUS`] are
+    /// ignored. `rgba` is blended onto the canvas if `blend` is set.
+    #[cfg(feature = "circle-command")]
+    fn fill_circle(
+        &mut self,
+        center_x: usize,
+        center_y: usize,
+        radius: usize,
+        rgba: u32,
+        blend: bool,
+    ) {
+        // Fully transparent circles don't change anything
+        if radius > MAX_CIRCLE_RADIUS || (blend && rgba >> 24 == 0) || self.is_in_maintenance_mode()
+        {
+            return;
+        }
+
+        let (canvas_width, canvas_height) = self.canvas_size();
+        // There is no last column to clip the rows to on empty canvases
+        if canvas_width == 0
+            || canvas_height == 0
+            || center_x >= canvas_width + radius
+            || center_y >= canvas_height + radius
+        {
+            return;
+        }
+
+        // Half the width of the current row, which only shrinks while moving away from the center row
+        let mut half_width = radius;
+        for distance in 0..=radius {
+            while half_width * half_width + distance * distance > radius * radius {
+                half_width -= 1;
+            }
+
+            let start_x = center_x.saturating_sub(half_width);
+            let end_x = (center_x + half_width).min(canvas_width - 1);
+            if start_x > end_x {
+                continue;
+            }
+
+            if let Some(y) = center_y
+                .checked_sub(distance)
+                .filter(|&y| y < canvas_height)
+            {
+                self.fill_row(start_x, end_x, y, rgba, blend);
+            }
+            if distance > 0 && center_y + distance < canvas_height {
+                self.fill_row(start_x, end_x, center_y + distance, rgba, blend);
+            }
+        }
+    }
+
+    /// Fills the canvas row `y` from `start_x` to `end_x` (inclusive), all of them must be within the canvas
+    #[cfg(feature = "circle-command")]
+    #[inline(always)]
+    fn fill_row(&mut self, start_x: usize, end_x: usize, y: usize, rgba: u32, blend: bool) {
+        for x in start_x..=end_x {
+            let (x, y) = self.to_framebuffer(x, y);
+            if self.is_write_protected(x, y) {
+                continue;
+            }
+
+            if blend {
+                let current = unsafe { self.fb.get_unchecked(x, y) };
+                self.fb.set(x, y, crate::alpha_blend(current, rgba));
+            } else {
+                self.fb.set(x, y, rgba & 0x00ff_ffff);
+            }
+            self.pixels_drawn += 1;
+        }
+    }
+
+    /// Draws the text with its top-left corner at `(x, y)`, see [`rasterize_text`]. The text is clipped to the canvas.
+    #[cfg(feature = "text-command")]
+    fn draw_text(&mut self, x: usize, y: usize, rgba: u32, text: &str) {
+        let Some(font) = &self.font else {
+            return;
+        };
+        if self.is_in_maintenance_mode() {
+            return;
+        }
+
+        let (canvas_width, canvas_height) = self.canvas_size();
+        let mut pixels_drawn = 0;
+        rasterize_text(font, x, y, TEXT_FONT_SIZE, text, |x, y| {
+            if x >= canvas_width || y >= canvas_height {
+                return;
+            }
+            let (x, y) = self.to_framebuffer(x, y);
+            if !self.is_write_protected(x, y) {
+                self.fb.set(x, y, rgba);
+                pixels_drawn += 1;
+            }
+        });
+        self.pixels_drawn += pixels_drawn;
+    }
+
+    /// Draws the sprite with its top-left corner at `(x, y)`, clipped to the canvas. Unknown sprites are ignored.
+    ///
+    /// As long as the canvas is neither rotated nor write-protected, the sprite is copied row by row using
+    /// [`FrameBuffer::set_multi`], otherwise pixel by pixel.
+    #[cfg(feature = "sprites")]
+    fn blit_sprite(&mut self, id: usize, x: usize, y: usize) {
+        let Some(sprite) = self.sprites.get(id) else {
+            return;
+        };
+        if self.is_in_maintenance_mode() {
+            return;
+        }
+
+        let (canvas_width, canvas_height) = self.canvas_size();
+        let width = sprite.width.min(canvas_width.saturating_sub(x));
+        let height = sprite.height.min(canvas_height.saturating_sub(y));
+        let copy_rows =
+            self.canvas_rotation == CanvasRotation::None && self.write_protected_regions.is_empty();
+
+        let mut pixels_drawn = 0;
+        for row in 0..height {
+            let pixels = &sprite.pixels[row * sprite.width * 4..][..width * 4];
+            if copy_rows {
+                self.fb.set_multi(x, y + row, pixels);
+                pixels_drawn += width as u64;
+                continue;
+            }
+
+            for (column, pixel) in pixels.chunks_exact(4).enumerate() {
+                let (fb_x, fb_y) = self.to_framebuffer(x + column, y + row);
+                if !self.is_write_protected(fb_x, fb_y) {
+                    let rgba = u32::from_le_bytes(pixel.try_into().expect("chunks are 4 bytes"));
+                    self.fb.set(fb_x, fb_y, rgba & 0x00ff_ffff);
+                    pixels_drawn += 1;
+                }
+            }
+        }
+        self.pixels_drawn += pixels_drawn;
+    }
+
+    /// Writes the region with the top-left corner `(x, y)` as `GETRECT <width> <height> <length in bytes>\n` followed by
+    /// the pixels as 4 bytes RGBA each (row by row, alpha is always `ff`). The region is clipped to the canvas and
+    /// rows at the bottom are dropped to stay within [`MAX_GETRECT_PIXELS`], the header contains the resulting size.
+    #[cfg(feature = "getrect")]
+    fn write_rect(&self, x: usize, y: usize, width: usize, height: usize, response: &mut Vec<u8>) {
+        let (canvas_width, canvas_height) = self.canvas_size();
+        let width = width.min(canvas_width.saturating_sub(x));
+        let height = height
+            .min(canvas_height.saturating_sub(y))
+            .min(MAX_GETRECT_PIXELS.checked_div(width).unwrap_or(0));
+
+        let len = width * height * 4;
+        response.reserve(len + 32);
+        response.extend_from_slice(format!("GETRECT {width} {height} {len}\n").as_bytes());
+        for row in y..y + height {
+            for column in x..x + width {
+                let (x, y) = self.to_framebuffer(column, row);
+                let rgb = unsafe { self.fb.get_unchecked(x, y) };
+                // The framebuffer stores 0x00bbggrr, so the little-endian bytes are r, g, b and a
+                response.extend_from_slice(&(rgb | 0xff00_0000).to_le_bytes());
+            }
+        }
+    }
+
+    /// Responds to `PX x y` reads, see [`Self::with_rgba_reads`]
+    #[inline(always)]
+    fn write_pixel_response(&self, response: &mut Vec<u8>, x: usize, y: usize, rgb: u32) {
+        #[cfg(feature = "alpha")]
+        if self.rgba_reads {
+            write_pixel_color(response, x, y, rgb);
+            // The canvas is opaque, alpha is blended into it when drawing
+            response.extend_from_slice(b"ff\n");
+            return;
+        }
+
+        write_pixel_response(response, x, y, rgb);
+    }
+
+    /// Writes the current color of the framebuffer pixel `(x, y)` using the coordinates the client sent
+    #[cold]
+    fn echo_pixel(
+        &self,
+        client_x: usize,
+        client_y: usize,
+        x: usize,
+        y: usize,
+        response: &mut Vec<u8>,
+    ) {
+        if let Some(rgb) = self.fb.get(x, y) {
+            response.extend_from_slice(
+                format!("ECHO PX {client_x} {client_y} {:06x}\n", rgb.to_be() >> 8).as_bytes(),
+            );
+        }
+    }
+
+    /// Whether the byte terminates a command
+    #[cfg(not(feature = "custom-separators"))]
+    #[inline(always)]
+    fn is_command_end(&self, byte: u8) -> bool {
+        byte == b'\n'
+    }
+
+    /// Whether the byte terminates a command, see [`Self::with_command_separator`]
+    #[cfg(feature = "custom-separators")]
+    #[inline(always)]
+    fn is_command_end(&self, byte: u8) -> bool {
+        byte == b'\n' || Some(byte) == self.command_separator
+    }
+
+    /// Commands that are cut off at the end of the data are not invalid, they will be completed by the next read.
+    /// So we only consider a command to be complete in case it is terminated.
+    fn is_terminated(&self, buffer: &[u8], command_start: usize, data_end: usize) -> bool {
+        self.command_end(buffer, command_start, data_end).is_some()
+    }
+
+    /// Index of the byte terminating the command starting at `command_start`, [`None`] in case it is cut off at the
+    /// end of the data, see [`Self::is_terminated`]
+    fn command_end(&self, buffer: &[u8], command_start: usize, data_end: usize) -> Option<usize> {
+        buffer[command_start..data_end]
+            .iter()
+            .position(|&byte| self.is_command_end(byte))
+            .map(|position| command_start + position)
+    }
+
+    /// No valid command is longer than [`PARSER_LOOKAHEAD`], so in case there is no command end within it, the client
+    /// sent garbage (e.g. megabytes of digits). Instead of scanning through it byte by byte, which could pick up a
+    /// command prefix somewhere in the middle of the garbage, we skip it entirely.
+    ///
+    /// Returns the index of the command end terminating the garbage, or the last byte of the data in case the garbage
+    /// continues beyond it. [`None`] in case the command is not overlong or might be completed by the next read.
+    fn overlong_command_end(
+        &self,
+        buffer: &[u8],
+        command_start: usize,
+        data_end: usize,
+    ) -> Option<usize> {
+        let max_command_end = command_start + PARSER_LOOKAHEAD;
+        if max_command_end > data_end || self.is_terminated(buffer, command_start, max_command_end)
+        {
+            return None;
+        }
+
+        Some(
+            buffer[max_command_end..data_end]
+                .iter()
+                .position(|&byte| self.is_command_end(byte))
+                .map_or(data_end - 1, |position| max_command_end + position),
+        )
+    }
+
+    /// Whether the framebuffer pixel `(x, y)` is part of the logical canvas, see [`Self::with_logical_size`]
+    #[inline(always)]
+    fn is_on_canvas(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    /// Whether clients are allowed to draw the framebuffer pixel `(x, y)`
+    #[inline(always)]
+    fn can_draw(&self, x: usize, y: usize) -> bool {
+        if !self.restricted {
+            // The logical size is the size of the framebuffer
+            return self.is_on_canvas(x, y);
+        }
+
+        self.is_on_canvas(x, y) && !self.is_write_protected(x, y) && !self.is_in_maintenance_mode()
+    }
+
+    /// See [`Self::restricted`]. The maintenance mode is toggled from the outside, so it is only picked up by the next
+    /// `parse` call.
+    fn update_restricted(&mut self) {
+        self.restricted = self.canvas_rotation != CanvasRotation::None
+            || self.width != self.fb.get_width()
+            || self.height != self.fb.get_height()
+            || !self.write_protected_regions.is_empty()
+            || self.is_in_maintenance_mode();
+    }
+
+    #[inline(always)]
+    fn is_in_maintenance_mode(&self) -> bool {
+        self.maintenance_mode.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    fn is_write_protected(&self, x: usize, y: usize) -> bool {
+        self.write_protected_regions
+            .iter()
+            .any(|region| region.contains(x, y))
+    }
+
+    /// Whether another command processing the whole canvas can be answered during this [`Parser::parse`] call, see
+    /// [`MAX_CANVAS_COMMANDS_PER_PARSE`]. Otherwise the client is told about it.
+    #[cfg(any(
+        feature = "hash-command",
+        feature = "qoi",
+        feature = "screenshot-command"
+    ))]
+    fn take_canvas_command(&mut self, response: &mut Vec<u8>) -> bool {
+        if self.canvas_commands >= MAX_CANVAS_COMMANDS_PER_PARSE {
+            response.extend_from_slice(TOO_MANY_CANVAS_COMMANDS_TEXT);
+            return false;
+        }
+        self.canvas_commands += 1;
+        true
+    }
+
+    /// Slow path for `PX` commands with runs of spaces between the tokens. The command is normalized to use single
+    /// spaces and parsed again, so that it behaves exactly the same as a normal command.
+    ///
+    /// Returns the index of the byte terminating the command, or [`None`] in case the command can not be normalized,
+    /// e.g. because it is cut off at the end of the data or does not contain any superfluous spaces.
+    #[cold]
+    fn parse_lenient_pixel(
+        &mut self,
+        buffer: &[u8],
+        command_start: usize,
+        data_end: usize,
+        response: &mut Vec<u8>,
+    ) -> Option<usize> {
+        let newline = command_start
+            + buffer[command_start..data_end]
+                .iter()
+                .position(|&byte| self.is_command_end(byte))?;
+
+        // Longest normalized command is "PX 12345678 12345678 12345678\n", followed by the lookahead
+        let mut normalized = [0_u8; 32 + PARSER_LOOKAHEAD];
+        let mut len = 0;
+        let tokens = buffer[command_start..newline]
+            .split(|&b| b == b' ')
+            .filter(|token| !token.is_empty());
+        for token in tokens {
+            // Tokens that long can not be valid
+            if token.len() > 8 || len + token.len() + 1 > 32 {
+                return None;
+            }
+            if len > 0 {
+                normalized[len] = b' ';
+                len += 1;
+            }
+            normalized[len..len + token.len()].copy_from_slice(token);
+            len += token.len();
+        }
+        if len == newline - command_start {
+            // Nothing to normalize, so the command is invalid
+            return None;
+        }
+        normalized[len] = b'\n';
+
+        self.parse_commands(&normalized[..len + 1 + PARSER_LOOKAHEAD], response);
+        Some(newline)
+    }
+
+    /// Does the actual work of [`Parser::parse`]. The slow paths call it for normalized commands as well, so everything
+    /// that must only happen once per [`Parser::parse`] call belongs there.
+    fn parse_commands(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        self.update_restricted();
         let mut last_byte_parsed = 0;
-        let mut help_count = 0;
 
         let mut i = 0; // We can't use a for loop here because Rust don't lets use skip characters by incrementing i
         let loop_end = buffer.len().saturating_sub(PARSER_LOOKAHEAD); // Let's extract the .len() call and the subtraction into it's own variable so we only compute it once
 
         #[cfg(feature = "binary-sync-pixels")]
-        if let Some(remaining) = &self.remaining_pixel_sync {
-            let buffer = &buffer[0..loop_end];
-
-            if remaining.bytes_remaining <= buffer.len() {
-                // Easy going here
-                self.fb
-                    .set_multi_from_start_index(remaining.current_index, unsafe {
-                        slice::from_raw_parts(buffer.as_ptr(), remaining.bytes_remaining)
-                    });
-                i += remaining.bytes_remaining;
+        if let Some(remaining) = &mut self.remaining_payload {
+            let (consumed, pixels_drawn) = remaining.consume(&*self.fb, &buffer[0..loop_end]);
+            self.pixels_drawn += pixels_drawn;
+
+            if remaining.is_finished() {
+                i += consumed;
                 last_byte_parsed = i;
-                self.remaining_pixel_sync = None;
+                self.remaining_payload = None;
             } else {
-                // The client requested to write more bytes that are currently in the buffer, we need to remember
-                // what the client is doing.
-
-                // We need to round down to the 4 bytes of a pixel alignment
-                let pixel_bytes = buffer.len() / 4 * 4;
-
-                let mut index = remaining.current_index;
-                index += self
-                    .fb
-                    .set_multi_from_start_index(remaining.current_index, unsafe {
-                        slice::from_raw_parts(buffer.as_ptr(), pixel_bytes)
-                    });
-
-                self.remaining_pixel_sync = Some(RemainingPixelSync {
-                    current_index: index,
-                    bytes_remaining: remaining.bytes_remaining.saturating_sub(pixel_bytes),
-                });
-
-                // Nothing to do left, we can early return
+                // The client requested to write more bytes that are currently in the buffer, so there is nothing to
+                // do left, we can early return.
                 // I have absolutely no idea why we need to subtract 1 here, but it is what it is. At least we have
                 // tests for this madness :)
-                return i + pixel_bytes.saturating_sub(1);
+                return i + consumed.saturating_sub(1);
             }
         }
 
+        #[cfg(feature = "sprites")]
+        if self.sprites.is_uploading() {
+            i += self.sprites.upload(&buffer[..loop_end]);
+            if self.sprites.is_uploading() {
+                // The whole buffer belongs to the sprite, we need to wait for the rest of it
+                return i.saturating_sub(1);
+            }
+            last_byte_parsed = i.saturating_sub(1);
+        }
+
         while i < loop_end {
             let current_command =
                 unsafe { (buffer.as_ptr().add(i) as *const u64).read_unaligned() };
             if current_command & 0x00ff_ffff == PX_PATTERN {
+                let command_start = i;
                 i += 3;
 
-                let (mut x, mut y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                let (client_x, client_y, present) =
+                    parse_pixel_coordinates(buffer.as_ptr(), &mut i);
 
                 if present {
-                    x += self.connection_x_offset;
-                    y += self.connection_y_offset;
+                    let (x, y) = self.to_framebuffer(
+                        client_x + self.connection_x_offset,
+                        client_y + self.connection_y_offset,
+                    );
 
                     // Separator between coordinates and color
                     if unsafe { *buffer.get_unchecked(i) } == b' ' {
@@ -111,56 +910,68 @@ impl<FB: FrameBuffer> Parser for OriginalParser<FB> {
                         // If RGBA is used more often move the RGB code below the RGBA code
 
                         // Must be followed by 6 bytes RGB and newline or ...
-                        if unsafe { *buffer.get_unchecked(i + 6) } == b'\n' {
+                        if self.is_command_end(unsafe { *buffer.get_unchecked(i + 6) }) {
                             last_byte_parsed = i + 6;
                             i += 7; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
                             let rgba: u32 = simd_unhex(unsafe { buffer.as_ptr().add(i - 7) });
 
-                            self.fb.set(x, y, rgba & 0x00ff_ffff);
+                            if self.can_draw(x, y) {
+                                self.fb.set(x, y, rgba & 0x00ff_ffff);
+                                if self.pixel_command_echo {
+                                    self.echo_pixel(client_x, client_y, x, y, response);
+                                }
+                            }
+                            self.pixels_drawn += 1;
                             continue;
                         }
 
                         // ... or must be followed by 8 bytes RGBA and newline
                         #[cfg(not(feature = "alpha"))]
-                        if unsafe { *buffer.get_unchecked(i + 8) } == b'\n' {
+                        if self.is_command_end(unsafe { *buffer.get_unchecked(i + 8) }) {
                             last_byte_parsed = i + 8;
                             i += 9; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
+                            if self.reject_alpha {
+                                response.extend_from_slice(ALPHA_NOT_SUPPORTED_TEXT);
+                                continue;
+                            }
+
                             let rgba: u32 = simd_unhex(unsafe { buffer.as_ptr().add(i - 9) });
 
-                            self.fb.set(x, y, rgba & 0x00ff_ffff);
+                            if self.can_draw(x, y) {
+                                self.fb.set(x, y, rgba & 0x00ff_ffff);
+                                if self.pixel_command_echo {
+                                    self.echo_pixel(client_x, client_y, x, y, response);
+                                }
+                            }
+                            self.pixels_drawn += 1;
                             continue;
                         }
                         #[cfg(feature = "alpha")]
-                        if unsafe { *buffer.get_unchecked(i + 8) } == b'\n' {
+                        if self.is_command_end(unsafe { *buffer.get_unchecked(i + 8) }) {
                             last_byte_parsed = i + 8;
                             i += 9; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
                             let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i - 9) });
 
                             let alpha = (rgba >> 24) & 0xff;
+                            self.pixels_drawn += 1;
 
-                            if alpha == 0 || x >= self.fb.get_width() || y >= self.fb.get_height() {
+                            if alpha == 0 || !self.can_draw(x, y) {
                                 continue;
                             }
 
-                            let alpha_comp = 0xff - alpha;
                             let current = unsafe { self.fb.get_unchecked(x, y) };
-                            let r = (rgba >> 16) & 0xff;
-                            let g = (rgba >> 8) & 0xff;
-                            let b = rgba & 0xff;
-
-                            let r: u32 = (((current >> 24) & 0xff) * alpha_comp + r * alpha) / 0xff;
-                            let g: u32 = (((current >> 16) & 0xff) * alpha_comp + g * alpha) / 0xff;
-                            let b: u32 = (((current >> 8) & 0xff) * alpha_comp + b * alpha) / 0xff;
-
-                            self.fb.set(x, y, (r << 16) | (g << 8) | b);
+                            self.fb.set(x, y, alpha_blend(current, rgba));
+                            if self.pixel_command_echo {
+                                self.echo_pixel(client_x, client_y, x, y, response);
+                            }
                             continue;
                         }
 
                         // ... for the efficient/lazy clients
-                        if unsafe { *buffer.get_unchecked(i + 2) } == b'\n' {
+                        if self.is_command_end(unsafe { *buffer.get_unchecked(i + 2) }) {
                             last_byte_parsed = i + 2;
                             i += 3; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
@@ -168,134 +979,498 @@ impl<FB: FrameBuffer> Parser for OriginalParser<FB> {
 
                             let rgba: u32 = (base << 16) | (base << 8) | base;
 
-                            self.fb.set(x, y, rgba);
+                            if self.can_draw(x, y) {
+                                self.fb.set(x, y, rgba);
+                                if self.pixel_command_echo {
+                                    self.echo_pixel(client_x, client_y, x, y, response);
+                                }
+                            }
+                            self.pixels_drawn += 1;
 
                             continue;
                         }
                     }
 
                     // End of command to read Pixel value
-                    if unsafe { *buffer.get_unchecked(i) } == b'\n' {
+                    if self.is_command_end(unsafe { *buffer.get_unchecked(i) }) {
                         last_byte_parsed = i;
                         i += 1;
-                        if let Some(rgb) = self.fb.get(x, y) {
-                            response.extend_from_slice(
-                                format!(
-                                    "PX {} {} {:06x}\n",
-                                    // We don't want to return the actual (absolute) coordinates, the client should also get the result offseted
-                                    x - self.connection_x_offset,
-                                    y - self.connection_y_offset,
-                                    rgb.to_be() >> 8
-                                )
-                                .as_bytes(),
-                            );
+                        if let Some(rgb) = self.fb.get(x, y).filter(|_| self.is_on_canvas(x, y)) {
+                            // We don't want to return the actual (absolute) coordinates, the client should also get the result offseted
+                            self.write_pixel_response(response, client_x, client_y, rgb);
+
+                            // Read-heavy clients would otherwise let the response grow with the buffer
+                            if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                                return last_byte_parsed;
+                            }
                         }
                         continue;
                     }
                 }
+
+                // Only reached for invalid commands, so this doesn't slow down the hot path
+                if self.lenient_whitespace {
+                    if let Some(newline) =
+                        self.parse_lenient_pixel(buffer, command_start, loop_end, response)
+                    {
+                        last_byte_parsed = newline;
+                        i = newline + 1;
+                        continue;
+                    }
+                }
+                if let Some(command_end) = self.command_end(buffer, command_start, loop_end) {
+                    self.malformed_commands += 1;
+                    if self.strict {
+                        response.extend_from_slice(INVALID_PX_COMMAND_TEXT);
+                    }
+                    // Skip the whole command, so that it's not parsed (and counted) again together with the next read
+                    last_byte_parsed = command_end;
+                    i = command_end + 1;
+                    continue;
+                }
+                if let Some(garbage_end) =
+                    self.overlong_command_end(buffer, command_start, loop_end)
+                {
+                    last_byte_parsed = garbage_end;
+                    i = garbage_end + 1;
+                    continue;
+                }
             }
             #[cfg(feature = "binary-set-pixel")]
             if current_command & 0x0000_ffff == PB_PATTERN {
                 let command_bytes =
                     unsafe { (buffer.as_ptr().add(i + 2) as *const u64).read_unaligned() };
 
-                let x = u16::from_le((command_bytes) as u16);
-                let y = u16::from_le((command_bytes >> 16) as u16);
+                let client_x = u16::from_le((command_bytes) as u16) as usize;
+                let client_y = u16::from_le((command_bytes >> 16) as u16) as usize;
                 let rgba = u32::from_le((command_bytes >> 32) as u32);
+                let (x, y) = self.to_framebuffer(
+                    client_x + self.connection_x_offset,
+                    client_y + self.connection_y_offset,
+                );
 
                 // TODO: Support alpha channel (behind alpha feature flag)
-                self.fb.set(x as usize, y as usize, rgba & 0x00ff_ffff);
+                if self.can_draw(x, y) {
+                    self.fb.set(x, y, rgba & 0x00ff_ffff);
+                    if self.pixel_command_echo {
+                        self.echo_pixel(client_x, client_y, x, y, response);
+                    }
+                }
+                self.pixels_drawn += 1;
                 //                 P   B   XX  YY  RGBA
                 last_byte_parsed = i + 1 + 2 + 2 + 4;
                 i += 10;
                 continue;
             }
+            #[cfg(feature = "binary-set-pixel")]
+            if current_command & 0x0000_ffff == PG_PATTERN {
+                let client_x = u16::from_le((current_command >> 16) as u16) as usize;
+                let client_y = u16::from_le((current_command >> 32) as u16) as usize;
+                let (x, y) = self.to_framebuffer(
+                    client_x + self.connection_x_offset,
+                    client_y + self.connection_y_offset,
+                );
+
+                if let Some(rgb) = self.fb.get(x, y).filter(|_| self.is_on_canvas(x, y)) {
+                    // The framebuffer stores 0x00bbggrr, so the little-endian bytes are r, g, b and a. The canvas is
+                    // opaque, so we send an alpha of ff, same as GETRECT and SCREENSHOT.
+                    response.extend_from_slice(&(rgb | 0xff00_0000).to_le_bytes());
+                }
+                //                 P   G   XX  YY
+                last_byte_parsed = i + 1 + 2 + 2;
+                i += 6;
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
+                continue;
+            }
             #[cfg(feature = "binary-sync-pixels")]
             if current_command & 0x00ff_ffff_ffff_ffff == PXMULTI_PATTERN {
                 i += "PXMULTI".len();
                 let header = unsafe { (buffer.as_ptr().add(i) as *const u64).read_unaligned() };
                 i += 8;
 
-                let start_x = u16::from_le((header) as u16);
-                let start_y = u16::from_le((header >> 16) as u16);
+                let start_x = u16::from_le((header) as u16) as usize + self.connection_x_offset;
+                let start_y =
+                    u16::from_le((header >> 16) as u16) as usize + self.connection_y_offset;
                 let len = u32::from_le((header >> 32) as u32);
                 let len_in_bytes = len as usize * 4;
                 let bytes_left_in_buffer = loop_end.saturating_sub(i);
 
+                // Otherwise a client could announce e.g. u32::MAX pixels and we would swallow gigabytes of its
+                // following commands as payload. We only skip the header, the following bytes are parsed as commands.
+                if start_x + start_y * self.fb.get_width() + len as usize > self.fb.get_size() {
+                    last_byte_parsed = i;
+                    continue;
+                }
+
                 if len_in_bytes <= bytes_left_in_buffer {
                     // Easy going here
-                    self.fb
-                        .set_multi(start_x as usize, start_y as usize, unsafe {
+                    if !self.is_in_maintenance_mode() {
+                        sync_pixels(&*self.fb, start_x + start_y * self.fb.get_width(), unsafe {
                             slice::from_raw_parts(buffer.as_ptr().add(i), len_in_bytes)
                         });
+                    }
 
                     i += len_in_bytes;
                     last_byte_parsed = i;
+                    self.pixels_drawn += len as u64;
                     continue;
                 } else {
-                    // We need to round down to the 4 bytes of a pixel alignment
-                    let pixel_bytes: usize = bytes_left_in_buffer / 4 * 4;
-
                     // The client requested to write more bytes that are currently in the buffer, we need to remember
                     // what the client is doing.
-                    let mut current_index =
-                        start_x as usize + start_y as usize * self.fb.get_width();
-                    current_index += self.fb.set_multi_from_start_index(current_index, unsafe {
-                        slice::from_raw_parts(buffer.as_ptr().add(i), pixel_bytes)
-                    });
-
-                    self.remaining_pixel_sync = Some(RemainingPixelSync {
+                    let current_index = start_x + start_y * self.fb.get_width();
+                    let pixel_sync = PixelSync {
                         current_index,
-                        bytes_remaining: len_in_bytes - pixel_bytes,
-                    });
+                        maintenance_mode: Some(Arc::clone(&self.maintenance_mode)),
+                    };
+                    let mut remaining = RemainingPayload::new(Box::new(pixel_sync), len_in_bytes);
+                    let (consumed, pixels_drawn) =
+                        remaining.consume(&*self.fb, &buffer[i..i + bytes_left_in_buffer]);
+                    self.pixels_drawn += pixels_drawn;
+                    self.remaining_payload = Some(remaining);
 
                     // Nothing to do left, we can early return
                     // I have absolutely no idea why we need to subtract 1 here, but it is what it is. At least we have
                     // tests for this madness :)
-                    return i + pixel_bytes.saturating_sub(1);
+                    return i + consumed.saturating_sub(1);
                 }
             }
             if current_command & 0x00ff_ffff_ffff_ffff == OFFSET_PATTERN {
+                let command_start = i;
                 i += 7;
 
                 let (x, y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
 
                 // End of command to set offset
-                if present && unsafe { *buffer.get_unchecked(i) } == b'\n' {
+                if present && self.is_command_end(unsafe { *buffer.get_unchecked(i) }) {
                     last_byte_parsed = i;
-                    self.connection_x_offset = x;
-                    self.connection_y_offset = y;
+                    // Larger offsets would only move all further draws and reads outside of the canvas as well
+                    let (width, height) = self.canvas_size();
+                    (self.connection_x_offset, self.connection_y_offset) =
+                        (x.min(width), y.min(height));
+                    continue;
+                }
+
+                if let Some(command_end) = self.command_end(buffer, command_start, loop_end) {
+                    self.malformed_commands += 1;
+                    if self.strict {
+                        response.extend_from_slice(INVALID_OFFSET_COMMAND_TEXT);
+                    }
+                    // Skip the whole command, so that it's not parsed (and counted) again together with the next read
+                    last_byte_parsed = command_end;
+                    i = command_end + 1;
                     continue;
                 }
             }
+            #[cfg(feature = "flip-command")]
+            if current_command & 0xff_ffff_ffff == FLIP_PATTERN {
+                i += 5;
+
+                let (x, y, position_present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                if position_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                    i += 1;
+
+                    let (width, height, size_present) =
+                        parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                    let direction = unsafe { *buffer.get_unchecked(i + 1) };
+                    if size_present
+                        && unsafe { *buffer.get_unchecked(i) } == b' '
+                        && (direction == b'h' || direction == b'v')
+                        && self.is_command_end(unsafe { *buffer.get_unchecked(i + 2) })
+                    {
+                        last_byte_parsed = i + 2;
+                        i += 3;
+                        self.flip(
+                            x + self.connection_x_offset,
+                            y + self.connection_y_offset,
+                            width,
+                            height,
+                            direction == b'v',
+                        );
+                        continue;
+                    }
+                }
+            }
+            #[cfg(feature = "sprites")]
+            if current_command & 0x00ff_ffff_ffff_ffff == SPRITE_PATTERN {
+                i += "SPRITE ".len();
+
+                if buffer[i..].starts_with(b"define ") {
+                    i += "define ".len();
+
+                    // The header is followed by a single space and the pixels of the sprite (without a newline)
+                    let (id, id_present) = parse_coordinate(buffer.as_ptr(), &mut i);
+                    if id_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                        i += 1;
+
+                        let (width, height, size_present) =
+                            parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                        if size_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                            last_byte_parsed = i;
+                            i += 1;
+
+                            // Same as for `PXMULTI`: In case the sprite is rejected (e.g. because of the limits) we
+                            // only skip the header, the following bytes are parsed as commands.
+                            if self.sprites.start_upload(id, width, height) {
+                                i += self.sprites.upload(&buffer[i..loop_end]);
+                                last_byte_parsed = i - 1;
+                                if self.sprites.is_uploading() {
+                                    // The rest of the sprite arrives in the next buffers
+                                    return last_byte_parsed;
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                } else if buffer[i..].starts_with(b"blit ") {
+                    i += "blit ".len();
+
+                    let (id, id_present) = parse_coordinate(buffer.as_ptr(), &mut i);
+                    if id_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                        i += 1;
+
+                        let (x, y, position_present) =
+                            parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                        if position_present
+                            && self.is_command_end(unsafe { *buffer.get_unchecked(i) })
+                        {
+                            last_byte_parsed = i;
+                            i += 1;
+                            self.blit_sprite(
+                                id,
+                                x + self.connection_x_offset,
+                                y + self.connection_y_offset,
+                            );
+                            continue;
+                        }
+                    }
+                }
+            }
+            #[cfg(feature = "cas-command")]
+            if current_command & 0xffff_ffff_ffff == PXCAS_PATTERN {
+                i += 6;
+
+                let (client_x, client_y, present) =
+                    parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                // Two colors of 6 bytes RGB each, separated by a space and followed by a newline
+                if present
+                    && unsafe { *buffer.get_unchecked(i) } == b' '
+                    && unsafe { *buffer.get_unchecked(i + 7) } == b' '
+                    && self.is_command_end(unsafe { *buffer.get_unchecked(i + 14) })
+                {
+                    let expected = simd_unhex(unsafe { buffer.as_ptr().add(i + 1) }) & 0x00ff_ffff;
+                    let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i + 8) }) & 0x00ff_ffff;
+                    last_byte_parsed = i + 14;
+                    i += 15;
+
+                    let (x, y) = self.to_framebuffer(
+                        client_x + self.connection_x_offset,
+                        client_y + self.connection_y_offset,
+                    );
+                    if self.can_draw(x, y)
+                        && self.fb.compare_and_set(x, y, expected, rgba)
+                        && self.pixel_command_echo
+                    {
+                        self.echo_pixel(client_x, client_y, x, y, response);
+                    }
+                    self.pixels_drawn += 1;
+                    continue;
+                }
+            }
+            #[cfg(feature = "text-command")]
+            if current_command & 0xff_ffff_ffff == TEXT_PATTERN {
+                i += 5;
+
+                let (x, y, position_present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                // The color is followed by the text, which can contain spaces and ends at the command end
+                if position_present
+                    && unsafe { *buffer.get_unchecked(i) } == b' '
+                    && unsafe { *buffer.get_unchecked(i + 7) } == b' '
+                {
+                    let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i + 1) }) & 0x00ff_ffff;
+                    let text_start = i + 8;
+                    let text_end = buffer[text_start..=text_start + MAX_TEXT_LENGTH]
+                        .iter()
+                        .position(|&byte| self.is_command_end(byte))
+                        .map(|length| text_start + length);
+                    if let Some(text_end) = text_end {
+                        last_byte_parsed = text_end;
+                        i = text_end + 1;
+                        if let Ok(text) = std::str::from_utf8(&buffer[text_start..text_end]) {
+                            self.draw_text(
+                                x + self.connection_x_offset,
+                                y + self.connection_y_offset,
+                                rgba,
+                                text,
+                            );
+                        }
+                        continue;
+                    }
+                }
+            }
+            #[cfg(feature = "circle-command")]
+            if current_command & 0x00ff_ffff_ffff_ffff == CIRCLE_PATTERN {
+                i += 7;
+
+                let (x, y, center_present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                if center_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                    i += 1;
+
+                    let (radius, radius_present) = parse_coordinate(buffer.as_ptr(), &mut i);
+                    if radius_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                        i += 1;
+
+                        // Either 6 bytes RGB or 8 bytes RGBA, followed by a newline
+                        let color_len =
+                            if self.is_command_end(unsafe { *buffer.get_unchecked(i + 6) }) {
+                                Some(6)
+                            } else if self.is_command_end(unsafe { *buffer.get_unchecked(i + 8) }) {
+                                Some(8)
+                            } else {
+                                None
+                            };
+                        if let Some(color_len) = color_len {
+                            let rgba = simd_unhex(unsafe { buffer.as_ptr().add(i) });
+                            last_byte_parsed = i + color_len;
+                            i += color_len + 1;
+
+                            self.fill_circle(
+                                x + self.connection_x_offset,
+                                y + self.connection_y_offset,
+                                radius,
+                                rgba,
+                                // Without the alpha feature the alpha channel is ignored, same as for `PX`
+                                cfg!(feature = "alpha") && color_len == 8,
+                            );
+                            continue;
+                        }
+                    }
+                }
+            }
+            #[cfg(feature = "getrect")]
+            if current_command == GETRECT_PATTERN {
+                i += 8;
+
+                let (x, y, position_present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                if position_present && unsafe { *buffer.get_unchecked(i) } == b' ' {
+                    i += 1;
+
+                    let (width, height, size_present) =
+                        parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+                    if size_present && self.is_command_end(unsafe { *buffer.get_unchecked(i) }) {
+                        last_byte_parsed = i;
+                        i += 1;
+                        self.write_rect(
+                            x + self.connection_x_offset,
+                            y + self.connection_y_offset,
+                            width,
+                            height,
+                            response,
+                        );
+                        if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                            return last_byte_parsed;
+                        }
+                        continue;
+                    }
+                }
+            }
             if current_command & 0xffff_ffff == SIZE_PATTERN {
                 i += 4;
-                last_byte_parsed = i + 1;
+                last_byte_parsed = i;
 
-                response.extend_from_slice(
-                    format!("SIZE {} {}\n", self.fb.get_width(), self.fb.get_height()).as_bytes(),
-                );
+                let (width, height) = self.canvas_size();
+                response.extend_from_slice(format!("SIZE {width} {height}\n").as_bytes());
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
+                continue;
+            }
+            if current_command & 0xffff_ffff == CAPS_PATTERN {
+                i += 4;
+                last_byte_parsed = i;
+
+                self.write_capabilities(response);
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
                 continue;
             }
             if current_command & 0xffff_ffff == HELP_PATTERN {
                 i += 4;
-                last_byte_parsed = i + 1;
+                last_byte_parsed = i;
 
-                match help_count {
+                match self.help_count {
                     0..=2 => {
                         response.extend_from_slice(HELP_TEXT);
-                        help_count += 1;
+                        self.help_count += 1;
                     }
                     3 => {
                         response.extend_from_slice(ALT_HELP_TEXT);
-                        help_count += 1;
+                        self.help_count += 1;
                     }
                     _ => {
                         // The client has requested the help to often, let's just ignore it
                     }
                 }
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
+                continue;
+            }
+            #[cfg(feature = "qoi")]
+            if current_command & 0xffff_ffff == QOI_PATTERN {
+                i += 4;
+                last_byte_parsed = i - 1;
+
+                if self.take_canvas_command(response) {
+                    write_qoi_snapshot(&*self.fb, response);
+                }
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
                 continue;
             }
+            #[cfg(feature = "hash-command")]
+            if current_command & 0xffff_ffff == HASH_PATTERN {
+                i += 4;
+                last_byte_parsed = i;
+
+                if self.take_canvas_command(response) {
+                    write_canvas_hash(&*self.fb, response);
+                }
+                if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                    return last_byte_parsed;
+                }
+                continue;
+            }
+            #[cfg(feature = "screenshot-command")]
+            if current_command == SCREENSHOT_PATTERN {
+                i += 8;
+
+                // "SCREENSHOT ZSTD\n" is shorter than the lookahead, so we can safely read the rest of the command
+                let rest = unsafe { buffer.get_unchecked(i..i + 8) };
+                let compression = if rest.starts_with(b"OT") && self.is_command_end(rest[2]) {
+                    i += 2;
+                    Some(ScreenshotCompression::None)
+                } else if rest.starts_with(b"OT ZSTD") && self.is_command_end(rest[7]) {
+                    i += 7;
+                    Some(ScreenshotCompression::Zstd)
+                } else {
+                    None
+                };
+                if let Some(compression) = compression {
+                    last_byte_parsed = i;
+                    i += 1;
+                    if self.take_canvas_command(response) {
+                        write_screenshot(&*self.fb, compression, response);
+                    }
+                    if response.len() >= RESPONSE_FLUSH_THRESHOLD {
+                        return last_byte_parsed;
+                    }
+                    continue;
+                }
+            }
 
             i += 1;
         }
@@ -303,10 +1478,40 @@ impl<FB: FrameBuffer> Parser for OriginalParser<FB> {
         last_byte_parsed
         // last_byte_parsed.saturating_sub(1)
     }
+}
+
+impl<FB: FrameBuffer, F: Deref<Target = FB>> Parser for OriginalParser<FB, F> {
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        #[cfg(any(
+            feature = "hash-command",
+            feature = "qoi",
+            feature = "screenshot-command"
+        ))]
+        {
+            self.canvas_commands = 0;
+        }
+        self.parse_commands(buffer, response)
+    }
 
     fn parser_lookahead(&self) -> usize {
         PARSER_LOOKAHEAD
     }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.pixels_drawn
+    }
+
+    fn malformed_commands(&self) -> u64 {
+        self.malformed_commands
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
 }
 
 const fn string_to_number(input: &[u8]) -> u64 {
@@ -351,6 +1556,54 @@ pub(crate) fn simd_unhex(value: *const u8) -> u32 {
     shifted.reduce_or()
 }
 
+/// Writes `PX x y rrggbb\n` for the pixel color `rgb` (as stored in the framebuffer) to the response.
+///
+/// Read-heavy clients send lots of `PX x y` commands, so this avoids the allocation and formatting machinery of
+/// `format!` and writes the digits directly into the response.
+#[inline(always)]
+pub(crate) fn write_pixel_response(response: &mut Vec<u8>, x: usize, y: usize, rgb: u32) {
+    write_pixel_color(response, x, y, rgb);
+    response.push(b'\n');
+}
+
+/// Writes `PX x y rrggbb` without the trailing newline, see [`write_pixel_response`]
+#[inline(always)]
+fn write_pixel_color(response: &mut Vec<u8>, x: usize, y: usize, rgb: u32) {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    response.extend_from_slice(b"PX ");
+    write_decimal(response, x);
+    response.push(b' ');
+    write_decimal(response, y);
+    response.push(b' ');
+    // The framebuffer stores 0x00bbggrr, so the little-endian bytes are r, g and b
+    let [r, g, b, _] = rgb.to_le_bytes();
+    response.extend_from_slice(&[
+        HEX_DIGITS[(r >> 4) as usize],
+        HEX_DIGITS[(r & 0xf) as usize],
+        HEX_DIGITS[(g >> 4) as usize],
+        HEX_DIGITS[(g & 0xf) as usize],
+        HEX_DIGITS[(b >> 4) as usize],
+        HEX_DIGITS[(b & 0xf) as usize],
+    ]);
+}
+
+#[inline(always)]
+fn write_decimal(response: &mut Vec<u8>, mut value: usize) {
+    // Enough for usize::MAX
+    let mut digits = [0; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    response.extend_from_slice(&digits[start..]);
+}
+
 #[inline(always)]
 fn parse_coordinate(buffer: *const u8, current_index: &mut usize) -> (usize, bool) {
     let digits = unsafe { (buffer.add(*current_index) as *const usize).read_unaligned() };
@@ -382,3 +1635,489 @@ pub(crate) fn parse_pixel_coordinates(
     let (y, y_visited) = parse_coordinate(buffer, current_index);
     (x, y, x_visited && y_visited)
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{
+        test_helpers::mock_framebuffer::MockFrameBuffer, JournalRecord, SimpleFrameBuffer,
+    };
+
+    #[rstest]
+    #[case(0, 0, 0x0000_0000)]
+    #[case(7, 42, 0x0012_3456)]
+    #[case(1919, 1079, 0x00ff_eedd)]
+    #[case(10_000, 65_535, 0x0000_00ff)]
+    #[case(usize::MAX, 1, 0x00ab_cdef)]
+    fn test_write_pixel_response(#[case] x: usize, #[case] y: usize, #[case] rgb: u32) {
+        let mut response = b"PX 1 2 ffffff\n".to_vec();
+        write_pixel_response(&mut response, x, y, rgb);
+
+        // Must be exactly the same as the formatting machinery would produce
+        let [r, g, b, _] = rgb.to_le_bytes();
+        assert_eq!(
+            std::str::from_utf8(&response).unwrap(),
+            format!("PX 1 2 ffffff\nPX {x} {y} {r:02x}{g:02x}{b:02x}\n")
+        );
+    }
+
+    #[test]
+    fn test_help_is_limited_across_parse_calls() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb);
+
+        let mut buffer = b"HELP\n".to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+
+        // Every HELP arrives in its own parse call, as it would when the client sends them one by one
+        let responses: Vec<Vec<u8>> = (0..6)
+            .map(|_| {
+                let mut response = Vec::new();
+                parser.parse(&buffer, &mut response);
+                response
+            })
+            .collect();
+
+        assert_eq!(responses[0], HELP_TEXT);
+        assert_eq!(responses[1], HELP_TEXT);
+        assert_eq!(responses[2], HELP_TEXT);
+        assert_eq!(responses[3], ALT_HELP_TEXT);
+        assert!(responses[4].is_empty());
+        assert!(responses[5].is_empty());
+    }
+
+    fn pixel(x: u32, y: u32, rgba: u32) -> JournalRecord {
+        JournalRecord::Pixel { x, y, rgba }
+    }
+
+    #[rstest]
+    // Gray is expanded to all channels
+    #[case("PX 1 2 80\n", &[pixel(1, 2, 0x808080)])]
+    // The pixels are stored as 0x00bbggrr
+    #[case("PX 1 2 ff0000\n", &[pixel(1, 2, 0x0000ff)])]
+    #[case("PX 1 2 123456\nPX 2 1 abcdef\n", &[pixel(1, 2, 0x563412), pixel(2, 1, 0xefcdab)])]
+    // The offset is applied before writing
+    #[case("OFFSET 10 20\nPX 1 2 ff\n", &[pixel(11, 22, 0xffffff)])]
+    #[case("PX 1 2 ff\nOFFSET 10 20\nPX 1 2 00\n", &[pixel(1, 2, 0xffffff), pixel(11, 22, 0)])]
+    // Reads and invalid commands don't write anything
+    #[case("PX 1 2\nSIZE\nPX 1\n", &[])]
+    fn test_writes(#[case] input: &str, #[case] expected: &[JournalRecord]) {
+        let fb = Arc::new(MockFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb.clone());
+
+        let mut buffer = input.as_bytes().to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        parser.parse(&buffer, &mut Vec::new());
+
+        assert_eq!(fb.take_writes(), expected);
+    }
+
+    #[rstest]
+    #[case("PX 1 2 abcdef\nPX 1 2\nPX 1 2 ff\nOFFSET 1 2\nSIZE\n", 0)]
+    #[case("PX abc\n", 1)]
+    #[case("PX 1\n", 1)]
+    #[case("PX 1 2 zzzz\n", 1)]
+    #[case("PX 1 2 abcdef 12\n", 1)]
+    #[case("PX 1 2  abcdef\n", 1)]
+    #[case("OFFSET 1\n", 1)]
+    #[case("PX abc\nPX 1 2 abcdef\nPX 1\nOFFSET x y\n", 3)]
+    // Unknown commands are not counted
+    #[case("FOO\nPXX\n", 0)]
+    // The command might be completed by the next read
+    #[case("PX 1 2 abc", 0)]
+    fn test_malformed_commands(#[case] input: &str, #[case] expected: u64) {
+        let mut parser = OriginalParser::new(Arc::new(SimpleFrameBuffer::new(640, 480)));
+
+        let mut buffer = input.as_bytes().to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        parser.parse(&buffer, &mut Vec::new());
+
+        assert_eq!(parser.malformed_commands(), expected);
+    }
+
+    #[test]
+    fn test_borrowed_framebuffer() {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        let mut parser = OriginalParser::new(&fb);
+
+        let mut buffer = b"PX 1 2 abcdef\nPX 1 2\n".to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        assert_eq!(response, b"PX 1 2 abcdef\n");
+        drop(parser);
+        assert_eq!(fb.get(1, 2), Some(0x00ef_cdab));
+    }
+
+    #[test]
+    fn test_parse_stops_once_response_is_large() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        fb.set(1, 2, 0x00ff_eedd);
+        let mut parser = OriginalParser::new(fb);
+
+        let commands = 20_000;
+        let mut buffer = "PX 1 2\n".repeat(commands).into_bytes();
+        let data_end = buffer.len();
+        buffer.resize(data_end + PARSER_LOOKAHEAD, 0);
+
+        let mut output = Vec::new();
+        let mut parse_start = 0;
+        let mut parse_calls = 0;
+        while parse_start < data_end {
+            let mut response = Vec::new();
+            let last_byte_parsed = parser.parse(&buffer[parse_start..], &mut response);
+            assert!(
+                response.len() < RESPONSE_FLUSH_THRESHOLD + "PX 1 2 ddeeff\n".len(),
+                "response grew to {} bytes",
+                response.len()
+            );
+            output.extend_from_slice(&response);
+            parse_start += last_byte_parsed + 1;
+            parse_calls += 1;
+        }
+
+        assert!(parse_calls > 1);
+        assert_eq!(output, "PX 1 2 ddeeff\n".repeat(commands).as_bytes());
+    }
+
+    #[cfg(feature = "custom-separators")]
+    #[rstest]
+    #[case(None, "PX 0 0 ff0000\nPX 1 0 00ff00\n", &[0xff, 0xff00])]
+    #[case(Some(b';'), "PX 0 0 ff0000;PX 1 0 00ff00;", &[0xff, 0xff00])]
+    #[case(Some(b';'), "PX 0 0 ff0000;PX 1 0 00ff00\nPX 2 0 0000ff;", &[0xff, 0xff00, 0xff0000])]
+    #[case(Some(b';'), "PX 0 0 ff;OFFSET 1 0;PX 0 0 80;OFFSET 0 0;", &[0xffffff, 0x808080])]
+    // Without the separator configured it is just garbage
+    #[case(None, "PX 0 0 ff0000;PX 1 0 00ff00;", &[])]
+    fn test_command_separator(
+        #[case] command_separator: Option<u8>,
+        #[case] input: &str,
+        #[case] expected: &[u32],
+    ) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb.clone()).with_command_separator(command_separator);
+
+        let mut buffer = format!("{input}PX 0 0;PX 1 0;PX 2 0\n").into_bytes();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        for (x, rgb) in expected.iter().enumerate() {
+            assert_eq!(fb.get(x, 0), Some(*rgb), "pixel {x}");
+        }
+        assert_eq!(fb.get(expected.len(), 0), Some(0));
+        if command_separator.is_some() {
+            // Reading pixels works with the separator as well
+            let rgb = |x| format!("{:06x}", fb.get(x, 0).unwrap().to_be() >> 8);
+            assert_eq!(
+                std::str::from_utf8(&response).unwrap(),
+                format!("PX 0 0 {}\nPX 1 0 {}\nPX 2 0 {}\n", rgb(0), rgb(1), rgb(2))
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(CanvasRotation::None, "SIZE 320 240\n")]
+    #[case(CanvasRotation::Clockwise90, "SIZE 240 320\n")]
+    fn test_logical_size(#[case] canvas_rotation: CanvasRotation, #[case] expected_size: &str) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb.clone())
+            .with_logical_size(Some(320), Some(240))
+            .with_canvas_rotation(canvas_rotation);
+        let (width, height) = canvas_rotation.canvas_size(320, 240);
+
+        // The framebuffer could hold all of these pixels, but they are outside of the logical canvas
+        let mut buffer = format!(
+            "SIZE\nPX {width} 0 ffffff\nPX 0 {height} ffffff\nPX {width} {height} ffffff\nPX 600 400 ffffff\n\
+            PX {width} 0 ffffffff\nPX {width} 0 ff\nPX {width} 0\nPX {} {} ffffff\nPX {} {}\n",
+            width - 1,
+            height - 1,
+            width - 1,
+            height - 1,
+        )
+        .into_bytes();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        assert_eq!(
+            std::str::from_utf8(&response).unwrap(),
+            format!("{expected_size}PX {} {} ffffff\n", width - 1, height - 1)
+        );
+        let drawn = (0..480)
+            .flat_map(|y| (0..640).map(move |x| (x, y)))
+            .filter(|&(x, y)| fb.get(x, y) != Some(0))
+            .collect::<Vec<_>>();
+        assert_eq!(drawn.len(), 1);
+        assert!(drawn[0].0 < 320 && drawn[0].1 < 240, "{drawn:?}");
+    }
+
+    #[test]
+    fn test_logical_size_is_capped_to_framebuffer() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb).with_logical_size(Some(10_000), None);
+
+        let mut buffer = b"SIZE\n".to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        assert_eq!(response, b"SIZE 640 480\n");
+    }
+
+    #[test]
+    fn test_maintenance_mode() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let maintenance_mode = Arc::new(AtomicBool::new(false));
+        let mut parser =
+            OriginalParser::new(fb.clone()).with_maintenance_mode(Arc::clone(&maintenance_mode));
+        let mut parse = |input: &str| {
+            let mut buffer = input.as_bytes().to_vec();
+            buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+            let mut response = Vec::new();
+            parser.parse(&buffer, &mut response);
+            String::from_utf8(response).unwrap()
+        };
+
+        assert_eq!(
+            parse(
+                "PX 0 0 ff0000
+PX 0 0
+"
+            ),
+            "PX 0 0 ff0000
+"
+        );
+
+        maintenance_mode.store(true, Ordering::Relaxed);
+        // Drawing is a no-op, but reading still works
+        assert_eq!(
+            parse(
+                "PX 0 0 00ff00
+PX 1 0 ff
+PX 0 0
+PX 1 0
+SIZE
+"
+            ),
+            "PX 0 0 ff0000
+PX 1 0 000000
+SIZE 640 480
+"
+        );
+
+        maintenance_mode.store(false, Ordering::Relaxed);
+        assert_eq!(
+            parse(
+                "PX 0 0 00ff00
+PX 1 0 ff
+PX 0 0
+PX 1 0
+"
+            ),
+            "PX 0 0 00ff00
+PX 1 0 ffffff
+"
+        );
+    }
+
+    #[cfg(feature = "binary-sync-pixels")]
+    #[test]
+    fn test_maintenance_mode_pxmulti_across_parse_calls() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let maintenance_mode = Arc::new(AtomicBool::new(false));
+        let mut parser =
+            OriginalParser::new(fb.clone()).with_maintenance_mode(Arc::clone(&maintenance_mode));
+        let mut parse = |input: &[u8]| {
+            let mut buffer = input.to_vec();
+            buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+            parser.parse(&buffer, &mut Vec::new());
+        };
+
+        let mut command = b"PXMULTI".to_vec();
+        command.extend(0_u16.to_le_bytes()); // x
+        command.extend(0_u16.to_le_bytes()); // y
+        command.extend(3_u32.to_le_bytes()); // length
+                                             // Opaque pixels, so that they are drawn the same with and without the alpha feature
+        command.extend(0xff00_0001_u32.to_le_bytes());
+        parse(&command);
+
+        // The second pixel arrives during maintenance and is dropped, the third one still ends up in the right place
+        maintenance_mode.store(true, Ordering::Relaxed);
+        parse(&0xff00_0002_u32.to_le_bytes());
+        maintenance_mode.store(false, Ordering::Relaxed);
+        parse(&0xff00_0003_u32.to_le_bytes());
+
+        assert_eq!(fb.get(0, 0), Some(1));
+        assert_eq!(fb.get(1, 0), Some(0));
+        assert_eq!(fb.get(2, 0), Some(3));
+    }
+
+    #[cfg(feature = "binary-sync-pixels")]
+    #[rstest]
+    #[case::absurd_len(0, 0, u32::MAX)]
+    #[case::one_pixel_too_many(0, 0, 640 * 480 + 1)]
+    #[case::exceeds_the_end(639, 479, 2)]
+    fn test_pxmulti_exceeding_the_framebuffer_is_skipped(
+        #[case] x: u16,
+        #[case] y: u16,
+        #[case] len: u32,
+    ) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb.clone());
+
+        let mut input = b"PXMULTI".to_vec();
+        input.extend(x.to_le_bytes());
+        input.extend(y.to_le_bytes());
+        input.extend(len.to_le_bytes());
+        // Would be swallowed as payload, in case the length is not rejected
+        input.extend(b"PX 0 0 ffffff\nPX 0 0\n");
+        input.resize(input.len() + PARSER_LOOKAHEAD, 0);
+
+        let mut response = Vec::new();
+        parser.parse(&input, &mut response);
+
+        assert_eq!(response, b"PX 0 0 ffffff\n");
+        assert_eq!(fb.get(0, 0), Some(0xffffff));
+    }
+
+    #[cfg(feature = "getrect")]
+    #[test]
+    fn test_getrect() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb);
+
+        // Every pixel encodes its own position in the red and green channel
+        let mut input = String::new();
+        for (x, y) in [(10, 20), (11, 20), (12, 20), (10, 21), (11, 21), (12, 21)] {
+            input.push_str(&format!("PX {x} {y} {x:02x}{y:02x}ab\n"));
+        }
+        input.push_str("PX 639 479 ffffff\n");
+        // The offset applies to the requested region, the last one is clipped to the bottom-right corner
+        input.push_str(
+            "OFFSET 10 20\nGETRECT 0 0 3 2\nOFFSET 0 0\nGETRECT 638 478 5 5\nGETRECT 640 0 1 1\n",
+        );
+
+        let mut buffer = input.into_bytes();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        let mut response = response.as_slice();
+        let mut next_rect = || {
+            let header_end = response.iter().position(|&b| b == b'\n').unwrap();
+            let header = std::str::from_utf8(&response[..header_end])
+                .unwrap()
+                .to_owned();
+            let len: usize = header.rsplit(' ').next().unwrap().parse().unwrap();
+            let pixels = response[header_end + 1..header_end + 1 + len].to_vec();
+            response = &response[header_end + 1 + len..];
+            (header, pixels)
+        };
+
+        let (header, pixels) = next_rect();
+        assert_eq!(header, "GETRECT 3 2 24");
+        let expected = [(10, 20), (11, 20), (12, 20), (10, 21), (11, 21), (12, 21)]
+            .into_iter()
+            .flat_map(|(x, y)| [x, y, 0xab, 0xff])
+            .collect::<Vec<u8>>();
+        assert_eq!(pixels, expected);
+
+        let (header, pixels) = next_rect();
+        assert_eq!(header, "GETRECT 2 2 16");
+        assert_eq!(
+            pixels,
+            [0, 0, 0, 0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+
+        assert_eq!(next_rect(), ("GETRECT 0 0 0".to_owned(), Vec::new()));
+        assert!(response.is_empty());
+    }
+
+    #[rstest]
+    #[case(
+        None,
+        None,
+        "max-pixels-per-connection=none max-bytes-per-connection=none"
+    )]
+    #[case(
+        Some(1000),
+        Some(42),
+        "max-pixels-per-connection=1000 max-bytes-per-connection=42"
+    )]
+    fn test_capabilities(
+        #[case] max_pixels: Option<u64>,
+        #[case] max_bytes: Option<u64>,
+        #[case] expected_limits: &str,
+    ) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb).with_connection_limits(max_pixels, max_bytes);
+
+        // The parser only looks at commands that have PARSER_LOOKAHEAD bytes following them
+        let mut buffer = b"CAPS\n".to_vec();
+        buffer.resize(buffer.len() + PARSER_LOOKAHEAD, 0);
+        let mut response = Vec::new();
+        parser.parse(&buffer, &mut response);
+
+        let features = format!(
+            "alpha={} binary-set-pixel={} binary-sync-pixels={} qoi={} flip-command={} circle-command={} getrect={} screenshot-command={} cas-command={} text-command={} sprites={} hash-command={}",
+            cfg!(feature = "alpha") as u8,
+            cfg!(feature = "binary-set-pixel") as u8,
+            cfg!(feature = "binary-sync-pixels") as u8,
+            cfg!(feature = "qoi") as u8,
+            cfg!(feature = "flip-command") as u8,
+            cfg!(feature = "circle-command") as u8,
+            cfg!(feature = "getrect") as u8,
+            cfg!(feature = "screenshot-command") as u8,
+            cfg!(feature = "cas-command") as u8,
+            cfg!(feature = "text-command") as u8,
+            cfg!(feature = "sprites") as u8,
+            cfg!(feature = "hash-command") as u8,
+        );
+        assert_eq!(
+            std::str::from_utf8(&response).unwrap(),
+            format!(
+                "CAPS width=640 height=480 max-x=639 max-y=479 bit-depth=24 {features} {expected_limits}\n"
+            )
+        );
+    }
+
+    #[cfg(feature = "sprites")]
+    #[rstest]
+    #[case::single_buffer(usize::MAX)]
+    #[case::split_after_header(22)]
+    #[case::split_in_pixels(25)]
+    #[case::split_in_blit(30)]
+    fn test_sprite_across_parse_calls(#[case] chunk_size: usize) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+        let mut parser = OriginalParser::new(fb.clone());
+
+        let mut input = b"SPRITE define 1 2 2 ".to_vec();
+        for rgba in [0x0000_00ff_u32, 0x0000_ff00, 0x00ff_0000, 0x00ff_ffff] {
+            input.extend_from_slice(&rgba.to_le_bytes());
+        }
+        input.extend_from_slice(b"SPRITE blit 1 10 20\n");
+
+        // Same as the server: The bytes behind the last byte parsed are passed again together with the next chunk
+        let mut pending = Vec::new();
+        for chunk in input.chunks(chunk_size.min(input.len())) {
+            pending.extend_from_slice(chunk);
+            let data_end = pending.len();
+            pending.resize(data_end + PARSER_LOOKAHEAD, 0);
+            let last_byte_parsed = parser.parse(&pending, &mut Vec::new());
+
+            pending.truncate(data_end);
+            pending.drain(..(last_byte_parsed + 1).min(data_end));
+        }
+        assert!(pending.is_empty());
+
+        assert_eq!(fb.get(10, 20), Some(0x0000_00ff));
+        assert_eq!(fb.get(11, 20), Some(0x0000_ff00));
+        assert_eq!(fb.get(10, 21), Some(0x00ff_0000));
+        assert_eq!(fb.get(11, 21), Some(0x00ff_ffff));
+        assert_eq!(fb.get(12, 20), Some(0));
+        assert_eq!(parser.pixels_drawn(), 4);
+    }
+}
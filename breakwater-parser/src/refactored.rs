@@ -1,9 +1,19 @@
+#[cfg(feature = "binary-sync-pixels")]
+use core::slice;
 use std::sync::Arc;
 
+#[cfg(feature = "alpha")]
+use crate::alpha_blend;
+#[cfg(feature = "binary-sync-pixels")]
+use crate::{
+    framebuffer::sync_pixels,
+    original::{PixelSync, PXMULTI_PATTERN},
+    RemainingPayload,
+};
 use crate::{
     original::{
-        parse_pixel_coordinates, simd_unhex, HELP_PATTERN, OFFSET_PATTERN, PB_PATTERN, PX_PATTERN,
-        SIZE_PATTERN,
+        parse_pixel_coordinates, simd_unhex, write_pixel_response, HELP_PATTERN, OFFSET_PATTERN,
+        PB_PATTERN, PX_PATTERN, SIZE_PATTERN,
     },
     FrameBuffer, Parser, HELP_TEXT,
 };
@@ -14,6 +24,10 @@ pub struct RefactoredParser<FB: FrameBuffer> {
     connection_x_offset: usize,
     connection_y_offset: usize,
     fb: Arc<FB>,
+    pixels_drawn: u64,
+    /// Payload of a variable-length command (e.g. `PXMULTI`), which did not fit into the last buffer
+    #[cfg(feature = "binary-sync-pixels")]
+    remaining_payload: Option<RemainingPayload<FB>>,
 }
 
 impl<FB: FrameBuffer> RefactoredParser<FB> {
@@ -22,12 +36,15 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
             connection_x_offset: 0,
             connection_y_offset: 0,
             fb,
+            pixels_drawn: 0,
+            #[cfg(feature = "binary-sync-pixels")]
+            remaining_payload: None,
         }
     }
 
     #[inline(always)]
     fn handle_pixel(
-        &self,
+        &mut self,
         buffer: &[u8],
         mut idx: usize,
         response: &mut Vec<u8>,
@@ -35,11 +52,11 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
         let previous = idx;
         idx += 3;
 
-        let (mut x, mut y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut idx);
+        let (client_x, client_y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut idx);
 
         if present {
-            x += self.connection_x_offset;
-            y += self.connection_y_offset;
+            let x = client_x + self.connection_x_offset;
+            let y = client_y + self.connection_y_offset;
 
             // Separator between coordinates and color
             if unsafe { *buffer.get_unchecked(idx) } == b' ' {
@@ -52,18 +69,21 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
                 if unsafe { *buffer.get_unchecked(idx + 6) } == b'\n' {
                     idx += 7;
                     self.handle_rgb(idx, buffer, x, y);
+                    self.pixels_drawn += 1;
                     (idx, idx)
                 }
                 // ... or must be followed by 8 bytes RGBA and newline
                 else if unsafe { *buffer.get_unchecked(idx + 8) } == b'\n' {
                     idx += 9;
                     self.handle_rgba(idx, buffer, x, y);
+                    self.pixels_drawn += 1;
                     (idx, idx)
                 }
                 // ... for the efficient/lazy clients
                 else if unsafe { *buffer.get_unchecked(idx + 2) } == b'\n' {
                     idx += 3;
                     self.handle_gray(idx, buffer, x, y);
+                    self.pixels_drawn += 1;
                     (idx, idx)
                 } else {
                     (idx, previous)
@@ -72,7 +92,7 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
             // End of command to read Pixel value
             else if unsafe { *buffer.get_unchecked(idx) } == b'\n' {
                 idx += 1;
-                self.handle_get_pixel(response, x, y);
+                self.handle_get_pixel(response, client_x, client_y);
                 (idx, idx)
             } else {
                 (idx, previous)
@@ -83,7 +103,7 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
     }
 
     #[inline(always)]
-    fn handle_binary_pixel(&self, buffer: &[u8], mut idx: usize) -> (usize, usize) {
+    fn handle_binary_pixel(&mut self, buffer: &[u8], mut idx: usize) -> (usize, usize) {
         let previous = idx;
         idx += 2;
 
@@ -95,19 +115,67 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
 
         // TODO: Support alpha channel (behind alpha feature flag)
         self.fb.set(x as usize, y as usize, rgba & 0x00ff_ffff);
+        self.pixels_drawn += 1;
 
         idx += 8;
         (idx, previous)
     }
 
+    /// Copies the pixels 1:1 into the framebuffer and returns the index after the payload. In case the payload does not
+    /// fit into the buffer, the rest of it is remembered for the next parse calls and [`Err`] contains the index the
+    /// parse call needs to return, as there is nothing left to parse in this buffer.
+    #[cfg(feature = "binary-sync-pixels")]
+    #[inline(always)]
+    fn handle_binary_sync_pixels(
+        &mut self,
+        buffer: &[u8],
+        mut idx: usize,
+        loop_end: usize,
+    ) -> Result<usize, usize> {
+        idx += "PXMULTI".len();
+        let header = unsafe { (buffer.as_ptr().add(idx) as *const u64).read_unaligned() };
+        idx += 8;
+
+        let start_x = u16::from_le((header) as u16) as usize + self.connection_x_offset;
+        let start_y = u16::from_le((header >> 16) as u16) as usize + self.connection_y_offset;
+        let len = u32::from_le((header >> 32) as u32);
+        let len_in_bytes = len as usize * 4;
+        let bytes_left_in_buffer = loop_end.saturating_sub(idx);
+
+        if len_in_bytes <= bytes_left_in_buffer {
+            sync_pixels(&*self.fb, start_x + start_y * self.fb.get_width(), unsafe {
+                slice::from_raw_parts(buffer.as_ptr().add(idx), len_in_bytes)
+            });
+            self.pixels_drawn += len as u64;
+            return Ok(idx + len_in_bytes);
+        }
+
+        let current_index = start_x + start_y * self.fb.get_width();
+        let mut remaining = RemainingPayload::new(
+            Box::new(PixelSync {
+                current_index,
+                maintenance_mode: None,
+            }),
+            len_in_bytes,
+        );
+        let (consumed, pixels_drawn) =
+            remaining.consume(self.fb.as_ref(), &buffer[idx..idx + bytes_left_in_buffer]);
+        self.pixels_drawn += pixels_drawn;
+        self.remaining_payload = Some(remaining);
+
+        // Same as in `OriginalParser`
+        Err(idx + consumed.saturating_sub(1))
+    }
+
     #[inline(always)]
     fn handle_offset(&mut self, idx: &mut usize, buffer: &[u8]) {
         let (x, y, present) = parse_pixel_coordinates(buffer.as_ptr(), idx);
 
         // End of command to set offset
         if present && unsafe { *buffer.get_unchecked(*idx) } == b'\n' {
-            self.connection_x_offset = x;
-            self.connection_y_offset = y;
+            // Larger offsets would only move all further draws and reads outside of the canvas as well
+            self.connection_x_offset = x.min(self.fb.get_width());
+            self.connection_y_offset = y.min(self.fb.get_height());
         }
     }
 
@@ -149,17 +217,8 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
             return;
         }
 
-        let alpha_comp = 0xff - alpha;
         let current = unsafe { self.fb.get_unchecked(x, y) };
-        let r = (rgba >> 16) & 0xff;
-        let g = (rgba >> 8) & 0xff;
-        let b = rgba & 0xff;
-
-        let r: u32 = (((current >> 24) & 0xff) * alpha_comp + r * alpha) / 0xff;
-        let g: u32 = (((current >> 16) & 0xff) * alpha_comp + g * alpha) / 0xff;
-        let b: u32 = (((current >> 8) & 0xff) * alpha_comp + b * alpha) / 0xff;
-
-        self.fb.set(x, y, (r << 16) | (g << 8) | b);
+        self.fb.set(x, y, alpha_blend(current, rgba));
     }
 
     #[inline(always)]
@@ -174,18 +233,12 @@ impl<FB: FrameBuffer> RefactoredParser<FB> {
     }
 
     #[inline(always)]
-    fn handle_get_pixel(&self, response: &mut Vec<u8>, x: usize, y: usize) {
+    fn handle_get_pixel(&self, response: &mut Vec<u8>, client_x: usize, client_y: usize) {
+        let x = client_x + self.connection_x_offset;
+        let y = client_y + self.connection_y_offset;
         if let Some(rgb) = self.fb.get(x, y) {
-            response.extend_from_slice(
-                format!(
-                    "PX {} {} {:06x}\n",
-                    // We don't want to return the actual (absolute) coordinates, the client should also get the result offseted
-                    x - self.connection_x_offset,
-                    y - self.connection_y_offset,
-                    rgb.to_be() >> 8
-                )
-                .as_bytes(),
-            );
+            // We don't want to return the actual (absolute) coordinates, the client should also get the result offseted
+            write_pixel_response(response, client_x, client_y, rgb);
         }
     }
 }
@@ -197,9 +250,37 @@ impl<FB: FrameBuffer> Parser for RefactoredParser<FB> {
         let mut i = 0; // We can't use a for loop here because Rust don't lets use skip characters by incrementing i
         let loop_end = buffer.len().saturating_sub(PARSER_LOOKAHEAD); // Let's extract the .len() call and the subtraction into it's own variable so we only compute it once
 
+        #[cfg(feature = "binary-sync-pixels")]
+        if let Some(remaining) = &mut self.remaining_payload {
+            let (consumed, pixels_drawn) =
+                remaining.consume(self.fb.as_ref(), &buffer[0..loop_end]);
+            self.pixels_drawn += pixels_drawn;
+
+            if !remaining.is_finished() {
+                // The client requested to write more bytes that are currently in the buffer, so there is nothing to
+                // do left
+                return consumed.saturating_sub(1);
+            }
+
+            self.remaining_payload = None;
+            i = consumed;
+            last_byte_parsed = consumed;
+        }
+
         while i < loop_end {
             let current_command =
                 unsafe { (buffer.as_ptr().add(i) as *const u64).read_unaligned() };
+            #[cfg(feature = "binary-sync-pixels")]
+            if current_command & 0x00ff_ffff_ffff_ffff == PXMULTI_PATTERN {
+                match self.handle_binary_sync_pixels(buffer, i, loop_end) {
+                    Ok(payload_end) => {
+                        i = payload_end;
+                        last_byte_parsed = payload_end;
+                        continue;
+                    }
+                    Err(last_byte_parsed) => return last_byte_parsed,
+                }
+            }
             if current_command & 0x00ff_ffff == PX_PATTERN {
                 (i, last_byte_parsed) = self.handle_pixel(buffer, i, response);
             } else if cfg!(feature = "binary-set-pixel")
@@ -223,10 +304,48 @@ impl<FB: FrameBuffer> Parser for RefactoredParser<FB> {
             }
         }
 
-        last_byte_parsed.wrapping_sub(1)
+        // `last_byte_parsed` points behind the last parsed command, but we need to return the index of its last byte.
+        // In case nothing was parsed, we report no progress (same as `OriginalParser`) instead of wrapping around.
+        last_byte_parsed.saturating_sub(1)
     }
 
     fn parser_lookahead(&self) -> usize {
         PARSER_LOOKAHEAD
     }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.pixels_drawn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{OriginalParser, SimpleFrameBuffer};
+
+    fn parse(parser: &mut impl Parser, input: &[u8]) -> usize {
+        let mut buffer = input.to_vec();
+        buffer.resize(input.len() + parser.parser_lookahead(), 0);
+        parser.parse(&buffer, &mut Vec::new())
+    }
+
+    #[rstest]
+    #[case::empty(b"", 0)]
+    #[case::incomplete_command(b"PX 1 2 ff", 0)]
+    #[case::incomplete_size(b"SIZ", 0)]
+    #[case::gibberish(b"hello world", 0)]
+    #[case::complete_command(b"PX 1 2 ffffff\n", 13)]
+    #[case::complete_and_incomplete_command(b"PX 1 2 ffffff\nPX 3 4 ff", 13)]
+    fn test_last_byte_parsed(#[case] input: &[u8], #[case] expected: usize) {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480));
+
+        assert_eq!(
+            parse(&mut RefactoredParser::new(fb.clone()), input),
+            expected
+        );
+        // Must be consistent with the other parsers, as the caller relies on it to keep the leftover bytes
+        assert_eq!(parse(&mut OriginalParser::new(fb), input), expected);
+    }
 }
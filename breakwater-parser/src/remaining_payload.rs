@@ -0,0 +1,131 @@
+use std::cmp::min;
+
+use crate::FrameBuffer;
+
+/// Handles the payload of a variable-length command (such as `PXMULTI`).
+///
+/// The payload of such a command can be way larger than the buffer passed to a single [`crate::Parser::parse`] call,
+/// so the payload is handed over in pieces.
+pub trait PayloadHandler<FB: FrameBuffer> {
+    /// The payload is only handed over in multiples of this size, e.g. 4 bytes if the payload consists of pixels.
+    /// All bytes not forming a complete chunk are left over for the next parse call.
+    fn chunk_size(&self) -> usize;
+
+    /// Handles the next piece of the payload. Returns the number of pixels drawn.
+    fn handle_payload(&mut self, fb: &FB, payload: &[u8]) -> u64;
+}
+
+/// Keeps track of a variable-length command, which payload did not fit into the buffer of a single parse call, so that
+/// it can be resumed across multiple parse calls.
+pub struct RemainingPayload<FB: FrameBuffer> {
+    handler: Box<dyn PayloadHandler<FB> + Send>,
+    bytes_remaining: usize,
+}
+
+impl<FB: FrameBuffer> RemainingPayload<FB> {
+    pub fn new(handler: Box<dyn PayloadHandler<FB> + Send>, bytes_remaining: usize) -> Self {
+        Self {
+            handler,
+            bytes_remaining,
+        }
+    }
+
+    /// Hands over as much of the given buffer to the [`PayloadHandler`] as possible.
+    ///
+    /// Returns the number of bytes consumed and the number of pixels drawn.
+    pub fn consume(&mut self, fb: &FB, buffer: &[u8]) -> (usize, u64) {
+        let chunk_size = self.handler.chunk_size();
+        // We need to round down to the chunk alignment
+        let bytes = min(self.bytes_remaining, buffer.len() / chunk_size * chunk_size);
+
+        let pixels_drawn = self.handler.handle_payload(fb, &buffer[..bytes]);
+        self.bytes_remaining -= bytes;
+
+        (bytes, pixels_drawn)
+    }
+
+    pub fn bytes_remaining(&self) -> usize {
+        self.bytes_remaining
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.bytes_remaining == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::SimpleFrameBuffer;
+
+    /// Custom variable-length command, which collects the payload in chunks of 3 bytes
+    struct CollectingHandler {
+        payload: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl<FB: FrameBuffer> PayloadHandler<FB> for CollectingHandler {
+        fn chunk_size(&self) -> usize {
+            3
+        }
+
+        fn handle_payload(&mut self, _fb: &FB, payload: &[u8]) -> u64 {
+            assert_eq!(payload.len() % 3, 0, "Payload must be chunk aligned");
+            self.payload.lock().unwrap().extend_from_slice(payload);
+            0
+        }
+    }
+
+    #[test]
+    fn test_payload_split_across_three_reads() {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        let payload = (0..30_000).map(|i| i as u8).collect::<Vec<_>>();
+        let collected = Arc::new(Mutex::new(Vec::new()));
+
+        let mut remaining = RemainingPayload::new(
+            Box::new(CollectingHandler {
+                payload: collected.clone(),
+            }),
+            payload.len(),
+        );
+
+        // First read: 10_001 bytes, which is not chunk aligned, so 2 bytes are left over
+        let (consumed, _) = remaining.consume(&fb, &payload[..10_001]);
+        assert_eq!(consumed, 9_999);
+        assert_eq!(remaining.bytes_remaining(), 20_001);
+        assert!(!remaining.is_finished());
+
+        // Second read: The leftover bytes followed by the next 10_000 bytes
+        let (consumed, _) = remaining.consume(&fb, &payload[9_999..20_001]);
+        assert_eq!(consumed, 10_002);
+        assert_eq!(remaining.bytes_remaining(), 9_999);
+
+        // Third read: The rest of the payload, followed by the next command, which must not be consumed
+        let mut buffer = payload[20_001..].to_vec();
+        buffer.extend_from_slice(b"PX 0 0 ffffff\n");
+        let (consumed, _) = remaining.consume(&fb, &buffer);
+        assert_eq!(consumed, 9_999);
+        assert!(remaining.is_finished());
+
+        assert_eq!(*collected.lock().unwrap(), payload);
+    }
+
+    #[test]
+    fn test_payload_smaller_than_chunk() {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        let collected = Arc::new(Mutex::new(Vec::new()));
+
+        let mut remaining = RemainingPayload::new(
+            Box::new(CollectingHandler {
+                payload: collected.clone(),
+            }),
+            6,
+        );
+
+        // Not a single complete chunk, so nothing must be consumed
+        assert_eq!(remaining.consume(&fb, &[1, 2]), (0, 0));
+        assert_eq!(remaining.bytes_remaining(), 6);
+        assert!(collected.lock().unwrap().is_empty());
+    }
+}
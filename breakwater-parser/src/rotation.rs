@@ -0,0 +1,87 @@
+/// Rotation of the canvas clients draw onto relative to the framebuffer, e.g. because the physical display is mounted
+/// sideways. The rotation is clockwise, so with [`CanvasRotation::Clockwise90`] the top-left corner of the clients
+/// canvas ends up in the top-right corner of the framebuffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CanvasRotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl CanvasRotation {
+    /// Size of the canvas as seen by the clients
+    pub fn canvas_size(self, fb_width: usize, fb_height: usize) -> (usize, usize) {
+        match self {
+            CanvasRotation::None | CanvasRotation::Clockwise180 => (fb_width, fb_height),
+            CanvasRotation::Clockwise90 | CanvasRotation::Clockwise270 => (fb_height, fb_width),
+        }
+    }
+
+    /// Translates canvas coordinates into framebuffer coordinates. Coordinates outside of the canvas end up outside of
+    /// the framebuffer, so they get ignored by the usual bounds checks.
+    #[inline(always)]
+    pub fn to_framebuffer(
+        self,
+        x: usize,
+        y: usize,
+        fb_width: usize,
+        fb_height: usize,
+    ) -> (usize, usize) {
+        match self {
+            CanvasRotation::None => (x, y),
+            CanvasRotation::Clockwise90 => ((fb_width - 1).wrapping_sub(y), x),
+            CanvasRotation::Clockwise180 => (
+                (fb_width - 1).wrapping_sub(x),
+                (fb_height - 1).wrapping_sub(y),
+            ),
+            CanvasRotation::Clockwise270 => (y, (fb_height - 1).wrapping_sub(x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(CanvasRotation::None, (0, 0), (0, 0))]
+    #[case(CanvasRotation::None, (3, 1), (3, 1))]
+    #[case(CanvasRotation::Clockwise90, (0, 0), (3, 0))]
+    #[case(CanvasRotation::Clockwise90, (1, 3), (0, 1))]
+    #[case(CanvasRotation::Clockwise180, (0, 0), (3, 1))]
+    #[case(CanvasRotation::Clockwise180, (3, 1), (0, 0))]
+    #[case(CanvasRotation::Clockwise270, (0, 0), (0, 1))]
+    #[case(CanvasRotation::Clockwise270, (1, 3), (3, 0))]
+    fn test_to_framebuffer(
+        #[case] rotation: CanvasRotation,
+        #[case] canvas: (usize, usize),
+        #[case] expected: (usize, usize),
+    ) {
+        // The framebuffer is 4x2, so the canvas is 2x4 for 90 and 270 degrees
+        assert_eq!(rotation.to_framebuffer(canvas.0, canvas.1, 4, 2), expected);
+    }
+
+    #[rstest]
+    fn test_outside_of_canvas_stays_outside_of_framebuffer(
+        #[values(
+            CanvasRotation::None,
+            CanvasRotation::Clockwise90,
+            CanvasRotation::Clockwise180,
+            CanvasRotation::Clockwise270
+        )]
+        rotation: CanvasRotation,
+    ) {
+        let (canvas_width, canvas_height) = rotation.canvas_size(4, 2);
+        for (x, y) in [(canvas_width, 0), (0, canvas_height), (1000, 1000)] {
+            let (fb_x, fb_y) = rotation.to_framebuffer(x, y, 4, 2);
+            assert!(
+                fb_x >= 4 || fb_y >= 2,
+                "({x}, {y}) ended up at ({fb_x}, {fb_y})"
+            );
+        }
+    }
+}
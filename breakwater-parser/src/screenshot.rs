@@ -0,0 +1,73 @@
+use crate::FrameBuffer;
+
+/// Compression of the pixels sent in response to `SCREENSHOT`, the discriminant is the flag byte in the header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreenshotCompression {
+    /// Requested using `SCREENSHOT`
+    None = 0,
+
+    /// Requested using `SCREENSHOT ZSTD`
+    Zstd = 1,
+}
+
+/// zstd level used for `SCREENSHOT ZSTD`, the fastest regular level
+const ZSTD_LEVEL: i32 = 1;
+
+/// Writes the whole framebuffer to `response` in the format
+/// `SCREENSHOT <width> <height> <compression flag> <length in bytes>\n<pixels>`, where the pixels are rgba (4 bytes
+/// each, alpha is always `ff`) row by row, optionally compressed according to the [`ScreenshotCompression`] flag. In
+/// case the compression fails an `ERROR: ...` line is written instead, so that the client does not wait forever.
+///
+/// We use the fastest compression level, as the canvas is usually either mostly flat (which compresses well anyway)
+/// or noise (which doesn't compress at all), and the parser should not be blocked for too long.
+pub fn write_screenshot<FB: FrameBuffer>(
+    fb: &FB,
+    compression: ScreenshotCompression,
+    response: &mut Vec<u8>,
+) {
+    let (width, height) = (fb.get_width(), fb.get_height());
+    let rgb0 = fb.as_rgb0_bytes();
+    let rgb0 = &rgb0[..width * height * 4];
+
+    match compression {
+        ScreenshotCompression::None => {
+            write_header(response, width, height, compression, rgb0.len());
+            // Copy the pixels straight into the response, instead of collecting them first
+            let start = response.len();
+            response.extend_from_slice(rgb0);
+            set_opaque(&mut response[start..]);
+        }
+        ScreenshotCompression::Zstd => {
+            let mut rgba = rgb0.to_vec();
+            set_opaque(&mut rgba);
+            match zstd::bulk::compress(&rgba, ZSTD_LEVEL) {
+                Ok(compressed) => {
+                    write_header(response, width, height, compression, compressed.len());
+                    response.extend_from_slice(&compressed);
+                }
+                Err(err) => response.extend_from_slice(
+                    format!("ERROR: Failed to compress the screenshot: {err}\n").as_bytes(),
+                ),
+            }
+        }
+    }
+}
+
+fn write_header(
+    response: &mut Vec<u8>,
+    width: usize,
+    height: usize,
+    compression: ScreenshotCompression,
+    len: usize,
+) {
+    response.extend_from_slice(
+        format!("SCREENSHOT {width} {height} {} {len}\n", compression as u8).as_bytes(),
+    );
+}
+
+/// The framebuffer stores 0x00bbggrr, so the bytes of every pixel are r, g, b and the unused (zero) alpha channel
+fn set_opaque(rgb0: &mut [u8]) {
+    for pixel in rgb0.chunks_exact_mut(4) {
+        pixel[3] = 0xff;
+    }
+}
@@ -0,0 +1,23 @@
+use crate::FrameBuffer;
+
+/// Encodes the whole framebuffer as QOI image and writes it to `response` in the format
+/// `QOI <length in bytes>\n<image>`.
+///
+/// QOI is way faster to encode than PNG, while being nearly as compact for the typical pixel-art canvas, so clients
+/// can request snapshots quite often.
+pub fn write_qoi_snapshot<FB: FrameBuffer>(fb: &FB, response: &mut Vec<u8>) {
+    // The framebuffer contains rgb0, but we don't want to transmit the unused (and zero) alpha channel
+    let rgb: Vec<u8> = fb
+        .as_rgb0_bytes()
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+
+    // This can only fail for invalid dimensions, which can't happen for a valid framebuffer
+    let Ok(image) = qoi::encode_to_vec(rgb, fb.get_width() as u32, fb.get_height() as u32) else {
+        return;
+    };
+
+    response.extend_from_slice(format!("QOI {}\n", image.len()).as_bytes());
+    response.extend_from_slice(&image);
+}
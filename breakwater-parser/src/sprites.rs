@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+/// Maximum number of sprites a single connection can define using `SPRITE define`
+pub const MAX_SPRITES_PER_CONNECTION: usize = 64;
+
+/// Maximum number of bytes the pixels of all sprites of a single connection can take up, so that clients can't exhaust
+/// the memory of the server
+pub const MAX_SPRITE_BYTES_PER_CONNECTION: usize = 4 * 1024 * 1024;
+
+/// Pixels uploaded once using `SPRITE define`, which can be drawn many times using `SPRITE blit`
+pub struct Sprite {
+    pub width: usize,
+    pub height: usize,
+    /// 4 bytes (`rgba`) per pixel, row by row
+    pub pixels: Vec<u8>,
+}
+
+/// A `SPRITE define`, which pixels did not fully arrive yet
+struct Upload {
+    id: usize,
+    sprite: Sprite,
+}
+
+/// The sprites defined by a single connection, limited to [`MAX_SPRITES_PER_CONNECTION`] sprites and
+/// [`MAX_SPRITE_BYTES_PER_CONNECTION`] bytes.
+///
+/// The pixels of a sprite can be way larger than the buffer passed to a single [`crate::Parser::parse`] call, so they
+/// are handed over in pieces using [`Sprites::upload`].
+#[derive(Default)]
+pub struct Sprites {
+    sprites: HashMap<usize, Sprite>,
+    /// Bytes taken up by all sprites, including the one currently uploaded
+    bytes: usize,
+    upload: Option<Upload>,
+}
+
+impl Sprites {
+    /// Starts uploading the sprite with the given id and size, a previous sprite with the same id is replaced. Returns
+    /// `false` in case the sprite is empty or would exceed the limits, in which case nothing is changed.
+    pub fn start_upload(&mut self, id: usize, width: usize, height: usize) -> bool {
+        let len = width * height * 4;
+        let replaced = self.sprites.get(&id).map(|sprite| sprite.pixels.len());
+        let sprites = self.sprites.len() + usize::from(replaced.is_none());
+        let bytes = self.bytes - replaced.unwrap_or_default() + len;
+        if len == 0
+            || self.upload.is_some()
+            || sprites > MAX_SPRITES_PER_CONNECTION
+            || bytes > MAX_SPRITE_BYTES_PER_CONNECTION
+        {
+            return false;
+        }
+
+        self.sprites.remove(&id);
+        self.bytes = bytes;
+        self.upload = Some(Upload {
+            id,
+            sprite: Sprite {
+                width,
+                height,
+                pixels: Vec::with_capacity(len),
+            },
+        });
+        true
+    }
+
+    /// Hands over the next piece of the pixels of the sprite currently uploaded. Returns the number of bytes consumed,
+    /// the sprite can be blitted once [`Sprites::is_uploading`] returns `false`.
+    pub fn upload(&mut self, payload: &[u8]) -> usize {
+        let Some(upload) = &mut self.upload else {
+            return 0;
+        };
+
+        let pixels = &mut upload.sprite.pixels;
+        let consumed = payload.len().min(pixels.capacity() - pixels.len());
+        pixels.extend_from_slice(&payload[..consumed]);
+
+        if pixels.len() == pixels.capacity() {
+            let upload = self.upload.take().expect("upload was checked above");
+            self.sprites.insert(upload.id, upload.sprite);
+        }
+        consumed
+    }
+
+    pub fn is_uploading(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// Returns the sprite with the given id, sprites that are still uploading are not returned
+    pub fn get(&self, id: usize) -> Option<&Sprite> {
+        self.sprites.get(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn test_upload_in_pieces() {
+        let mut sprites = Sprites::default();
+        assert!(sprites.start_upload(7, 2, 1));
+        assert!(sprites.is_uploading());
+
+        assert_eq!(sprites.upload(&[1, 2, 3]), 3);
+        assert!(sprites.get(7).is_none());
+        // Bytes behind the pixels are not consumed
+        assert_eq!(sprites.upload(&[4, 5, 6, 7, 8, 9, 10]), 5);
+        assert!(!sprites.is_uploading());
+
+        let sprite = sprites.get(7).unwrap();
+        assert_eq!((sprite.width, sprite.height), (2, 1));
+        assert_eq!(sprite.pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(sprites.upload(&[1, 2, 3, 4]), 0);
+    }
+
+    #[test]
+    fn test_replace_sprite() {
+        let mut sprites = Sprites::default();
+        assert!(sprites.start_upload(1, 1, 1));
+        sprites.upload(&[1; 4]);
+        assert!(sprites.start_upload(1, 2, 1));
+        sprites.upload(&[2; 8]);
+
+        assert_eq!(sprites.get(1).unwrap().pixels, [2; 8]);
+        assert_eq!(sprites.bytes, 8);
+    }
+
+    #[rstest]
+    #[case::empty(0, 10)]
+    #[case::too_large(1024, 1025)]
+    fn test_invalid_sprite(#[case] width: usize, #[case] height: usize) {
+        let mut sprites = Sprites::default();
+        assert!(!sprites.start_upload(1, width, height));
+        assert!(!sprites.is_uploading());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut sprites = Sprites::default();
+        for id in 0..MAX_SPRITES_PER_CONNECTION {
+            assert!(sprites.start_upload(id, 1, 1));
+            sprites.upload(&[0; 4]);
+        }
+        assert!(!sprites.start_upload(MAX_SPRITES_PER_CONNECTION, 1, 1));
+        // Replacing an existing sprite does not increase the number of sprites
+        assert!(sprites.start_upload(0, 1, 1));
+        sprites.upload(&[0; 4]);
+
+        // The memory of the replaced sprite is freed
+        let mut sprites = Sprites::default();
+        assert!(sprites.start_upload(0, 1024, 1024));
+        sprites.upload(&vec![0; MAX_SPRITE_BYTES_PER_CONNECTION]);
+        assert!(!sprites.start_upload(1, 1, 1));
+        assert!(sprites.start_upload(0, 1, 1));
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+
+use crate::{FrameBuffer, JournalRecord, SimpleFrameBuffer};
+
+/// Records every write (in the same format as the [`crate::RecordingFrameBuffer`] journal), so that tests can assert
+/// the exact sequence of writes instead of only the final state. The writes are applied to an inner
+/// [`SimpleFrameBuffer`], so reading pixels works as usual.
+pub struct MockFrameBuffer {
+    fb: SimpleFrameBuffer,
+    writes: Mutex<Vec<JournalRecord>>,
+}
+
+impl MockFrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            fb: SimpleFrameBuffer::new(width, height),
+            writes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns all writes since the last call in the order they happened
+    pub fn take_writes(&self) -> Vec<JournalRecord> {
+        std::mem::take(&mut self.writes.lock().unwrap())
+    }
+}
+
+impl FrameBuffer for MockFrameBuffer {
+    fn get_width(&self) -> usize {
+        self.fb.get_width()
+    }
+
+    fn get_height(&self) -> usize {
+        self.fb.get_height()
+    }
+
+    unsafe fn get_unchecked(&self, x: usize, y: usize) -> u32 {
+        self.fb.get_unchecked(x, y)
+    }
+
+    fn set(&self, x: usize, y: usize, rgba: u32) {
+        self.writes.lock().unwrap().push(JournalRecord::Pixel {
+            x: x as u32,
+            y: y as u32,
+            rgba,
+        });
+        self.fb.set(x, y, rgba);
+    }
+
+    fn set_multi_from_start_index(&self, starting_index: usize, pixels: &[u8]) -> usize {
+        self.writes.lock().unwrap().push(JournalRecord::Pixels {
+            starting_index: starting_index as u32,
+            pixels: pixels.to_vec(),
+        });
+        self.fb.set_multi_from_start_index(starting_index, pixels)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.fb.as_bytes()
+    }
+
+    fn as_pixels(&self) -> &[u32] {
+        self.fb.as_pixels()
+    }
+}
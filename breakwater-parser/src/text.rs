@@ -0,0 +1,103 @@
+use rusttype::{point, Font, Scale};
+
+use crate::FrameBuffer;
+
+/// Rasterizes `text` with its top-left corner at `(x, y)` and calls `set_pixel` for every pixel that is covered by
+/// more than half. Anti-aliasing would need blending, so the edges are kept sharp instead.
+///
+/// The coordinates passed to `set_pixel` are not bounds-checked, this is up to the caller.
+pub fn rasterize_text(
+    font: &Font,
+    x: usize,
+    y: usize,
+    scale: f32,
+    text: &str,
+    mut set_pixel: impl FnMut(usize, usize),
+) {
+    let scale = Scale::uniform(scale);
+    let v_metrics = font.v_metrics(scale);
+
+    for glyph in font.layout(text, scale, point(x as f32, y as f32 + v_metrics.ascent)) {
+        if let Some(bounding_box) = glyph.pixel_bounding_box() {
+            glyph.draw(|glyph_x, glyph_y, coverage| {
+                if coverage <= 0.5 {
+                    return;
+                }
+                // Glyphs can reach a bit to the left of (or above) the start position
+                if let (Ok(x), Ok(y)) = (
+                    usize::try_from(bounding_box.min.x + glyph_x as i32),
+                    usize::try_from(bounding_box.min.y + glyph_y as i32),
+                ) {
+                    set_pixel(x, y);
+                }
+            });
+        }
+    }
+}
+
+/// Draws `text` with the color `rgba` onto the framebuffer, see [`rasterize_text`]. Pixels outside of the framebuffer
+/// are skipped.
+pub fn draw_text<FB: FrameBuffer + ?Sized>(
+    fb: &FB,
+    font: &Font,
+    x: usize,
+    y: usize,
+    scale: f32,
+    rgba: u32,
+    text: &str,
+) {
+    rasterize_text(font, x, y, scale, text, |x, y| fb.set(x, y, rgba));
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::{fixture, rstest};
+
+    use super::*;
+    use crate::SimpleFrameBuffer;
+
+    #[fixture]
+    fn font() -> Font<'static> {
+        Font::try_from_bytes(include_bytes!("../../Arial.ttf")).unwrap()
+    }
+
+    fn drawn_pixels(fb: &SimpleFrameBuffer) -> Vec<(usize, usize)> {
+        (0..fb.get_height())
+            .flat_map(|y| (0..fb.get_width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| fb.get(x, y) != Some(0))
+            .collect()
+    }
+
+    #[rstest]
+    fn test_draw_text(font: Font<'static>) {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        draw_text(&fb, &font, 100, 200, 20.0, 0x00ff_ffff, "Hi");
+
+        let pixels = drawn_pixels(&fb);
+        assert!(!pixels.is_empty());
+        // Everything is drawn close to the start position and only in the given color
+        for (x, y) in pixels {
+            assert!((100..130).contains(&x), "x = {x}");
+            assert!((200..220).contains(&y), "y = {y}");
+            assert_eq!(fb.get(x, y), Some(0x00ff_ffff));
+        }
+    }
+
+    #[rstest]
+    fn test_draw_text_is_clipped(font: Font<'static>) {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        draw_text(&fb, &font, 630, 470, 20.0, 0x00ff_ffff, "Hello");
+
+        let pixels = drawn_pixels(&fb);
+        assert!(!pixels.is_empty());
+        assert!(pixels.iter().all(|&(x, y)| x >= 630 && y >= 470));
+    }
+
+    #[rstest]
+    fn test_whitespace_draws_nothing(font: Font<'static>) {
+        let fb = SimpleFrameBuffer::new(640, 480);
+        draw_text(&fb, &font, 100, 200, 20.0, 0x00ff_ffff, "   ");
+
+        assert!(drawn_pixels(&fb).is_empty());
+    }
+}
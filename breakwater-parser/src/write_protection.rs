@@ -0,0 +1,38 @@
+/// A rectangular area of the screen clients are not allowed to draw into, e.g. because the server renders something
+/// (such as statistics) there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteProtectedRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl WriteProtectedRegion {
+    #[inline(always)]
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let region = WriteProtectedRegion {
+            x: 10,
+            y: 20,
+            width: 5,
+            height: 2,
+        };
+
+        assert!(region.contains(10, 20));
+        assert!(region.contains(14, 21));
+        assert!(!region.contains(9, 20));
+        assert!(!region.contains(15, 20));
+        assert!(!region.contains(10, 19));
+        assert!(!region.contains(10, 22));
+    }
+}
@@ -0,0 +1,48 @@
+//! Compares the hashers that can be used for the maps keyed by client IP addresses, see the `fx-hash` feature.
+
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rustc_hash::FxBuildHasher;
+
+fn ips(count: u32) -> Vec<IpAddr> {
+    (0..count)
+        .map(|i| match i % 2 {
+            0 => IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)),
+            _ => IpAddr::V6(Ipv6Addr::from(0x2001_0db8_u128 << 96 | i as u128)),
+        })
+        .collect()
+}
+
+fn count_bytes<S: BuildHasher + Default>(ips: &[IpAddr]) -> HashMap<IpAddr, u64, S> {
+    let mut bytes_for_ip = HashMap::<IpAddr, u64, S>::default();
+    for (i, ip) in ips.iter().enumerate() {
+        *bytes_for_ip.entry(*ip).or_insert(0) += i as u64;
+    }
+    bytes_for_ip
+}
+
+fn compare_hashers(c: &mut Criterion) {
+    let mut c_group = c.benchmark_group("ip_map_insert");
+    for count in [100, 10_000] {
+        let ips = ips(count);
+        c_group.bench_with_input(BenchmarkId::new("siphash", count), &ips, |b, ips| {
+            b.iter(|| count_bytes::<std::hash::RandomState>(ips))
+        });
+        c_group.bench_with_input(BenchmarkId::new("fxhash", count), &ips, |b, ips| {
+            b.iter(|| count_bytes::<FxBuildHasher>(ips))
+        });
+    }
+}
+
+criterion_group!(
+    name = ip_maps;
+    config = Criterion::default().warm_up_time(Duration::from_secs(1)).measurement_time(Duration::from_secs(3));
+    targets = compare_hashers
+);
+criterion_main!(ip_maps);
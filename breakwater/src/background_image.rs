@@ -0,0 +1,69 @@
+use breakwater_parser::FrameBuffer;
+use image::imageops::FilterType;
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to load background image from {path:?}"))]
+    LoadImage {
+        source: image::ImageError,
+        path: String,
+    },
+}
+
+/// Loads the image at the given path, scales it to the size of the framebuffer and draws it onto the framebuffer.
+pub fn load_background_image<FB: FrameBuffer>(fb: &FB, path: &str) -> Result<(), Error> {
+    let image = image::open(path).context(LoadImageSnafu { path })?;
+    let image = image
+        .resize_exact(
+            fb.get_width() as u32,
+            fb.get_height() as u32,
+            FilterType::Triangle,
+        )
+        .to_rgb8();
+
+    // The framebuffer stores the pixels as rgb0
+    let pixels = image
+        .pixels()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0])
+        .collect::<Vec<_>>();
+    fb.set_multi(0, 0, &pixels);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_load_background_image() {
+        let mut image = RgbImage::new(4, 3);
+        image.put_pixel(0, 0, Rgb([0xff, 0x00, 0x00]));
+        image.put_pixel(3, 0, Rgb([0x00, 0xff, 0x00]));
+        image.put_pixel(0, 2, Rgb([0x00, 0x00, 0xff]));
+        image.put_pixel(3, 2, Rgb([0x12, 0x34, 0x56]));
+
+        let path = std::env::temp_dir().join("breakwater-test-background-image.png");
+        image.save(&path).unwrap();
+
+        let fb = SimpleFrameBuffer::new(4, 3);
+        load_background_image(&fb, path.to_str().unwrap()).unwrap();
+
+        assert_eq!(fb.get(0, 0), Some(0x0000_00ff));
+        assert_eq!(fb.get(3, 0), Some(0x0000_ff00));
+        assert_eq!(fb.get(0, 2), Some(0x00ff_0000));
+        assert_eq!(fb.get(3, 2), Some(0x0056_3412));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_background_image() {
+        let fb = SimpleFrameBuffer::new(4, 3);
+        assert!(load_background_image(&fb, "/does/not/exist.png").is_err());
+    }
+}
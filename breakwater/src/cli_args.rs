@@ -1,16 +1,35 @@
-use clap::Parser;
+use std::net::IpAddr;
+
+use breakwater_parser::{CanvasRotation, WriteProtectedRegion};
+use clap::{Parser, ValueEnum};
 use const_format::formatcp;
+use ipnet::IpNet;
+
+use crate::statistics::DEFAULT_STATS_TOP_N;
 
 pub const DEFAULT_NETWORK_BUFFER_SIZE: usize = 256 * 1024;
 pub const DEFAULT_NETWORK_BUFFER_SIZE_STR: &str = formatcp!("{}", DEFAULT_NETWORK_BUFFER_SIZE);
+pub const DEFAULT_RESPONSE_BUFFER_SIZE: usize = 0;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct CliArgs {
-    /// Listen address to bind to.
+    /// Listen address to bind to. Can be specified multiple times to listen on multiple addresses or ports at once.
     /// The default value will listen on all interfaces for IPv4 and IPv6 packets.
+    /// Use port 0 to let the operating system pick a free port, see `--port-file` to find out which one it picked.
     #[clap(short, long, default_value = "[::]:1234")]
-    pub listen_address: String,
+    pub listen_address: Vec<String>,
+
+    /// Number of accept loops per listen address. With more than one the address is bound multiple times using
+    /// `SO_REUSEPORT`, so that the kernel load-balances new connections across the accept loops, which helps when a
+    /// single accept loop can't keep up with the incoming connections.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub acceptor_threads: u64,
+
+    /// File the ports the Pixelflut server is bound to are written to once it is listening, one line per listen
+    /// address. Useful in combination with port 0 for test harnesses or dynamic orchestration.
+    #[clap(long)]
+    pub port_file: Option<String>,
 
     /// Width of the drawing surface.
     #[clap(long, default_value_t = 1280)]
@@ -20,24 +39,80 @@ pub struct CliArgs {
     #[clap(long, default_value_t = 720)]
     pub height: usize,
 
-    /// Frames per second the server should aim for.
-    #[clap(short, long, default_value_t = 30)]
+    /// Store the canvas in the named shared memory region (`/dev/shm/<SHARED_MEMORY_NAME>`), so that external tools
+    /// can read it live. The region starts with a 16 byte header (`BRKWATER`, followed by the width and height as
+    /// little endian u32), followed by the pixels. An existing region is reused, so the canvas survives restarts. The
+    /// region can't follow resizes of the canvas.
+    #[cfg(feature = "shared-memory")]
+    #[clap(long, conflicts_with = "resize_file")]
+    pub shared_memory_name: Option<String>,
+
+    /// In case the shared memory region given by `--shared-memory-name` contains a canvas of a different size (e.g.
+    /// because `--width` or `--height` changed), replace it with a black canvas of the new size instead of refusing
+    /// to start.
+    #[cfg(feature = "shared-memory")]
+    #[clap(long, requires = "shared_memory_name")]
+    pub recreate_shared_memory: bool,
+
+    /// Only store the given area of the canvas in the shared memory given by `--shared-memory-name`, e.g. because
+    /// external tools are only interested in that area. The canvas itself stays in the memory of breakwater and the
+    /// area is copied into the (smaller) shared memory with `--fps` frames per second.
+    #[cfg(feature = "shared-memory")]
+    #[clap(
+        long,
+        num_args = 4,
+        value_names = ["X", "Y", "WIDTH", "HEIGHT"],
+        requires = "shared_memory_name"
+    )]
+    pub shared_memory_region: Option<Vec<usize>>,
+
+    /// Width of the canvas clients can draw onto, e.g. the visible part of a wall. Pixels right of it are rejected,
+    /// even though the drawing surface can hold them. `SIZE` reports this width. Defaults to `--width`.
+    #[clap(long)]
+    pub logical_width: Option<usize>,
+
+    /// Height of the canvas clients can draw onto, see `--logical-width`. Defaults to `--height`.
+    #[clap(long)]
+    pub logical_height: Option<usize>,
+
+    /// Image (e.g. PNG or JPEG) that is drawn onto the canvas during startup, so that the screen is not black before
+    /// clients start drawing. The image is scaled to the size of the drawing surface.
+    #[clap(long)]
+    pub background_image: Option<String>,
+
+    /// Frames per second the server should aim for. Sinks can override it, e.g. using `--vnc-fps` or `--ffmpeg-fps`.
+    #[clap(short, long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
     pub fps: u32,
 
+    /// Caps the frames per second of all sinks (including the per-sink overrides such as `--vnc-fps`, `--gif-fps` and
+    /// the native display, which otherwise redraws as fast as possible).
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_fps: Option<u32>,
+
     /// The size in bytes of the network buffer used for each open TCP connection.
     /// Please use at least 64 KB (64_000 bytes).
     #[clap(long, default_value = DEFAULT_NETWORK_BUFFER_SIZE_STR, value_parser = 64_000..100_000_000)]
     pub network_buffer_size: i64,
 
+    /// The number of bytes reserved upfront for the responses (e.g. to `PX x y` or `GETRECT`) of each open TCP
+    /// connection. By default the response buffer grows on demand, which takes a few reallocations for read-heavy
+    /// clients. If most of your clients read pixels, use a bit more than 65536 bytes (the size at which the responses are
+    /// written), e.g. 66560.
+    #[clap(long, default_value_t = DEFAULT_RESPONSE_BUFFER_SIZE as u64, value_parser = clap::value_parser!(u64).range(..100_000_000))]
+    pub response_buffer_size: u64,
+
     /// Text to display on the screen.
     #[clap(short, long, default_value = "Pixelflut server (breakwater)")]
     pub text: String,
 
-    /// The font used to render the text on the screen.
-    /// Should be a ttf file.
-    /// If you use the default value a copy that ships with breakwater will be used - no need to download and provide the font.
-    #[clap(long, default_value = "Arial.ttf")]
-    pub font: String,
+    /// The font used to render the text on the screen and the `TEXT` command, chosen from the fonts that ship with breakwater - no need to
+    /// download and provide the font.
+    #[clap(long, value_enum, default_value_t = EmbeddedFont::Arial)]
+    pub font_name: EmbeddedFont,
+
+    /// Path to a custom ttf file used to render the text on the screen and the `TEXT` command instead of one of the embedded fonts.
+    #[clap(long, alias = "font", conflicts_with = "font_name")]
+    pub font_path: Option<String>,
 
     /// Listen address the prometheus exporter should listen on.
     #[clap(short, long, default_value = "[::]:9100")]
@@ -57,6 +132,18 @@ pub struct CliArgs {
     #[clap(long)]
     pub disable_statistics_save_file: bool,
 
+    /// Number of most active IPs (by bytes per second) that are reported in the statistics. They are logged on the debug
+    /// level and exported to Prometheus. Only the top N are exported to keep the number of labels bounded, 0 disables
+    /// it.
+    #[clap(long, default_value_t = DEFAULT_STATS_TOP_N)]
+    pub stats_top_n: usize,
+
+    /// Interval (in milliseconds) in which the statistics (e.g. bytes per second) are calculated and reported to the
+    /// Prometheus exporter and VNC overlay. Use a larger interval to reduce the overhead on low-power machines or a
+    /// smaller one for a finer granularity.
+    #[clap(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(10..))]
+    pub stats_report_interval_ms: u64,
+
     /// Enable rtmp streaming to configured address, e.g. `rtmp://127.0.0.1:1935/live/test`
     #[clap(long)]
     pub rtmp_address: Option<String>,
@@ -65,10 +152,193 @@ pub struct CliArgs {
     #[clap(long)]
     pub video_save_folder: Option<String>,
 
+    /// Report (log and count in the statistics) every frame that takes longer than the given number of milliseconds to
+    /// be written to ffmpeg. This happens when ffmpeg can't keep up with encoding, which causes stutter in the
+    /// recording or stream.
+    #[clap(long = "max-ffmpeg-stdin-lag", default_value_t = 100)]
+    pub max_ffmpeg_stdin_lag_ms: u64,
+
+    /// Frames per second of the ffmpeg video. Defaults to `--fps`.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub ffmpeg_fps: Option<u32>,
+
+    /// How often ffmpeg is restarted in case it dies unexpectedly (e.g. because the rtmp server is not reachable)
+    /// before giving up. The restarts start over once ffmpeg ran for at least a minute.
+    #[clap(long, default_value_t = 5)]
+    pub ffmpeg_max_restarts: u32,
+
+    /// Enable recording of the canvas into an animated GIF, which is written on shutdown.
+    /// File location will be `<GIF_SAVE_FOLDER>/pixelflut_dump_{timestamp}.gif`.
+    #[clap(long)]
+    pub gif_save_folder: Option<String>,
+
+    /// Number of seconds the GIF recording should contain. Only the last seconds before the shutdown are kept.
+    #[clap(long, default_value_t = 10)]
+    pub gif_duration_s: u64,
+
+    /// Frames per second of the GIF recording. GIFs store the delay of every frame in units of 10ms, so for frame
+    /// rates that don't divide 100 the delays alternate (e.g. 30ms, 30ms, 40ms for 30 fps) to keep the playback speed.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=100))]
+    pub gif_fps: u32,
+
+    /// Stream the raw framebuffer (4 bytes per pixel, `rgb0`) with `--fps` frames per second to all processes
+    /// connected to the unix socket at the given path, e.g. to process the canvas locally without ffmpeg.
+    #[clap(long)]
+    pub unix_socket_frame_path: Option<String>,
+
+    /// Serve the canvas as MJPEG stream over HTTP with `--fps` frames per second on the given listen address, e.g.
+    /// `[::]:8080`, so that it can be watched in any browser without a VNC client.
+    #[clap(long)]
+    pub mjpeg_listen_address: Option<String>,
+
+    /// Track which areas of the framebuffer are drawn to, so that the MJPEG stream skips the frames in which nothing
+    /// changed instead of encoding and sending the same frame over and over again. This saves CPU time and bandwidth
+    /// for mostly static canvases, at the cost of an additional atomic operation for every pixel drawn.
+    #[clap(long, requires = "mjpeg_listen_address")]
+    pub mjpeg_dirty_regions: bool,
+
+    /// Save a screenshot of the canvas as PNG every time breakwater receives a SIGUSR2 (e.g.
+    /// `pkill -USR2 breakwater`). File location will be `<SCREENSHOT_SAVE_FOLDER>/pixelflut_screenshot_{timestamp}.png`.
+    #[clap(long)]
+    pub screenshot_save_folder: Option<String>,
+
+    /// Resize the canvas every time breakwater receives a SIGHUP (e.g. `pkill -HUP breakwater`), e.g. to reconfigure a
+    /// wall of screens without restarting. The new size is read from this file as `<width>x<height>`, the pixels that
+    /// are still within the new bounds are kept. Open connections draw on the new canvas with their next read. Only the
+    /// VNC server and the native display follow the new size, so the sinks that can't (recordings, streams, the unix
+    /// socket, MJPEG and v4l2) can't be combined with it, neither can `--write-protect-stats`.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "serialize_draws",
+            "draw_journal_file",
+            "rtmp_address",
+            "video_save_folder",
+            "gif_save_folder",
+            "unix_socket_frame_path",
+            "mjpeg_listen_address",
+        ]
+    )]
+    pub resize_file: Option<String>,
+
+    /// Reveal at most the given number of changed pixels per frame to recordings and streams (`--rtmp-address`,
+    /// `--video-save-folder` and `--gif-save-folder`). Bursts of writes are spread over multiple frames instead of
+    /// showing up at once, which looks less choppy.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub draw_budget_per_frame: Option<u64>,
+
     /// Allow only a certain number of connections per ip address
     #[clap(short, long)]
     pub connections_per_ip: Option<u64>,
 
+    /// Allow only a certain number of connections across all ip addresses, so that lots of clients can't exhaust the
+    /// file descriptors
+    #[clap(long)]
+    pub max_total_connections: Option<u64>,
+
+    /// Only allow connections from the given network in CIDR notation (e.g. `10.0.0.0/8` or `2001:db8::/32`) or single
+    /// IP address. Can be specified multiple times. By default all IP addresses are allowed.
+    #[clap(long, value_parser = parse_ip_net)]
+    pub allow_cidr: Vec<IpNet>,
+
+    /// Deny connections from the given network in CIDR notation or single IP address. Can be specified multiple times.
+    /// Takes precedence over `--allow-cidr`. Denied connections are counted in the `breakwater_denied_connections`
+    /// metric.
+    #[clap(long, value_parser = parse_ip_net)]
+    pub deny_cidr: Vec<IpNet>,
+
+    /// Close a connection after it has drawn the given number of pixels.
+    /// The limit is checked after every chunk of data read from the connection, so a connection might draw a few more
+    /// pixels than the limit.
+    #[clap(long)]
+    pub max_pixels_per_connection: Option<u64>,
+
+    /// Close a connection after it has sent the given number of bytes. All bytes exceeding the limit are ignored.
+    #[clap(long)]
+    pub max_bytes_per_connection: Option<u64>,
+
+    /// Bytes a connection sends within the given number of seconds after it was opened don't count toward
+    /// `--max-bytes-per-connection`, so that clients are not penalized for an initial burst, e.g. pushing the whole
+    /// canvas once.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connection_warm_up_s: Option<u64>,
+
+    /// Close a connection in case it has not sent any data for the given number of seconds. Disabled by default.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connection_idle_timeout_s: Option<u64>,
+
+    /// Set `TCP_NODELAY` on all client connections, so that responses (e.g. to `PX x y`) are sent immediately instead
+    /// of being delayed by Nagle's algorithm. This helps latency-sensitive clients, at the cost of more packets.
+    #[clap(long)]
+    pub tcp_nodelay: bool,
+
+    /// Enable TCP keepalive on all client connections, sending the first probe once the connection was idle for the
+    /// given number of seconds. This way connections of clients that vanished without closing them are dropped
+    /// eventually. Disabled by default.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive_s: Option<u64>,
+
+    /// Respond with a short `ERROR: ...` line to commands that could not be parsed instead of silently skipping them.
+    /// This is intended to help debugging clients.
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Accept `PX` commands with multiple spaces between the tokens (e.g. `PX  0   0  ffffff`), as sent by some
+    /// hand-written clients. Such commands are handled in a slower compatibility path.
+    #[clap(long)]
+    pub lenient_whitespace: bool,
+
+    /// Respond with a short `ERROR: ...` line to `PX x y rrggbbaa` commands instead of silently discarding the alpha
+    /// channel and drawing the pixel opaque. Only available without the `alpha` feature.
+    #[cfg(not(feature = "alpha"))]
+    #[clap(long)]
+    pub reject_alpha: bool,
+
+    /// Respond to `PX x y` with `PX x y rrggbbaa` instead of `PX x y rrggbb`. The canvas is always opaque, so the alpha
+    /// is always `ff`. Only available with the `alpha` feature.
+    #[cfg(feature = "alpha")]
+    #[clap(long)]
+    pub rgba_reads: bool,
+
+    /// Respond with a line `ECHO PX x y rrggbb` to every pixel drawn by `PX` or `PB`, containing the color that ended
+    /// up on the canvas. This is very verbose and only intended for debugging clients.
+    #[clap(long)]
+    pub pixel_command_echo: bool,
+
+    /// Silently drop all pixels clients draw into the given region, in the format `x,y,width,height`. Can be specified
+    /// multiple times. `PXMULTI` is not affected.
+    #[clap(long, value_parser = parse_write_protected_region)]
+    pub write_protect_region: Vec<WriteProtectedRegion>,
+
+    /// Rotate the canvas clients draw onto clockwise by the given degrees (0, 90, 180 or 270), e.g. when the display is
+    /// mounted sideways. The coordinates of `PX` and `PB` commands as well as the `SIZE` response are transformed, so
+    /// the framebuffer (and every recording of it) stays in the orientation of the display.
+    #[clap(long, default_value = "0", value_parser = parse_canvas_rotation)]
+    pub canvas_rotate: CanvasRotation,
+
+    /// Additionally terminate commands with the given character (e.g. `;`), for clients that batch commands like
+    /// `PX 0 0 ff0000;PX 1 0 00ff00;`. The newline always terminates commands.
+    #[cfg(feature = "custom-separators")]
+    #[clap(long, value_parser = parse_command_separator)]
+    pub command_separator: Option<u8>,
+
+    /// Handle all client connections on the given number of worker tasks instead of spawning a dedicated task per
+    /// connection. Every worker drives many connections at once, which reduces the per-connection overhead when
+    /// serving a huge number (e.g. 100k) of connections. By default a task is spawned per connection.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub connection_workers: Option<u64>,
+
+    /// Apply the draws of all connections one after another on a single writer thread. This way the canvas only
+    /// depends on the order in which the draws arrive, which makes recordings reproducible, at the cost of some
+    /// throughput. Pixels read by clients might not contain the draws that are still queued.
+    #[clap(long)]
+    pub serialize_draws: bool,
+
+    /// Append all draws of the clients to this journal file, e.g. to create replays. Every draw is stored as compact
+    /// binary record, the file is created if it does not exist.
+    #[clap(long)]
+    pub draw_journal_file: Option<String>,
+
     /// Enabled a VNC server
     #[cfg(feature = "vnc")]
     #[clap(long)]
@@ -79,9 +349,162 @@ pub struct CliArgs {
     #[clap(short, long, default_value_t = 5900)]
     pub vnc_port: u16,
 
+    /// Frames per second of the VNC server. Defaults to `--fps`.
+    #[cfg(feature = "vnc")]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub vnc_fps: Option<u32>,
+
+    /// Save power by dropping the frame rate of the VNC server to `--idle-fps` while no client is connected. The normal
+    /// frame rate is resumed as soon as the first client connects. Recording sinks (ffmpeg and GIF) are not affected,
+    /// as dropping frames would distort the recorded timeline.
+    #[cfg(feature = "vnc")]
+    #[clap(long)]
+    pub drop_frames_when_no_clients: bool,
+
+    /// Frames per second used while no client is connected, see `--drop-frames-when-no-clients`.
+    #[cfg(feature = "vnc")]
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub idle_fps: u32,
+
+    /// Don't allow clients to draw into the strip of the screen the VNC server renders the statistics into. This
+    /// prevents flickering and saves the wasted writes. The strip is computed from the size breakwater was started with,
+    /// so this can't be combined with `--resize-file`.
+    #[cfg(feature = "vnc")]
+    #[clap(long, conflicts_with = "resize_file")]
+    pub write_protect_stats: bool,
+
+    /// Height in pixels of the strip the VNC server renders the statistics into.
+    #[cfg(feature = "vnc")]
+    #[clap(long, default_value_t = 35, value_parser = clap::value_parser!(u32).range(1..))]
+    pub vnc_stats_height: u32,
+
+    /// Whether the VNC server renders the statistics at the top or the bottom of the screen.
+    #[cfg(feature = "vnc")]
+    #[clap(long, value_enum, default_value_t = StatsPosition::Bottom)]
+    pub vnc_stats_position: StatsPosition,
+
+    /// Track which areas of the framebuffer are drawn to, so that the VNC server only needs to copy and refresh those
+    /// instead of the whole screen every frame. This saves CPU time and bandwidth when only parts of the screen
+    /// change, at the cost of an additional atomic operation for every pixel drawn.
+    #[cfg(feature = "vnc")]
+    #[clap(long)]
+    pub vnc_dirty_regions: bool,
+
     /// Enable native display output. This requires some form of graphical system (so will probably not work on your
     /// server).
     #[cfg(feature = "native-display")]
     #[clap(long)]
     pub native_display: bool,
+
+    /// Start the native display window maximized.
+    #[cfg(feature = "native-display")]
+    #[clap(long, conflicts_with = "native_display_fullscreen")]
+    pub native_display_maximized: bool,
+
+    /// Start the native display window in (borderless) fullscreen on the current monitor, e.g. for unattended
+    /// installations.
+    #[cfg(feature = "native-display")]
+    #[clap(long)]
+    pub native_display_fullscreen: bool,
+
+    /// Frames per second of the native display. By default it redraws as fast as possible (capped by `--max-fps`).
+    #[cfg(feature = "native-display")]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub native_display_fps: Option<u32>,
+
+    /// Hide the mouse cursor over the native display window once it was not moved for the given number of seconds.
+    #[cfg(feature = "native-display")]
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub native_display_hide_cursor_after_s: Option<u64>,
+
+    /// Write the canvas into the given v4l2 (loopback) device, e.g. `/dev/video0`, so that it can be used as a webcam in
+    /// video-conferencing tools or OBS. The frames are written with `--fps`.
+    #[cfg(feature = "v4l2")]
+    #[clap(long, conflicts_with = "resize_file")]
+    pub v4l2_device: Option<String>,
+
+    /// Only show the canvas on the local (primary) display. This disables all sinks that expose the canvas over the
+    /// network (such as VNC, RTMP streaming or video dumps), regardless of their individual settings.
+    #[clap(long)]
+    pub primary_display_only: bool,
+
+    /// Format of the log output. The log level can be set using the `RUST_LOG` environment variable.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+/// How log lines are written to stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, e.g. to be ingested by a log aggregation system
+    Json,
+}
+
+/// Where the VNC server renders the statistics
+#[cfg(feature = "vnc")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StatsPosition {
+    Top,
+    Bottom,
+}
+
+/// Fonts that are compiled into breakwater, so that they can be used without providing a ttf file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EmbeddedFont {
+    Arial,
+    #[value(name = "dejavu-sans-mono")]
+    DejaVuSansMono,
+}
+
+fn parse_write_protected_region(input: &str) -> Result<WriteProtectedRegion, String> {
+    let values = input
+        .split(',')
+        .map(|value| value.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid number in region {input:?}: {err}"))?;
+    let [x, y, width, height] = values[..] else {
+        return Err(format!(
+            "expected region in the format `x,y,width,height`, got {input:?}"
+        ));
+    };
+
+    Ok(WriteProtectedRegion {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Only punctuation is allowed, so that the separator can't be confused with a part of a command (e.g. a digit)
+#[cfg(feature = "custom-separators")]
+fn parse_command_separator(input: &str) -> Result<u8, String> {
+    match input.as_bytes() {
+        [separator] if separator.is_ascii_punctuation() => Ok(*separator),
+        _ => Err(format!(
+            "expected a single punctuation character (e.g. `;`), got {input:?}"
+        )),
+    }
+}
+
+fn parse_ip_net(input: &str) -> Result<IpNet, String> {
+    // Single IP addresses are networks with a single address
+    input
+        .parse::<IpNet>()
+        .or_else(|_| input.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("expected a network in CIDR notation or an IP address, got {input:?}"))
+}
+
+fn parse_canvas_rotation(input: &str) -> Result<CanvasRotation, String> {
+    match input {
+        "0" => Ok(CanvasRotation::None),
+        "90" => Ok(CanvasRotation::Clockwise90),
+        "180" => Ok(CanvasRotation::Clockwise180),
+        "270" => Ok(CanvasRotation::Clockwise270),
+        _ => Err(format!(
+            "expected a rotation of 0, 90, 180 or 270 degrees, got {input:?}"
+        )),
+    }
 }
@@ -0,0 +1,96 @@
+use snafu::{ensure, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "breakwater was compiled for the CPU features {missing:?}, which are not supported by this CPU. This happens \
+        when compiling with `-C target-cpu=native` (which is the default in .cargo/config.toml) on a different \
+        machine. Please compile breakwater on the machine it should run on or without `-C target-cpu=native`"
+    ))]
+    MissingCpuFeatures { missing: Vec<&'static str> },
+}
+
+/// Defines [`compiled_features`] and [`is_detected`] for the SIMD features that are relevant for us, so that the list
+/// only needs to be maintained in one place
+macro_rules! simd_features {
+    ($($feature:tt),* $(,)?) => {
+        /// The features the binary was compiled for, so they might be used anywhere in the code
+        fn compiled_features() -> Vec<&'static str> {
+            let mut features = Vec::new();
+            $(
+                if cfg!(target_feature = $feature) {
+                    features.push($feature);
+                }
+            )*
+            features
+        }
+
+        /// Whether the feature is supported by the CPU we are running on
+        fn is_detected(feature: &str) -> bool {
+            match feature {
+                $($feature => std::arch::is_x86_feature_detected!($feature),)*
+                _ => true,
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+simd_features!(
+    "sse2", "ssse3", "sse4.1", "sse4.2", "popcnt", "avx", "avx2", "fma", "bmi1", "bmi2", "avx512f",
+    "avx512bw", "avx512vl",
+);
+
+#[cfg(not(target_arch = "x86_64"))]
+fn compiled_features() -> Vec<&'static str> {
+    Vec::new()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn is_detected(_feature: &str) -> bool {
+    true
+}
+
+/// Refuses to start in case the binary was compiled for CPU features (e.g. AVX2 used by the SIMD parsing), that the
+/// current CPU doesn't support. Otherwise we would crash with a SIGILL at some random point in time (e.g. once the
+/// first client connects), which is hard to debug.
+///
+/// This needs to be called as early as possible, as the compiler is free to use the features anywhere.
+pub fn check_cpu_support() -> Result<(), Error> {
+    check_features(compiled_features(), is_detected)
+}
+
+fn check_features(
+    compiled_features: Vec<&'static str>,
+    is_detected: impl Fn(&str) -> bool,
+) -> Result<(), Error> {
+    let missing: Vec<_> = compiled_features
+        .into_iter()
+        .filter(|feature| !is_detected(feature))
+        .collect();
+    ensure!(missing.is_empty(), MissingCpuFeaturesSnafu { missing });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_features() {
+        assert!(check_features(vec!["sse2", "avx2"], |_| true).is_ok());
+        assert!(check_features(vec![], |_| false).is_ok());
+
+        let err = check_features(vec!["sse2", "avx2", "avx512f"], |feature| feature == "sse2")
+            .unwrap_err();
+        let Error::MissingCpuFeatures { missing } = err;
+        assert_eq!(missing, ["avx2", "avx512f"]);
+    }
+
+    #[test]
+    fn test_cpu_supports_itself() {
+        // The tests are compiled and run on the same machine
+        check_cpu_support().unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use rusttype::Font;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::cli_args::{CliArgs, EmbeddedFont};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read font from file {font_file}"))]
+    ReadFontFile {
+        source: std::io::Error,
+        font_file: String,
+    },
+
+    #[snafu(display("Failed to construct font from font file {font_file}"))]
+    ConstructFontFromFontFile { font_file: String },
+}
+
+/// The font configured using `--font-path` or `--font-name`
+pub fn load_font(cli_args: &CliArgs) -> Result<Font<'static>, Error> {
+    match &cli_args.font_path {
+        Some(font_path) => {
+            let font_bytes = std::fs::read(font_path).context(ReadFontFileSnafu {
+                font_file: font_path.clone(),
+            })?;
+
+            Font::try_from_vec(font_bytes).context(ConstructFontFromFontFileSnafu {
+                font_file: font_path.clone(),
+            })
+        }
+        None => load_embedded_font(cli_args.font_name),
+    }
+}
+
+/// We ship our own copies of these fonts, so that users don't need to download and provide them
+pub fn load_embedded_font(font: EmbeddedFont) -> Result<Font<'static>, Error> {
+    let (font_file, font_bytes): (&str, &'static [u8]) = match font {
+        EmbeddedFont::Arial => ("Arial.ttf", include_bytes!("../../Arial.ttf")),
+        EmbeddedFont::DejaVuSansMono => (
+            "DejaVuSansMono.ttf",
+            include_bytes!("../../DejaVuSansMono.ttf"),
+        ),
+    };
+
+    Font::try_from_bytes(font_bytes).context(ConstructFontFromFontFileSnafu { font_file })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Parser, ValueEnum};
+
+    use super::*;
+
+    #[test]
+    fn test_embedded_fonts_load() {
+        for font in EmbeddedFont::value_variants() {
+            let font = load_embedded_font(*font)
+                .unwrap_or_else(|err| panic!("Failed to load embedded font {font:?}: {err}"));
+            assert!(font.glyph_count() > 0);
+        }
+    }
+
+    #[test]
+    fn test_missing_font_file() {
+        let cli_args = CliArgs::parse_from(["breakwater", "--font-path", "/does/not/exist.ttf"]);
+        assert!(matches!(
+            load_font(&cli_args),
+            Err(Error::ReadFontFile { .. })
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Decides which IP addresses are allowed to connect, see `--allow-cidr` and `--deny-cidr`.
+///
+/// Denied networks take precedence over allowed networks. In case no network is allowed explicitly, all IP addresses
+/// that are not denied are allowed.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(allowed: Vec<IpNet>, denied: Vec<IpNet>) -> Self {
+        Self { allowed, denied }
+    }
+
+    /// The IP address needs to be canonical (see [`IpAddr::to_canonical`]), so that IPv4 addresses embedded in IPv6
+    /// addresses are matched against IPv4 networks.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.denied.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[rstest]
+    // Everything is allowed by default
+    #[case(&[], &[], "10.0.0.1", true)]
+    #[case(&[], &[], "2001:db8::1", true)]
+    // Only denied networks
+    #[case(&[], &["10.0.0.0/8"], "10.1.2.3", false)]
+    #[case(&[], &["10.0.0.0/8"], "11.0.0.1", true)]
+    #[case(&[], &["2001:db8::/32"], "2001:db8:1::1", false)]
+    #[case(&[], &["2001:db8::/32"], "2001:db9::1", true)]
+    // Only allowed networks
+    #[case(&["192.168.0.0/16"], &[], "192.168.42.1", true)]
+    #[case(&["192.168.0.0/16"], &[], "192.169.0.1", false)]
+    #[case(&["192.168.0.0/16"], &[], "2001:db8::1", false)]
+    #[case(&["192.168.0.0/16", "2001:db8::/32"], &[], "2001:db8::1", true)]
+    // Denying takes precedence
+    #[case(&["192.168.0.0/16"], &["192.168.1.0/24"], "192.168.1.1", false)]
+    #[case(&["192.168.0.0/16"], &["192.168.1.0/24"], "192.168.2.1", true)]
+    #[case(&["192.168.1.1/32"], &["192.168.1.1/32"], "192.168.1.1", false)]
+    // IPv4 networks don't match IPv6 addresses and the other way around
+    #[case(&[], &["0.0.0.0/0"], "::1", true)]
+    #[case(&[], &["::/0"], "127.0.0.1", true)]
+    fn test_is_allowed(
+        #[case] allowed: &[&str],
+        #[case] denied: &[&str],
+        #[case] ip: IpAddr,
+        #[case] expected: bool,
+    ) {
+        let ip_filter = IpFilter::new(nets(allowed), nets(denied));
+        assert_eq!(ip_filter.is_allowed(ip), expected);
+    }
+}
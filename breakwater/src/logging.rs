@@ -0,0 +1,79 @@
+use snafu::{ResultExt, Snafu};
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::MakeWriter, util::SubscriberInitExt, EnvFilter,
+};
+
+use crate::cli_args::LogFormat;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to install global logger"))]
+    InstallLogger {
+        source: tracing_subscriber::util::TryInitError,
+    },
+}
+
+/// Installs the global logger writing to stderr. The log level defaults to `info` and can be changed using the
+/// `RUST_LOG` environment variable (e.g. `RUST_LOG=debug` or `RUST_LOG=breakwater=trace`).
+///
+/// Everything logged using the `log` crate (which breakwater and most of its dependencies use) is forwarded as well.
+pub fn init(log_format: LogFormat) -> Result<(), Error> {
+    let env_filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+
+    subscriber(log_format, env_filter, std::io::stderr)
+        .try_init()
+        .context(InstallLoggerSnafu)
+}
+
+fn subscriber<W>(
+    log_format: LogFormat,
+    env_filter: EnvFilter,
+    make_writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(make_writer);
+
+    match log_format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_helpers::captured_output::CapturedOutput;
+
+    #[test]
+    fn test_json_log_lines() {
+        // Other tests might have installed it already, which is fine
+        let _ = tracing_log::LogTracer::init();
+
+        let output = CapturedOutput::default();
+        let subscriber = subscriber(LogFormat::Json, EnvFilter::new("info"), output.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            log::debug!("Filtered out by the log level");
+            log::info!("Started Pixelflut server on {}", "[::]:1234");
+        });
+
+        let output = output.get_output();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1, "Expected a single log line: {output}");
+
+        let line: Value = serde_json::from_str(lines[0]).expect("log line must be valid JSON");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(
+            line["fields"]["message"],
+            "Started Pixelflut server on [::]:1234"
+        );
+    }
+}
@@ -1,31 +1,60 @@
-use std::{env, num::TryFromIntError, sync::Arc};
+use std::{
+    fs::File,
+    num::TryFromIntError,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use breakwater_parser::SimpleFrameBuffer;
+use breakwater_parser::{
+    FrameBuffer, RecordingFrameBuffer, ResizableFrameBuffer, SerializedFrameBuffer,
+    SimpleFrameBuffer,
+};
 use clap::Parser;
-use log::info;
+use log::{error, info};
 use prometheus_exporter::PrometheusExporter;
-use sinks::ffmpeg::FfmpegSink;
-use snafu::{ResultExt, Snafu};
+use sinks::{ffmpeg::FfmpegSink, gif::GifSink, mjpeg::MjpegSink, unix_socket::UnixSocketSink};
+use snafu::{ensure, ResultExt, Snafu};
 use tokio::{
+    signal::unix::{signal, SignalKind},
     sync::{broadcast, mpsc},
-    task::JoinError,
+    task::{JoinError, JoinHandle},
 };
 
 use crate::{
+    background_image::load_background_image,
     cli_args::CliArgs,
-    server::Server,
-    sinks::DisplaySink,
-    statistics::{Statistics, StatisticsEvent, StatisticsInformationEvent, StatisticsSaveMode},
+    ip_filter::IpFilter,
+    server::{ConnectionLimits, ParserOptions, Server, TcpOptions},
+    sinks::{fps::FpsConfig, DisplaySink},
+    statistics::{
+        BytesReadCounters, Statistics, StatisticsEvent, StatisticsInformationEvent,
+        StatisticsSaveMode,
+    },
 };
 
 #[cfg(feature = "native-display")]
 use crate::sinks::native_display::NativeDisplaySink;
 
+#[cfg(feature = "shared-memory")]
+use crate::sinks::shared_memory::SharedMemorySink;
+#[cfg(feature = "v4l2")]
+use crate::sinks::v4l2::V4l2Sink;
 #[cfg(feature = "vnc")]
 use crate::sinks::vnc::VncSink;
 
+mod background_image;
 mod cli_args;
+mod cpu_support;
+#[cfg(any(feature = "vnc", feature = "text-command"))]
+mod font;
+mod ip_filter;
+mod logging;
 mod prometheus_exporter;
+mod resize;
+mod screenshot;
 mod server;
 mod sinks;
 mod statistics;
@@ -37,9 +66,60 @@ mod tests;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("Failed to initialize logging"))]
+    InitLogging { source: logging::Error },
+
+    #[snafu(display("The CPU does not support all features breakwater was compiled for"))]
+    CheckCpuSupport { source: cpu_support::Error },
+
+    #[snafu(display(
+        "The logical size {logical_width}x{logical_height} must not exceed the size of the drawing surface {width}x{height}"
+    ))]
+    LogicalSizeTooLarge {
+        logical_width: usize,
+        logical_height: usize,
+        width: usize,
+        height: usize,
+    },
+
+    #[cfg(feature = "text-command")]
+    #[snafu(display("Failed to load font for the TEXT command"))]
+    LoadFont { source: font::Error },
+
+    #[cfg(feature = "shared-memory")]
+    #[snafu(display("Failed to open shared memory {shared_memory_name:?}"))]
+    OpenSharedMemory {
+        source: std::io::Error,
+        shared_memory_name: String,
+    },
+
+    #[snafu(display("Failed to load background image"))]
+    LoadBackgroundImage { source: background_image::Error },
+
     #[snafu(display("Failed to start Pixelflut server"))]
     StartPixelflutServer { source: server::Error },
 
+    #[snafu(display("Failed to write port file {port_file:?}"))]
+    WritePortFile {
+        source: std::io::Error,
+        port_file: String,
+    },
+
+    #[snafu(display("Failed to open draw journal file {draw_journal_file:?}"))]
+    OpenDrawJournalFile {
+        source: std::io::Error,
+        draw_journal_file: String,
+    },
+
+    #[snafu(display("Failed to listen for SIGUSR1 to toggle the maintenance mode"))]
+    ListenForMaintenanceModeSignal { source: std::io::Error },
+
+    #[snafu(display("Failed to listen for SIGUSR2 to save screenshots"))]
+    ListenForScreenshotSignal { source: std::io::Error },
+
+    #[snafu(display("Failed to listen for SIGHUP to resize the canvas"))]
+    ListenForResizeSignal { source: std::io::Error },
+
     #[snafu(display("Failed to wait for CTRL + C signal"))]
     WaitForCtrlCSignal { source: std::io::Error },
 
@@ -73,18 +153,63 @@ pub enum Error {
 #[tokio::main]
 #[snafu::report]
 async fn main() -> Result<(), Error> {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info")
-    }
-    env_logger::init();
-
     let args = CliArgs::parse();
+    logging::init(args.log_format).context(InitLoggingSnafu)?;
+
+    cpu_support::check_cpu_support().context(CheckCpuSupportSnafu)?;
+
+    let logical_width = args.logical_width.unwrap_or(args.width);
+    let logical_height = args.logical_height.unwrap_or(args.height);
+    ensure!(
+        logical_width <= args.width && logical_height <= args.height,
+        LogicalSizeTooLargeSnafu {
+            logical_width,
+            logical_height,
+            width: args.width,
+            height: args.height,
+        }
+    );
 
     // Not using dynamic dispatch here for performance reasons
-    let fb = Arc::new(SimpleFrameBuffer::new(args.width, args.height));
+    #[cfg(not(feature = "shared-memory"))]
+    let fb = SimpleFrameBuffer::new(args.width, args.height);
+    #[cfg(feature = "shared-memory")]
+    let fb = match &args.shared_memory_name {
+        // With a region the SharedMemorySink copies the region into the shared memory instead
+        Some(shared_memory_name) if args.shared_memory_region.is_none() => {
+            let shared_memory = sinks::shared_memory::open_shared_memory(
+                &args,
+                shared_memory_name,
+                args.width,
+                args.height,
+            )
+            .context(OpenSharedMemorySnafu { shared_memory_name })?;
+            info!(
+                "Storing the canvas in the shared memory {:?}",
+                shared_memory.path()
+            );
+            SimpleFrameBuffer::from_shared_memory(shared_memory)
+        }
+        _ => SimpleFrameBuffer::new(args.width, args.height),
+    };
+    let dirty_tracking = args.mjpeg_dirty_regions;
+    #[cfg(feature = "vnc")]
+    let dirty_tracking = dirty_tracking || (args.vnc && args.vnc_dirty_regions);
+    let fb = if dirty_tracking {
+        fb.with_dirty_tracking()
+    } else {
+        fb
+    };
+    let fb = Arc::new(fb);
 
-    // If we make the channel to big, stats will start to lag behind
-    // TODO: Check performance impact in real-world scenario. Maybe the statistics thread blocks the other threads
+    if let Some(background_image) = &args.background_image {
+        load_background_image(fb.as_ref(), background_image).context(LoadBackgroundImageSnafu)?;
+    }
+    // Sinks that can't follow resizes keep using `fb`, the canvas breakwater was started with
+    let resizable_fb = Arc::new(ResizableFrameBuffer::from_arc(fb.clone()));
+
+    // If we make the channel to big, stats will start to lag behind. The bytes read are not sent through the channel,
+    // so only comparatively rare events end up in it.
     let (statistics_tx, statistics_rx) = mpsc::channel::<StatisticsEvent>(100);
     let (statistics_information_tx, statistics_information_rx) =
         broadcast::channel::<StatisticsInformationEvent>(2);
@@ -98,37 +223,179 @@ async fn main() -> Result<(), Error> {
             interval_s: args.statistics_save_interval_s,
         }
     };
+    let bytes_read_counters = Arc::new(BytesReadCounters::default());
     let mut statistics = Statistics::new(
         statistics_rx,
+        Arc::clone(&bytes_read_counters),
         statistics_information_tx,
         statistics_save_mode,
-    );
-
-    let mut server = Server::new(
-        &args.listen_address,
-        fb.clone(),
-        statistics_tx.clone(),
-        args.network_buffer_size
-            .try_into()
-            // This should never happen as clap checks the range for us
-            .context(InvalidNetworkBufferSizeSnafu {
-                network_buffer_size: args.network_buffer_size,
-            })?,
-        args.connections_per_ip,
     )
-    .await
-    .context(StartPixelflutServerSnafu)?;
+    .with_top_n(args.stats_top_n)
+    .with_report_interval(Duration::from_millis(args.stats_report_interval_ms));
+    let statistics_snapshot = statistics.snapshot();
+
+    #[allow(unused_mut)] // Only mutated with the vnc feature
+    let mut write_protected_regions = args.write_protect_region.clone();
+    #[cfg(feature = "vnc")]
+    if args.write_protect_stats {
+        write_protected_regions.push(
+            sinks::vnc::StatsLayout::new(
+                args.width,
+                args.height,
+                args.vnc_stats_height as usize,
+                args.vnc_stats_position,
+            )
+            .stats_region(),
+        );
+    }
+
+    // Toggled by sending SIGUSR1 to breakwater, e.g. `pkill -USR1 breakwater`
+    let maintenance_mode = Arc::new(AtomicBool::new(false));
+    let mut maintenance_mode_signal =
+        signal(SignalKind::user_defined1()).context(ListenForMaintenanceModeSignalSnafu)?;
+    let maintenance_mode_for_signal = Arc::clone(&maintenance_mode);
+    tokio::spawn(async move {
+        while maintenance_mode_signal.recv().await.is_some() {
+            toggle_maintenance_mode(&maintenance_mode_for_signal);
+        }
+    });
+
+    // Triggered by sending SIGUSR2 to breakwater, e.g. `pkill -USR2 breakwater`
+    if let Some(screenshot_save_folder) = args.screenshot_save_folder.clone() {
+        let mut screenshot_signal =
+            signal(SignalKind::user_defined2()).context(ListenForScreenshotSignalSnafu)?;
+        let resizable_fb = Arc::clone(&resizable_fb);
+        tokio::spawn(async move {
+            while screenshot_signal.recv().await.is_some() {
+                let fb = resizable_fb.load();
+                let screenshot_save_folder = screenshot_save_folder.clone();
+                // Encoding a PNG takes a while, so let's not block the async runtime
+                match tokio::task::spawn_blocking(move || {
+                    screenshot::save_screenshot(fb.as_ref(), &screenshot_save_folder)
+                })
+                .await
+                {
+                    Ok(Ok(screenshot_file)) => info!("Saved screenshot to {screenshot_file:?}"),
+                    Ok(Err(err)) => error!("Failed to save screenshot: {err}"),
+                    Err(err) => error!("Failed to join screenshot task: {err}"),
+                }
+            }
+        });
+    }
+
+    // Triggered by sending SIGHUP to breakwater, e.g. `pkill -HUP breakwater`
+    if let Some(resize_file) = args.resize_file.clone() {
+        let mut resize_signal = signal(SignalKind::hangup()).context(ListenForResizeSignalSnafu)?;
+        let resizable_fb = Arc::clone(&resizable_fb);
+        let (logical_width, logical_height) = (args.logical_width, args.logical_height);
+        tokio::spawn(async move {
+            while resize_signal.recv().await.is_some() {
+                let resizable_fb = Arc::clone(&resizable_fb);
+                let resize_file = resize_file.clone();
+                // Copying the pixels of a large canvas takes a while, so let's not block the async runtime
+                match tokio::task::spawn_blocking(move || {
+                    resize::resize_canvas(
+                        resizable_fb.as_ref(),
+                        &resize_file,
+                        logical_width,
+                        logical_height,
+                    )
+                })
+                .await
+                {
+                    Ok(Ok((width, height))) => info!("Resized canvas to {width}x{height}"),
+                    Ok(Err(err)) => error!("Failed to resize canvas: {err}"),
+                    Err(err) => error!("Failed to join resize task: {err}"),
+                }
+            }
+        });
+    }
+
+    let network_buffer_size = args
+        .network_buffer_size
+        .try_into()
+        // This should never happen as clap checks the range for us
+        .context(InvalidNetworkBufferSizeSnafu {
+            network_buffer_size: args.network_buffer_size,
+        })?;
+    let connection_limits = ConnectionLimits {
+        max_pixels: args.max_pixels_per_connection,
+        max_bytes: args.max_bytes_per_connection,
+        idle_timeout: args.connection_idle_timeout_s.map(Duration::from_secs),
+        warm_up: args.connection_warm_up_s.map(Duration::from_secs),
+    };
+    let parser_options = ParserOptions {
+        strict: args.strict,
+        lenient_whitespace: args.lenient_whitespace,
+        #[cfg(not(feature = "alpha"))]
+        reject_alpha: args.reject_alpha,
+        #[cfg(feature = "alpha")]
+        rgba_reads: args.rgba_reads,
+        pixel_command_echo: args.pixel_command_echo,
+        write_protected_regions,
+        canvas_rotation: args.canvas_rotate,
+        logical_width: args.logical_width,
+        logical_height: args.logical_height,
+        #[cfg(feature = "custom-separators")]
+        command_separator: args.command_separator,
+        #[cfg(feature = "text-command")]
+        font: Some(font::load_font(&args).context(LoadFontSnafu)?),
+        maintenance_mode,
+    };
+
+    let draw_journal = match &args.draw_journal_file {
+        Some(draw_journal_file) => {
+            let journal = File::options()
+                .create(true)
+                .append(true)
+                .open(draw_journal_file)
+                .context(OpenDrawJournalFileSnafu { draw_journal_file })?;
+            info!("Appending all draws to the journal {draw_journal_file:?}");
+            Some(Arc::new(RecordingFrameBuffer::new(fb.clone(), journal)))
+        }
+        None => None,
+    };
+
+    let server_listener_thread = match &draw_journal {
+        Some(draw_journal) => {
+            start_server_maybe_serialized(
+                &args,
+                Arc::new(ResizableFrameBuffer::from_arc(draw_journal.clone())),
+                statistics_tx.clone(),
+                bytes_read_counters,
+                network_buffer_size,
+                connection_limits,
+                parser_options,
+            )
+            .await?
+        }
+        None => {
+            start_server_maybe_serialized(
+                &args,
+                resizable_fb.clone(),
+                statistics_tx.clone(),
+                bytes_read_counters,
+                network_buffer_size,
+                connection_limits,
+                parser_options,
+            )
+            .await?
+        }
+    };
 
     let mut prometheus_exporter = PrometheusExporter::new(
         &args.prometheus_listen_address,
+        resizable_fb.clone(),
         statistics_information_rx.resubscribe(),
+        terminate_signal_rx.resubscribe(),
     )
+    .await
     .context(StartPrometheusExporterSnafu)?;
 
-    let server_listener_thread = tokio::spawn(async move { server.start().await });
     let statistics_thread = tokio::spawn(async move { statistics.start().await });
     let prometheus_exporter_thread = tokio::spawn(async move { prometheus_exporter.run().await });
 
+    let fps = FpsConfig::from_cli_args(&args);
     let mut display_sinks = Vec::<Box<dyn DisplaySink<SimpleFrameBuffer> + Send>>::new();
 
     #[cfg(feature = "native-display")]
@@ -136,6 +403,7 @@ async fn main() -> Result<(), Error> {
         if let Some(native_display_sink) = NativeDisplaySink::new(
             fb.clone(),
             &args,
+            &fps,
             statistics_tx.clone(),
             statistics_information_rx.resubscribe(),
             terminate_signal_rx.resubscribe(),
@@ -143,7 +411,9 @@ async fn main() -> Result<(), Error> {
         .await
         .context(CreateSinkSnafu)?
         {
-            display_sinks.push(Box::new(native_display_sink));
+            display_sinks.push(Box::new(
+                native_display_sink.with_resizable_framebuffer(resizable_fb.clone()),
+            ));
         }
     }
 
@@ -152,6 +422,26 @@ async fn main() -> Result<(), Error> {
         if let Some(vnc_sink) = VncSink::new(
             fb.clone(),
             &args,
+            &fps,
+            statistics_tx.clone(),
+            statistics_information_rx.resubscribe(),
+            terminate_signal_rx.resubscribe(),
+        )
+        .await
+        .context(CreateSinkSnafu)?
+        {
+            display_sinks.push(Box::new(
+                vnc_sink.with_resizable_framebuffer(resizable_fb.clone()),
+            ));
+        }
+    }
+
+    #[cfg(feature = "v4l2")]
+    {
+        if let Some(v4l2_sink) = V4l2Sink::new(
+            fb.clone(),
+            &args,
+            &fps,
             statistics_tx.clone(),
             statistics_information_rx.resubscribe(),
             terminate_signal_rx.resubscribe(),
@@ -159,23 +449,75 @@ async fn main() -> Result<(), Error> {
         .await
         .context(CreateSinkSnafu)?
         {
-            display_sinks.push(Box::new(vnc_sink));
+            display_sinks.push(Box::new(v4l2_sink));
         }
     }
 
-    let mut ffmpeg_thread_present = false;
-    if let Some(ffmpeg_sink) = FfmpegSink::new(
-        fb,
+    if let Some(gif_sink) = GifSink::new(
+        fb.clone(),
         &args,
+        &fps,
         statistics_tx.clone(),
-        statistics_information_rx,
-        terminate_signal_rx,
+        statistics_information_rx.resubscribe(),
+        terminate_signal_rx.resubscribe(),
     )
     .await
     .context(CreateSinkSnafu)?
     {
+        display_sinks.push(Box::new(gif_sink));
+    }
+
+    if let Some(unix_socket_sink) = UnixSocketSink::new(
+        fb.clone(),
+        &args,
+        &fps,
+        statistics_tx.clone(),
+        statistics_information_rx.resubscribe(),
+        terminate_signal_rx.resubscribe(),
+    )
+    .await
+    .context(CreateSinkSnafu)?
+    {
+        display_sinks.push(Box::new(unix_socket_sink));
+    }
+
+    #[cfg(feature = "shared-memory")]
+    {
+        if let Some(shared_memory_sink) = SharedMemorySink::new(
+            fb.clone(),
+            &args,
+            &fps,
+            statistics_tx.clone(),
+            statistics_information_rx.resubscribe(),
+            terminate_signal_rx.resubscribe(),
+        )
+        .await
+        .context(CreateSinkSnafu)?
+        {
+            display_sinks.push(Box::new(shared_memory_sink));
+        }
+    }
+
+    if let Some(mjpeg_sink) = MjpegSink::new(
+        fb.clone(),
+        &args,
+        &fps,
+        statistics_tx.clone(),
+        statistics_information_rx.resubscribe(),
+        terminate_signal_rx.resubscribe(),
+    )
+    .await
+    .context(CreateSinkSnafu)?
+    {
+        display_sinks.push(Box::new(mjpeg_sink));
+    }
+
+    // Every output (e.g. file and rtmp) gets its own ffmpeg process
+    let ffmpeg_sinks =
+        FfmpegSink::new_per_output(fb, &args, &fps, statistics_tx.clone(), terminate_signal_rx);
+    let ffmpeg_thread_present = !ffmpeg_sinks.is_empty();
+    for ffmpeg_sink in ffmpeg_sinks {
         display_sinks.push(Box::new(ffmpeg_sink));
-        ffmpeg_thread_present = true;
     }
 
     let mut sink_threads = Vec::new();
@@ -194,9 +536,14 @@ async fn main() -> Result<(), Error> {
         .send(())
         .context(SendTerminationSignalSnafu)?;
 
-    prometheus_exporter_thread.abort();
     server_listener_thread.abort();
 
+    if let Some(draw_journal) = draw_journal {
+        if let Err(err) = draw_journal.flush() {
+            error!("Failed to write draw journal: {err}");
+        }
+    }
+
     for sink_thread in sink_threads {
         sink_thread
             .await
@@ -204,9 +551,18 @@ async fn main() -> Result<(), Error> {
             .context(StopSinkSnafu)?;
     }
 
+    // Keep reporting as not ready while the sinks are shutting down
+    prometheus_exporter_thread.abort();
+
     // We need to stop this thread as the last, as others always try to send statistics to it
     statistics_thread.abort();
 
+    let final_statistics = statistics_snapshot.load();
+    info!(
+        "Received {} bytes in total, {} connections from {} IPs were still open",
+        final_statistics.bytes, final_statistics.connections, final_statistics.ips
+    );
+
     if ffmpeg_thread_present {
         info!("Successfully shut down (there might still be a ffmpeg process running - it's complicated)");
     } else {
@@ -215,3 +571,106 @@ async fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// The sinks keep reading the framebuffer directly, only the draws of the clients go through the single writer. The
+/// draws are serialized before they are recorded, so that the journal has the same order as the canvas.
+///
+/// `--resize-file` conflicts with `--serialize-draws`, so the single writer can stick to the current framebuffer.
+async fn start_server_maybe_serialized<FB: FrameBuffer + Send + Sync + 'static>(
+    args: &CliArgs,
+    fb: Arc<ResizableFrameBuffer<FB>>,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
+    bytes_read_counters: Arc<BytesReadCounters>,
+    network_buffer_size: usize,
+    connection_limits: ConnectionLimits,
+    parser_options: ParserOptions,
+) -> Result<JoinHandle<Result<(), server::Error>>, Error> {
+    if args.serialize_draws {
+        info!("Serializing all draws through a single writer");
+        start_server(
+            args,
+            Arc::new(ResizableFrameBuffer::new(SerializedFrameBuffer::new(
+                fb.load(),
+            ))),
+            statistics_tx,
+            bytes_read_counters,
+            network_buffer_size,
+            connection_limits,
+            parser_options,
+        )
+        .await
+    } else {
+        start_server(
+            args,
+            fb,
+            statistics_tx,
+            bytes_read_counters,
+            network_buffer_size,
+            connection_limits,
+            parser_options,
+        )
+        .await
+    }
+}
+
+/// Starts the Pixelflut server in the background. It's generic over the framebuffer, so that the parsers are compiled
+/// for the concrete framebuffer the clients draw on.
+async fn start_server<FB: FrameBuffer + Send + Sync + 'static>(
+    args: &CliArgs,
+    fb: Arc<ResizableFrameBuffer<FB>>,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
+    bytes_read_counters: Arc<BytesReadCounters>,
+    network_buffer_size: usize,
+    connection_limits: ConnectionLimits,
+    parser_options: ParserOptions,
+) -> Result<JoinHandle<Result<(), server::Error>>, Error> {
+    let mut server = Server::new(
+        &args.listen_address,
+        args.acceptor_threads as usize,
+        fb.load(),
+        statistics_tx,
+        bytes_read_counters,
+        network_buffer_size,
+        args.connections_per_ip,
+        connection_limits,
+        parser_options,
+    )
+    .await
+    .context(StartPixelflutServerSnafu)?
+    .with_resizable_framebuffer(fb)
+    .with_connection_workers(args.connection_workers.map(|workers| workers as usize))
+    .with_max_total_connections(args.max_total_connections)
+    .with_response_buffer_size(args.response_buffer_size as usize)
+    .with_tcp_options(TcpOptions {
+        nodelay: args.tcp_nodelay,
+        keepalive: args.tcp_keepalive_s.map(Duration::from_secs),
+    })
+    .with_ip_filter(IpFilter::new(
+        args.allow_cidr.clone(),
+        args.deny_cidr.clone(),
+    ));
+
+    if let Some(port_file) = &args.port_file {
+        let ports = server
+            .local_addrs()
+            .iter()
+            .map(|local_addr| format!("{}\n", local_addr.port()))
+            .collect::<String>();
+        std::fs::write(port_file, &ports).context(WritePortFileSnafu { port_file })?;
+        info!(
+            "Wrote ports {:?} to {port_file:?}",
+            ports.lines().collect::<Vec<_>>()
+        );
+    }
+
+    Ok(tokio::spawn(async move { server.start().await }))
+}
+
+/// While in maintenance mode the canvas is read-only, clients stay connected and can still read pixels
+fn toggle_maintenance_mode(maintenance_mode: &AtomicBool) {
+    if maintenance_mode.fetch_xor(true, Ordering::Relaxed) {
+        info!("Left maintenance mode, clients can draw again");
+    } else {
+        info!("Entered maintenance mode, the canvas is read-only until the next SIGUSR1");
+    }
+}
@@ -1,13 +1,32 @@
-use std::net::AddrParseError;
+use std::{
+    collections::HashMap,
+    net::{AddrParseError, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use prometheus_exporter::{
-    self,
-    prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec},
+use breakwater_parser::{FrameBuffer, ResizableFrameBuffer};
+use log::debug;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{self, MetricFamily, MetricType},
+    Encoder, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
 };
 use snafu::{ResultExt, Snafu};
-use tokio::sync::broadcast;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
 
-use crate::statistics::StatisticsInformationEvent;
+use crate::statistics::{
+    ConnectionDurations, StatisticsInformationEvent, CONNECTION_DURATION_BUCKETS,
+};
+
+/// Requests are tiny, everything longer is not a request we care about
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -17,124 +36,694 @@ pub enum Error {
         listen_address: String,
     },
 
-    #[snafu(display("Failed to start Prometheus server"))]
-    StartPrometheusServer { source: prometheus_exporter::Error },
+    #[snafu(display("Failed to bind to Prometheus listen address {listen_address:?}"))]
+    BindToListenAddress {
+        source: std::io::Error,
+        listen_address: SocketAddr,
+    },
 
     #[snafu(display("Failed to register prometheus gauge {name:?}"))]
     RegisterPrometheusGauge {
-        source: prometheus_exporter::prometheus::Error,
+        source: prometheus::Error,
+        name: String,
+    },
+
+    #[snafu(display("Failed to register prometheus histogram {name:?}"))]
+    RegisterPrometheusHistogram {
+        source: prometheus::Error,
         name: String,
     },
 }
 
-pub struct PrometheusExporter {
+const CONNECTION_DURATION_METRIC: &str = "breakwater_connection_duration_seconds";
+
+/// Exports the [`ConnectionDurations`] calculated by the statistics as histogram. We can't use a
+/// [`prometheus::Histogram`] here, as we only get the already aggregated buckets.
+struct ConnectionDurationCollector {
+    desc: Desc,
+    connection_durations: Arc<Mutex<ConnectionDurations>>,
+}
+
+impl Collector for ConnectionDurationCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let connection_durations = self
+            .connection_durations
+            .lock()
+            .expect("connection durations lock poisoned");
+
+        let mut cumulative_count = 0;
+        let buckets = CONNECTION_DURATION_BUCKETS
+            .iter()
+            .zip(connection_durations.bucket_counts)
+            .map(|(upper_bound, count)| {
+                cumulative_count += count;
+                let mut bucket = proto::Bucket::default();
+                bucket.set_upper_bound(*upper_bound);
+                bucket.set_cumulative_count(cumulative_count);
+                bucket
+            })
+            .collect::<Vec<_>>();
+
+        let mut histogram = proto::Histogram::default();
+        histogram.set_sample_count(connection_durations.count);
+        histogram.set_sample_sum(connection_durations.sum_s);
+        histogram.set_bucket(buckets);
+        let mut metric = proto::Metric::default();
+        metric.set_histogram(histogram);
+
+        let mut metric_family = MetricFamily::default();
+        metric_family.set_name(self.desc.fq_name.clone());
+        metric_family.set_help(self.desc.help.clone());
+        metric_family.set_field_type(MetricType::HISTOGRAM);
+        metric_family.set_metric(vec![metric]);
+        vec![metric_family]
+    }
+}
+
+/// State reported by the `/healthz` and `/readyz` endpoints
+#[derive(Debug)]
+struct Health {
+    /// Set to false once the statistics thread is gone, as we would serve stale metrics from then on
+    statistics_alive: AtomicBool,
+
+    /// Set to false once we are shutting down, so that no new clients are sent our way
+    ready: AtomicBool,
+}
+
+/// Serves the Prometheus metrics on `/metrics`, as well as the `/healthz` and `/readyz` endpoints, e.g. for
+/// Kubernetes probes.
+pub struct PrometheusExporter<FB: FrameBuffer> {
+    listener: TcpListener,
+    /// Only used to export its size, which changes when the canvas is resized
+    fb: Arc<ResizableFrameBuffer<FB>>,
+    registry: Registry,
+    health: Arc<Health>,
+
     statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+    terminate_signal_rx: broadcast::Receiver<()>,
 
     // Prometheus metrics
+    metric_framebuffer_width: IntGauge,
+    metric_framebuffer_height: IntGauge,
+    metric_framebuffer_bytes: IntGauge,
     metric_ips: IntGauge,
     metric_legacy_ips: IntGauge,
     metric_frame: IntGauge,
     metric_statistic_events: IntGauge,
+    metric_ffmpeg_stdin_lags: IntGauge,
 
     metric_connections_for_ip: IntGaugeVec,
     metric_denied_connections_for_ip: IntGaugeVec,
+    metric_connection_limit_hits_for_ip: IntGaugeVec,
+    metric_discarded_bytes_for_ip: IntGaugeVec,
+    metric_malformed_commands_for_ip: IntGaugeVec,
     metric_bytes_for_ip: IntGaugeVec,
+    metric_bytes_per_s_for_top_ip: IntGaugeVec,
+    /// Read by the [`ConnectionDurationCollector`]
+    metric_connection_durations: Arc<Mutex<ConnectionDurations>>,
 }
 
-impl PrometheusExporter {
-    pub fn new(
+impl<FB: FrameBuffer> PrometheusExporter<FB> {
+    /// The dimensions of the current framebuffer of `fb` are exported together with the statistics, so that they follow
+    /// resizes of the canvas.
+    pub async fn new(
         listen_addr: &str,
+        fb: Arc<ResizableFrameBuffer<FB>>,
         statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
     ) -> Result<Self, Error> {
-        let listen_addr = listen_addr.parse().context(ParseListenAddressSnafu {
+        let listen_addr: SocketAddr = listen_addr.parse().context(ParseListenAddressSnafu {
             listen_address: listen_addr.to_string(),
         })?;
+        let listener = TcpListener::bind(listen_addr)
+            .await
+            .context(BindToListenAddressSnafu {
+                listen_address: listen_addr,
+            })?;
 
-        prometheus_exporter::start(listen_addr).context(StartPrometheusServerSnafu)?;
+        let registry = Registry::new();
+        let metric_connection_durations = Arc::default();
+        registry
+            .register(Box::new(ConnectionDurationCollector {
+                desc: Desc::new(
+                    CONNECTION_DURATION_METRIC.to_owned(),
+                    "How long the closed connections lasted in seconds".to_owned(),
+                    Vec::new(),
+                    HashMap::new(),
+                )
+                .context(RegisterPrometheusHistogramSnafu {
+                    name: CONNECTION_DURATION_METRIC,
+                })?,
+                connection_durations: Arc::clone(&metric_connection_durations),
+            }))
+            .context(RegisterPrometheusHistogramSnafu {
+                name: CONNECTION_DURATION_METRIC,
+            })?;
 
-        Ok(PrometheusExporter {
+        let exporter = PrometheusExporter {
+            listener,
+            fb,
+            health: Arc::new(Health {
+                statistics_alive: AtomicBool::new(true),
+                ready: AtomicBool::new(true),
+            }),
             statistics_information_rx,
-            metric_legacy_ips: register_int_gauge(
+            terminate_signal_rx,
+            metric_framebuffer_width: register_int_gauge(
+                &registry,
+                "breakwater_framebuffer_width",
+                "Width of the framebuffer in pixels",
+            )?,
+            metric_framebuffer_height: register_int_gauge(
+                &registry,
+                "breakwater_framebuffer_height",
+                "Height of the framebuffer in pixels",
+            )?,
+            metric_framebuffer_bytes: register_int_gauge(
+                &registry,
+                "breakwater_framebuffer_bytes",
+                "Memory used by the framebuffer in bytes",
+            )?,
+            metric_ips: register_int_gauge(
+                &registry,
                 "breakwater_ips",
                 "Total number of IPs connected",
             )?,
-            metric_ips: register_int_gauge(
+            metric_legacy_ips: register_int_gauge(
+                &registry,
                 "breakwater_legacy_ips",
                 "Total number of legacy (v4) IPs connected",
             )?,
-            metric_frame: register_int_gauge("breakwater_frame", "Frame number of the VNC server")?,
+            metric_frame: register_int_gauge(
+                &registry,
+                "breakwater_frame",
+                "Frame number of the VNC server",
+            )?,
             metric_statistic_events: register_int_gauge(
+                &registry,
                 "breakwater_statistic_events",
                 "Number of statistics events send internally",
             )?,
+            metric_ffmpeg_stdin_lags: register_int_gauge(
+                &registry,
+                "breakwater_ffmpeg_stdin_lags",
+                "Number of frames that took longer than --max-ffmpeg-stdin-lag to be written to ffmpeg",
+            )?,
             metric_connections_for_ip: register_int_gauge_vec(
+                &registry,
                 "breakwater_connections",
                 "Number of client connections per IP address",
                 &["ip"],
             )?,
             metric_denied_connections_for_ip: register_int_gauge_vec(
+                &registry,
                 "breakwater_denied_connections",
                 "Number of denied connections per IP address because it tried to open too many connections",
                 &["ip"],
             )?,
+            metric_connection_limit_hits_for_ip: register_int_gauge_vec(
+                &registry,
+                "breakwater_connection_limit_hits",
+                "Number of connections per IP address that were closed because they reached the connection limits",
+                &["ip"],
+            )?,
+            metric_discarded_bytes_for_ip: register_int_gauge_vec(
+                &registry,
+                "breakwater_discarded_bytes",
+                "Number of bytes per IP address that were discarded, as they did not form a complete command",
+                &["ip"],
+            )?,
+            metric_malformed_commands_for_ip: register_int_gauge_vec(
+                &registry,
+                "breakwater_malformed_commands_total",
+                "Number of commands per IP address that looked like a known command (e.g. PX or OFFSET), but could not be parsed",
+                &["ip"],
+            )?,
             metric_bytes_for_ip: register_int_gauge_vec(
+                &registry,
                 "breakwater_bytes",
                 "Number of bytes received per IP address",
                 &["ip"],
             )?,
-        })
+            metric_bytes_per_s_for_top_ip: register_int_gauge_vec(
+                &registry,
+                "breakwater_top_ips_bytes_per_s",
+                "Bytes per second received from the --stats-top-n most active IP addresses",
+                &["ip"],
+            )?,
+            metric_connection_durations,
+            registry,
+        };
+        // Otherwise they would be missing until the first statistics arrive
+        exporter.update_framebuffer_metrics();
+        Ok(exporter)
     }
 
     pub async fn run(&mut self) {
-        while let Ok(event) = self.statistics_information_rx.recv().await {
-            self.metric_ips.set(event.ips as i64);
-            self.metric_legacy_ips.set(event.legacy_ips as i64);
-            self.metric_frame.set(event.frame as i64);
-            self.metric_statistic_events
-                .set(event.statistic_events as i64);
-
-            // When clients drop a connection the item will be missing in `event.connections_for_ip,
-            // but would stay forever in the Prometheus metric
-            self.metric_connections_for_ip.reset();
-            event
-                .connections_for_ip
-                .iter()
-                .for_each(|(ip, connections)| {
-                    self.metric_connections_for_ip
-                        .with_label_values(&[&ip.to_string()])
-                        .set(*connections as i64)
-                });
-            self.metric_denied_connections_for_ip.reset();
-            event
-                .denied_connections_for_ip
-                .iter()
-                .for_each(|(ip, denied)| {
-                    self.metric_denied_connections_for_ip
-                        .with_label_values(&[&ip.to_string()])
-                        .set(*denied as i64)
-                });
-            self.metric_bytes_for_ip.reset();
-            event.bytes_for_ip.iter().for_each(|(ip, bytes)| {
-                self.metric_bytes_for_ip
+        loop {
+            tokio::select! {
+                event = self.statistics_information_rx.recv() => match event {
+                    Ok(event) => self.update_metrics(event),
+                    // We will get the next event soon
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        self.health.statistics_alive.store(false, Ordering::Relaxed);
+                        self.serve_until_terminated().await;
+                        return;
+                    }
+                },
+                Ok((stream, _)) = self.listener.accept() => self.spawn_handle_request(stream),
+                _ = self.terminate_signal_rx.recv() => {
+                    self.health.ready.store(false, Ordering::Relaxed);
+                    self.serve_until_terminated().await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keep answering the probes (e.g. with "not ready") until the task gets aborted
+    async fn serve_until_terminated(&mut self) {
+        loop {
+            if let Ok((stream, _)) = self.listener.accept().await {
+                self.spawn_handle_request(stream);
+            }
+        }
+    }
+
+    fn spawn_handle_request(&self, stream: TcpStream) {
+        let registry = self.registry.clone();
+        let health = Arc::clone(&self.health);
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(stream, &registry, &health).await {
+                debug!("Failed to answer HTTP request on the Prometheus listen address: {err}");
+            }
+        });
+    }
+
+    fn update_framebuffer_metrics(&self) {
+        let fb = self.fb.load();
+        self.metric_framebuffer_width.set(fb.get_width() as i64);
+        self.metric_framebuffer_height.set(fb.get_height() as i64);
+        self.metric_framebuffer_bytes
+            .set(fb.as_bytes().len() as i64);
+    }
+
+    fn update_metrics(&mut self, event: StatisticsInformationEvent) {
+        self.update_framebuffer_metrics();
+        self.metric_ips.set(event.ips as i64);
+        self.metric_legacy_ips.set(event.legacy_ips as i64);
+        self.metric_frame.set(event.frame as i64);
+        self.metric_statistic_events
+            .set(event.statistic_events as i64);
+        self.metric_ffmpeg_stdin_lags
+            .set(event.ffmpeg_stdin_lags as i64);
+        *self
+            .metric_connection_durations
+            .lock()
+            .expect("connection durations lock poisoned") = event.connection_durations;
+
+        // When clients drop a connection the item will be missing in `event.connections_for_ip,
+        // but would stay forever in the Prometheus metric
+        self.metric_connections_for_ip.reset();
+        event
+            .connections_for_ip
+            .iter()
+            .for_each(|(ip, connections)| {
+                self.metric_connections_for_ip
+                    .with_label_values(&[&ip.to_string()])
+                    .set(*connections as i64)
+            });
+        self.metric_denied_connections_for_ip.reset();
+        event
+            .denied_connections_for_ip
+            .iter()
+            .for_each(|(ip, denied)| {
+                self.metric_denied_connections_for_ip
+                    .with_label_values(&[&ip.to_string()])
+                    .set(*denied as i64)
+            });
+        self.metric_connection_limit_hits_for_ip.reset();
+        event
+            .connection_limit_hits_for_ip
+            .iter()
+            .for_each(|(ip, hits)| {
+                self.metric_connection_limit_hits_for_ip
                     .with_label_values(&[&ip.to_string()])
-                    .set(*bytes as i64)
+                    .set(*hits as i64)
             });
+        self.metric_discarded_bytes_for_ip.reset();
+        event.discarded_bytes_for_ip.iter().for_each(|(ip, bytes)| {
+            self.metric_discarded_bytes_for_ip
+                .with_label_values(&[&ip.to_string()])
+                .set(*bytes as i64)
+        });
+        self.metric_malformed_commands_for_ip.reset();
+        event
+            .malformed_commands_for_ip
+            .iter()
+            .for_each(|(ip, commands)| {
+                self.metric_malformed_commands_for_ip
+                    .with_label_values(&[&ip.to_string()])
+                    .set(*commands as i64)
+            });
+        self.metric_bytes_for_ip.reset();
+        event.bytes_for_ip.iter().for_each(|(ip, bytes)| {
+            self.metric_bytes_for_ip
+                .with_label_values(&[&ip.to_string()])
+                .set(*bytes as i64)
+        });
+        // Only contains the top N IPs, so that the number of labels stays bounded
+        self.metric_bytes_per_s_for_top_ip.reset();
+        event
+            .top_ips_by_bytes_per_s
+            .iter()
+            .for_each(|(ip, bytes_per_s)| {
+                self.metric_bytes_per_s_for_top_ip
+                    .with_label_values(&[&ip.to_string()])
+                    .set(*bytes_per_s as i64)
+            });
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+}
+
+/// A very minimal HTTP server, as we only need to answer a few `GET` requests
+async fn handle_request(
+    mut stream: TcpStream,
+    registry: &Registry,
+    health: &Health,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let bytes_read = stream.read(&mut buffer).await?;
+        if bytes_read == 0 || request.len() > MAX_REQUEST_SIZE {
+            return Ok(());
         }
+        request.extend_from_slice(&buffer[..bytes_read]);
     }
+
+    // E.g. `GET /metrics HTTP/1.1`
+    let path = request
+        .split(|&b| b == b' ')
+        .nth(1)
+        .unwrap_or_default()
+        .to_owned();
+    let statistics_alive = health.statistics_alive.load(Ordering::Relaxed);
+
+    let (status, content_type, body) = match path.as_slice() {
+        b"/metrics" => {
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            match encoder.encode(&registry.gather(), &mut body) {
+                Ok(()) => ("200 OK", encoder.format_type().to_owned(), body),
+                Err(err) => (
+                    "500 Internal Server Error",
+                    "text/plain".to_owned(),
+                    format!("Failed to encode metrics: {err}\n").into_bytes(),
+                ),
+            }
+        }
+        b"/healthz" if statistics_alive => ("200 OK", "text/plain".to_owned(), b"ok\n".to_vec()),
+        b"/healthz" => (
+            "503 Service Unavailable",
+            "text/plain".to_owned(),
+            b"statistics channel closed\n".to_vec(),
+        ),
+        b"/readyz" if statistics_alive && health.ready.load(Ordering::Relaxed) => {
+            ("200 OK", "text/plain".to_owned(), b"ready\n".to_vec())
+        }
+        b"/readyz" => (
+            "503 Service Unavailable",
+            "text/plain".to_owned(),
+            b"not ready\n".to_vec(),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain".to_owned(),
+            b"not found\n".to_vec(),
+        ),
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await
 }
 
-fn register_int_gauge(name: &str, description: &str) -> Result<IntGauge, Error> {
-    register_int_gauge!(name, description).context(RegisterPrometheusGaugeSnafu {
-        name: name.to_string(),
-    })
+fn register_int_gauge(
+    registry: &Registry,
+    name: &str,
+    description: &str,
+) -> Result<IntGauge, Error> {
+    let gauge = IntGauge::new(name, description).context(RegisterPrometheusGaugeSnafu { name })?;
+    registry
+        .register(Box::new(gauge.clone()))
+        .context(RegisterPrometheusGaugeSnafu { name })?;
+    Ok(gauge)
 }
 
 fn register_int_gauge_vec(
+    registry: &Registry,
     name: &str,
     description: &str,
     label_names: &[&str],
 ) -> Result<IntGaugeVec, Error> {
-    register_int_gauge_vec!(name, description, label_names).context(RegisterPrometheusGaugeSnafu {
-        name: name.to_string(),
-    })
+    let gauge = IntGaugeVec::new(Opts::new(name, description), label_names)
+        .context(RegisterPrometheusGaugeSnafu { name })?;
+    registry
+        .register(Box::new(gauge.clone()))
+        .context(RegisterPrometheusGaugeSnafu { name })?;
+    Ok(gauge)
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_healthz_and_readyz() {
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+        let mut exporter = PrometheusExporter::new(
+            "127.0.0.1:0",
+            Arc::new(ResizableFrameBuffer::new(SimpleFrameBuffer::new(640, 480))),
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+        let addr = exporter.local_addr();
+        let exporter_thread = tokio::spawn(async move { exporter.run().await });
+
+        let response = get(addr, "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok\n"), "{response}");
+
+        let response = get(addr, "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("breakwater_frame 0\n"), "{response}");
+        assert!(
+            response.contains("breakwater_connection_duration_seconds_count 0\n"),
+            "{response}"
+        );
+
+        // We are shutting down, but are still alive
+        terminate_signal_tx.send(()).unwrap();
+        let mut response = get(addr, "/readyz").await;
+        // The exporter might not have seen the termination signal yet
+        while response.starts_with("HTTP/1.1 200 OK\r\n") {
+            tokio::task::yield_now().await;
+            response = get(addr, "/readyz").await;
+        }
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        let response = get(addr, "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+        exporter_thread.abort();
+    }
+
+    #[tokio::test]
+    async fn test_framebuffer_metrics() {
+        let (statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+        let fb = Arc::new(ResizableFrameBuffer::new(SimpleFrameBuffer::new(
+            1920, 1080,
+        )));
+        let mut exporter = PrometheusExporter::new(
+            "127.0.0.1:0",
+            Arc::clone(&fb),
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+        let addr = exporter.local_addr();
+        let exporter_thread = tokio::spawn(async move { exporter.run().await });
+
+        let response = get(addr, "/metrics").await;
+        assert!(
+            response.contains("\nbreakwater_framebuffer_width 1920\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains("\nbreakwater_framebuffer_height 1080\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains(&format!(
+                "\nbreakwater_framebuffer_bytes {}\n",
+                1920 * 1080 * 4
+            )),
+            "{response}"
+        );
+
+        // The metrics follow resizes of the canvas (e.g. on SIGHUP) with the next statistics
+        fb.resize(3840, 1080);
+        statistics_information_tx
+            .send(StatisticsInformationEvent::default())
+            .unwrap();
+        let mut response = get(addr, "/metrics").await;
+        // The exporter might not have seen the statistics yet
+        while !response.contains("\nbreakwater_framebuffer_width 3840\n") {
+            tokio::task::yield_now().await;
+            response = get(addr, "/metrics").await;
+        }
+        assert!(
+            response.contains(&format!(
+                "\nbreakwater_framebuffer_bytes {}\n",
+                3840 * 1080 * 4
+            )),
+            "{response}"
+        );
+
+        exporter_thread.abort();
+    }
+
+    #[tokio::test]
+    async fn test_ip_metrics() {
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+        let mut exporter = PrometheusExporter::new(
+            "127.0.0.1:0",
+            Arc::new(ResizableFrameBuffer::new(SimpleFrameBuffer::new(640, 480))),
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+
+        exporter.update_metrics(StatisticsInformationEvent {
+            ips: 3,
+            legacy_ips: 1,
+            ..Default::default()
+        });
+
+        let mut metrics = Vec::new();
+        TextEncoder::new()
+            .encode(&exporter.registry.gather(), &mut metrics)
+            .unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(metrics.contains("\nbreakwater_ips 3\n"), "{metrics}");
+        assert!(metrics.contains("\nbreakwater_legacy_ips 1\n"), "{metrics}");
+    }
+
+    #[tokio::test]
+    async fn test_top_ips_metric_drops_previous_top_ips() {
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+        let mut exporter = PrometheusExporter::new(
+            "127.0.0.1:0",
+            Arc::new(ResizableFrameBuffer::new(SimpleFrameBuffer::new(640, 480))),
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+
+        for top_ip in ["10.0.0.1", "10.0.0.2"] {
+            exporter.update_metrics(StatisticsInformationEvent {
+                top_ips_by_bytes_per_s: vec![(top_ip.parse().unwrap(), 42)],
+                ..Default::default()
+            });
+        }
+
+        let mut metrics = Vec::new();
+        TextEncoder::new()
+            .encode(&exporter.registry.gather(), &mut metrics)
+            .unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(
+            metrics.contains("\nbreakwater_top_ips_bytes_per_s{ip=\"10.0.0.2\"} 42\n"),
+            "{metrics}"
+        );
+        assert!(!metrics.contains("10.0.0.1"), "{metrics}");
+    }
+
+    #[tokio::test]
+    async fn test_healthz_fails_without_statistics() {
+        let (statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+        let mut exporter = PrometheusExporter::new(
+            "127.0.0.1:0",
+            Arc::new(ResizableFrameBuffer::new(SimpleFrameBuffer::new(640, 480))),
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+        let addr = exporter.local_addr();
+        drop(statistics_information_tx);
+        let exporter_thread = tokio::spawn(async move { exporter.run().await });
+
+        let mut response = get(addr, "/healthz").await;
+        while response.starts_with("HTTP/1.1 200 OK\r\n") {
+            tokio::task::yield_now().await;
+            response = get(addr, "/healthz").await;
+        }
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.ends_with("statistics channel closed\n"),
+            "{response}"
+        );
+
+        exporter_thread.abort();
+    }
 }
@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use breakwater_parser::{ResizableFrameBuffer, Resize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read resize file {resize_file:?}"))]
+    ReadResizeFile {
+        source: std::io::Error,
+        resize_file: String,
+    },
+
+    #[snafu(display("Invalid canvas size {size:?}, expected e.g. \"1920x1080\""))]
+    InvalidCanvasSize { size: String },
+
+    #[snafu(display(
+        "The canvas size {width}x{height} must not be smaller than the logical size {logical_width}x{logical_height}"
+    ))]
+    SmallerThanLogicalSize {
+        width: usize,
+        height: usize,
+        logical_width: usize,
+        logical_height: usize,
+    },
+}
+
+/// Resizes the canvas to the size read from the resize file and returns the new size. The canvas must stay at least as
+/// large as the logical size (if any), as the parsers only check the logical bounds.
+pub fn resize_canvas<FB: Resize>(
+    fb: &ResizableFrameBuffer<FB>,
+    resize_file: impl AsRef<Path>,
+    logical_width: Option<usize>,
+    logical_height: Option<usize>,
+) -> Result<(usize, usize), Error> {
+    let (width, height) = read_canvas_size(resize_file)?;
+    let (logical_width, logical_height) = (logical_width.unwrap_or(0), logical_height.unwrap_or(0));
+    ensure!(
+        width >= logical_width && height >= logical_height,
+        SmallerThanLogicalSizeSnafu {
+            width,
+            height,
+            logical_width,
+            logical_height,
+        }
+    );
+
+    fb.resize(width, height);
+    Ok((width, height))
+}
+
+/// Reads the size the canvas should be resized to from the given file, which contains `<width>x<height>`
+fn read_canvas_size(resize_file: impl AsRef<Path>) -> Result<(usize, usize), Error> {
+    let resize_file = resize_file.as_ref();
+    let size = std::fs::read_to_string(resize_file).context(ReadResizeFileSnafu {
+        resize_file: resize_file.display().to_string(),
+    })?;
+
+    parse_canvas_size(size.trim()).context(InvalidCanvasSizeSnafu { size: size.trim() })
+}
+
+fn parse_canvas_size(size: &str) -> Option<(usize, usize)> {
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::{FrameBuffer, SimpleFrameBuffer};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("1920x1080", Some((1920, 1080)))]
+    #[case("1x1", Some((1, 1)))]
+    #[case("0x1080", None)]
+    #[case("1920x0", None)]
+    #[case("1920 1080", None)]
+    #[case("1920x", None)]
+    #[case("-1920x1080", None)]
+    fn test_parse_canvas_size(#[case] size: &str, #[case] expected: Option<(usize, usize)>) {
+        assert_eq!(parse_canvas_size(size), expected);
+    }
+
+    #[test]
+    fn test_read_canvas_size() {
+        let resize_file = std::env::temp_dir().join("breakwater-test-resize-file");
+        std::fs::write(&resize_file, "800x600\n").unwrap();
+        assert_eq!(read_canvas_size(&resize_file).unwrap(), (800, 600));
+
+        std::fs::write(&resize_file, "large\n").unwrap();
+        assert!(read_canvas_size(&resize_file).is_err());
+
+        std::fs::remove_file(resize_file).unwrap();
+        assert!(read_canvas_size("/does/not/exist").is_err());
+    }
+
+    #[test]
+    fn test_resize_canvas() {
+        let fb = ResizableFrameBuffer::new(SimpleFrameBuffer::new(640, 480));
+        let resize_file = std::env::temp_dir().join("breakwater-test-resize-canvas");
+
+        std::fs::write(&resize_file, "320x200").unwrap();
+        assert!(resize_canvas(&fb, &resize_file, Some(400), None).is_err());
+        assert_eq!(fb.load().get_width(), 640);
+
+        assert_eq!(
+            resize_canvas(&fb, &resize_file, Some(320), Some(200)).unwrap(),
+            (320, 200)
+        );
+        assert_eq!((fb.load().get_width(), fb.load().get_height()), (320, 200));
+
+        std::fs::remove_file(resize_file).unwrap();
+    }
+}
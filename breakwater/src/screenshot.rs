@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use breakwater_parser::FrameBuffer;
+use chrono::Local;
+use image::RgbImage;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The framebuffer size {width}x{height} does not fit into an image"))]
+    InvalidImageSize { width: usize, height: usize },
+
+    #[snafu(display("Failed to write screenshot to {screenshot_file:?}"))]
+    WriteScreenshot {
+        source: image::ImageError,
+        screenshot_file: PathBuf,
+    },
+}
+
+/// Saves the current canvas as PNG into the given folder and returns the path of the written file. The file location
+/// will be `<folder>/pixelflut_screenshot_{timestamp}.png`.
+pub fn save_screenshot<FB: FrameBuffer>(fb: &FB, folder: &str) -> Result<PathBuf, Error> {
+    let screenshot_file = PathBuf::from(format!(
+        "{folder}/pixelflut_screenshot_{}.png",
+        Local::now().format("%Y-%m-%d_%H-%M-%S%.3f")
+    ));
+
+    let rgb = fb
+        .as_rgb0_bytes()
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect::<Vec<_>>();
+    let image = RgbImage::from_raw(fb.get_width() as u32, fb.get_height() as u32, rgb).context(
+        InvalidImageSizeSnafu {
+            width: fb.get_width(),
+            height: fb.get_height(),
+        },
+    )?;
+
+    image.save(&screenshot_file).context(WriteScreenshotSnafu {
+        screenshot_file: screenshot_file.clone(),
+    })?;
+
+    Ok(screenshot_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::{Packed24FrameBuffer, SimpleFrameBuffer};
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn test_save_screenshot() {
+        assert_screenshot_matches(&SimpleFrameBuffer::new(4, 3), "breakwater-test-screenshot");
+    }
+
+    /// The framebuffer only stores 3 bytes per pixel, which are converted to rgb0 first
+    #[test]
+    fn test_save_screenshot_of_packed24_framebuffer() {
+        assert_screenshot_matches(
+            &Packed24FrameBuffer::new(4, 3),
+            "breakwater-test-screenshot-packed24",
+        );
+    }
+
+    fn assert_screenshot_matches(fb: &impl FrameBuffer, folder: &str) {
+        fb.set(0, 0, 0x0000_00ff);
+        fb.set(3, 2, 0x0056_3412);
+
+        let folder = std::env::temp_dir().join(folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        let screenshot_file = save_screenshot(fb, folder.to_str().unwrap()).unwrap();
+
+        assert_eq!(screenshot_file.extension().unwrap(), "png");
+        let image = image::open(&screenshot_file).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (4, 3));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0xff, 0x00, 0x00]));
+        assert_eq!(image.get_pixel(3, 2), &Rgb([0x12, 0x34, 0x56]));
+        assert_eq!(image.get_pixel(1, 1), &Rgb([0x00, 0x00, 0x00]));
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_save_screenshot_to_missing_folder() {
+        let fb = SimpleFrameBuffer::new(4, 3);
+        assert!(save_screenshot(&fb, "/does/not/exist").is_err());
+    }
+}
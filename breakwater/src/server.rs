@@ -1,34 +1,75 @@
 use std::alloc;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::{cmp::min, net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    cmp::min,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use breakwater_parser::{FrameBuffer, OriginalParser, Parser};
+use breakwater_parser::{
+    CanvasRotation, FrameBuffer, OriginalParser, Parser, ResizableFrameBuffer,
+    WriteProtectedRegion, RESPONSE_FLUSH_THRESHOLD,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
 use log::{debug, info, warn};
 use memadvise::{Advice, MemAdviseError};
-use snafu::{ResultExt, Snafu};
+use snafu::{OptionExt, ResultExt, Snafu};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     sync::mpsc,
-    time::Instant,
+    task::JoinSet,
+    time::{timeout, Instant},
 };
 
-use crate::statistics::StatisticsEvent;
-
-const CONNECTION_DENIED_TEXT: &[u8] = b"Connection denied as connection limit is reached";
+use crate::{
+    cli_args::{DEFAULT_NETWORK_BUFFER_SIZE, DEFAULT_RESPONSE_BUFFER_SIZE},
+    ip_filter::IpFilter,
+    statistics::{BytesReadCounter, BytesReadCounters, IpMap, StatisticsEvent},
+};
 
-// Every client connection spawns a new thread, so we need to limit the number of stat events we send
-const STATISTICS_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+pub const CONNECTION_DENIED_TEXT: &[u8] =
+    b"Connection denied as the server has reached its connection limit, please retry in a few seconds\n";
+const IP_NOT_ALLOWED_TEXT: &[u8] =
+    b"Connection denied as your IP address is not allowed to connect\n";
+pub const CONNECTION_LIMIT_HIT_TEXT: &[u8] =
+    b"Connection closed as the connection has reached its limit of drawn pixels or sent bytes\n";
+
+/// Tells clients exceeding `--connections-per-ip` how many connections they are allowed to open
+pub fn connections_per_ip_denied_text(connections_per_ip: u64) -> String {
+    format!(
+        "Connection denied as the limit of {connections_per_ip} connections per IP address is reached, please close \
+        one of your other connections or retry in a few seconds\n"
+    )
+}
 
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("Failed to resolve listen address {listen_address:?}"))]
+    ResolveListenAddress {
+        source: std::io::Error,
+        listen_address: String,
+    },
+
+    #[snafu(display("The listen address {listen_address:?} does not resolve to any address"))]
+    NoAddressForListenAddress { listen_address: String },
+
     #[snafu(display("Failed to bind to listen address {listen_address:?}"))]
     BindToListenAddress {
         source: std::io::Error,
         listen_address: String,
     },
 
+    #[snafu(display("Failed to get the address the server is bound to"))]
+    GetLocalAddress { source: std::io::Error },
+
     #[snafu(display("Failed to accept new client connection"))]
     AcceptNewClientConnection { source: std::io::Error },
 
@@ -41,39 +82,331 @@ pub enum Error {
     },
 }
 
+/// Limits that are enforced for every single client connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionLimits {
+    /// Maximum number of pixels a connection can draw before it is closed.
+    pub max_pixels: Option<u64>,
+
+    /// Maximum number of bytes a connection can send before it is closed. All bytes exceeding the limit are ignored.
+    pub max_bytes: Option<u64>,
+
+    /// Close a connection in case it hasn't sent any data for the given time.
+    pub idle_timeout: Option<Duration>,
+
+    /// Bytes sent within the given time after the connection was opened don't count toward `max_bytes`, so that clients
+    /// are not penalized for an initial burst.
+    pub warm_up: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    fn is_reached(&self, bytes_read: u64, pixels_drawn: u64) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| bytes_read >= max_bytes)
+            || self
+                .max_pixels
+                .is_some_and(|max_pixels| pixels_drawn >= max_pixels)
+    }
+}
+
+/// How every single client connection is handled, see [`handle_connection`].
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionOptions {
+    /// Size of the buffer the data of the client is read into.
+    pub network_buffer_size: usize,
+
+    /// Number of bytes reserved for the responses upfront, the response buffer grows on demand beyond that.
+    pub response_buffer_size: usize,
+
+    /// The network buffer is aligned to the page size of the system.
+    pub page_size: usize,
+
+    pub limits: ConnectionLimits,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            response_buffer_size: DEFAULT_RESPONSE_BUFFER_SIZE,
+            page_size: page_size::get(),
+            limits: ConnectionLimits::default(),
+        }
+    }
+}
+
+/// Socket options applied to every accepted client connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpOptions {
+    /// Disable Nagle's algorithm, so that responses are sent immediately.
+    pub nodelay: bool,
+
+    /// Send TCP keepalive probes once the connection was idle for the given duration, so that dead clients are noticed.
+    pub keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+    pub fn apply(&self, socket: &TcpStream) -> std::io::Result<()> {
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Options that change how the commands of every client connection are parsed.
+#[derive(Clone, Debug, Default)]
+pub struct ParserOptions {
+    /// Respond with a short error message to commands that could not be parsed.
+    pub strict: bool,
+
+    /// Accept runs of spaces between the tokens of `PX` commands.
+    pub lenient_whitespace: bool,
+
+    /// Respond with an error to `PX` commands containing alpha instead of drawing the pixel opaque.
+    #[cfg(not(feature = "alpha"))]
+    pub reject_alpha: bool,
+
+    /// Respond to reads with `rrggbbaa` instead of `rrggbb`.
+    #[cfg(feature = "alpha")]
+    pub rgba_reads: bool,
+
+    /// Confirm every drawn pixel to the client.
+    pub pixel_command_echo: bool,
+
+    /// Areas clients are not allowed to draw into.
+    pub write_protected_regions: Vec<WriteProtectedRegion>,
+
+    /// Rotation of the canvas clients draw onto relative to the framebuffer.
+    pub canvas_rotation: CanvasRotation,
+
+    /// Size of the canvas clients can draw onto, [`None`] uses the size of the framebuffer.
+    pub logical_width: Option<usize>,
+    pub logical_height: Option<usize>,
+
+    /// Byte that terminates commands in addition to the newline.
+    #[cfg(feature = "custom-separators")]
+    pub command_separator: Option<u8>,
+
+    /// Font used to render `TEXT` commands, [`None`] ignores them.
+    #[cfg(feature = "text-command")]
+    pub font: Option<rusttype::Font<'static>>,
+
+    /// Shared by all connections, the canvas is read-only while it is set.
+    pub maintenance_mode: Arc<AtomicBool>,
+}
+
+/// Number of open connections across all IPs, which can be limited to not run out of file descriptors
+#[derive(Debug, Default)]
+pub struct TotalConnections {
+    open: Arc<AtomicU64>,
+    limit: Option<u64>,
+}
+
+impl TotalConnections {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            open: Arc::default(),
+            limit,
+        }
+    }
+
+    /// Reserves a slot for a new connection, [`None`] in case the limit is reached. The slot is freed once the returned
+    /// guard is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let acquired = match self.limit {
+            Some(limit) => self
+                .open
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                    (open < limit).then_some(open + 1)
+                })
+                .is_ok(),
+            None => {
+                self.open.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        };
+
+        acquired.then(|| ConnectionSlot(Arc::clone(&self.open)))
+    }
+}
+
+/// Counts as open connection until dropped, see [`TotalConnections::try_acquire`]
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicU64>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub struct Server<FB: FrameBuffer> {
-    // listen_address: String,
-    listener: TcpListener,
-    fb: Arc<FB>,
+    listeners: Vec<TcpListener>,
+    local_addrs: Vec<SocketAddr>,
+    /// Connections draw on the current framebuffer, see [`Server::with_resizable_framebuffer`]
+    fb: Arc<ResizableFrameBuffer<FB>>,
     statistics_tx: mpsc::Sender<StatisticsEvent>,
+    bytes_read_counters: Arc<BytesReadCounters>,
     network_buffer_size: usize,
-    connections_per_ip: HashMap<IpAddr, u64>,
+    response_buffer_size: usize,
+    connections_per_ip: IpMap<u64>,
     max_connections_per_ip: Option<u64>,
+    total_connections: TotalConnections,
+    connection_limits: ConnectionLimits,
+    parser_options: ParserOptions,
+    connection_workers: Option<usize>,
+    ip_filter: IpFilter,
+    tcp_options: TcpOptions,
+}
+
+type ConnectionFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+/// Lets a connection follow resizes of the [`ResizableFrameBuffer`]: Before parsing the next chunk of data the parser
+/// switches to the current framebuffer, in case it was resized in the meantime. This way long-running connections
+/// don't keep drawing on a framebuffer nobody shows anymore.
+pub struct ResizingParser<FB: FrameBuffer> {
+    parser: OriginalParser<FB>,
+    fb: Arc<ResizableFrameBuffer<FB>>,
+}
+
+impl<FB: FrameBuffer> ResizingParser<FB> {
+    pub fn new(parser: OriginalParser<FB>, fb: Arc<ResizableFrameBuffer<FB>>) -> Self {
+        Self { parser, fb }
+    }
+}
+
+impl<FB: FrameBuffer> Parser for ResizingParser<FB> {
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        if !self.fb.is_current(self.parser.framebuffer()) {
+            self.parser.set_framebuffer(self.fb.load());
+        }
+        self.parser.parse(buffer, response)
+    }
+
+    fn parser_lookahead(&self) -> usize {
+        self.parser.parser_lookahead()
+    }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.parser.pixels_drawn()
+    }
+
+    fn malformed_commands(&self) -> u64 {
+        self.parser.malformed_commands()
+    }
 }
 
 impl<FB: FrameBuffer + Send + Sync + 'static> Server<FB> {
+    #[allow(clippy::too_many_arguments)]
+    /// Binds to all given listen addresses, clients connecting to any of them draw on the same framebuffer.
+    ///
+    /// With multiple acceptors every listen address is bound multiple times using `SO_REUSEPORT`, so that the kernel
+    /// load-balances the incoming connections across multiple accept loops.
     pub async fn new(
-        listen_address: &str,
+        listen_addresses: &[String],
+        acceptors: usize,
         fb: Arc<FB>,
         statistics_tx: mpsc::Sender<StatisticsEvent>,
+        bytes_read_counters: Arc<BytesReadCounters>,
         network_buffer_size: usize,
         max_connections_per_ip: Option<u64>,
+        connection_limits: ConnectionLimits,
+        parser_options: ParserOptions,
     ) -> Result<Self, Error> {
-        let listener = TcpListener::bind(listen_address)
-            .await
-            .context(BindToListenAddressSnafu { listen_address })?;
-        info!("Started Pixelflut server on {listen_address}");
+        let reuse_port = acceptors > 1;
+        let mut listeners = Vec::with_capacity(listen_addresses.len() * acceptors);
+        let mut local_addrs = Vec::with_capacity(listen_addresses.len());
+        for listen_address in listen_addresses {
+            let listener = bind_listen_address(listen_address, reuse_port).await?;
+            // The listen address can use port 0, in which case the operating system picks a free port
+            let local_addr = listener.local_addr().context(GetLocalAddressSnafu)?;
+            listeners.push(listener);
+
+            // All other acceptors need to use the port picked for the first one
+            for _ in 1..acceptors {
+                listeners.push(
+                    bind_listener(local_addr, reuse_port)
+                        .context(BindToListenAddressSnafu { listen_address })?,
+                );
+            }
+
+            if reuse_port {
+                info!("Started Pixelflut server on {local_addr} with {acceptors} acceptors");
+            } else {
+                info!("Started Pixelflut server on {local_addr}");
+            }
+            local_addrs.push(local_addr);
+        }
 
         Ok(Self {
-            listener,
-            fb,
+            listeners,
+            local_addrs,
+            fb: Arc::new(ResizableFrameBuffer::from_arc(fb)),
             statistics_tx,
+            bytes_read_counters,
             network_buffer_size,
-            connections_per_ip: HashMap::new(),
+            response_buffer_size: DEFAULT_RESPONSE_BUFFER_SIZE,
+            connections_per_ip: IpMap::default(),
             max_connections_per_ip,
+            total_connections: TotalConnections::default(),
+            connection_limits,
+            parser_options,
+            connection_workers: None,
+            ip_filter: IpFilter::default(),
+            tcp_options: TcpOptions::default(),
         })
     }
 
+    /// Handle all connections on the given number of worker tasks instead of spawning a task per connection, see
+    /// [`connection_worker`].
+    pub fn with_connection_workers(mut self, connection_workers: Option<usize>) -> Self {
+        self.connection_workers = connection_workers;
+        self
+    }
+
+    /// Let connections draw on the current framebuffer of the given [`ResizableFrameBuffer`] instead of the framebuffer
+    /// passed to [`Server::new`]. Open connections switch to the resized framebuffer with their next read, see
+    /// [`ResizingParser`].
+    pub fn with_resizable_framebuffer(mut self, fb: Arc<ResizableFrameBuffer<FB>>) -> Self {
+        self.fb = fb;
+        self
+    }
+
+    /// Reserve the given number of bytes for the responses of every connection upfront, see [`handle_connection`].
+    pub fn with_response_buffer_size(mut self, response_buffer_size: usize) -> Self {
+        self.response_buffer_size = response_buffer_size;
+        self
+    }
+
+    /// Deny new connections once the given number of connections (across all IPs) is open
+    pub fn with_max_total_connections(mut self, max_total_connections: Option<u64>) -> Self {
+        self.total_connections = TotalConnections::new(max_total_connections);
+        self
+    }
+
+    /// Only accept connections from the IP addresses allowed by the given filter, see [`IpFilter`].
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Configure every accepted client connection using the given socket options, see [`TcpOptions`].
+    pub fn with_tcp_options(mut self, tcp_options: TcpOptions) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
+
+    /// The addresses the server is actually bound to, in the order of the listen addresses. Contain the port picked by
+    /// the operating system in case a listen address used port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
     pub async fn start(&mut self) -> Result<(), Error> {
         let (connection_dropped_tx, mut connection_dropped_rx) =
             mpsc::unbounded_channel::<IpAddr>();
@@ -81,13 +414,40 @@ impl<FB: FrameBuffer + Send + Sync + 'static> Server<FB> {
 
         let page_size = page_size::get();
         debug!("System has a page size of {page_size} bytes");
+        let connection_options = ConnectionOptions {
+            network_buffer_size: self.network_buffer_size,
+            response_buffer_size: self.response_buffer_size,
+            page_size,
+            limits: self.connection_limits,
+        };
 
-        loop {
-            let (mut socket, socket_addr) = self
-                .listener
-                .accept()
-                .await
-                .context(AcceptNewClientConnectionSnafu)?;
+        let workers_tx = (0..self.connection_workers.unwrap_or_default())
+            .map(|_| {
+                let (worker_tx, worker_rx) = mpsc::unbounded_channel::<ConnectionFuture>();
+                tokio::spawn(connection_worker(worker_rx));
+                worker_tx
+            })
+            .collect::<Vec<_>>();
+        if !workers_tx.is_empty() {
+            info!("Handling connections on {} workers", workers_tx.len());
+        }
+        let mut next_worker = 0;
+
+        // Every listener accepts on its own task and hands the connections over to us. The tasks are aborted once the
+        // set is dropped, e.g. because the server is stopped.
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(64);
+        let mut accept_loops = JoinSet::new();
+        for listener in std::mem::take(&mut self.listeners) {
+            accept_loops.spawn(accept_loop(listener, accepted_tx.clone()));
+        }
+        drop(accepted_tx);
+
+        while let Some(accepted) = accepted_rx.recv().await {
+            let (mut socket, socket_addr) = accepted.context(AcceptNewClientConnectionSnafu)?;
+            // The connection works fine without the options, so there is no need to drop it
+            if let Err(err) = self.tcp_options.apply(&socket) {
+                warn!("Failed to set socket options for connection from {socket_addr}: {err}");
+            }
 
             // If connections are unlimited, will execute one try_recv per new connection
             while let Ok(ip) = connection_dropped_rx.try_recv() {
@@ -104,54 +464,205 @@ impl<FB: FrameBuffer + Send + Sync + 'static> Server<FB> {
             // Extracting the embedded information here, so we get the real (TM) address
             let ip = socket_addr.ip().to_canonical();
 
-            if let Some(limit) = self.max_connections_per_ip {
-                let current_connections = self.connections_per_ip.entry(ip).or_default();
-                if *current_connections < limit {
-                    *current_connections += 1;
-                } else {
-                    self.statistics_tx
-                        .send(StatisticsEvent::ConnectionDenied { ip })
-                        .await
-                        .context(WriteToStatisticsChannelSnafu)?;
-
-                    // Only best effort, it's ok if this message get's missed
-                    let _ = socket.write_all(CONNECTION_DENIED_TEXT).await;
-                    // This can error if a connection is dropped prematurely, which is totally fine
-                    let _ = socket.shutdown().await;
-                    continue;
-                }
+            let Some(connection_slot) = self.admit_connection(&mut socket, ip).await? else {
+                continue;
             };
 
-            let fb_for_thread = Arc::clone(&self.fb);
-            let statistics_tx_for_thread = self.statistics_tx.clone();
-            let network_buffer_size = self.network_buffer_size;
-            let connection_dropped_tx_clone = connection_dropped_tx.clone();
-            tokio::spawn(async move {
-                handle_connection(
-                    socket,
-                    ip,
-                    fb_for_thread,
-                    statistics_tx_for_thread,
-                    page_size,
-                    network_buffer_size,
-                    connection_dropped_tx_clone,
+            // Not using `ParserImplementation` to avoid the dynamic dispatch.
+            let parser = OriginalParser::new(self.fb.load())
+                .with_strict(self.parser_options.strict)
+                .with_lenient_whitespace(self.parser_options.lenient_whitespace)
+                .with_pixel_command_echo(self.parser_options.pixel_command_echo)
+                .with_write_protected_regions(self.parser_options.write_protected_regions.clone())
+                .with_canvas_rotation(self.parser_options.canvas_rotation)
+                .with_logical_size(
+                    self.parser_options.logical_width,
+                    self.parser_options.logical_height,
                 )
-                .await
-            });
+                .with_connection_limits(
+                    self.connection_limits.max_pixels,
+                    self.connection_limits.max_bytes,
+                )
+                .with_maintenance_mode(Arc::clone(&self.parser_options.maintenance_mode));
+            #[cfg(feature = "custom-separators")]
+            let parser = parser.with_command_separator(self.parser_options.command_separator);
+            #[cfg(not(feature = "alpha"))]
+            let parser = parser.with_reject_alpha(self.parser_options.reject_alpha);
+            #[cfg(feature = "alpha")]
+            let parser = parser.with_rgba_reads(self.parser_options.rgba_reads);
+            #[cfg(feature = "text-command")]
+            let parser = parser.with_font(self.parser_options.font.clone());
+            let parser = ResizingParser::new(parser, Arc::clone(&self.fb));
+            let connection = handle_connection(
+                socket,
+                socket_addr,
+                parser,
+                self.statistics_tx.clone(),
+                self.bytes_read_counters.register(ip),
+                connection_options,
+                connection_dropped_tx.clone(),
+            );
+            let connection = async move {
+                let result = connection.await;
+                drop(connection_slot);
+                result
+            };
+
+            if workers_tx.is_empty() {
+                tokio::spawn(connection);
+            } else {
+                // Round-robin is good enough, as the connections are typically equally busy
+                // The workers only stop once we drop the senders, so sending can not fail
+                let _ = workers_tx[next_worker].send(Box::pin(connection));
+                next_worker = (next_worker + 1) % workers_tx.len();
+            }
         }
+
+        // Only happens without any listener
+        Ok(())
+    }
+
+    /// Checks whether the client is allowed to connect, otherwise tells it why its connection is denied. The returned
+    /// slot needs to be kept as long as the connection is open.
+    pub async fn admit_connection(
+        &mut self,
+        socket: &mut (impl AsyncWriteExt + Unpin),
+        ip: IpAddr,
+    ) -> Result<Option<ConnectionSlot>, Error> {
+        if !self.ip_filter.is_allowed(ip) {
+            self.deny_connection(socket, ip, IP_NOT_ALLOWED_TEXT)
+                .await?;
+            return Ok(None);
+        }
+
+        let Some(connection_slot) = self.total_connections.try_acquire() else {
+            self.deny_connection(socket, ip, CONNECTION_DENIED_TEXT)
+                .await?;
+            return Ok(None);
+        };
+
+        if let Some(limit) = self.max_connections_per_ip {
+            let current_connections = self.connections_per_ip.entry(ip).or_default();
+            if *current_connections < limit {
+                *current_connections += 1;
+            } else {
+                self.deny_connection(socket, ip, connections_per_ip_denied_text(limit).as_bytes())
+                    .await?;
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(connection_slot))
+    }
+
+    /// Tells the client why its connection is denied and closes it
+    async fn deny_connection(
+        &self,
+        socket: &mut (impl AsyncWriteExt + Unpin),
+        ip: IpAddr,
+        reason: &[u8],
+    ) -> Result<(), Error> {
+        self.statistics_tx
+            .send(StatisticsEvent::ConnectionDenied { ip })
+            .await
+            .context(WriteToStatisticsChannelSnafu)?;
+
+        // Only best effort, it's ok if this message get's missed
+        let _ = socket.write_all(reason).await;
+        // This can error if a connection is dropped prematurely, which is totally fine
+        let _ = socket.shutdown().await;
+
+        Ok(())
     }
 }
 
-pub async fn handle_connection<FB: FrameBuffer>(
+/// Binds to the first address the listen address resolves to that can be bound, same as [`TcpListener::bind`]
+async fn bind_listen_address(listen_address: &str, reuse_port: bool) -> Result<TcpListener, Error> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(listen_address)
+        .await
+        .context(ResolveListenAddressSnafu { listen_address })?
+    {
+        match bind_listener(addr, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    let err = last_err.context(NoAddressForListenAddressSnafu { listen_address })?;
+    Err(err).context(BindToListenAddressSnafu { listen_address })
+}
+
+/// Binds a listener to the given address. With `reuse_port` other listeners (also using `reuse_port`) can bind to the
+/// same address, the kernel distributes the incoming connections across them.
+pub fn bind_listener(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Same as tokio does, so that restarting the server does not fail because of connections in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts connections on the given listener until accepting fails or nobody is interested in the connections anymore
+async fn accept_loop(
+    listener: TcpListener,
+    accepted_tx: mpsc::Sender<std::io::Result<(TcpStream, SocketAddr)>>,
+) {
+    loop {
+        let accepted = listener.accept().await;
+        let failed = accepted.is_err();
+        if accepted_tx.send(accepted).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Drives all connections it receives concurrently on a single task, so that we don't need to spawn a task per
+/// connection. Returns once the sender got dropped and all connections are closed.
+pub async fn connection_worker<F: Future<Output = Result<(), Error>>>(
+    mut new_connections_rx: mpsc::UnboundedReceiver<F>,
+) {
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            connection = new_connections_rx.recv() => match connection {
+                Some(connection) => connections.push(connection),
+                None => break,
+            },
+            // Same as with dedicated tasks, there is nobody we could report the error of a single connection to
+            Some(_) = connections.next(), if !connections.is_empty() => {}
+        }
+    }
+
+    while connections.next().await.is_some() {}
+}
+
+pub async fn handle_connection(
     mut stream: impl AsyncReadExt + AsyncWriteExt + Send + Unpin,
-    ip: IpAddr,
-    fb: Arc<FB>,
+    peer_addr: SocketAddr,
+    mut parser: impl Parser,
     statistics_tx: mpsc::Sender<StatisticsEvent>,
-    page_size: usize,
-    network_buffer_size: usize,
+    bytes_read_counter: BytesReadCounter,
+    options: ConnectionOptions,
     connection_dropped_tx: Option<mpsc::UnboundedSender<IpAddr>>,
 ) -> Result<(), Error> {
-    debug!("Handling connection from {ip}");
+    let ConnectionOptions {
+        network_buffer_size,
+        response_buffer_size,
+        page_size,
+        limits: connection_limits,
+    } = options;
+    // The statistics are keyed by the (canonical) IP only, so that the number of entries stays bounded. The logs
+    // contain the port as well, e.g. to tell apart multiple clients behind the same NAT.
+    let ip = peer_addr.ip().to_canonical();
+    debug!("Handling connection from {peer_addr}");
+    let connection_start = Instant::now();
 
     statistics_tx
         .send(StatisticsEvent::ConnectionCreated { ip })
@@ -161,7 +672,8 @@ pub async fn handle_connection<FB: FrameBuffer>(
     let layout = alloc::Layout::from_size_align(network_buffer_size, page_size).unwrap();
     let ptr = unsafe { alloc::alloc(layout) };
     let buffer = unsafe { std::slice::from_raw_parts_mut(ptr, network_buffer_size) };
-    let mut response_buf = Vec::new();
+    // Cleared after every write, so that its capacity is reused for the whole connection
+    let mut response_buf = Vec::with_capacity(response_buffer_size);
 
     if let Err(err) = memadvise::advise(buffer.as_ptr() as _, buffer.len(), Advice::Sequential) {
         // [`MemAdviseError`] does not implement Debug...
@@ -177,42 +689,50 @@ pub async fn handle_connection<FB: FrameBuffer>(
     // Number bytes left over **on the first bytes of the buffer** from the previous loop iteration
     let mut leftover_bytes_in_buffer = 0;
 
-    // Not using `ParserImplementation` to avoid the dynamic dispatch.
-    // let mut parser = ParserImplementation::Simple(SimpleParser::new(fb));
-    let mut parser = OriginalParser::new(fb);
     let parser_lookahead = parser.parser_lookahead();
 
-    // If we send e.g. an StatisticsEvent::BytesRead for every time we read something from the socket the statistics thread would go crazy.
-    // Instead we bulk the statistics and send them pre-aggregated.
-    let mut last_statistics = Instant::now();
-    let mut statistics_bytes_read: u64 = 0;
+    // Total number of bytes read from this connection, used to enforce the connection limits
+    let mut connection_bytes_read: u64 = 0;
+    let mut reported_malformed_commands = 0;
+    let warm_up_end = connection_limits
+        .warm_up
+        .map(|warm_up| Instant::now() + warm_up);
 
+    // Fill the buffer up with new data from the socket
+    // If there are any bytes left over from the previous loop iteration leave them as is and put the new data behind
     loop {
-        // Fill the buffer up with new data from the socket
-        // If there are any bytes left over from the previous loop iteration leave them as is and put the new data behind
-        let Ok(bytes_read) = stream
-            .read(&mut buffer[leftover_bytes_in_buffer..network_buffer_size - parser_lookahead])
-            .await
-        else {
+        let read = stream
+            .read(&mut buffer[leftover_bytes_in_buffer..network_buffer_size - parser_lookahead]);
+        let read = match connection_limits.idle_timeout {
+            Some(idle_timeout) => match timeout(idle_timeout, read).await {
+                Ok(read) => read,
+                Err(_) => {
+                    debug!("Closing connection from {peer_addr}, as it has not sent any data for {idle_timeout:?}");
+                    break;
+                }
+            },
+            None => read.await,
+        };
+        let Ok(bytes_read) = read else {
             break;
         };
 
-        statistics_bytes_read += bytes_read as u64;
-        if last_statistics.elapsed() > STATISTICS_REPORT_INTERVAL {
-            statistics_tx
-                // We use a blocking call here as we want to process the stats.
-                // Otherwise the stats will lag behind resulting in weird spikes in bytes/s statistics.
-                // As the statistics calculation should be trivial let's wait for it
-                .send(StatisticsEvent::BytesRead {
-                    ip,
-                    bytes: statistics_bytes_read,
-                })
-                .await
-                .context(WriteToStatisticsChannelSnafu)?;
-            last_statistics = Instant::now();
-            statistics_bytes_read = 0;
+        // Bytes exceeding the byte limit of the connection are ignored, unless the connection is still warming up
+        let warming_up = warm_up_end.is_some_and(|warm_up_end| Instant::now() < warm_up_end);
+        let bytes_read = match connection_limits.max_bytes {
+            Some(max_bytes) if !warming_up => min(
+                bytes_read as u64,
+                max_bytes.saturating_sub(connection_bytes_read),
+            ) as usize,
+            _ => bytes_read,
+        };
+        if !warming_up {
+            connection_bytes_read += bytes_read as u64;
         }
 
+        // Only an atomic add, the statistics task collects the counters periodically
+        bytes_read_counter.add(bytes_read as u64);
+
         let data_end = leftover_bytes_in_buffer + bytes_read;
         if bytes_read == 0 {
             if leftover_bytes_in_buffer == 0 {
@@ -231,16 +751,33 @@ pub async fn handle_connection<FB: FrameBuffer>(
                 *i = 0;
             }
 
-            let last_byte_parsed =
-                parser.parse(&buffer[..data_end + parser_lookahead], &mut response_buf);
+            // The parser stops early once the response reached RESPONSE_FLUSH_THRESHOLD, so that the response does not
+            // grow unbounded for read-heavy clients. In this case we write the response and continue where it stopped.
+            let mut parse_start = 0;
+            let last_byte_parsed = loop {
+                let parsed = parser.parse(
+                    &buffer[parse_start..data_end + parser_lookahead],
+                    &mut response_buf,
+                );
+                let stopped_early = response_buf.len() >= RESPONSE_FLUSH_THRESHOLD;
 
-            if !response_buf.is_empty() {
-                stream
-                    .write_all(&response_buf)
-                    .await
-                    .context(WriteToClientConnectionSnafu)?;
-                response_buf.clear();
-            }
+                if !response_buf.is_empty() {
+                    stream
+                        .write_all(&response_buf)
+                        .await
+                        .context(WriteToClientConnectionSnafu)?;
+                    response_buf.clear();
+                }
+
+                if parse_start > 0 && parsed == 0 {
+                    // Nothing left to parse after the previous (early stopped) call
+                    break parse_start - 1;
+                }
+                if !stopped_early {
+                    break parse_start + parsed;
+                }
+                parse_start += parsed + 1;
+            };
 
             // IMPORTANT: We have to subtract 1 here, as e.g. we have "PX 0 0\n" data_end is 7 and parser_state.last_byte_parsed is 6.
             // This happens, because last_byte_parsed is an index starting at 0, so index 6 is from an array of length 7
@@ -257,7 +794,30 @@ pub async fn handle_connection<FB: FrameBuffer>(
 
             // There is no need to leave anything longer than a command can take
             // This prevents malicious clients from sending gibberish and the buffer not getting drained
-            leftover_bytes_in_buffer = min(leftover_bytes_in_buffer, parser_lookahead);
+            if leftover_bytes_in_buffer > parser_lookahead {
+                let discarded_bytes = leftover_bytes_in_buffer - parser_lookahead;
+                debug!("Discarding {discarded_bytes} bytes from {peer_addr}, as they did not form a complete command");
+                statistics_tx
+                    .send(StatisticsEvent::BytesDiscarded {
+                        ip,
+                        bytes: discarded_bytes as u64,
+                    })
+                    .await
+                    .context(WriteToStatisticsChannelSnafu)?;
+                leftover_bytes_in_buffer = parser_lookahead;
+            }
+
+            let malformed_commands = parser.malformed_commands();
+            if malformed_commands > reported_malformed_commands {
+                statistics_tx
+                    .send(StatisticsEvent::MalformedCommands {
+                        ip,
+                        commands: malformed_commands - reported_malformed_commands,
+                    })
+                    .await
+                    .context(WriteToStatisticsChannelSnafu)?;
+                reported_malformed_commands = malformed_commands;
+            }
 
             if leftover_bytes_in_buffer > 0 {
                 // We need to move the leftover bytes to the beginning of the buffer so that the next loop iteration con work on them
@@ -267,10 +827,26 @@ pub async fn handle_connection<FB: FrameBuffer>(
                 );
             }
         }
+
+        // The limits are checked once per read, so a connection can draw a few pixels more than the limit
+        if connection_limits.is_reached(connection_bytes_read, parser.pixels_drawn()) {
+            debug!("Closing connection from {peer_addr}, as it has reached the connection limits");
+            statistics_tx
+                .send(StatisticsEvent::ConnectionLimitHit { ip })
+                .await
+                .context(WriteToStatisticsChannelSnafu)?;
+
+            // Only best effort, it's ok if this message get's missed
+            let _ = stream.write_all(CONNECTION_LIMIT_HIT_TEXT).await;
+            break;
+        }
     }
 
     statistics_tx
-        .send(StatisticsEvent::ConnectionClosed { ip })
+        .send(StatisticsEvent::ConnectionClosed {
+            ip,
+            duration: connection_start.elapsed(),
+        })
         .await
         .context(WriteToStatisticsChannelSnafu)?;
 
@@ -0,0 +1,118 @@
+/// Reveals at most a given number of changed pixels of the framebuffer per frame, see `--draw-budget-per-frame`.
+///
+/// Clients often draw a whole image at once, which shows up within a single frame of a recording and looks choppy.
+/// Instead, the recording sinks look at a copy of the framebuffer, which catches up with the framebuffer by at most
+/// `budget` pixels per frame. This spreads bursts of writes over multiple frames.
+///
+/// The pixels are passed as `rgb0` bytes (see [`breakwater_parser::FrameBuffer::as_rgb0_bytes`]), so that it works
+/// regardless of how the framebuffer stores them.
+pub struct DrawBudget {
+    budget: usize,
+    revealed: Vec<u8>,
+
+    /// Where the next search for changed pixels starts, so that all areas of the screen make progress, even if the
+    /// budget is exhausted in every frame
+    cursor: usize,
+}
+
+impl DrawBudget {
+    /// Starts with the given `rgb0` pixels already revealed
+    pub fn new(budget: usize, pixels: &[u8]) -> Self {
+        Self {
+            budget,
+            revealed: pixels.to_vec(),
+            cursor: 0,
+        }
+    }
+
+    /// Reveals at most `budget` pixels that differ between the `rgb0` `pixels` and the revealed pixels. Returns the
+    /// revealed pixels as `rgb0` bytes.
+    pub fn reveal(&mut self, pixels: &[u8]) -> &[u8] {
+        assert_eq!(
+            pixels.len(),
+            self.revealed.len(),
+            "The framebuffer must not change its size"
+        );
+
+        let len = self.revealed.len() / 4;
+        let mut remaining = self.budget;
+        let mut index = self.cursor;
+        for _ in 0..len {
+            if remaining == 0 {
+                break;
+            }
+
+            let pixel = index * 4..index * 4 + 4;
+            if self.revealed[pixel.clone()] != pixels[pixel.clone()] {
+                self.revealed[pixel.clone()].copy_from_slice(&pixels[pixel]);
+                remaining -= 1;
+                self.cursor = (index + 1) % len;
+            }
+
+            index += 1;
+            if index == len {
+                index = 0;
+            }
+        }
+
+        &self.revealed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_bytes(pixels: &[u32]) -> Vec<u8> {
+        pixels
+            .iter()
+            .flat_map(|pixel| pixel.to_ne_bytes())
+            .collect()
+    }
+
+    /// Passes the pixels as rgb0 bytes and returns the revealed ones as pixels again, so that the tests stay readable
+    fn reveal(budget: &mut DrawBudget, pixels: &[u32]) -> Vec<u32> {
+        budget
+            .reveal(&as_bytes(pixels))
+            .chunks_exact(4)
+            .map(|pixel| u32::from_ne_bytes(pixel.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_burst_is_spread_over_frames() {
+        let mut budget = DrawBudget::new(3, &as_bytes(&[0; 8]));
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert_eq!(reveal(&mut budget, &pixels), [1, 2, 3, 0, 0, 0, 0, 0]);
+        assert_eq!(reveal(&mut budget, &pixels), [1, 2, 3, 4, 5, 6, 0, 0]);
+        assert_eq!(reveal(&mut budget, &pixels), pixels);
+        // Nothing changed any more
+        assert_eq!(reveal(&mut budget, &pixels), pixels);
+    }
+
+    #[test]
+    fn test_unchanged_pixels_do_not_count() {
+        let mut budget = DrawBudget::new(2, &as_bytes(&[0, 0, 3, 4, 0, 0]));
+
+        assert_eq!(reveal(&mut budget, &[1, 2, 3, 4, 5, 6]), [1, 2, 3, 4, 0, 0]);
+        assert_eq!(reveal(&mut budget, &[1, 2, 3, 4, 5, 6]), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_search_wraps_around() {
+        let mut budget = DrawBudget::new(2, &as_bytes(&[0; 4]));
+
+        // Reveals the first two pixels and continues after them in the next frame
+        assert_eq!(reveal(&mut budget, &[1, 1, 0, 0]), [1, 1, 0, 0]);
+        // The search continues where it stopped, so the last pixel is revealed before the second one
+        assert_eq!(reveal(&mut budget, &[2, 2, 0, 2]), [2, 1, 0, 2]);
+        assert_eq!(reveal(&mut budget, &[2, 2, 0, 2]), [2, 2, 0, 2]);
+    }
+
+    #[test]
+    fn test_large_budget_reveals_everything() {
+        let mut budget = DrawBudget::new(usize::MAX, &as_bytes(&[0; 4]));
+        assert_eq!(reveal(&mut budget, &[1, 2, 3, 4]), [1, 2, 3, 4]);
+    }
+}
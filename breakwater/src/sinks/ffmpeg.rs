@@ -1,18 +1,44 @@
-use std::{process::Stdio, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use breakwater_parser::FrameBuffer;
 use chrono::Local;
-use log::debug;
-use snafu::{ResultExt, Snafu};
+use log::{debug, warn};
+use snafu::{ensure, ResultExt, Snafu};
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
     process::Command,
     sync::{broadcast, mpsc},
-    time,
+    time::{self, Instant},
+};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        draw_budget::DrawBudget,
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
 };
 
-use crate::{sinks::DisplaySink, statistics::StatisticsInformationEvent};
+/// Unchanged frames are skipped, but still sent at least this often, so that streams don't stall on a static canvas
+const MAX_UNCHANGED_FRAMES_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waiting time before the first restart of a died ffmpeg process, it's doubled for every further restart
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+/// In case ffmpeg ran at least this long before dying, it's considered a new failure and the restarts start over
+const RESET_RESTARTS_AFTER: Duration = Duration::from_secs(60);
+
+/// Number of lines of the ffmpeg stderr that are logged once ffmpeg died
+const FFMPEG_STDERR_TAIL_LINES: usize = 20;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -24,100 +50,161 @@ pub enum Error {
 
     #[snafu(display("Failed to write new data to ffmpeg via stdout"))]
     WriteDataToFfmpeg { source: std::io::Error },
+
+    #[snafu(display(
+        "ffmpeg died unexpectedly and was already restarted {restarts} times, giving up"
+    ))]
+    FfmpegDied { restarts: u32 },
+
+    #[snafu(display("Failed to write to statistics channel"))]
+    WriteToStatisticsChannel {
+        source: mpsc::error::SendError<StatisticsEvent>,
+    },
+}
+
+/// Where a [`FfmpegSink`] sends the video to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FfmpegOutput {
+    /// Stream to the given rtmp address, see `--rtmp-address`
+    Rtmp { rtmp_address: String },
+    /// Dump into a new file in the given folder, see `--video-save-folder`
+    File { video_save_folder: String },
+}
+
+impl FfmpegOutput {
+    /// All outputs configured in the `cli_args`
+    pub fn from_cli_args(cli_args: &CliArgs) -> Vec<Self> {
+        let rtmp = cli_args
+            .rtmp_address
+            .clone()
+            .map(|rtmp_address| Self::Rtmp { rtmp_address });
+        let file = cli_args
+            .video_save_folder
+            .clone()
+            .map(|video_save_folder| Self::File { video_save_folder });
+        rtmp.into_iter().chain(file).collect()
+    }
 }
 
+/// Pipes the framebuffer into a ffmpeg process, which sends it to a single [`FfmpegOutput`]. In case multiple outputs
+/// are configured, every output gets its own sink (and ffmpeg process), see [`FfmpegSink::new_per_output`].
 pub struct FfmpegSink<FB: FrameBuffer> {
     fb: Arc<FB>,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
     terminate_signal_rx: broadcast::Receiver<()>,
 
-    rtmp_address: Option<String>,
-    video_save_folder: Option<String>,
+    output: FfmpegOutput,
     fps: u32,
+    max_stdin_lag: Duration,
+    draw_budget: Option<DrawBudget>,
+
+    /// The ffmpeg executable, only changed by tests
+    program: String,
+    max_restarts: u32,
+    restart_backoff: Duration,
+}
+
+/// Why [`FfmpegSink::run_ffmpeg`] returned
+enum FfmpegExit {
+    Terminated,
+    Died,
 }
 
 #[async_trait]
 impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for FfmpegSink<FB> {
+    /// Only creates a sink for the first configured output, use [`FfmpegSink::new_per_output`] to get a sink for
+    /// every configured output.
     async fn new(
         fb: Arc<FB>,
-        cli_args: &crate::cli_args::CliArgs,
-        _statistics_tx: mpsc::Sender<crate::statistics::StatisticsEvent>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        statistics_tx: mpsc::Sender<StatisticsEvent>,
         _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
         terminate_signal_rx: broadcast::Receiver<()>,
     ) -> Result<Option<Self>, super::Error> {
-        if cli_args.rtmp_address.is_some() || cli_args.video_save_folder.is_some() {
-            Ok(Some(Self {
-                fb,
-                terminate_signal_rx,
-                rtmp_address: cli_args.rtmp_address.clone(),
-                video_save_folder: cli_args.video_save_folder.clone(),
-                fps: cli_args.fps,
-            }))
-        } else {
-            Ok(None)
-        }
+        Ok(
+            Self::new_per_output(fb, cli_args, fps, statistics_tx, terminate_signal_rx)
+                .into_iter()
+                .next(),
+        )
     }
 
+    /// Restarts ffmpeg with an exponential backoff in case it dies unexpectedly (e.g. because the rtmp server is not
+    /// reachable), up to `--ffmpeg-max-restarts` times.
     async fn run(&mut self) -> Result<(), super::Error> {
-        let mut ffmpeg_args: Vec<String> = self
-            .ffmpeg_input_args()
-            .into_iter()
-            .flat_map(|(arg, value)| [format!("-{arg}"), value])
-            .collect();
+        let mut restarts = 0;
+        loop {
+            let started = Instant::now();
+            match self.run_ffmpeg().await? {
+                FfmpegExit::Terminated => return Ok(()),
+                FfmpegExit::Died => {}
+            }
 
-        match &self.rtmp_address {
-            Some(rtmp_address) => match &self.video_save_folder {
-                Some(video_save_folder) => {
-                    // Write to rtmp and file
-                    ffmpeg_args.extend(
-                        self.ffmpeg_rtmp_sink_args()
-                            .into_iter()
-                            .flat_map(|(arg, value)| [format!("-{arg}"), value])
-                            .collect::<Vec<_>>(),
-                    );
-                    ffmpeg_args.extend([
-                        "-f".to_string(),
-                        "tee".to_string(),
-                        "-map".to_string(),
-                        "0:v".to_string(),
-                        "-map".to_string(),
-                        "1:a".to_string(),
-                        format!(
-                            "{video_file}|[f=flv]{rtmp_address}",
-                            video_file = Self::video_file(video_save_folder),
-                            rtmp_address = rtmp_address.clone(),
-                        ),
-                    ]);
-
-                    todo!("Writing to file and rtmp sink simultaneously currently not supported, sorry!");
-                }
-                None => {
-                    // Only write to rtmp
-                    ffmpeg_args.extend(
-                        self.ffmpeg_rtmp_sink_args()
-                            .into_iter()
-                            .flat_map(|(arg, value)| [format!("-{arg}"), value])
-                            .collect::<Vec<_>>(),
-                    );
-                    ffmpeg_args.extend(["-f".to_string(), "flv".to_string(), rtmp_address.clone()])
-                }
-            },
-            None => match &self.video_save_folder {
-                // Only write to file
-                Some(video_save_folder) => {
-                    ffmpeg_args.extend([Self::video_file(video_save_folder)])
-                }
-                None => unreachable!(
-                    "FfmpegSink can only be created when either rtmp or video file is activated"
-                ),
-            },
+            if started.elapsed() >= RESET_RESTARTS_AFTER {
+                restarts = 0;
+            }
+            ensure!(restarts < self.max_restarts, FfmpegDiedSnafu { restarts });
+
+            let backoff = self
+                .restart_backoff
+                .saturating_mul(2_u32.saturating_pow(restarts))
+                .min(MAX_RESTART_BACKOFF);
+            restarts += 1;
+            warn!(
+                "Restarting ffmpeg for {:?} in {backoff:?} (restart {restarts} of {})",
+                self.output, self.max_restarts
+            );
+            tokio::select! {
+                _ = self.terminate_signal_rx.recv() => return Ok(()),
+                _ = time::sleep(backoff) => {}
+            }
         }
+    }
+}
 
-        let ffmpeg_command = format!("ffmpeg {}", ffmpeg_args.join(" "));
+impl<FB: FrameBuffer> FfmpegSink<FB> {
+    /// Creates an independent sink (running its own ffmpeg process) for every output configured in the `cli_args`,
+    /// e.g. to write to a file and stream to rtmp at the same time.
+    pub fn new_per_output(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        statistics_tx: mpsc::Sender<StatisticsEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Vec<Self> {
+        if cli_args.primary_display_only {
+            return Vec::new();
+        }
+
+        FfmpegOutput::from_cli_args(cli_args)
+            .into_iter()
+            .map(|output| Self {
+                fb: Arc::clone(&fb),
+                statistics_tx: statistics_tx.clone(),
+                terminate_signal_rx: terminate_signal_rx.resubscribe(),
+                output,
+                fps: fps.ffmpeg,
+                max_stdin_lag: Duration::from_millis(cli_args.max_ffmpeg_stdin_lag_ms),
+                draw_budget: cli_args
+                    .draw_budget_per_frame
+                    .map(|budget| DrawBudget::new(budget as usize, &fb.as_rgb0_bytes())),
+                program: "ffmpeg".to_owned(),
+                max_restarts: cli_args.ffmpeg_max_restarts,
+                restart_backoff: INITIAL_RESTART_BACKOFF,
+            })
+            .collect()
+    }
+
+    /// Runs a single ffmpeg process until the sink is terminated or ffmpeg dies
+    async fn run_ffmpeg(&mut self) -> Result<FfmpegExit, Error> {
+        let ffmpeg_args = self.ffmpeg_args();
+        let ffmpeg_command = format!("{} {}", self.program, ffmpeg_args.join(" "));
         debug!("Executing {ffmpeg_command:?}");
-        let mut command = Command::new("ffmpeg")
+        let mut command = Command::new(&self.program)
             .kill_on_drop(false)
             .args(ffmpeg_args.clone())
             .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .context(StartFfmpegSnafu {
                 command: ffmpeg_command,
@@ -127,8 +214,17 @@ impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for FfmpegSink<FB> {
             .stdin
             .take()
             .expect("child did not have a handle to stdin");
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let stderr_reader = tokio::spawn(read_stderr(
+            command
+                .stderr
+                .take()
+                .expect("child did not have a handle to stderr"),
+            Arc::clone(&stderr_tail),
+        ));
 
-        let mut interval = time::interval(Duration::from_micros(1_000_000 / 30));
+        let mut interval = time::interval(self.frame_interval());
+        let mut changed_frames = ChangedFrames::default();
         loop {
             if self.terminate_signal_rx.try_recv().is_ok() {
                 // Normally we would send SIGINT to ffmpeg and let the process shutdown gracefully and afterwards call
@@ -162,25 +258,81 @@ impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for FfmpegSink<FB> {
                 // command.wait().await.unwrap();
                 // trace!("Killied ffmpeg process in {:?}", start.elapsed());
 
-                return Ok(());
+                return Ok(FfmpegExit::Terminated);
+            }
+            // ffmpeg is told to expect rgb0, regardless of how the framebuffer stores the pixels
+            let rgb0 = self.fb.as_rgb0_bytes();
+            let frame = match &mut self.draw_budget {
+                Some(draw_budget) => draw_budget.reveal(&rgb0),
+                None => &*rgb0,
+            };
+            let died = if let Ok(Some(_)) = command.try_wait() {
+                true
+            } else if changed_frames.should_send(frame, Instant::now()) {
+                match write_frame(&mut stdin, frame, self.max_stdin_lag, &self.statistics_tx).await
+                {
+                    Ok(()) => false,
+                    Err(Error::WriteDataToFfmpeg { .. }) => true,
+                    Err(err) => return Err(err),
+                }
+            } else {
+                false
+            };
+
+            if died {
+                // In case ffmpeg only closed stdin it might still be running
+                let _ = command.start_kill();
+                let status = command.wait().await;
+                let _ = stderr_reader.await;
+                let stderr_tail = stderr_tail.lock().unwrap();
+                warn!(
+                    "ffmpeg for {:?} died unexpectedly with {status:?}, last lines of its stderr:\n{}",
+                    self.output,
+                    stderr_tail.iter().cloned().collect::<Vec<_>>().join("\n")
+                );
+                return Ok(FfmpegExit::Died);
             }
-            let bytes = self.fb.as_bytes();
-            stdin
-                .write_all(bytes)
-                .await
-                .context(WriteDataToFfmpegSnafu)?;
             interval.tick().await;
         }
     }
-}
 
-impl<FB: FrameBuffer> FfmpegSink<FB> {
+    fn frame_interval(&self) -> Duration {
+        interval_for_fps(self.fps)
+    }
+
+    fn ffmpeg_args(&self) -> Vec<String> {
+        let mut ffmpeg_args: Vec<String> = self
+            .ffmpeg_input_args()
+            .into_iter()
+            .flat_map(|(arg, value)| [format!("-{arg}"), value])
+            .collect();
+
+        match &self.output {
+            FfmpegOutput::Rtmp { rtmp_address } => {
+                ffmpeg_args.extend(
+                    self.ffmpeg_rtmp_sink_args()
+                        .into_iter()
+                        .flat_map(|(arg, value)| [format!("-{arg}"), value]),
+                );
+                ffmpeg_args.extend(["-f".to_string(), "flv".to_string(), rtmp_address.clone()])
+            }
+            FfmpegOutput::File { video_save_folder } => {
+                ffmpeg_args.push(Self::video_file(video_save_folder))
+            }
+        }
+
+        ffmpeg_args
+    }
+
     fn ffmpeg_input_args(&self) -> Vec<(String, String)> {
         let video_size = format!("{}x{}", self.fb.get_width(), self.fb.get_height());
         [
             ("f", "rawvideo"),
             ("pixel_format", "rgb0"),
             ("video_size", video_size.as_str()),
+            ("framerate", self.fps.to_string().as_str()),
+            // Unchanged frames are skipped, so the frames need to be placed by the time they arrive
+            ("use_wallclock_as_timestamps", "1"),
             ("i", "-"),
             ("f", "lavfi"),
             ("i", "anullsrc=channel_layout=stereo:sample_rate=44100"),
@@ -214,3 +366,312 @@ impl<FB: FrameBuffer> FfmpegSink<FB> {
         )
     }
 }
+
+/// Remembers the last frame sent to ffmpeg, so that unchanged frames don't need to be encoded again
+#[derive(Default)]
+struct ChangedFrames {
+    last_frame: Vec<u8>,
+    last_sent: Option<Instant>,
+}
+
+impl ChangedFrames {
+    /// Whether the frame differs from the last sent one, or the last one was sent more than
+    /// [`MAX_UNCHANGED_FRAMES_INTERVAL`] ago
+    fn should_send(&mut self, frame: &[u8], now: Instant) -> bool {
+        let overdue = self
+            .last_sent
+            .is_none_or(|last_sent| now.duration_since(last_sent) >= MAX_UNCHANGED_FRAMES_INTERVAL);
+        if !overdue && self.last_frame == frame {
+            return false;
+        }
+
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(frame);
+        self.last_sent = Some(now);
+        true
+    }
+}
+
+/// Logs every line ffmpeg writes to stderr and keeps the last [`FFMPEG_STDERR_TAIL_LINES`], so that they can be shown
+/// in case ffmpeg dies
+async fn read_stderr(stderr: impl AsyncRead + Unpin, tail: Arc<Mutex<VecDeque<String>>>) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!("ffmpeg: {line}");
+        let mut tail = tail.lock().unwrap();
+        if tail.len() == FFMPEG_STDERR_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+}
+
+/// Writes a frame to ffmpeg and reports it in case this took longer than `max_lag`. Writing to stdin blocks in case
+/// ffmpeg can't keep up with encoding, which would otherwise silently slow down (and stutter) the recording.
+async fn write_frame(
+    stdin: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+    max_lag: Duration,
+    statistics_tx: &mpsc::Sender<StatisticsEvent>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    stdin
+        .write_all(bytes)
+        .await
+        .context(WriteDataToFfmpegSnafu)?;
+
+    let lag = start.elapsed();
+    if lag > max_lag {
+        warn!("Writing a frame to ffmpeg took {lag:?}, ffmpeg can not keep up with encoding the video");
+        statistics_tx
+            .send(StatisticsEvent::FfmpegStdinLagged)
+            .await
+            .context(WriteToStatisticsChannelSnafu)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_primary_display_only_disables_ffmpeg_sink() {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--rtmp-address",
+            "rtmp://127.0.0.1:1935/live/test",
+            "--video-save-folder",
+            "/tmp",
+            "--primary-display-only",
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sink = FfmpegSink::new(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+
+        assert!(sink.is_none());
+    }
+
+    #[test]
+    fn test_sink_per_output() {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--rtmp-address",
+            "rtmp://127.0.0.1:1935/live/test",
+            "--video-save-folder",
+            "/tmp/recordings",
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sinks = FfmpegSink::new_per_output(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            terminate_signal_rx,
+        );
+
+        let outputs = sinks
+            .iter()
+            .map(|sink| sink.output.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outputs,
+            [
+                FfmpegOutput::Rtmp {
+                    rtmp_address: "rtmp://127.0.0.1:1935/live/test".to_owned()
+                },
+                FfmpegOutput::File {
+                    video_save_folder: "/tmp/recordings".to_owned()
+                }
+            ]
+        );
+
+        // Every sink only writes to its own output
+        let rtmp_args = sinks[0].ffmpeg_args();
+        assert!(rtmp_args.ends_with(&[
+            "-f".to_owned(),
+            "flv".to_owned(),
+            "rtmp://127.0.0.1:1935/live/test".to_owned()
+        ]));
+        assert!(!rtmp_args.iter().any(|arg| arg.contains("/tmp/recordings")));
+        let file_args = sinks[1].ffmpeg_args();
+        assert!(file_args
+            .last()
+            .unwrap()
+            .starts_with("/tmp/recordings/pixelflut_dump_"));
+        assert!(!file_args.iter().any(|arg| arg.starts_with("rtmp://")));
+    }
+
+    #[rstest]
+    #[case(30, Duration::from_micros(33_333))]
+    #[case(60, Duration::from_micros(16_666))]
+    #[case(1, Duration::from_secs(1))]
+    fn test_frame_interval_uses_fps(#[case] fps: u32, #[case] expected: Duration) {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--video-save-folder",
+            "/tmp/recordings",
+            "--fps",
+            &fps.to_string(),
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sinks = FfmpegSink::new_per_output(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            terminate_signal_rx,
+        );
+
+        assert_eq!(sinks[0].frame_interval(), expected);
+        // ffmpeg needs to expect the same frame rate
+        assert!(sinks[0]
+            .ffmpeg_args()
+            .windows(2)
+            .any(|args| args == ["-framerate".to_owned(), fps.to_string()]));
+    }
+
+    #[test]
+    fn test_unchanged_frames_are_skipped() {
+        let start = Instant::now();
+        let mut changed_frames = ChangedFrames::default();
+
+        assert!(changed_frames.should_send(&[1, 2, 3, 0], start));
+        assert!(!changed_frames.should_send(&[1, 2, 3, 0], start + Duration::from_millis(100)));
+        assert!(changed_frames.should_send(&[4, 5, 6, 0], start + Duration::from_millis(200)));
+        assert!(!changed_frames.should_send(&[4, 5, 6, 0], start + Duration::from_millis(300)));
+
+        // Unchanged frames are still sent from time to time
+        assert!(changed_frames.should_send(
+            &[4, 5, 6, 0],
+            start + Duration::from_millis(200) + MAX_UNCHANGED_FRAMES_INTERVAL
+        ));
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(2)]
+    #[tokio::test]
+    async fn test_died_ffmpeg_is_restarted(#[case] max_restarts: u32) {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--video-save-folder",
+            "/tmp/recordings",
+            "--ffmpeg-max-restarts",
+            &max_restarts.to_string(),
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let mut sink = FfmpegSink::new_per_output(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            terminate_signal_rx,
+        )
+        .remove(0);
+        // Exits immediately without reading any frame
+        sink.program = "true".to_owned();
+        sink.restart_backoff = Duration::from_millis(1);
+
+        let result = time::timeout(Duration::from_secs(10), sink.run())
+            .await
+            .expect("the sink should give up on ffmpeg");
+        assert!(
+            matches!(
+                result,
+                Err(super::super::Error::FfmpegError {
+                    source: Error::FfmpegDied { restarts }
+                }) if restarts == max_restarts
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_terminate_during_restart_backoff() {
+        let cli_args =
+            CliArgs::parse_from(["breakwater", "--video-save-folder", "/tmp/recordings"]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let mut sink = FfmpegSink::new_per_output(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            terminate_signal_rx,
+        )
+        .remove(0);
+        sink.program = "true".to_owned();
+        sink.restart_backoff = Duration::from_secs(3600);
+
+        let run = tokio::spawn(async move { sink.run().await });
+        time::sleep(Duration::from_millis(500)).await;
+        terminate_signal_tx.send(()).unwrap();
+
+        time::timeout(Duration::from_secs(10), run)
+            .await
+            .expect("the sink should stop waiting for the restart")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_stdin_is_reported_as_lag() {
+        let (statistics_tx, mut statistics_rx) = mpsc::channel(1);
+        let frame = vec![0_u8; 4096];
+
+        // ffmpeg keeps up, so there is no lag
+        let (mut stdin, _ffmpeg) = tokio::io::duplex(frame.len());
+        write_frame(
+            &mut stdin,
+            &frame,
+            Duration::from_millis(10),
+            &statistics_tx,
+        )
+        .await
+        .unwrap();
+        assert!(statistics_rx.try_recv().is_err());
+
+        // ffmpeg only starts reading after some time
+        let (mut stdin, mut ffmpeg) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            tokio::io::copy(&mut ffmpeg, &mut tokio::io::sink()).await
+        });
+        write_frame(
+            &mut stdin,
+            &frame,
+            Duration::from_millis(10),
+            &statistics_tx,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            statistics_rx.try_recv(),
+            Ok(StatisticsEvent::FfmpegStdinLagged)
+        ));
+    }
+}
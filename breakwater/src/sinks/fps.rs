@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::cli_args::CliArgs;
+
+/// Frames per second every sink renders with. Every sink uses its override (e.g. `--vnc-fps`) or falls back to
+/// `--fps`, all of them are capped by `--max-fps`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FpsConfig {
+    #[cfg(feature = "vnc")]
+    pub vnc: u32,
+    pub ffmpeg: u32,
+    pub gif: u32,
+    pub unix_socket: u32,
+    pub mjpeg: u32,
+    #[cfg(feature = "v4l2")]
+    pub v4l2: u32,
+    #[cfg(feature = "shared-memory")]
+    pub shared_memory: u32,
+    /// [`None`] means the native display redraws as fast as the window system allows
+    #[cfg(feature = "native-display")]
+    pub native_display: Option<u32>,
+}
+
+impl FpsConfig {
+    pub fn from_cli_args(cli_args: &CliArgs) -> Self {
+        let max_fps = cli_args.max_fps.unwrap_or(u32::MAX);
+        let capped = |fps: Option<u32>| fps.unwrap_or(cli_args.fps).min(max_fps);
+
+        Self {
+            #[cfg(feature = "vnc")]
+            vnc: capped(cli_args.vnc_fps),
+            ffmpeg: capped(cli_args.ffmpeg_fps),
+            // The GIF recording has its own (lower) default frame rate
+            gif: capped(Some(cli_args.gif_fps)),
+            unix_socket: capped(None),
+            mjpeg: capped(None),
+            #[cfg(feature = "v4l2")]
+            v4l2: capped(None),
+            #[cfg(feature = "shared-memory")]
+            shared_memory: capped(None),
+            #[cfg(feature = "native-display")]
+            native_display: cli_args
+                .native_display_fps
+                .or(cli_args.max_fps)
+                .map(|fps| fps.min(max_fps)),
+        }
+    }
+}
+
+/// Time between two frames when rendering with the given fps. It's at least 1µs, as [`tokio::time::interval`] panics
+/// for a zero interval, which more than 1 million fps would result in otherwise.
+pub fn interval_for_fps(fps: u32) -> Duration {
+    Duration::from_micros((1_000_000 / fps as u64).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(1, Duration::from_secs(1))]
+    #[case(10, Duration::from_millis(100))]
+    #[case(30, Duration::from_micros(33_333))]
+    #[case(60, Duration::from_micros(16_666))]
+    #[case(1_000_000, Duration::from_micros(1))]
+    #[case(1_000_001, Duration::from_micros(1))]
+    #[case(u32::MAX, Duration::from_micros(1))]
+    fn test_interval_for_fps(#[case] fps: u32, #[case] expected: Duration) {
+        assert_eq!(interval_for_fps(fps), expected);
+    }
+
+    #[rstest]
+    #[case::defaults(&[], 30, 10, 30)]
+    #[case::fps(&["--fps", "60"], 60, 10, 60)]
+    #[case::max_fps(&["--fps", "60", "--max-fps", "25"], 25, 10, 25)]
+    #[case::max_fps_below_gif_fps(&["--max-fps", "5"], 5, 5, 5)]
+    #[case::ffmpeg_fps(&["--ffmpeg-fps", "15"], 15, 10, 30)]
+    #[case::ffmpeg_fps_capped(&["--ffmpeg-fps", "50", "--max-fps", "40"], 40, 10, 30)]
+    fn test_from_cli_args(
+        #[case] args: &[&str],
+        #[case] expected_ffmpeg: u32,
+        #[case] expected_gif: u32,
+        #[case] expected_unix_socket: u32,
+    ) {
+        let cli_args = CliArgs::parse_from(["breakwater"].iter().chain(args.iter()));
+        let fps = FpsConfig::from_cli_args(&cli_args);
+
+        assert_eq!(fps.ffmpeg, expected_ffmpeg);
+        assert_eq!(fps.gif, expected_gif);
+        assert_eq!(fps.unix_socket, expected_unix_socket);
+    }
+}
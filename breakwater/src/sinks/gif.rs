@@ -0,0 +1,318 @@
+use std::{collections::VecDeque, fs::File, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use breakwater_parser::FrameBuffer;
+use chrono::Local;
+use log::info;
+use snafu::{ensure, ResultExt, Snafu};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinError,
+    time,
+};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        draw_budget::DrawBudget,
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
+};
+
+/// Speed of the color quantization (1 to 30). Higher values are faster, but produce worse colors.
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "The framebuffer of size {width}x{height} is too large to be stored as GIF (max {max}x{max})",
+        max = u16::MAX
+    ))]
+    FramebufferTooLargeForGif { width: usize, height: usize },
+
+    #[snafu(display("Failed to create GIF file {gif_file:?}"))]
+    CreateGifFile {
+        source: std::io::Error,
+        gif_file: PathBuf,
+    },
+
+    #[snafu(display("Failed to encode GIF"))]
+    EncodeGif { source: gif::EncodingError },
+
+    #[snafu(display("Failed to join GIF encoding thread"))]
+    JoinGifEncodingThread { source: JoinError },
+}
+
+/// Records the last `--gif-duration-s` seconds of the canvas and writes them into an animated GIF on shutdown.
+pub struct GifSink<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    terminate_signal_rx: broadcast::Receiver<()>,
+
+    gif_save_folder: String,
+    fps: u32,
+
+    /// Ring buffer of the recorded frames. Every frame is stored as RGB (3 bytes per pixel).
+    frames: VecDeque<Vec<u8>>,
+    max_frames: usize,
+
+    draw_budget: Option<DrawBudget>,
+}
+
+#[async_trait]
+impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for GifSink<FB> {
+    async fn new(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        _statistics_tx: mpsc::Sender<StatisticsEvent>,
+        _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Option<Self>, super::Error> {
+        if cli_args.primary_display_only {
+            return Ok(None);
+        }
+        let Some(gif_save_folder) = &cli_args.gif_save_folder else {
+            return Ok(None);
+        };
+
+        ensure!(
+            fb.get_width() <= u16::MAX as usize && fb.get_height() <= u16::MAX as usize,
+            FramebufferTooLargeForGifSnafu {
+                width: fb.get_width(),
+                height: fb.get_height(),
+            }
+        );
+
+        let max_frames = (cli_args.gif_duration_s * fps.gif as u64) as usize;
+        Ok(Some(Self {
+            draw_budget: cli_args
+                .draw_budget_per_frame
+                .map(|budget| DrawBudget::new(budget as usize, &fb.as_rgb0_bytes())),
+            fb,
+            terminate_signal_rx,
+            gif_save_folder: gif_save_folder.clone(),
+            fps: fps.gif,
+            frames: VecDeque::with_capacity(max_frames),
+            max_frames,
+        }))
+    }
+
+    async fn run(&mut self) -> Result<(), super::Error> {
+        let mut interval = time::interval(interval_for_fps(self.fps));
+        loop {
+            if self.terminate_signal_rx.try_recv().is_ok() {
+                let gif_file = self.write_gif().await?;
+                info!("Saved GIF recording to {gif_file:?}");
+                return Ok(());
+            }
+
+            self.record_frame();
+            interval.tick().await;
+        }
+    }
+}
+
+impl<FB: FrameBuffer> GifSink<FB> {
+    /// Takes a snapshot of the framebuffer. In case the ring buffer is full the oldest frame is dropped.
+    fn record_frame(&mut self) {
+        if self.max_frames == 0 {
+            return;
+        }
+
+        // Re-use the allocation of the oldest frame
+        let mut frame = if self.frames.len() >= self.max_frames {
+            self.frames.pop_front().unwrap_or_default()
+        } else {
+            Vec::with_capacity(self.fb.get_size() * 3)
+        };
+        frame.clear();
+        let rgb0 = self.fb.as_rgb0_bytes();
+        let pixels = match &mut self.draw_budget {
+            Some(draw_budget) => draw_budget.reveal(&rgb0),
+            None => &*rgb0,
+        };
+        frame.extend(
+            pixels
+                .chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]),
+        );
+
+        self.frames.push_back(frame);
+    }
+
+    /// Encodes all recorded frames into an animated GIF and returns the path of the written file.
+    async fn write_gif(&mut self) -> Result<PathBuf, Error> {
+        let gif_file = PathBuf::from(format!(
+            "{}/pixelflut_dump_{}.gif",
+            self.gif_save_folder,
+            Local::now().format("%Y-%m-%d_%H-%M-%S")
+        ));
+        let width = self.fb.get_width() as u16;
+        let height = self.fb.get_height() as u16;
+        let delays = frame_delays(self.fps);
+        let frames = std::mem::take(&mut self.frames);
+
+        // Quantizing the colors is pretty expensive, so let's not block the async runtime
+        tokio::task::spawn_blocking(move || {
+            let file = File::create(&gif_file).context(CreateGifFileSnafu {
+                gif_file: gif_file.clone(),
+            })?;
+            let mut encoder =
+                gif::Encoder::new(file, width, height, &[]).context(EncodeGifSnafu)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .context(EncodeGifSnafu)?;
+
+            for (rgb, delay) in frames.into_iter().zip(delays) {
+                let mut frame =
+                    gif::Frame::from_rgb_speed(width, height, &rgb, GIF_QUANTIZATION_SPEED);
+                frame.delay = delay;
+                encoder.write_frame(&frame).context(EncodeGifSnafu)?;
+            }
+
+            Ok(gif_file)
+        })
+        .await
+        .context(JoinGifEncodingThreadSnafu)?
+    }
+}
+
+/// Returns the delays of the frames in units of 10ms, which is all GIF supports. In case the fps don't divide 100, the
+/// rounding remainder is carried over to the next frame, so that e.g. 30 fps are played back with delays of 30ms,
+/// 30ms, 40ms and so on instead of 30ms each, which would be 11% too fast.
+fn frame_delays(fps: u32) -> impl Iterator<Item = u16> {
+    // Time of the start of the frame, in units of 10ms and rounded down
+    let frame_start = move |frame: u64| frame * 100 / fps as u64;
+    (0..).map(move |frame| (frame_start(frame + 1) - frame_start(frame)) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::{Rgb565FrameBuffer, SimpleFrameBuffer};
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_records_gif() {
+        let gif_save_folder = std::env::temp_dir().join("breakwater-test-gif-sink");
+        std::fs::create_dir_all(&gif_save_folder).unwrap();
+
+        let fb = Arc::new(SimpleFrameBuffer::new(64, 48));
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--gif-save-folder",
+            gif_save_folder.to_str().unwrap(),
+            "--gif-duration-s",
+            "1",
+            "--gif-fps",
+            "2",
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let mut sink = GifSink::new(
+            fb.clone(),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap()
+        .expect("GIF sink should be enabled");
+
+        // We record more frames than fit into the ring buffer of 1s * 2 fps = 2 frames
+        for rgba in [0x0000_00ff, 0x0000_ff00, 0x00ff_0000] {
+            fb.set(0, 0, rgba);
+            sink.record_frame();
+        }
+        let gif_file = sink.write_gif().await.unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&gif_file).unwrap())
+            .unwrap();
+        assert_eq!(decoder.width(), 64);
+        assert_eq!(decoder.height(), 48);
+
+        let mut frames = 0;
+        while decoder.read_next_frame().unwrap().is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, 2);
+
+        std::fs::remove_file(gif_file).unwrap();
+    }
+
+    /// The framebuffer only stores 2 bytes per pixel, the frames are recorded as RGB nevertheless
+    #[tokio::test]
+    async fn test_records_rgb565_framebuffer_with_draw_budget() {
+        let fb = Arc::new(Rgb565FrameBuffer::new(4, 2));
+        fb.set(1, 0, 0x0000_00ff);
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--gif-save-folder",
+            "/tmp",
+            "--gif-duration-s",
+            "1",
+            "--draw-budget-per-frame",
+            "1",
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let mut sink = GifSink::new(
+            fb.clone(),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap()
+        .expect("GIF sink should be enabled");
+
+        fb.set(2, 0, 0x0000_ff00);
+        fb.set(3, 1, 0x00ff_0000);
+        sink.record_frame();
+        sink.record_frame();
+
+        // The first pixel was already drawn when the sink started, the others are revealed one per frame
+        let mut expected = vec![0; 4 * 2 * 3];
+        expected[3..6].copy_from_slice(&[0xff, 0, 0]);
+        expected[6..9].copy_from_slice(&[0, 0xff, 0]);
+        assert_eq!(sink.frames[0], expected);
+        expected[21..24].copy_from_slice(&[0, 0, 0xff]);
+        assert_eq!(sink.frames[1], expected);
+    }
+
+    #[rstest]
+    #[case(1, &[100, 100, 100])]
+    #[case(2, &[50, 50, 50])]
+    #[case(10, &[10, 10, 10])]
+    #[case(30, &[3, 3, 4, 3, 3, 4, 3])]
+    #[case(40, &[2, 3, 2, 3, 2])]
+    #[case(100, &[1, 1, 1])]
+    fn test_frame_delays(#[case] fps: u32, #[case] expected: &[u16]) {
+        assert_eq!(
+            frame_delays(fps).take(expected.len()).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    /// Regardless of the fps, one second of frames is played back in exactly one second
+    #[test]
+    fn test_frame_delays_keep_playback_speed() {
+        for fps in 1..=100 {
+            let delays = frame_delays(fps).take(fps as usize * 10).map(u64::from);
+            assert_eq!(delays.sum::<u64>(), 1000, "{fps} fps");
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    net::{AddrParseError, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use breakwater_parser::FrameBuffer;
+use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+use log::{debug, info};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    task::JoinError,
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
+};
+
+/// Requests are tiny, everything longer is not a request we care about
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Quality (1-100) of the streamed JPEG frames
+const JPEG_QUALITY: u8 = 80;
+
+/// Separates the frames within the `multipart/x-mixed-replace` response
+const BOUNDARY: &str = "breakwaterframe";
+
+/// Number of encoded frames buffered per viewer. Slow viewers skip the frames they missed instead of slowing down the
+/// other viewers.
+const FRAMES_BUFFERED_PER_VIEWER: usize = 2;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to parse MJPEG listen address {listen_address:?}"))]
+    ParseListenAddress {
+        source: AddrParseError,
+        listen_address: String,
+    },
+
+    #[snafu(display("Failed to bind to MJPEG listen address {listen_address:?}"))]
+    BindToListenAddress {
+        source: std::io::Error,
+        listen_address: SocketAddr,
+    },
+
+    #[snafu(display("Failed to accept MJPEG viewer"))]
+    AcceptViewer { source: std::io::Error },
+
+    #[snafu(display("Failed to encode frame of size {width}x{height} as JPEG"))]
+    EncodeJpeg {
+        source: image::ImageError,
+        width: usize,
+        height: usize,
+    },
+
+    #[snafu(display("Failed to join JPEG encoding thread"))]
+    JoinJpegEncodingThread { source: JoinError },
+}
+
+/// Serves the canvas as MJPEG stream (`multipart/x-mixed-replace`) over HTTP on `--mjpeg-listen-address`, so that it
+/// can be watched in any browser without a VNC client.
+///
+/// Every frame is only encoded once for all viewers, and not at all in case nobody is watching. In case the framebuffer
+/// tracks the changes (`--mjpeg-dirty-regions`), frames in which nothing changed are skipped.
+pub struct MjpegSink<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    terminate_signal_rx: broadcast::Receiver<()>,
+
+    listener: TcpListener,
+    frames_tx: broadcast::Sender<Arc<Vec<u8>>>,
+    fps: u32,
+
+    /// The last frame sent together with the dirty epoch it was read in, only set in case the framebuffer tracks the
+    /// changes
+    last_frame: Option<(Arc<Vec<u8>>, u64)>,
+    /// Number of viewers the last frame was sent to
+    last_viewers: usize,
+}
+
+#[async_trait]
+impl<FB: FrameBuffer + Sync + Send + 'static> DisplaySink<FB> for MjpegSink<FB> {
+    async fn new(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        _statistics_tx: mpsc::Sender<StatisticsEvent>,
+        _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Option<Self>, super::Error> {
+        if cli_args.primary_display_only {
+            return Ok(None);
+        }
+        let Some(listen_address) = &cli_args.mjpeg_listen_address else {
+            return Ok(None);
+        };
+
+        let listen_address: SocketAddr =
+            listen_address.parse().context(ParseListenAddressSnafu {
+                listen_address: listen_address.clone(),
+            })?;
+        let listener = TcpListener::bind(listen_address)
+            .await
+            .context(BindToListenAddressSnafu { listen_address })?;
+        info!("Serving MJPEG stream on http://{listen_address}");
+
+        let (frames_tx, _) = broadcast::channel(FRAMES_BUFFERED_PER_VIEWER);
+        Ok(Some(Self {
+            fb,
+            terminate_signal_rx,
+            listener,
+            frames_tx,
+            fps: fps.mjpeg,
+            last_frame: None,
+            last_viewers: 0,
+        }))
+    }
+
+    async fn run(&mut self) -> Result<(), super::Error> {
+        let mut interval = time::interval(interval_for_fps(self.fps));
+        // Encoding can take longer than a frame for huge canvases, we don't want to encode multiple frames in a row then
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                // Dropping the sender on return closes the streams of all viewers
+                _ = self.terminate_signal_rx.recv() => return Ok(()),
+                viewer = self.listener.accept() => {
+                    let (viewer, address) = viewer.context(AcceptViewerSnafu)?;
+                    debug!("MJPEG viewer {address} connected");
+                    let frames_tx = self.frames_tx.clone();
+                    let mut terminate_signal_rx = self.terminate_signal_rx.resubscribe();
+                    tokio::spawn(async move {
+                        // Viewers that did not finish their request yet keep the stream open, so they need to be
+                        // terminated explicitly
+                        let result = tokio::select! {
+                            result = stream_to_viewer(viewer, frames_tx) => result,
+                            _ = terminate_signal_rx.recv() => Ok(()),
+                        };
+                        if let Err(err) = result {
+                            debug!("MJPEG viewer {address} disconnected: {err}");
+                        }
+                    });
+                }
+                _ = interval.tick() => {
+                    let viewers = self.frames_tx.receiver_count();
+                    if viewers > 0 {
+                        if let Some(frame) = self.next_frame(viewers).await? {
+                            // Only fails in case all viewers disconnected in the meantime
+                            let _ = self.frames_tx.send(frame);
+                        }
+                    }
+                    self.last_viewers = viewers;
+                }
+            }
+        }
+    }
+}
+
+impl<FB: FrameBuffer + Sync + Send + 'static> MjpegSink<FB> {
+    /// Returns the frame to send to the given number of viewers. [`None`] means nothing changed since the last frame
+    /// and there are no new viewers that still need it.
+    async fn next_frame(&mut self, viewers: usize) -> Result<Option<Arc<Vec<u8>>>, Error> {
+        if let Some((last_frame, epoch)) = &self.last_frame {
+            if self
+                .fb
+                .changed_tiles_since(*epoch)
+                .is_some_and(|changed_tiles| changed_tiles.is_empty())
+            {
+                // New viewers only get a frame once something changes otherwise. A viewer leaving at the same time as
+                // another one joins goes unnoticed, the new one then also has to wait for the next change.
+                return Ok((viewers > self.last_viewers).then(|| Arc::clone(last_frame)));
+            }
+        }
+
+        let epoch = self.fb.advance_dirty_epoch();
+        let fb = Arc::clone(&self.fb);
+        // Encoding huge canvases takes a while, so let's not block the async runtime
+        let frame = tokio::task::spawn_blocking(move || encode_jpeg(fb.as_ref()))
+            .await
+            .context(JoinJpegEncodingThreadSnafu)??;
+        let frame = Arc::new(frame);
+        self.last_frame = epoch.map(|epoch| (Arc::clone(&frame), epoch));
+        Ok(Some(frame))
+    }
+}
+
+/// Encodes the current canvas as JPEG
+fn encode_jpeg<FB: FrameBuffer>(fb: &FB) -> Result<Vec<u8>, Error> {
+    let (width, height) = (fb.get_width(), fb.get_height());
+
+    // The framebuffer stores the pixels as rgb0
+    let rgb = fb
+        .as_rgb0_bytes()
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect::<Vec<_>>();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+        .encode(&rgb, width as u32, height as u32, ExtendedColorType::Rgb8)
+        .context(EncodeJpegSnafu { width, height })?;
+    Ok(jpeg)
+}
+
+/// Answers the HTTP request of the viewer (regardless of the requested path) with an endless stream of frames.
+///
+/// The viewer only subscribes to the frames once its request is complete, so that connections that never send a
+/// request don't count as viewers and frames are not encoded for them.
+async fn stream_to_viewer(
+    mut stream: TcpStream,
+    frames_tx: broadcast::Sender<Arc<Vec<u8>>>,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let bytes_read = stream.read(&mut buffer).await?;
+        if bytes_read == 0 || request.len() > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let mut frames_rx = frames_tx.subscribe();
+    // Only the sink should keep the channel open, so that the stream ends once the sink stops
+    drop(frames_tx);
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n\
+                Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    loop {
+        let frame = match frames_rx.recv().await {
+            Ok(frame) => frame,
+            // The viewer is too slow, it simply gets the next frame
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        stream
+            .write_all(
+                format!(
+                    "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                    frame.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        stream.write_all(&frame).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use clap::Parser;
+    use image::{GenericImageView, ImageFormat};
+
+    use super::*;
+
+    /// Reads from the stream until (including) the next empty line and returns everything read
+    async fn read_headers(stream: &mut TcpStream) -> String {
+        let mut headers = Vec::new();
+        while !headers.ends_with(b"\r\n\r\n") {
+            headers.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(headers).unwrap()
+    }
+
+    async fn start_sink(
+        fb: Arc<SimpleFrameBuffer>,
+        extra_args: &[&str],
+    ) -> (MjpegSink<SimpleFrameBuffer>, broadcast::Sender<()>) {
+        let cli_args = CliArgs::parse_from(
+            ["breakwater", "--mjpeg-listen-address", "127.0.0.1:0"]
+                .iter()
+                .chain(extra_args),
+        );
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sink = MjpegSink::new(
+            fb,
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap()
+        .expect("MJPEG sink should be enabled");
+        (sink, terminate_signal_tx)
+    }
+
+    #[tokio::test]
+    async fn test_streams_jpeg_frames() {
+        let fb = Arc::new(SimpleFrameBuffer::new(64, 48));
+        for x in 0..32 {
+            for y in 0..48 {
+                fb.set(x, y, 0x0000_00ff);
+            }
+        }
+        let (mut sink, terminate_signal_tx) = start_sink(fb.clone(), &[]).await;
+        let address = sink.listener.local_addr().unwrap();
+        let sink_thread = tokio::spawn(async move { sink.run().await });
+
+        // Multiple viewers can watch at the same time
+        let mut viewers = Vec::new();
+        for _ in 0..2 {
+            let mut viewer = TcpStream::connect(address).await.unwrap();
+            viewer
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            viewers.push(viewer);
+        }
+
+        for viewer in &mut viewers {
+            let headers = read_headers(viewer).await;
+            assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"), "{headers}");
+            assert!(
+                headers.contains(&format!(
+                    "Content-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n"
+                )),
+                "{headers}"
+            );
+
+            let part_headers = read_headers(viewer).await;
+            assert!(
+                part_headers.starts_with(&format!("--{BOUNDARY}\r\nContent-Type: image/jpeg\r\n")),
+                "{part_headers}"
+            );
+            let content_length: usize = part_headers
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .expect("part must have a Content-Length")
+                .parse()
+                .unwrap();
+
+            let mut jpeg = vec![0; content_length];
+            viewer.read_exact(&mut jpeg).await.unwrap();
+            let image = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap();
+            assert_eq!(image.dimensions(), (64, 48));
+
+            // JPEG is lossy, so we only check that the left half is (mostly) red and the right half (mostly) black
+            let [r, g, b, _] = image.get_pixel(10, 24).0;
+            assert!(r > 200 && g < 50 && b < 50, "left pixel: {r} {g} {b}");
+            let [r, g, b, _] = image.get_pixel(54, 24).0;
+            assert!(r < 50 && g < 50 && b < 50, "right pixel: {r} {g} {b}");
+        }
+
+        terminate_signal_tx.send(()).unwrap();
+        sink_thread.await.unwrap().unwrap();
+
+        // The stream ends once the sink is terminated
+        let mut rest = Vec::new();
+        viewers[0].read_to_end(&mut rest).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_viewers_are_only_counted_after_their_request() {
+        let (mut sink, terminate_signal_tx) =
+            start_sink(Arc::new(SimpleFrameBuffer::new(64, 48)), &[]).await;
+        let address = sink.listener.local_addr().unwrap();
+        let frames_tx = sink.frames_tx.clone();
+        let sink_thread = tokio::spawn(async move { sink.run().await });
+
+        // Connections that don't send a request are not watching anything
+        let mut idle_connection = TcpStream::connect(address).await.unwrap();
+        let mut viewer = TcpStream::connect(address).await.unwrap();
+        time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(frames_tx.receiver_count(), 0);
+
+        viewer
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        read_headers(&mut viewer).await;
+        assert_eq!(frames_tx.receiver_count(), 1);
+
+        terminate_signal_tx.send(()).unwrap();
+        sink_thread.await.unwrap().unwrap();
+        drop(frames_tx);
+
+        // Both the connection that never completed its request and the viewer are closed
+        let mut rest = Vec::new();
+        viewer.read_to_end(&mut rest).await.unwrap();
+        let mut rest = Vec::new();
+        idle_connection.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_frames_are_skipped() {
+        let fb = Arc::new(SimpleFrameBuffer::new(640, 480).with_dirty_tracking());
+        let (mut sink, _terminate_signal_tx) =
+            start_sink(fb.clone(), &["--mjpeg-dirty-regions"]).await;
+
+        let first_frame = sink.next_frame(1).await.unwrap().expect("first frame");
+        sink.last_viewers = 1;
+        assert_eq!(sink.next_frame(1).await.unwrap(), None);
+
+        fb.set(300, 200, 0x00ff_ffff);
+        let second_frame = sink.next_frame(1).await.unwrap().expect("changed frame");
+        assert_ne!(first_frame, second_frame);
+        // The pixel was drawn right before the second frame was read, so the frame is encoded once more to be sure
+        let third_frame = sink
+            .next_frame(1)
+            .await
+            .unwrap()
+            .expect("frame after change");
+        assert_eq!(sink.next_frame(1).await.unwrap(), None);
+
+        // New viewers get the last frame without encoding it again
+        let frame_for_new_viewer = sink
+            .next_frame(2)
+            .await
+            .unwrap()
+            .expect("frame for new viewer");
+        assert!(Arc::ptr_eq(&third_frame, &frame_for_new_viewer));
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_not_skipped_without_dirty_tracking() {
+        let (mut sink, _terminate_signal_tx) =
+            start_sink(Arc::new(SimpleFrameBuffer::new(64, 48)), &[]).await;
+        for _ in 0..3 {
+            assert!(sink.next_frame(1).await.unwrap().is_some());
+            sink.last_viewers = 1;
+        }
+    }
+}
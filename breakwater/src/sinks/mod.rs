@@ -6,13 +6,25 @@ use tokio::sync::{broadcast, mpsc};
 
 use crate::{
     cli_args::CliArgs,
+    sinks::fps::FpsConfig,
     statistics::{StatisticsEvent, StatisticsInformationEvent},
 };
 
+pub mod draw_budget;
 pub mod ffmpeg;
+pub mod fps;
+pub mod gif;
+pub mod mjpeg;
 #[cfg(feature = "native-display")]
 pub mod native_display;
 #[cfg(feature = "vnc")]
+pub mod render_interval;
+#[cfg(feature = "shared-memory")]
+pub mod shared_memory;
+pub mod unix_socket;
+#[cfg(feature = "v4l2")]
+pub mod v4l2;
+#[cfg(feature = "vnc")]
 pub mod vnc;
 
 #[allow(clippy::enum_variant_names)]
@@ -26,8 +38,25 @@ pub enum Error {
     #[snafu(display("VNC error"), context(false))]
     VncError { source: vnc::Error },
 
+    #[cfg(feature = "v4l2")]
+    #[snafu(display("v4l2 error"), context(false))]
+    V4l2Error { source: v4l2::Error },
+
     #[snafu(display("ffmpeg error"), context(false))]
     FfmpegError { source: ffmpeg::Error },
+
+    #[snafu(display("GIF error"), context(false))]
+    GifError { source: self::gif::Error },
+
+    #[snafu(display("Unix socket error"), context(false))]
+    UnixSocketError { source: unix_socket::Error },
+
+    #[snafu(display("MJPEG error"), context(false))]
+    MjpegError { source: mjpeg::Error },
+
+    #[cfg(feature = "shared-memory")]
+    #[snafu(display("Shared memory error"), context(false))]
+    SharedMemoryError { source: shared_memory::Error },
 }
 
 // The stabilization of async functions in traits in Rust 1.75 did not include support for using traits containing async
@@ -38,6 +67,7 @@ pub trait DisplaySink<FB> {
     async fn new(
         fb: Arc<FB>,
         cli_args: &CliArgs,
+        fps: &FpsConfig,
         statistics_tx: mpsc::Sender<StatisticsEvent>,
         statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
         terminate_signal_rx: broadcast::Receiver<()>,
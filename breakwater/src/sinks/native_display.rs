@@ -1,7 +1,11 @@
-use std::{num::NonZero, sync::Arc};
+use std::{
+    num::NonZero,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use breakwater_parser::FrameBuffer;
+use breakwater_parser::{FrameBuffer, ResizableFrameBuffer};
 use log::{debug, warn};
 use snafu::{ResultExt, Snafu};
 use softbuffer::{Context, Surface};
@@ -12,16 +16,19 @@ use tokio::{
 use winit::{
     application::ApplicationHandler,
     error::{EventLoopError, OsError},
-    event::WindowEvent,
-    event_loop::{self, EventLoop},
+    event::{StartCause, WindowEvent},
+    event_loop::{self, ControlFlow, EventLoop},
     platform::wayland::EventLoopBuilderExtWayland,
     raw_window_handle::{DisplayHandle, HandleError, HasDisplayHandle},
-    window::{Window, WindowAttributes, WindowId},
+    window::{Fullscreen, Window, WindowAttributes, WindowId},
 };
 
 use crate::{
     cli_args::CliArgs,
-    sinks::DisplaySink,
+    sinks::{
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
     statistics::{StatisticsEvent, StatisticsInformationEvent},
 };
 
@@ -47,10 +54,26 @@ pub enum Error {
 unsafe impl<FB: FrameBuffer> Send for NativeDisplaySink<FB> {}
 
 pub struct NativeDisplaySink<FB: FrameBuffer> {
-    fb: Arc<FB>,
+    /// Loaded every frame, so that we notice resizes, see [`NativeDisplaySink::with_resizable_framebuffer`]
+    fb: Arc<ResizableFrameBuffer<FB>>,
+    /// The framebuffer the window buffer currently has the size of
+    current_fb: Arc<FB>,
     terminate_signal_rx: broadcast::Receiver<()>,
+    window_options: WindowOptions,
 
     surface: Option<Surface<DisplayHandle<'static>, Arc<Window>>>,
+    last_cursor_movement: Instant,
+    cursor_visible: bool,
+}
+
+/// How the window is started, mostly relevant for kiosk-like installations
+#[derive(Clone, Debug, Default)]
+struct WindowOptions {
+    maximized: bool,
+    fullscreen: bool,
+    hide_cursor_after: Option<Duration>,
+    /// [`None`] means redrawing as fast as possible
+    frame_interval: Option<Duration>,
 }
 
 #[async_trait]
@@ -58,6 +81,7 @@ impl<FB: FrameBuffer + Sync + Send + 'static> DisplaySink<FB> for NativeDisplayS
     async fn new(
         fb: Arc<FB>,
         cli_args: &CliArgs,
+        fps: &FpsConfig,
         _statistics_tx: mpsc::Sender<StatisticsEvent>,
         _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
         terminate_signal_rx: broadcast::Receiver<()>,
@@ -66,24 +90,29 @@ impl<FB: FrameBuffer + Sync + Send + 'static> DisplaySink<FB> for NativeDisplayS
             return Ok(None);
         }
 
-        Ok(Some(Self {
-            fb,
+        Ok(Some(Self::new_with_window_options(
+            Arc::new(ResizableFrameBuffer::from_arc(fb)),
             terminate_signal_rx,
-            surface: None,
-        }))
+            WindowOptions {
+                maximized: cli_args.native_display_maximized,
+                fullscreen: cli_args.native_display_fullscreen,
+                hide_cursor_after: cli_args
+                    .native_display_hide_cursor_after_s
+                    .map(Duration::from_secs),
+                frame_interval: fps.native_display.map(interval_for_fps),
+            },
+        )))
     }
 
     async fn run(&mut self) -> Result<(), super::Error> {
         let fb_clone = self.fb.clone();
         let terminate_signal_rx = self.terminate_signal_rx.resubscribe();
+        let window_options = self.window_options.clone();
 
         tokio::task::spawn_blocking(move || {
             // We need a owned self, so let's re-create one
-            let mut self_clone = Self {
-                fb: fb_clone,
-                terminate_signal_rx,
-                surface: None,
-            };
+            let mut self_clone =
+                Self::new_with_window_options(fb_clone, terminate_signal_rx, window_options);
 
             let event_loop = EventLoop::builder()
                 // FIXME: Can we get rid of this?
@@ -105,6 +134,13 @@ impl<FB: FrameBuffer + Sync + Send + 'static> DisplaySink<FB> for NativeDisplayS
 }
 
 impl<FB: FrameBuffer> ApplicationHandler for NativeDisplaySink<FB> {
+    fn new_events(&mut self, _event_loop: &event_loop::ActiveEventLoop, cause: StartCause) {
+        // The next frame is due, see the end of `WindowEvent::RedrawRequested`
+        if let (StartCause::ResumeTimeReached { .. }, Some(surface)) = (cause, &self.surface) {
+            surface.window().request_redraw();
+        }
+    }
+
     fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
         let window = Arc::new(
             event_loop
@@ -146,17 +182,31 @@ impl<FB: FrameBuffer> ApplicationHandler for NativeDisplaySink<FB> {
             WindowEvent::Resized(_size) => {
                 surface
                     .resize(
-                        NonZero::new(self.fb.get_width() as u32).unwrap(),
-                        NonZero::new(self.fb.get_height() as u32).unwrap(),
+                        NonZero::new(self.current_fb.get_width() as u32).unwrap(),
+                        NonZero::new(self.current_fb.get_height() as u32).unwrap(),
                     )
                     .expect("Failed to resize surface");
                 surface.window().request_redraw();
             }
             WindowEvent::RedrawRequested => {
+                let fb = self.fb.load();
+                if !Arc::ptr_eq(&fb, &self.current_fb) {
+                    let (width, height) = (fb.get_width() as u32, fb.get_height() as u32);
+                    surface
+                        .resize(NonZero::new(width).unwrap(), NonZero::new(height).unwrap())
+                        .expect("Failed to resize surface");
+                    // Ignored e.g. for fullscreen windows, the window shows the top left part of the buffer then
+                    let _ = surface
+                        .window()
+                        .request_inner_size(winit::dpi::PhysicalSize::new(width, height));
+                    self.current_fb = fb;
+                }
+
                 let window = surface.window().clone();
                 let mut buffer = surface.buffer_mut().expect("Failed to get mutable buffer");
 
-                let fbsize = self.fb.as_pixels().len();
+                let rgb0 = self.current_fb.as_rgb0_bytes();
+                let fbsize = rgb0.len() / 4;
                 if buffer.len() != fbsize {
                     warn!(
                         "window buffer has size {}, but fb has size {}! Skipping redraw.",
@@ -166,21 +216,24 @@ impl<FB: FrameBuffer> ApplicationHandler for NativeDisplaySink<FB> {
                     return;
                 }
 
-                buffer.copy_from_slice(
-                    &self
-                        .fb
-                        .as_pixels()
-                        .iter()
-                        .map(|pixel| (pixel << 8).swap_bytes())
-                        .collect::<Vec<_>>(),
-                );
+                rgb0_to_softbuffer(&rgb0, &mut buffer);
                 window.pre_present_notify();
                 buffer.present().expect("Failed to present buffer");
-                window.request_redraw();
+                self.hide_cursor_if_idle(&window);
+                match self.window_options.frame_interval {
+                    Some(frame_interval) => event_loop
+                        .set_control_flow(ControlFlow::WaitUntil(Instant::now() + frame_interval)),
+                    None => window.request_redraw(),
+                }
+            }
+            WindowEvent::CursorMoved { .. } => {
+                self.last_cursor_movement = Instant::now();
+                if !self.cursor_visible {
+                    surface.window().set_cursor_visible(true);
+                    self.cursor_visible = true;
+                }
             }
-            WindowEvent::CursorMoved { .. }
-            | WindowEvent::CursorEntered { .. }
-            | WindowEvent::CursorLeft { .. } => (),
+            WindowEvent::CursorEntered { .. } | WindowEvent::CursorLeft { .. } => (),
             _ => {
                 debug!("Received window event: {event:?}");
             }
@@ -189,12 +242,142 @@ impl<FB: FrameBuffer> ApplicationHandler for NativeDisplaySink<FB> {
 }
 
 impl<FB: FrameBuffer> NativeDisplaySink<FB> {
+    /// Follow the resizes of the given framebuffer instead of always showing the framebuffer passed to
+    /// [`NativeDisplaySink::new`]. The window is resized as soon as the next frame is drawn.
+    pub fn with_resizable_framebuffer(mut self, fb: Arc<ResizableFrameBuffer<FB>>) -> Self {
+        self.fb = fb;
+        self
+    }
+
+    fn new_with_window_options(
+        fb: Arc<ResizableFrameBuffer<FB>>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+        window_options: WindowOptions,
+    ) -> Self {
+        Self {
+            current_fb: fb.load(),
+            fb,
+            terminate_signal_rx,
+            window_options,
+            surface: None,
+            last_cursor_movement: Instant::now(),
+            cursor_visible: true,
+        }
+    }
+
     fn window_attributes(&self) -> WindowAttributes {
         Window::default_attributes()
             .with_title("Pixelflut server (breakwater)")
             .with_inner_size(winit::dpi::PhysicalSize::new(
-                self.fb.get_width() as u32,
-                self.fb.get_height() as u32,
+                self.current_fb.get_width() as u32,
+                self.current_fb.get_height() as u32,
             ))
+            .with_maximized(self.window_options.maximized)
+            .with_fullscreen(
+                self.window_options
+                    .fullscreen
+                    .then_some(Fullscreen::Borderless(None)),
+            )
+    }
+
+    fn hide_cursor_if_idle(&mut self, window: &Window) {
+        let Some(hide_cursor_after) = self.window_options.hide_cursor_after else {
+            return;
+        };
+
+        if self.cursor_visible && self.last_cursor_movement.elapsed() >= hide_cursor_after {
+            window.set_cursor_visible(false);
+            self.cursor_visible = false;
+        }
+    }
+}
+
+/// softbuffer expects the pixels as `0x00rrggbb`
+fn rgb0_to_softbuffer(rgb0: &[u8], buffer: &mut [u32]) {
+    for (target, pixel) in buffer.iter_mut().zip(rgb0.chunks_exact(4)) {
+        *target = u32::from_be_bytes([0, pixel[0], pixel[1], pixel[2]]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::{Packed24FrameBuffer, SimpleFrameBuffer};
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(&[], false, false, None)]
+    #[case(&["--native-display-maximized"], true, false, None)]
+    #[case(&["--native-display-fullscreen"], false, true, None)]
+    #[case(&["--native-display-fullscreen", "--native-display-hide-cursor-after-s", "5"], false, true, Some(5))]
+    #[tokio::test]
+    async fn test_window_options(
+        #[case] args: &[&str],
+        #[case] expected_maximized: bool,
+        #[case] expected_fullscreen: bool,
+        #[case] expected_hide_cursor_after_s: Option<u64>,
+    ) {
+        let cli_args =
+            CliArgs::parse_from(["breakwater", "--native-display"].iter().chain(args.iter()));
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sink = NativeDisplaySink::new(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap()
+        .expect("native display sink should be enabled");
+
+        let window_attributes = sink.window_attributes();
+        assert_eq!(window_attributes.maximized, expected_maximized);
+        assert_eq!(
+            window_attributes.fullscreen,
+            expected_fullscreen.then_some(Fullscreen::Borderless(None))
+        );
+        assert_eq!(
+            sink.window_options.hide_cursor_after,
+            expected_hide_cursor_after_s.map(Duration::from_secs)
+        );
+    }
+
+    #[test]
+    fn test_maximized_conflicts_with_fullscreen() {
+        assert!(CliArgs::try_parse_from([
+            "breakwater",
+            "--native-display-maximized",
+            "--native-display-fullscreen",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_hide_cursor_after_must_be_positive() {
+        assert!(CliArgs::try_parse_from([
+            "breakwater",
+            "--native-display-hide-cursor-after-s",
+            "0",
+        ])
+        .is_err());
+    }
+
+    /// The framebuffer only stores 3 bytes per pixel, which are converted to rgb0 first
+    #[test]
+    fn test_rgb0_to_softbuffer() {
+        let fb = Packed24FrameBuffer::new(4, 2);
+        fb.set(0, 0, 0x0000_00ff);
+        fb.set(3, 1, 0x0056_3412);
+
+        let mut buffer = vec![0xffff_ffff; fb.get_size()];
+        rgb0_to_softbuffer(&fb.as_rgb0_bytes(), &mut buffer);
+        assert_eq!(buffer, [0x00ff_0000, 0, 0, 0, 0, 0, 0, 0x0012_3456]);
     }
 }
@@ -0,0 +1,100 @@
+use tokio::time::{self, Interval};
+
+use crate::{
+    cli_args::CliArgs, sinks::fps::interval_for_fps, statistics::StatisticsInformationEvent,
+};
+
+/// Interval in which a sink renders its frames.
+///
+/// In case `--drop-frames-when-no-clients` is set, the interval drops to `--idle-fps` as long as no client is connected
+/// and goes back to the normal fps as soon as the first client connects.
+pub struct RenderInterval {
+    fps: u32,
+    idle_fps: Option<u32>,
+    idle: bool,
+    interval: Interval,
+}
+
+impl RenderInterval {
+    pub fn new(cli_args: &CliArgs, fps: u32) -> Self {
+        Self {
+            fps,
+            idle_fps: cli_args
+                .drop_frames_when_no_clients
+                .then_some(cli_args.idle_fps),
+            idle: false,
+            interval: time::interval(interval_for_fps(fps)),
+        }
+    }
+
+    /// Needs to be called with every [`StatisticsInformationEvent`] the sink receives, so that we can react to the
+    /// number of connected clients.
+    pub fn update(&mut self, statistics_information_event: &StatisticsInformationEvent) {
+        let Some(idle_fps) = self.idle_fps else {
+            return;
+        };
+
+        let idle = statistics_information_event.connections == 0;
+        if idle != self.idle {
+            self.idle = idle;
+            let fps = if idle { idle_fps } else { self.fps };
+            self.interval = time::interval(interval_for_fps(fps));
+        }
+    }
+
+    #[cfg(test)]
+    fn period(&self) -> std::time::Duration {
+        self.interval.period()
+    }
+
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+
+    fn statistics_with_connections(connections: u32) -> StatisticsInformationEvent {
+        StatisticsInformationEvent {
+            connections,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interval_increases_without_clients() {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--drop-frames-when-no-clients",
+            "--idle-fps",
+            "2",
+        ]);
+        let mut render_interval = RenderInterval::new(&cli_args, 30);
+        assert_eq!(render_interval.period(), Duration::from_micros(33_333));
+
+        render_interval.update(&statistics_with_connections(3));
+        assert_eq!(render_interval.period(), Duration::from_micros(33_333));
+
+        render_interval.update(&statistics_with_connections(0));
+        assert_eq!(render_interval.period(), Duration::from_millis(500));
+
+        // Resume on the first connection
+        render_interval.update(&statistics_with_connections(1));
+        assert_eq!(render_interval.period(), Duration::from_micros(33_333));
+    }
+
+    #[tokio::test]
+    async fn test_interval_unchanged_when_disabled() {
+        let cli_args = CliArgs::parse_from(["breakwater"]);
+        let mut render_interval = RenderInterval::new(&cli_args, 30);
+
+        render_interval.update(&statistics_with_connections(0));
+        assert_eq!(render_interval.period(), Duration::from_micros(33_333));
+    }
+}
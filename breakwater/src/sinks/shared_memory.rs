@@ -0,0 +1,236 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use async_trait::async_trait;
+use breakwater_parser::{FrameBuffer, SharedMemory, SimpleFrameBuffer};
+use log::{info, warn};
+use snafu::{ensure, ResultExt, Snafu};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "The shared memory region {x},{y} {width}x{height} exceeds the canvas of {canvas_width}x{canvas_height}"
+    ))]
+    RegionOutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        canvas_width: usize,
+        canvas_height: usize,
+    },
+
+    #[snafu(display("Failed to open shared memory {shared_memory_name:?}"))]
+    OpenSharedMemory {
+        source: std::io::Error,
+        shared_memory_name: String,
+    },
+}
+
+/// Opens the shared memory `--shared-memory-name` for a canvas of the given size. In case it contains a canvas of a
+/// different size, it is only replaced with `--recreate-shared-memory`.
+pub fn open_shared_memory(
+    cli_args: &CliArgs,
+    shared_memory_name: &str,
+    width: usize,
+    height: usize,
+) -> std::io::Result<SharedMemory> {
+    match SharedMemory::open_or_create(shared_memory_name, width, height) {
+        // The region contains a canvas of a different size
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            if cli_args.recreate_shared_memory {
+                warn!("{err}, recreating it");
+                SharedMemory::recreate(shared_memory_name, width, height)
+            } else {
+                warn!("{err}. Use --recreate-shared-memory to replace it with a canvas of the new size");
+                Err(err)
+            }
+        }
+        result => result,
+    }
+}
+
+/// Copies the area of the canvas given by `--shared-memory-region` into the shared memory `--shared-memory-name` with
+/// `--fps` frames per second, so that external tools only interested in that area don't need to map the whole canvas.
+/// The shared memory has the size of the area. Without `--shared-memory-region` the whole canvas is stored in the
+/// shared memory instead, so there is nothing to copy.
+pub struct SharedMemorySink<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    terminate_signal_rx: broadcast::Receiver<()>,
+
+    x: usize,
+    y: usize,
+    shared_memory: SimpleFrameBuffer,
+    fps: u32,
+}
+
+#[async_trait]
+impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for SharedMemorySink<FB> {
+    async fn new(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        _statistics_tx: mpsc::Sender<StatisticsEvent>,
+        _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Option<Self>, super::Error> {
+        let (Some(shared_memory_name), Some(region)) =
+            (&cli_args.shared_memory_name, &cli_args.shared_memory_region)
+        else {
+            return Ok(None);
+        };
+        // clap ensures there are exactly four values
+        let [x, y, width, height] = region[..] else {
+            unreachable!("--shared-memory-region takes four values");
+        };
+
+        ensure!(
+            x.saturating_add(width) <= fb.get_width()
+                && y.saturating_add(height) <= fb.get_height(),
+            RegionOutOfBoundsSnafu {
+                x,
+                y,
+                width,
+                height,
+                canvas_width: fb.get_width(),
+                canvas_height: fb.get_height(),
+            }
+        );
+        let shared_memory = open_shared_memory(cli_args, shared_memory_name, width, height)
+            .context(OpenSharedMemorySnafu { shared_memory_name })?;
+        info!(
+            "Copying the region {x},{y} {width}x{height} of the canvas into the shared memory {:?}",
+            shared_memory.path()
+        );
+
+        Ok(Some(Self {
+            fb,
+            terminate_signal_rx,
+            x,
+            y,
+            shared_memory: SimpleFrameBuffer::from_shared_memory(shared_memory),
+            fps: fps.shared_memory,
+        }))
+    }
+
+    async fn run(&mut self) -> Result<(), super::Error> {
+        let mut interval = time::interval(interval_for_fps(self.fps));
+        loop {
+            tokio::select! {
+                _ = self.terminate_signal_rx.recv() => return Ok(()),
+                _ = interval.tick() => self.copy_region(),
+            }
+        }
+    }
+}
+
+impl<FB: FrameBuffer> SharedMemorySink<FB> {
+    fn copy_region(&self) {
+        let rgb0 = self.fb.as_rgb0_bytes();
+        let (canvas_width, width) = (self.fb.get_width(), self.shared_memory.get_width());
+        for row in 0..self.shared_memory.get_height() {
+            let start = ((self.y + row) * canvas_width + self.x) * 4;
+            self.shared_memory
+                .set_multi_from_start_index(row * width, &rgb0[start..start + width * 4]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use breakwater_parser::SHARED_MEMORY_DIR;
+    use clap::Parser;
+
+    use super::*;
+
+    async fn start_sink(
+        fb: Arc<SimpleFrameBuffer>,
+        shared_memory_name: &str,
+        region: [&str; 4],
+    ) -> Result<Option<SharedMemorySink<SimpleFrameBuffer>>, super::super::Error> {
+        let cli_args = CliArgs::parse_from(
+            [
+                "breakwater",
+                "--shared-memory-name",
+                shared_memory_name,
+                "--shared-memory-region",
+            ]
+            .into_iter()
+            .chain(region),
+        );
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        SharedMemorySink::new(
+            fb,
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_region_is_mirrored() {
+        let shared_memory_name = format!("breakwater-test-{}-sink-region", std::process::id());
+        let fb = Arc::new(SimpleFrameBuffer::new(8, 4));
+        let sink = start_sink(fb.clone(), &shared_memory_name, ["2", "1", "3", "2"])
+            .await
+            .unwrap()
+            .expect("shared memory sink should be enabled");
+
+        // Within the region
+        fb.set(2, 1, 0x0000_00ff);
+        fb.set(4, 2, 0x0000_ff00);
+        // Around the region
+        fb.set(1, 1, 0x00ff_ffff);
+        fb.set(5, 1, 0x00ff_ffff);
+        fb.set(2, 0, 0x00ff_ffff);
+        fb.set(3, 3, 0x00ff_ffff);
+        sink.copy_region();
+
+        let mirrored =
+            SimpleFrameBuffer::from_shared_memory(SharedMemory::open(&shared_memory_name).unwrap());
+        assert_eq!(mirrored.get_width(), 3);
+        assert_eq!(mirrored.get_height(), 2);
+        assert_eq!(mirrored.as_pixels(), [0x0000_00ff, 0, 0, 0, 0, 0x0000_ff00]);
+
+        std::fs::remove_file(Path::new(SHARED_MEMORY_DIR).join(shared_memory_name)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_region_out_of_bounds() {
+        let shared_memory_name =
+            format!("breakwater-test-{}-sink-out-of-bounds", std::process::id());
+        let fb = Arc::new(SimpleFrameBuffer::new(8, 4));
+
+        let result = start_sink(fb, &shared_memory_name, ["6", "0", "3", "1"]).await;
+        assert!(
+            matches!(
+                result,
+                Err(super::super::Error::SharedMemoryError {
+                    source: Error::RegionOutOfBounds { .. }
+                })
+            ),
+            "{:?}",
+            result.err()
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use async_trait::async_trait;
+use breakwater_parser::FrameBuffer;
+use log::{debug, info};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::{broadcast, mpsc},
+    time,
+};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to remove stale unix socket {path:?}"))]
+    RemoveStaleUnixSocket {
+        source: std::io::Error,
+        path: String,
+    },
+
+    #[snafu(display("Failed to bind to unix socket {path:?}"))]
+    BindToUnixSocket {
+        source: std::io::Error,
+        path: String,
+    },
+
+    #[snafu(display("Failed to accept consumer on unix socket"))]
+    AcceptConsumer { source: std::io::Error },
+}
+
+/// Streams the raw framebuffer (see [`FrameBuffer::as_bytes`]) with `--fps` frames per second to all consumers
+/// connected to the unix socket at `--unix-socket-frame-path`. There is no header, every frame simply consists of
+/// `width * height * 4` bytes.
+///
+/// Consumers receive the frames one after another, so a slow consumer also slows down all others. Consumers can
+/// disconnect at any time.
+pub struct UnixSocketSink<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    terminate_signal_rx: broadcast::Receiver<()>,
+
+    path: String,
+    listener: UnixListener,
+    consumers: Vec<UnixStream>,
+    fps: u32,
+}
+
+#[async_trait]
+impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for UnixSocketSink<FB> {
+    async fn new(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        _statistics_tx: mpsc::Sender<StatisticsEvent>,
+        _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Option<Self>, super::Error> {
+        if cli_args.primary_display_only {
+            return Ok(None);
+        }
+        let Some(path) = &cli_args.unix_socket_frame_path else {
+            return Ok(None);
+        };
+
+        // A previous run might not have cleaned up its socket (e.g. because it was killed)
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context(RemoveStaleUnixSocketSnafu { path })?
+            }
+            _ => {}
+        }
+        let listener = UnixListener::bind(path).context(BindToUnixSocketSnafu { path })?;
+        info!("Streaming frames to consumers of unix socket {path:?}");
+
+        Ok(Some(Self {
+            fb,
+            terminate_signal_rx,
+            path: path.clone(),
+            listener,
+            consumers: Vec::new(),
+            fps: fps.unix_socket,
+        }))
+    }
+
+    async fn run(&mut self) -> Result<(), super::Error> {
+        let mut interval = time::interval(interval_for_fps(self.fps));
+        loop {
+            tokio::select! {
+                _ = self.terminate_signal_rx.recv() => {
+                    // Nothing we can do about it in case it fails, we are shutting down anyway
+                    let _ = std::fs::remove_file(&self.path);
+                    return Ok(());
+                }
+                consumer = self.listener.accept() => {
+                    let (consumer, _) = consumer.context(AcceptConsumerSnafu)?;
+                    debug!("Consumer connected to unix socket {:?}", self.path);
+                    self.consumers.push(consumer);
+                }
+                _ = interval.tick() => self.send_frame().await,
+            }
+        }
+    }
+}
+
+impl<FB: FrameBuffer> UnixSocketSink<FB> {
+    /// Writes the current framebuffer to all consumers, consumers that disconnected are dropped
+    async fn send_frame(&mut self) {
+        let frame = self.fb.as_rgb0_bytes();
+        let mut index = 0;
+        while index < self.consumers.len() {
+            if let Err(err) = self.consumers[index].write_all(&frame).await {
+                debug!("Dropping consumer of unix socket {:?}: {err}", self.path);
+                self.consumers.swap_remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use clap::Parser;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_streams_frames() {
+        let path = std::env::temp_dir().join(format!(
+            "breakwater-test-unix-socket-sink-{}.sock",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+
+        let fb = Arc::new(SimpleFrameBuffer::new(64, 48));
+        fb.set(1, 0, 0x0012_3456);
+        let cli_args = CliArgs::parse_from(["breakwater", "--unix-socket-frame-path", path]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let mut sink = UnixSocketSink::new(
+            fb.clone(),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap()
+        .expect("unix socket sink should be enabled");
+        let sink_thread = tokio::spawn(async move { sink.run().await });
+
+        // A consumer disconnecting must not affect the others
+        drop(UnixStream::connect(path).await.unwrap());
+        let mut consumer = UnixStream::connect(path).await.unwrap();
+
+        let mut frame = vec![0; 64 * 48 * 4];
+        consumer.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame.len(), fb.get_width() * fb.get_height() * 4);
+        assert_eq!(frame, fb.as_bytes());
+        consumer.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, fb.as_bytes());
+
+        terminate_signal_tx.send(()).unwrap();
+        sink_thread.await.unwrap().unwrap();
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
@@ -0,0 +1,236 @@
+use std::{io::Write, sync::Arc};
+
+use async_trait::async_trait;
+use breakwater_parser::FrameBuffer;
+use log::info;
+use snafu::{ensure, ResultExt, Snafu};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+use v4l::{video::Output, Device, Format, FourCC};
+
+use crate::{
+    cli_args::CliArgs,
+    sinks::{
+        fps::{interval_for_fps, FpsConfig},
+        DisplaySink,
+    },
+    statistics::{StatisticsEvent, StatisticsInformationEvent},
+};
+
+/// YUYV (YUV 4:2:2) is understood by pretty much every video-conferencing tool, so we prefer it
+const FOURCC_YUYV: FourCC = FourCC { repr: *b"YUYV" };
+const FOURCC_RGB24: FourCC = FourCC { repr: *b"RGB3" };
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to open v4l2 device {device:?}"))]
+    OpenV4l2Device {
+        source: std::io::Error,
+        device: String,
+    },
+
+    #[snafu(display("Failed to set the output format of v4l2 device {device:?}"))]
+    SetV4l2Format {
+        source: std::io::Error,
+        device: String,
+    },
+
+    #[snafu(display(
+        "The v4l2 device {device:?} does not support the format {width}x{height} in YUYV or RGB3, it offered {format}"
+    ))]
+    UnsupportedV4l2Format {
+        device: String,
+        width: usize,
+        height: usize,
+        format: String,
+    },
+
+    #[snafu(display("Failed to write frame to v4l2 device {device:?}"))]
+    WriteFrameToV4l2Device {
+        source: std::io::Error,
+        device: String,
+    },
+}
+
+/// Writes the canvas into a v4l2 (loopback) device, e.g. `/dev/video0`, so that it can be used as webcam in
+/// video-conferencing tools or OBS.
+pub struct V4l2Sink<FB: FrameBuffer> {
+    fb: Arc<FB>,
+    terminate_signal_rx: broadcast::Receiver<()>,
+
+    device_path: String,
+    device: Device,
+    fourcc: FourCC,
+    fps: u32,
+}
+
+#[async_trait]
+impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for V4l2Sink<FB> {
+    async fn new(
+        fb: Arc<FB>,
+        cli_args: &CliArgs,
+        fps: &FpsConfig,
+        _statistics_tx: mpsc::Sender<StatisticsEvent>,
+        _statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+        terminate_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Option<Self>, super::Error> {
+        let Some(device_path) = &cli_args.v4l2_device else {
+            return Ok(None);
+        };
+
+        let device = Device::with_path(device_path).context(OpenV4l2DeviceSnafu {
+            device: device_path,
+        })?;
+        let format = Output::set_format(
+            &device,
+            &Format::new(fb.get_width() as u32, fb.get_height() as u32, FOURCC_YUYV),
+        )
+        .context(SetV4l2FormatSnafu {
+            device: device_path,
+        })?;
+
+        // The device is free to pick a different format, we can only handle some of them
+        ensure!(
+            format.width as usize == fb.get_width()
+                && format.height as usize == fb.get_height()
+                && ((format.fourcc == FOURCC_YUYV && fb.get_width().is_multiple_of(2))
+                    || format.fourcc == FOURCC_RGB24),
+            UnsupportedV4l2FormatSnafu {
+                device: device_path,
+                width: fb.get_width(),
+                height: fb.get_height(),
+                format: format!("{}x{} in {}", format.width, format.height, format.fourcc),
+            }
+        );
+        info!(
+            "Writing canvas to v4l2 device {device_path:?} in format {}",
+            format.fourcc
+        );
+
+        Ok(Some(Self {
+            fb,
+            terminate_signal_rx,
+            device_path: device_path.clone(),
+            device,
+            fourcc: format.fourcc,
+            fps: fps.v4l2,
+        }))
+    }
+
+    async fn run(&mut self) -> Result<(), super::Error> {
+        let mut frame = Vec::new();
+        let mut interval = time::interval(interval_for_fps(self.fps));
+        loop {
+            if self.terminate_signal_rx.try_recv().is_ok() {
+                return Ok(());
+            }
+
+            frame.clear();
+            if self.fourcc == FOURCC_YUYV {
+                rgb0_to_yuyv(&self.fb.as_rgb0_bytes(), &mut frame);
+            } else {
+                rgb0_to_rgb24(&self.fb.as_rgb0_bytes(), &mut frame);
+            }
+
+            // Writing to a loopback device is only a memcpy in the kernel, so we don't need spawn_blocking here
+            self.device
+                .write_all(&frame)
+                .context(WriteFrameToV4l2DeviceSnafu {
+                    device: &self.device_path,
+                })?;
+
+            interval.tick().await;
+        }
+    }
+}
+
+fn rgb0_to_rgb24(rgb0: &[u8], rgb24: &mut Vec<u8>) {
+    rgb24.extend(
+        rgb0.chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]),
+    );
+}
+
+/// Converts two pixels at a time, as they share the U and V values. The width of the framebuffer needs to be even.
+fn rgb0_to_yuyv(rgb0: &[u8], yuyv: &mut Vec<u8>) {
+    for pixels in rgb0.chunks_exact(8) {
+        let (y0, u0, v0) = rgb_to_yuv(pixels[0], pixels[1], pixels[2]);
+        let (y1, u1, v1) = rgb_to_yuv(pixels[4], pixels[5], pixels[6]);
+        yuyv.extend_from_slice(&[
+            y0,
+            ((u0 as u16 + u1 as u16) / 2) as u8,
+            y1,
+            ((v0 as u16 + v1 as u16) / 2) as u8,
+        ]);
+    }
+}
+
+/// BT.601 (limited range) using integer arithmetics, see <https://en.wikipedia.org/wiki/YCbCr#ITU-R_BT.601_conversion>
+#[inline(always)]
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_rgb0_to_rgb24() {
+        let mut rgb24 = Vec::new();
+        rgb0_to_rgb24(&[1, 2, 3, 0, 4, 5, 6, 0], &mut rgb24);
+        assert_eq!(rgb24, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_rgb0_to_yuyv() {
+        let mut yuyv = Vec::new();
+        rgb0_to_yuyv(
+            &[
+                0x00, 0x00, 0x00, 0, // black
+                0xff, 0xff, 0xff, 0, // white
+                0xff, 0x00, 0x00, 0, // red
+                0xff, 0x00, 0x00, 0, // red
+            ],
+            &mut yuyv,
+        );
+        assert_eq!(yuyv, [16, 128, 235, 128, 82, 90, 82, 240]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_v4l2_device() {
+        let cli_args = CliArgs::parse_from([
+            "breakwater",
+            "--v4l2-device",
+            "/dev/breakwater-test-does-not-exist",
+        ]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let result = V4l2Sink::new(
+            Arc::new(SimpleFrameBuffer::new(64, 48)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(super::super::Error::V4l2Error {
+                source: Error::OpenV4l2Device { .. }
+            })
+        ));
+    }
+}
@@ -1,38 +1,135 @@
 use core::slice;
-use std::{sync::Arc, time::Duration};
+use std::{
+    ffi::{c_char, c_int, c_void},
+    ops::Range,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
-use breakwater_parser::FrameBuffer;
-use number_prefix::NumberPrefix;
-use rusttype::{point, Font, Scale};
-use snafu::{OptionExt, ResultExt, Snafu};
-use tokio::{
-    sync::{broadcast, mpsc},
-    time,
+use breakwater_parser::{
+    rasterize_text, DirtyRegion, FrameBuffer, ResizableFrameBuffer, WriteProtectedRegion,
 };
+use number_prefix::NumberPrefix;
+use rusttype::Font;
+use snafu::{ResultExt, Snafu};
+use tokio::sync::{broadcast, mpsc};
 use vncserver::{
     rfb_framebuffer_malloc, rfb_get_screen, rfb_init_server, rfb_mark_rect_as_modified,
     rfb_run_event_loop, RfbScreenInfoPtr,
 };
 
 use crate::{
-    cli_args::CliArgs,
-    sinks::DisplaySink,
+    cli_args::{CliArgs, StatsPosition},
+    font::{self, load_font},
+    sinks::{fps::FpsConfig, render_interval::RenderInterval, DisplaySink},
     statistics::{StatisticsEvent, StatisticsInformationEvent},
 };
 
-const STATS_HEIGHT: usize = 35;
+extern "C" {
+    /// Not wrapped by the vncserver crate. Swaps in a framebuffer of another size, the connected clients are told about
+    /// the new size. The previous framebuffer is not used by libvncserver anymore once this returns.
+    fn rfbNewFramebuffer(
+        screen: *mut c_void,
+        framebuffer: *mut c_char,
+        width: c_int,
+        height: c_int,
+        bits_per_sample: c_int,
+        samples_per_pixel: c_int,
+        bytes_per_pixel: c_int,
+    );
+}
+
+/// Font size of the statistics text for the default `--vnc-stats-height`, it is scaled for other heights
+const STATS_FONT_SIZE_PER_HEIGHT: f32 = 27.0 / 35.0;
+
+/// Splits the screen into the drawing surface and the strip the statistics are rendered into. Both span the whole
+/// width of the screen, so we only keep track of the rows.
+///
+/// We leave a line between both, which belongs to neither of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsLayout {
+    width: usize,
+    height: usize,
+    /// Rows copied from the framebuffer
+    drawing_rows: Range<usize>,
+    /// Rows the statistics are rendered into
+    stats_rows: Range<usize>,
+}
+
+impl StatsLayout {
+    pub fn new(width: usize, height: usize, stats_height: usize, position: StatsPosition) -> Self {
+        let (drawing_rows, stats_rows) = match position {
+            StatsPosition::Top => (
+                (stats_height + 1).min(height)..height,
+                0..stats_height.min(height),
+            ),
+            StatsPosition::Bottom => (
+                0..height.saturating_sub(stats_height + 1),
+                height.saturating_sub(stats_height)..height,
+            ),
+        };
+
+        Self {
+            width,
+            height,
+            drawing_rows,
+            stats_rows,
+        }
+    }
+
+    /// The area the statistics are rendered into (including the line in between). Pixels drawn there are never shown
+    /// via VNC.
+    pub fn stats_region(&self) -> WriteProtectedRegion {
+        let rows = if self.drawing_rows.start > 0 {
+            0..self.drawing_rows.start
+        } else {
+            self.drawing_rows.end..self.height
+        };
+
+        WriteProtectedRegion {
+            x: 0,
+            y: rows.start,
+            width: self.width,
+            height: rows.len(),
+        }
+    }
+
+    /// The pixels of the drawing surface as range of indices into the framebuffer
+    fn drawing_pixels(&self) -> Range<usize> {
+        self.drawing_rows.start * self.width..self.drawing_rows.end * self.width
+    }
+
+    /// Corners (x1, y1, x2, y2) of the drawing surface, as expected by [`rfb_mark_rect_as_modified`]
+    fn drawing_rect(&self) -> (i32, i32, i32, i32) {
+        Self::rect(self.width, &self.drawing_rows)
+    }
+
+    /// The part of the dirty region on the drawing surface as (columns, rows), [`None`] in case it only covers the
+    /// statistics
+    fn clip_to_drawing_surface(
+        &self,
+        region: &DirtyRegion,
+    ) -> Option<(Range<usize>, Range<usize>)> {
+        let rows = region.y.max(self.drawing_rows.start)
+            ..(region.y + region.height).min(self.drawing_rows.end);
+
+        (!rows.is_empty()).then_some((region.x..region.x + region.width, rows))
+    }
+
+    /// Corners (x1, y1, x2, y2) of the statistics, as expected by [`rfb_mark_rect_as_modified`]
+    fn stats_rect(&self) -> (i32, i32, i32, i32) {
+        Self::rect(self.width, &self.stats_rows)
+    }
+
+    fn rect(width: usize, rows: &Range<usize>) -> (i32, i32, i32, i32) {
+        (0, rows.start as i32, width as i32, rows.end as i32)
+    }
+}
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Failed to read font from file {font_file}"))]
-    ReadFontFile {
-        source: std::io::Error,
-        font_file: String,
-    },
-
-    #[snafu(display("Failed to construct font from font file {font_file}"))]
-    ConstructFontFromFontFile { font_file: String },
+    #[snafu(display("Failed to load font"))]
+    LoadFont { source: font::Error },
 
     #[snafu(display("Failed to write to statistics channel"))]
     WriteToStatisticsChannel {
@@ -49,15 +146,23 @@ pub enum Error {
 unsafe impl<FB: FrameBuffer> Send for VncSink<'_, FB> {}
 
 pub struct VncSink<'a, FB: FrameBuffer> {
-    fb: Arc<FB>,
+    /// Loaded every frame, so that we notice resizes, see [`VncSink::with_resizable_framebuffer`]
+    fb: Arc<ResizableFrameBuffer<FB>>,
+    /// The framebuffer the screen currently has the size of
+    current_fb: Arc<FB>,
     statistics_tx: mpsc::Sender<StatisticsEvent>,
     statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
     terminate_signal_rx: broadcast::Receiver<()>,
 
     screen: RfbScreenInfoPtr,
-    target_fps: u32,
+    /// The framebuffer of the screen after it got resized. The initial framebuffer is allocated by libvncserver.
+    resized_vnc_fb: Option<Vec<u32>>,
+    render_interval: RenderInterval,
     text: String,
     font: Font<'a>,
+    stats_height: usize,
+    stats_position: StatsPosition,
+    stats_layout: StatsLayout,
 }
 
 #[async_trait]
@@ -65,32 +170,23 @@ impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for VncSink<'_, FB> {
     async fn new(
         fb: Arc<FB>,
         cli_args: &CliArgs,
+        fps: &FpsConfig,
         statistics_tx: mpsc::Sender<StatisticsEvent>,
         statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
         terminate_signal_rx: broadcast::Receiver<()>,
     ) -> Result<Option<Self>, super::Error> {
-        if !cli_args.vnc {
+        if !cli_args.vnc || cli_args.primary_display_only {
             return Ok(None);
         }
 
-        let font = match cli_args.font.as_str() {
-            // We ship our own copy of Arial.ttf, so that users don't need to download and provide it
-            "Arial.ttf" => {
-                let font_bytes = include_bytes!("../../../Arial.ttf");
-                Font::try_from_bytes(font_bytes).context(ConstructFontFromFontFileSnafu {
-                    font_file: "Arial.ttf".to_string(),
-                })?
-            }
-            _ => {
-                let font_bytes = std::fs::read(&cli_args.font).context(ReadFontFileSnafu {
-                    font_file: cli_args.font.clone(),
-                })?;
-
-                Font::try_from_vec(font_bytes).context(ConstructFontFromFontFileSnafu {
-                    font_file: cli_args.font.clone(),
-                })?
-            }
-        };
+        let font = load_font(cli_args).context(LoadFontSnafu)?;
+
+        let stats_layout = StatsLayout::new(
+            fb.get_width(),
+            fb.get_height(),
+            cli_args.vnc_stats_height as usize,
+            cli_args.vnc_stats_position,
+        );
 
         let screen = rfb_get_screen(fb.get_width() as i32, fb.get_height() as i32, 8, 3, 4);
         unsafe {
@@ -111,46 +207,83 @@ impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for VncSink<'_, FB> {
 
         // FIXME: Only return Some in case VNC is enabled
         Ok(Some(Self {
-            fb,
+            fb: Arc::new(ResizableFrameBuffer::from_arc(fb.clone())),
+            current_fb: fb,
             statistics_tx,
             statistics_information_rx,
             terminate_signal_rx,
             screen,
-            target_fps: cli_args.fps,
+            resized_vnc_fb: None,
+            render_interval: RenderInterval::new(cli_args, fps.vnc),
             text: cli_args.text.clone(),
             font,
+            stats_height: cli_args.vnc_stats_height as usize,
+            stats_position: cli_args.vnc_stats_position,
+            stats_layout,
         }))
     }
 
     async fn run(&mut self) -> Result<(), super::Error> {
-        let vnc_fb_slice: &mut [u32] = unsafe {
-            slice::from_raw_parts_mut((*self.screen).frameBuffer as *mut u32, self.fb.get_size())
-        };
-
-        // A line less because the (height - STATS_SURFACE_HEIGHT) belongs to the stats and gets refreshed by them
-        let height_up_to_stats_text = self.fb.get_height() - STATS_HEIGHT - 1;
-        let fb_size_up_to_stats_text = self.fb.get_width() * height_up_to_stats_text;
+        // The VNC framebuffer is not initialized, so the first frame always needs to be copied completely
+        let mut first_frame = true;
 
-        let mut interval =
-            time::interval(Duration::from_micros(1_000_000 / self.target_fps as u64));
         loop {
             if self.terminate_signal_rx.try_recv().is_ok() {
                 return Ok(());
             }
 
+            let fb = self.fb.load();
+            if !Arc::ptr_eq(&fb, &self.current_fb) {
+                self.resize_screen(fb);
+                // The new VNC framebuffer is not initialized either
+                first_frame = true;
+            }
+            let fb = Arc::clone(&self.current_fb);
+            let vnc_fb_slice: &mut [u8] = unsafe {
+                slice::from_raw_parts_mut(
+                    (*self.screen).frameBuffer as *mut u8,
+                    fb.get_size() * 4, /* bytes per pixel */
+                )
+            };
+            // The VNC framebuffer is rgb0, regardless of how the framebuffer stores the pixels
+            let rgb0 = fb.as_rgb0_bytes();
+
+            // The stats are refreshed by themselves
+            let drawing_pixels = self.stats_layout.drawing_pixels();
+            let (x1, y1, x2, y2) = self.stats_layout.drawing_rect();
+            let width = fb.get_width();
+
             // I don't think we need to use spawn_blocking or something like that, as this operation should hopefully be
             // a quick memcpy. But I'm no expert on this.
-            vnc_fb_slice[0..fb_size_up_to_stats_text]
-                .copy_from_slice(&self.fb.as_pixels()[0..fb_size_up_to_stats_text]);
-
-            // Only refresh the drawing surface, not the stats surface
-            rfb_mark_rect_as_modified(
-                self.screen,
-                0,
-                0,
-                self.fb.get_width() as i32,
-                height_up_to_stats_text as i32,
-            );
+            match fb.take_dirty_regions().filter(|_| !first_frame) {
+                Some(dirty_regions) => {
+                    for (columns, rows) in dirty_regions
+                        .iter()
+                        .filter_map(|region| self.stats_layout.clip_to_drawing_surface(region))
+                    {
+                        for y in rows.clone() {
+                            let bytes =
+                                (y * width + columns.start) * 4..(y * width + columns.end) * 4;
+                            vnc_fb_slice[bytes.clone()].copy_from_slice(&rgb0[bytes]);
+                        }
+                        rfb_mark_rect_as_modified(
+                            self.screen,
+                            columns.start as i32,
+                            rows.start as i32,
+                            columns.end as i32,
+                            rows.end as i32,
+                        );
+                    }
+                }
+                None => {
+                    let bytes = drawing_pixels.start * 4..drawing_pixels.end * 4;
+                    vnc_fb_slice[bytes.clone()].copy_from_slice(&rgb0[bytes]);
+
+                    // Only refresh the drawing surface, not the stats surface
+                    rfb_mark_rect_as_modified(self.screen, x1, y1, x2, y2);
+                }
+            }
+            first_frame = false;
             self.statistics_tx
                 .send(StatisticsEvent::VncFrameRendered)
                 .await
@@ -161,27 +294,64 @@ impl<FB: FrameBuffer + Sync + Send> DisplaySink<FB> for VncSink<'_, FB> {
                     .statistics_information_rx
                     .try_recv()
                     .context(ReadFromStatisticsInformationChannelSnafu)?;
+                self.render_interval.update(&statistics_information_event);
                 self.display_stats(statistics_information_event);
             }
 
-            interval.tick().await;
+            self.render_interval.tick().await;
         }
     }
 }
 
 impl<FB: FrameBuffer> VncSink<'_, FB> {
+    /// Follow the resizes of the given framebuffer instead of always showing the framebuffer passed to
+    /// [`VncSink::new`]. The screen is resized as soon as the next frame is rendered.
+    pub fn with_resizable_framebuffer(mut self, fb: Arc<ResizableFrameBuffer<FB>>) -> Self {
+        self.fb = fb;
+        self
+    }
+
+    /// Gives the screen a new (black) framebuffer of the size of `fb`, the statistics are rendered again with the next
+    /// statistics update.
+    fn resize_screen(&mut self, fb: Arc<FB>) {
+        let (width, height) = (fb.get_width(), fb.get_height());
+        let mut vnc_fb = vec![0_u32; width * height];
+        unsafe {
+            rfbNewFramebuffer(
+                self.screen as *mut c_void,
+                vnc_fb.as_mut_ptr() as *mut c_char,
+                width as c_int,
+                height as c_int,
+                8,
+                3,
+                4,
+            );
+            // Same as in `new`, see there
+            (*self.screen).bitsPerPixel = 32;
+            (*self.screen).depth = 24;
+            (*self.screen).serverFormat.depth = 24;
+        }
+        // Frees the framebuffer of the previous resize, the initial framebuffer is leaked as it was allocated by
+        // libvncserver
+        self.resized_vnc_fb = Some(vnc_fb);
+
+        self.stats_layout = StatsLayout::new(width, height, self.stats_height, self.stats_position);
+        self.current_fb = fb;
+    }
+
     fn display_stats(&mut self, stats: StatisticsInformationEvent) {
+        let stats_rows = self.stats_layout.stats_rows.clone();
         self.draw_rect(
             0,
-            self.fb.get_height() - STATS_HEIGHT,
-            self.fb.get_width(),
-            self.fb.get_height(),
+            stats_rows.start,
+            self.stats_layout.width,
+            stats_rows.end,
             0,
         );
         self.draw_text(
             20,
-            self.fb.get_height() - STATS_HEIGHT + 2,
-            27_f32,
+            stats_rows.start + 2,
+            stats_rows.len() as f32 * STATS_FONT_SIZE_PER_HEIGHT,
             0x00ff_ffff,
             format!(
                 "{}. {} Bit/s ({}B total) by {} connections from {} IPs ({} legacy)",
@@ -196,38 +366,15 @@ impl<FB: FrameBuffer> VncSink<'_, FB> {
         );
 
         // Only refresh the stats surface, not the drawing surface
-        rfb_mark_rect_as_modified(
-            self.screen,
-            0,
-            (self.fb.get_height() - STATS_HEIGHT) as i32,
-            self.fb.get_width() as i32,
-            self.fb.get_height() as i32,
-        );
+        let (x1, y1, x2, y2) = self.stats_layout.stats_rect();
+        rfb_mark_rect_as_modified(self.screen, x1, y1, x2, y2);
     }
 
     fn draw_text(&mut self, x: usize, y: usize, scale: f32, text_rgba: u32, text: &str) {
-        let scale = Scale::uniform(scale);
-
-        let v_metrics = self.font.v_metrics(scale);
-
-        let glyphs: Vec<_> = self
-            .font
-            .layout(text, scale, point(x as f32, y as f32 + v_metrics.ascent))
-            .collect();
-
-        for glyph in glyphs {
-            if let Some(bounding_box) = glyph.pixel_bounding_box() {
-                glyph.draw(|x, y, v| {
-                    if v > 0.5 {
-                        self.set_pixel_checked(
-                            x as usize + bounding_box.min.x as usize,
-                            y as usize + bounding_box.min.y as usize,
-                            text_rgba,
-                        )
-                    }
-                });
-            }
-        }
+        let font = self.font.clone();
+        rasterize_text(&font, x, y, scale, text, |x, y| {
+            self.set_pixel_checked(x, y, text_rgba)
+        });
     }
 
     fn draw_rect(&mut self, start_x: usize, start_y: usize, end_x: usize, end_y: usize, rgba: u32) {
@@ -238,13 +385,14 @@ impl<FB: FrameBuffer> VncSink<'_, FB> {
         }
     }
 
-    /// Check for bounds. If out of bound do nothing.
+    /// Check for the bounds of the stats surface. If out of bound do nothing, so that we never draw onto the drawing
+    /// surface.
     fn set_pixel_checked(&mut self, x: usize, y: usize, rgba: u32) {
-        if x < self.fb.get_width() && y < self.fb.get_height() {
+        if x < self.stats_layout.width && self.stats_layout.stats_rows.contains(&y) {
             unsafe {
                 let addr = (*self.screen).frameBuffer as *mut u32;
-                let slice: &mut [u32] = slice::from_raw_parts_mut(addr, self.fb.get_size());
-                slice[x + self.fb.get_width() * y] = rgba;
+                let slice: &mut [u32] = slice::from_raw_parts_mut(addr, self.current_fb.get_size());
+                slice[x + self.stats_layout.width * y] = rgba;
             }
         }
     }
@@ -263,3 +411,113 @@ fn format(value: f64) -> String {
         NumberPrefix::Standalone(n) => format!("{n}"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use breakwater_parser::SimpleFrameBuffer;
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_primary_display_only_disables_vnc_sink() {
+        let cli_args = CliArgs::parse_from(["breakwater", "--vnc", "--primary-display-only"]);
+        let (statistics_tx, _statistics_rx) = mpsc::channel(1);
+        let (_statistics_information_tx, statistics_information_rx) = broadcast::channel(1);
+        let (_terminate_signal_tx, terminate_signal_rx) = broadcast::channel(1);
+
+        let sink = VncSink::new(
+            Arc::new(SimpleFrameBuffer::new(640, 480)),
+            &cli_args,
+            &FpsConfig::from_cli_args(&cli_args),
+            statistics_tx,
+            statistics_information_rx,
+            terminate_signal_rx,
+        )
+        .await
+        .unwrap();
+
+        assert!(sink.is_none());
+    }
+
+    #[rstest]
+    #[case::bottom(StatsPosition::Bottom, 0..1044, 1045..1080, 1044, 36)]
+    #[case::top(StatsPosition::Top, 36..1080, 0..35, 0, 36)]
+    fn test_stats_layout(
+        #[case] position: StatsPosition,
+        #[case] expected_drawing_rows: Range<usize>,
+        #[case] expected_stats_rows: Range<usize>,
+        #[case] expected_region_y: usize,
+        #[case] expected_region_height: usize,
+    ) {
+        let layout = StatsLayout::new(1920, 1080, 35, position);
+
+        assert_eq!(layout.drawing_rows, expected_drawing_rows);
+        assert_eq!(layout.stats_rows, expected_stats_rows);
+        assert_eq!(
+            layout.drawing_pixels(),
+            expected_drawing_rows.start * 1920..expected_drawing_rows.end * 1920
+        );
+        assert_eq!(
+            layout.drawing_rect(),
+            (
+                0,
+                expected_drawing_rows.start as i32,
+                1920,
+                expected_drawing_rows.end as i32
+            )
+        );
+        assert_eq!(
+            layout.stats_rect(),
+            (
+                0,
+                expected_stats_rows.start as i32,
+                1920,
+                expected_stats_rows.end as i32
+            )
+        );
+        assert_eq!(
+            layout.stats_region(),
+            WriteProtectedRegion {
+                x: 0,
+                y: expected_region_y,
+                width: 1920,
+                height: expected_region_height,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case::bottom_drawing(StatsPosition::Bottom, (64, 960, 128, 64), Some((64..192, 960..1024)))]
+    #[case::bottom_partially_stats(StatsPosition::Bottom, (0, 1024, 1920, 56), Some((0..1920, 1024..1044)))]
+    #[case::bottom_stats(StatsPosition::Bottom, (0, 1045, 64, 35), None)]
+    #[case::top_partially_stats(StatsPosition::Top, (64, 0, 64, 64), Some((64..128, 36..64)))]
+    #[case::top_drawing(StatsPosition::Top, (0, 64, 64, 64), Some((0..64, 64..128)))]
+    fn test_clip_to_drawing_surface(
+        #[case] position: StatsPosition,
+        #[case] (x, y, width, height): (usize, usize, usize, usize),
+        #[case] expected: Option<(Range<usize>, Range<usize>)>,
+    ) {
+        let layout = StatsLayout::new(1920, 1080, 35, position);
+        let region = DirtyRegion {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        assert_eq!(layout.clip_to_drawing_surface(&region), expected);
+    }
+
+    #[rstest]
+    #[case(StatsPosition::Bottom)]
+    #[case(StatsPosition::Top)]
+    fn test_stats_layout_larger_than_screen(#[case] position: StatsPosition) {
+        let layout = StatsLayout::new(640, 20, 35, position);
+
+        assert!(layout.drawing_rows.is_empty());
+        assert_eq!(layout.stats_rows, 0..20);
+        assert_eq!(layout.stats_region().height, 20);
+    }
+}
@@ -1,3 +1,5 @@
+use arc_swap::ArcSwap;
+use log::debug;
 use serde::{Deserialize, Serialize};
 use simple_moving_average::{SingleSumSMA, SMA};
 use snafu::{ResultExt, Snafu};
@@ -6,12 +8,46 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     fs::File,
     net::IpAddr,
+    sync::{
+        atomic::{fence, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::available_parallelism,
     time::{Duration, Instant},
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{interval, MissedTickBehavior},
+};
 
-pub const STATS_REPORT_INTERVAL: Duration = Duration::from_millis(1000);
+/// Map keyed by the IP addresses of clients.
+///
+/// By default the std hasher (SipHash) is used, which is resistant against HashDoS, but comparatively slow. The `fx-hash`
+/// feature switches to the much faster FxHash instead. Keep in mind that clients choose their IP addresses (e.g. from an
+/// IPv6 /64 subnet) and can therefore craft collisions, so only enable it if you trust your clients.
+#[cfg(not(feature = "fx-hash"))]
+pub type IpMap<V> = HashMap<IpAddr, V>;
+#[cfg(feature = "fx-hash")]
+pub type IpMap<V> = HashMap<IpAddr, V, rustc_hash::FxBuildHasher>;
+
+pub const DEFAULT_STATS_REPORT_INTERVAL: Duration = Duration::from_millis(1000);
 pub const STATS_SLIDING_WINDOW_SIZE: usize = 5;
+pub const DEFAULT_STATS_TOP_N: usize = 10;
+
+/// Upper bounds (in seconds) of the buckets of [`ConnectionDurations`]. Most flooders either connect very briefly (e.g.
+/// to read the size or a single pixel) or stay connected for hours.
+pub const CONNECTION_DURATION_BUCKETS: [f64; 10] = [
+    0.1,
+    1.0,
+    10.0,
+    60.0,
+    300.0,
+    900.0,
+    3600.0,
+    3.0 * 3600.0,
+    12.0 * 3600.0,
+    24.0 * 3600.0,
+];
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -39,13 +75,141 @@ pub enum Error {
     },
 }
 
+/// Events that are rare enough to be sent through a channel. The number of bytes read is counted using
+/// [`BytesReadCounters`] instead, as sending an event for every read would make the statistics task a bottleneck.
 #[derive(Debug)]
 pub enum StatisticsEvent {
-    ConnectionCreated { ip: IpAddr },
-    ConnectionClosed { ip: IpAddr },
-    ConnectionDenied { ip: IpAddr },
-    BytesRead { ip: IpAddr, bytes: u64 },
+    ConnectionCreated {
+        ip: IpAddr,
+    },
+    ConnectionClosed {
+        ip: IpAddr,
+        /// How long the connection was open
+        duration: Duration,
+    },
+    ConnectionDenied {
+        ip: IpAddr,
+    },
+    ConnectionLimitHit {
+        ip: IpAddr,
+    },
+    /// The connection sent more bytes than fit into the parser lookahead without forming a complete command, so they
+    /// had to be thrown away
+    BytesDiscarded {
+        ip: IpAddr,
+        bytes: u64,
+    },
+    /// The connection sent commands that looked like a known command, but could not be parsed
+    MalformedCommands {
+        ip: IpAddr,
+        commands: u64,
+    },
     VncFrameRendered,
+    /// Writing a frame to ffmpeg took longer than `--max-ffmpeg-stdin-lag`
+    FfmpegStdinLagged,
+}
+
+/// Counts the bytes read by all connections without sending events to the statistics task.
+///
+/// Every connection gets its own atomic counter, so reading from the socket only does a single atomic add. The
+/// counters are spread across multiple shards (one per CPU) to avoid contention on registration. The statistics task
+/// periodically drains all counters and drops the counters of closed connections.
+pub struct BytesReadCounters {
+    shards: Box<[Mutex<Vec<ConnectionCounter>>]>,
+    next_shard: AtomicUsize,
+}
+
+type ConnectionCounter = (IpAddr, Arc<PaddedCounter>);
+
+/// Padded to a cache line, so that connections running on different CPUs don't fight over the same cache line
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct PaddedCounter(AtomicU64);
+
+/// The counter of a single connection, see [`BytesReadCounters`].
+#[derive(Clone, Debug, Default)]
+pub struct BytesReadCounter {
+    bytes: Arc<PaddedCounter>,
+}
+
+impl BytesReadCounter {
+    #[inline(always)]
+    pub fn add(&self, bytes: u64) {
+        self.bytes.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Default for BytesReadCounters {
+    fn default() -> Self {
+        Self::new(available_parallelism().map_or(1, |cpus| cpus.get()))
+    }
+}
+
+impl BytesReadCounters {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a new counter for a connection from the given IP address
+    pub fn register(&self, ip: IpAddr) -> BytesReadCounter {
+        let counter = BytesReadCounter::default();
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        self.shards[shard]
+            .lock()
+            .expect("statistics shard lock poisoned")
+            .push((ip, Arc::clone(&counter.bytes)));
+        counter
+    }
+
+    /// Adds the bytes read since the last call to `bytes_for_ip`
+    pub fn drain_into(&self, bytes_for_ip: &mut IpMap<u64>) {
+        for shard in self.shards.iter() {
+            let mut counters = shard.lock().expect("statistics shard lock poisoned");
+            counters.retain(|(ip, counter)| {
+                // We need to check this before draining, the connection might add some last bytes in between
+                let closed = Arc::strong_count(counter) == 1;
+                if closed {
+                    // Make sure we see all bytes added before the connection dropped its reference
+                    fence(Ordering::Acquire);
+                }
+
+                let bytes = counter.0.swap(0, Ordering::Relaxed);
+                if bytes > 0 {
+                    *bytes_for_ip.entry(*ip).or_insert(0) += bytes;
+                }
+
+                !closed
+            });
+        }
+    }
+}
+
+/// Histogram of how long the connections lasted, using the buckets [`CONNECTION_DURATION_BUCKETS`]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ConnectionDurations {
+    /// Number of connections per bucket (not cumulative). Connections lasting longer than the largest bucket are only
+    /// counted in `count`.
+    pub bucket_counts: [u64; CONNECTION_DURATION_BUCKETS.len()],
+    /// Sum of all durations in seconds
+    pub sum_s: f64,
+    pub count: u64,
+}
+
+impl ConnectionDurations {
+    pub fn observe(&mut self, duration: Duration) {
+        let duration_s = duration.as_secs_f64();
+        if let Some(bucket) = CONNECTION_DURATION_BUCKETS
+            .iter()
+            .position(|&upper_bound| duration_s <= upper_bound)
+        {
+            self.bucket_counts[bucket] += 1;
+        }
+        self.sum_s += duration_s;
+        self.count += 1;
+    }
 }
 
 pub enum StatisticsSaveMode {
@@ -57,31 +221,68 @@ pub enum StatisticsSaveMode {
 pub struct StatisticsInformationEvent {
     pub frame: u64,
     pub connections: u32,
+    /// Number of distinct IPs (v4 and v6) with at least one open connection
     pub ips: u32,
+    /// Number of distinct IPv4 addresses with at least one open connection, they are also counted in `ips`
     pub legacy_ips: u32,
     pub bytes: u64,
     pub fps: u64,
     pub bytes_per_s: u64,
 
-    pub connections_for_ip: HashMap<IpAddr, u32>,
-    pub denied_connections_for_ip: HashMap<IpAddr, u32>,
-    pub bytes_for_ip: HashMap<IpAddr, u64>,
+    pub connections_for_ip: IpMap<u32>,
+    pub denied_connections_for_ip: IpMap<u32>,
+    #[serde(default)]
+    pub connection_limit_hits_for_ip: IpMap<u32>,
+    /// Bytes thrown away per IP address, as they did not form a complete command
+    #[serde(default)]
+    pub discarded_bytes_for_ip: IpMap<u64>,
+    /// Commands per IP address that looked like a known command, but could not be parsed
+    #[serde(default)]
+    pub malformed_commands_for_ip: IpMap<u64>,
+    pub bytes_for_ip: IpMap<u64>,
+    /// The most active IPs by bytes per second since the previous event, the most active one first. Capped to
+    /// `--stats-top-n` entries.
+    #[serde(default)]
+    pub top_ips_by_bytes_per_s: Vec<(IpAddr, u64)>,
+
+    /// Number of frames that took too long to be written to ffmpeg
+    #[serde(default)]
+    pub ffmpeg_stdin_lags: u64,
+
+    /// How long the closed connections lasted
+    #[serde(default)]
+    pub connection_durations: ConnectionDurations,
 
     pub statistic_events: u64,
 }
 
+/// The latest [`StatisticsInformationEvent`] calculated by [`Statistics`]. It can be read at any time, without
+/// subscribing to the broadcast channel and waiting for the next event.
+pub type StatisticsSnapshot = Arc<ArcSwap<StatisticsInformationEvent>>;
+
 pub struct Statistics {
     statistics_rx: mpsc::Receiver<StatisticsEvent>,
+    bytes_read_counters: Arc<BytesReadCounters>,
     statistics_information_tx: broadcast::Sender<StatisticsInformationEvent>,
+    snapshot: StatisticsSnapshot,
     statistic_events: u64,
 
     frame: u64,
-    connections_for_ip: HashMap<IpAddr, u32>,
-    denied_connections_for_ip: HashMap<IpAddr, u32>,
-    bytes_for_ip: HashMap<IpAddr, u64>,
+    ffmpeg_stdin_lags: u64,
+    connection_durations: ConnectionDurations,
+    connections_for_ip: IpMap<u32>,
+    denied_connections_for_ip: IpMap<u32>,
+    connection_limit_hits_for_ip: IpMap<u32>,
+    discarded_bytes_for_ip: IpMap<u64>,
+    malformed_commands_for_ip: IpMap<u64>,
+    bytes_for_ip: IpMap<u64>,
 
+    /// The rates are normalized to one second before they are added, so the averages keep their unit regardless of
+    /// the report interval. They span the last [`STATS_SLIDING_WINDOW_SIZE`] reports.
     bytes_per_s_window: SingleSumSMA<u64, u64, STATS_SLIDING_WINDOW_SIZE>,
     fps_window: SingleSumSMA<u64, u64, STATS_SLIDING_WINDOW_SIZE>,
+    top_n: usize,
+    report_interval: Duration,
 
     statistics_save_mode: StatisticsSaveMode,
 }
@@ -109,19 +310,29 @@ impl StatisticsInformationEvent {
 impl Statistics {
     pub fn new(
         statistics_rx: mpsc::Receiver<StatisticsEvent>,
+        bytes_read_counters: Arc<BytesReadCounters>,
         statistics_information_tx: broadcast::Sender<StatisticsInformationEvent>,
         statistics_save_mode: StatisticsSaveMode,
     ) -> Self {
         let mut statistics = Statistics {
             statistics_rx,
+            bytes_read_counters,
             statistics_information_tx,
+            snapshot: StatisticsSnapshot::default(),
             statistic_events: 0,
             frame: 0,
-            connections_for_ip: HashMap::new(),
-            denied_connections_for_ip: HashMap::new(),
-            bytes_for_ip: HashMap::new(),
+            ffmpeg_stdin_lags: 0,
+            connection_durations: ConnectionDurations::default(),
+            connections_for_ip: IpMap::default(),
+            denied_connections_for_ip: IpMap::default(),
+            connection_limit_hits_for_ip: IpMap::default(),
+            discarded_bytes_for_ip: IpMap::default(),
+            malformed_commands_for_ip: IpMap::default(),
+            bytes_for_ip: IpMap::default(),
             bytes_per_s_window: SingleSumSMA::new(),
             fps_window: SingleSumSMA::new(),
+            top_n: DEFAULT_STATS_TOP_N,
+            report_interval: DEFAULT_STATS_REPORT_INTERVAL,
             statistics_save_mode,
         };
 
@@ -131,49 +342,72 @@ impl Statistics {
                 statistics.statistic_events = save_point.statistic_events;
                 statistics.frame = save_point.frame;
                 statistics.bytes_for_ip = save_point.bytes_for_ip;
+                statistics.connection_durations = save_point.connection_durations;
             }
         }
 
         statistics
     }
 
+    /// Number of IPs reported in [`StatisticsInformationEvent::top_ips_by_bytes_per_s`], 0 disables it
+    pub fn with_top_n(mut self, top_n: usize) -> Self {
+        self.top_n = top_n;
+        self
+    }
+
+    /// Interval in which the statistics are calculated and reported, defaults to [`DEFAULT_STATS_REPORT_INTERVAL`]
+    pub fn with_report_interval(mut self, report_interval: Duration) -> Self {
+        self.report_interval = report_interval;
+        self
+    }
+
+    /// Returns a handle to the latest statistics, which is updated every report interval
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        Arc::clone(&self.snapshot)
+    }
+
     pub async fn start(&mut self) -> Result<(), Error> {
         let mut last_stat_report = Instant::now();
         let mut last_save_file_written = Instant::now();
         let mut statistics_information_event = StatisticsInformationEvent::default();
 
-        while let Some(statistics_update) = self.statistics_rx.recv().await {
-            self.statistic_events += 1;
-            match statistics_update {
-                StatisticsEvent::ConnectionCreated { ip } => {
-                    *self.connections_for_ip.entry(ip).or_insert(0) += 1;
-                }
-                StatisticsEvent::ConnectionClosed { ip } => {
-                    if let Entry::Occupied(mut o) = self.connections_for_ip.entry(ip) {
-                        let connections = o.get_mut();
-                        *connections -= 1;
-                        if *connections == 0 {
-                            o.remove_entry();
-                        }
-                    }
-                }
-                StatisticsEvent::ConnectionDenied { ip } => {
-                    *self.denied_connections_for_ip.entry(ip).or_insert(0) += 1;
-                }
-                StatisticsEvent::BytesRead { ip, bytes } => {
-                    *self.bytes_for_ip.entry(ip).or_insert(0) += bytes;
-                }
-                StatisticsEvent::VncFrameRendered => self.frame += 1,
+        // We can't rely on events arriving (e.g. without the VNC sink), so we also wake up regularly to collect the
+        // bytes read
+        let mut report_interval = interval(self.report_interval);
+        report_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                statistics_update = self.statistics_rx.recv() => match statistics_update {
+                    Some(statistics_update) => self.handle_event(statistics_update),
+                    None => break,
+                },
+                _ = report_interval.tick() => {}
             }
 
-            // As there is an event for every frame we are guaranteed to land here every second
             let last_stat_report_elapsed = last_stat_report.elapsed();
-            if last_stat_report_elapsed > STATS_REPORT_INTERVAL {
+            if last_stat_report_elapsed > self.report_interval {
                 last_stat_report = Instant::now();
                 statistics_information_event = self.calculate_statistics_information_event(
                     &statistics_information_event,
                     last_stat_report_elapsed,
                 );
+                if !statistics_information_event
+                    .top_ips_by_bytes_per_s
+                    .is_empty()
+                {
+                    debug!(
+                        "Most active IPs: {}",
+                        statistics_information_event
+                            .top_ips_by_bytes_per_s
+                            .iter()
+                            .map(|(ip, bytes_per_s)| format!("{ip} ({bytes_per_s} bytes/s)"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                self.snapshot
+                    .store(Arc::new(statistics_information_event.clone()));
                 self.statistics_information_tx
                     .send(statistics_information_event.clone())
                     .map_err(Box::new)
@@ -195,12 +429,46 @@ impl Statistics {
         Ok(())
     }
 
+    fn handle_event(&mut self, statistics_update: StatisticsEvent) {
+        self.statistic_events += 1;
+        match statistics_update {
+            StatisticsEvent::ConnectionCreated { ip } => {
+                *self.connections_for_ip.entry(ip).or_insert(0) += 1;
+            }
+            StatisticsEvent::ConnectionClosed { ip, duration } => {
+                self.connection_durations.observe(duration);
+                if let Entry::Occupied(mut o) = self.connections_for_ip.entry(ip) {
+                    let connections = o.get_mut();
+                    *connections -= 1;
+                    if *connections == 0 {
+                        o.remove_entry();
+                    }
+                }
+            }
+            StatisticsEvent::ConnectionDenied { ip } => {
+                *self.denied_connections_for_ip.entry(ip).or_insert(0) += 1;
+            }
+            StatisticsEvent::ConnectionLimitHit { ip } => {
+                *self.connection_limit_hits_for_ip.entry(ip).or_insert(0) += 1;
+            }
+            StatisticsEvent::BytesDiscarded { ip, bytes } => {
+                *self.discarded_bytes_for_ip.entry(ip).or_insert(0) += bytes;
+            }
+            StatisticsEvent::MalformedCommands { ip, commands } => {
+                *self.malformed_commands_for_ip.entry(ip).or_insert(0) += commands;
+            }
+            StatisticsEvent::VncFrameRendered => self.frame += 1,
+            StatisticsEvent::FfmpegStdinLagged => self.ffmpeg_stdin_lags += 1,
+        }
+    }
+
     fn calculate_statistics_information_event(
         &mut self,
         prev: &StatisticsInformationEvent,
         elapsed: Duration,
     ) -> StatisticsInformationEvent {
         let elapsed_ms = max(1, elapsed.as_millis()) as u64;
+        self.bytes_read_counters.drain_into(&mut self.bytes_for_ip);
         let frame = self.frame;
         let connections = self.connections_for_ip.values().sum();
         let ips = self.connections_for_ip.len() as u32;
@@ -215,6 +483,7 @@ impl Statistics {
         self.fps_window
             .add_sample((frame - prev.frame) * 1000 / elapsed_ms);
         let statistic_events = self.statistic_events;
+        let top_ips_by_bytes_per_s = self.top_ips_by_bytes_per_s(prev, elapsed_ms);
 
         StatisticsInformationEvent {
             frame,
@@ -226,8 +495,253 @@ impl Statistics {
             bytes_per_s: self.bytes_per_s_window.get_average(),
             connections_for_ip: self.connections_for_ip.clone(),
             denied_connections_for_ip: self.denied_connections_for_ip.clone(),
+            connection_limit_hits_for_ip: self.connection_limit_hits_for_ip.clone(),
+            discarded_bytes_for_ip: self.discarded_bytes_for_ip.clone(),
+            malformed_commands_for_ip: self.malformed_commands_for_ip.clone(),
             bytes_for_ip: self.bytes_for_ip.clone(),
+            top_ips_by_bytes_per_s,
+            ffmpeg_stdin_lags: self.ffmpeg_stdin_lags,
+            connection_durations: self.connection_durations.clone(),
             statistic_events,
         }
     }
+
+    /// The `top_n` IPs that sent the most bytes per second since `prev`, the most active one first
+    fn top_ips_by_bytes_per_s(
+        &self,
+        prev: &StatisticsInformationEvent,
+        elapsed_ms: u64,
+    ) -> Vec<(IpAddr, u64)> {
+        if self.top_n == 0 {
+            return Vec::new();
+        }
+
+        let mut bytes_per_s_for_ip = self
+            .bytes_for_ip
+            .iter()
+            .filter_map(|(ip, bytes)| {
+                let prev_bytes = prev.bytes_for_ip.get(ip).copied().unwrap_or_default();
+                let bytes_per_s = bytes.saturating_sub(prev_bytes) * 1000 / elapsed_ms;
+                (bytes_per_s > 0).then_some((*ip, bytes_per_s))
+            })
+            .collect::<Vec<_>>();
+
+        // The IP only breaks ties, so that the order is stable
+        let most_active_first =
+            |a: &(IpAddr, u64), b: &(IpAddr, u64)| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0));
+        if bytes_per_s_for_ip.len() > self.top_n {
+            // There can be plenty of IPs, so we don't sort the ones we are going to drop anyway
+            bytes_per_s_for_ip.select_nth_unstable_by(self.top_n, most_active_first);
+            bytes_per_s_for_ip.truncate(self.top_n);
+        }
+        bytes_per_s_for_ip.sort_unstable_by(most_active_first);
+
+        bytes_per_s_for_ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statistics_information_event_roundtrip() {
+        let save_file = std::env::temp_dir().join(format!(
+            "breakwater-statistics-roundtrip-{}.json",
+            std::process::id()
+        ));
+        let save_file = save_file.to_str().unwrap();
+
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let event = StatisticsInformationEvent {
+            connections_for_ip: IpMap::from_iter([(ip, 2)]),
+            bytes_for_ip: IpMap::from_iter([(ip, 42)]),
+            ..Default::default()
+        };
+        event.save_to_file(save_file).unwrap();
+        let loaded = StatisticsInformationEvent::load_from_file(save_file).unwrap();
+        std::fs::remove_file(save_file).unwrap();
+
+        assert_eq!(loaded.connections_for_ip, event.connections_for_ip);
+        assert_eq!(loaded.bytes_for_ip, event.bytes_for_ip);
+    }
+
+    #[test]
+    fn test_top_ips_by_bytes_per_s() {
+        let (_statistics_tx, statistics_rx) = mpsc::channel(10);
+        let (statistics_information_tx, _statistics_information_rx) = broadcast::channel(2);
+        let bytes_read_counters = Arc::new(BytesReadCounters::new(2));
+        let mut statistics = Statistics::new(
+            statistics_rx,
+            Arc::clone(&bytes_read_counters),
+            statistics_information_tx,
+            StatisticsSaveMode::Disabled,
+        )
+        .with_top_n(3);
+
+        let ips = (1..=5)
+            .map(|i| IpAddr::from([10, 0, 0, i]))
+            .collect::<Vec<_>>();
+        let counters = ips
+            .iter()
+            .map(|ip| bytes_read_counters.register(*ip))
+            .collect::<Vec<_>>();
+
+        for (counter, bytes) in counters.iter().zip([300, 5000, 100, 5000, 2000]) {
+            counter.add(bytes);
+        }
+        let first = statistics.calculate_statistics_information_event(
+            &StatisticsInformationEvent::default(),
+            Duration::from_secs(2),
+        );
+        // Ties are ordered by IP
+        assert_eq!(
+            first.top_ips_by_bytes_per_s,
+            [(ips[1], 2500), (ips[3], 2500), (ips[4], 1000)]
+        );
+
+        // Only the bytes since the previous event count, not the total bytes
+        for (counter, bytes) in counters.iter().zip([4000, 10, 0, 20, 3000]) {
+            counter.add(bytes);
+        }
+        let second =
+            statistics.calculate_statistics_information_event(&first, Duration::from_secs(1));
+        assert_eq!(
+            second.top_ips_by_bytes_per_s,
+            [(ips[0], 4000), (ips[4], 3000), (ips[3], 20)]
+        );
+
+        // IPs that didn't send anything are not reported at all
+        let third =
+            statistics.calculate_statistics_information_event(&second, Duration::from_secs(1));
+        assert!(third.top_ips_by_bytes_per_s.is_empty());
+    }
+
+    #[test]
+    fn test_connection_durations() {
+        let (_statistics_tx, statistics_rx) = mpsc::channel(10);
+        let (statistics_information_tx, _statistics_information_rx) = broadcast::channel(2);
+        let mut statistics = Statistics::new(
+            statistics_rx,
+            Arc::new(BytesReadCounters::new(1)),
+            statistics_information_tx,
+            StatisticsSaveMode::Disabled,
+        );
+
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        statistics.handle_event(StatisticsEvent::ConnectionCreated { ip });
+        statistics.handle_event(StatisticsEvent::ConnectionCreated { ip });
+        statistics.handle_event(StatisticsEvent::ConnectionClosed {
+            ip,
+            duration: Duration::from_secs(42),
+        });
+        statistics.handle_event(StatisticsEvent::ConnectionClosed {
+            ip,
+            duration: Duration::from_millis(50),
+        });
+
+        let event = statistics.calculate_statistics_information_event(
+            &StatisticsInformationEvent::default(),
+            Duration::from_secs(1),
+        );
+        assert_eq!(event.connections, 0);
+        assert_eq!(
+            event.connection_durations.bucket_counts,
+            [1, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(event.connection_durations.count, 2);
+        assert!((event.connection_durations.sum_s - 42.05).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_reflects_latest_event() {
+        let (statistics_tx, statistics_rx) = mpsc::channel(10);
+        let (statistics_information_tx, mut statistics_information_rx) = broadcast::channel(2);
+        let bytes_read_counters = Arc::new(BytesReadCounters::new(1));
+        let mut statistics = Statistics::new(
+            statistics_rx,
+            Arc::clone(&bytes_read_counters),
+            statistics_information_tx,
+            StatisticsSaveMode::Disabled,
+        );
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.load().bytes, 0);
+
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let counter = bytes_read_counters.register(ip);
+        statistics_tx
+            .send(StatisticsEvent::ConnectionCreated { ip })
+            .await
+            .unwrap();
+        counter.add(42);
+
+        let statistics_thread = tokio::spawn(async move { statistics.start().await });
+
+        for expected_bytes in [42, 50] {
+            let event = statistics_information_rx.recv().await.unwrap();
+            let latest = snapshot.load();
+            assert_eq!(latest.bytes, expected_bytes);
+            assert_eq!(latest.bytes, event.bytes);
+            assert_eq!(latest.connections, 1);
+            assert_eq!(latest.bytes_for_ip, event.bytes_for_ip);
+            assert_eq!(latest.statistic_events, event.statistic_events);
+
+            counter.add(8);
+        }
+
+        statistics_thread.abort();
+    }
+
+    #[test]
+    fn test_bytes_per_s_with_custom_report_interval() {
+        let report_interval = Duration::from_millis(250);
+        let (_statistics_tx, statistics_rx) = mpsc::channel(10);
+        let (statistics_information_tx, _statistics_information_rx) = broadcast::channel(2);
+        let bytes_read_counters = Arc::new(BytesReadCounters::new(1));
+        let mut statistics = Statistics::new(
+            statistics_rx,
+            Arc::clone(&bytes_read_counters),
+            statistics_information_tx,
+            StatisticsSaveMode::Disabled,
+        )
+        .with_report_interval(report_interval);
+        let counter = bytes_read_counters.register(IpAddr::from([10, 0, 0, 1]));
+
+        // The rates are per second, not per report interval
+        counter.add(1000);
+        let first =
+            statistics.calculate_statistics_information_event(&Default::default(), report_interval);
+        assert_eq!(first.bytes_per_s, 4000);
+
+        // Averaged over the last reports
+        counter.add(500);
+        let second = statistics.calculate_statistics_information_event(&first, report_interval);
+        assert_eq!(second.bytes, 1500);
+        assert_eq!(second.bytes_per_s, 3000);
+    }
+
+    #[tokio::test]
+    async fn test_report_interval() {
+        let (_statistics_tx, statistics_rx) = mpsc::channel(10);
+        let (statistics_information_tx, mut statistics_information_rx) = broadcast::channel(2);
+        let mut statistics = Statistics::new(
+            statistics_rx,
+            Arc::new(BytesReadCounters::new(1)),
+            statistics_information_tx,
+            StatisticsSaveMode::Disabled,
+        )
+        .with_report_interval(Duration::from_millis(20));
+        let statistics_thread = tokio::spawn(async move { statistics.start().await });
+
+        // Would take multiple seconds with the default interval
+        tokio::time::timeout(Duration::from_millis(500), async {
+            for _ in 0..3 {
+                statistics_information_rx.recv().await.unwrap();
+            }
+        })
+        .await
+        .expect("statistics must be reported every report interval");
+
+        statistics_thread.abort();
+    }
 }
@@ -10,6 +10,8 @@ use tokio::io::{AsyncRead, AsyncWrite};
 pub struct MockTcpStream {
     read_data: Vec<u8>,
     write_data: Vec<u8>,
+    /// Don't signal EOF once all data has been read, but wait forever for more data, as an idle client would
+    never_closing: bool,
 }
 
 impl MockTcpStream {
@@ -17,6 +19,7 @@ impl MockTcpStream {
         MockTcpStream {
             read_data: input.as_bytes().to_vec(),
             write_data: Vec::new(),
+            never_closing: false,
         }
     }
 
@@ -24,12 +27,29 @@ impl MockTcpStream {
         MockTcpStream {
             read_data: input,
             write_data: Vec::new(),
+            never_closing: false,
         }
     }
 
+    /// Once all data has been read, further reads wait forever instead of signaling EOF
+    pub fn never_closing(mut self) -> Self {
+        self.never_closing = true;
+        self
+    }
+
     pub fn get_output(self) -> String {
         String::from_utf8(self.write_data).unwrap()
     }
+
+    /// Use this instead of [`Self::get_output`] in case the server responds with binary data
+    #[cfg(any(
+        feature = "qoi",
+        feature = "binary-set-pixel",
+        feature = "screenshot-command"
+    ))]
+    pub fn get_output_bytes(self) -> Vec<u8> {
+        self.write_data
+    }
 }
 
 impl Read for MockTcpStream {
@@ -59,6 +79,11 @@ impl AsyncRead for MockTcpStream {
         _cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
+        if self.read_data.is_empty() && self.never_closing {
+            // We never wake up the task, as there will never be any new data
+            return std::task::Poll::Pending;
+        }
+
         let size: usize = min(self.read_data.len(), buf.remaining());
         buf.put_slice(&self.read_data[..size]);
         self.get_mut().read_data.drain(..size);
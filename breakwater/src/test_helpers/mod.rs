@@ -1,2 +1,3 @@
+pub mod captured_output;
 pub mod dev_null_tcp_stream;
 pub mod mock_tcp_stream;
@@ -1,17 +1,36 @@
 #![allow(clippy::octal_escapes)]
 
 use std::{
-    net::{IpAddr, Ipv4Addr},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-use breakwater_parser::{FrameBuffer, SimpleFrameBuffer, HELP_TEXT};
+use breakwater_parser::{
+    CanvasRotation, FrameBuffer, MemchrParser, OriginalParser, Parser, RefactoredParser,
+    ResizableFrameBuffer, SimpleFrameBuffer, WriteProtectedRegion, HELP_TEXT,
+    INVALID_PX_COMMAND_TEXT, RESPONSE_FLUSH_THRESHOLD,
+};
+#[cfg(feature = "hash-command")]
+use breakwater_parser::{MAX_CANVAS_COMMANDS_PER_PARSE, TOO_MANY_CANVAS_COMMANDS_TEXT};
 use rstest::{fixture, rstest};
-use tokio::sync::mpsc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
 
 use crate::{
-    cli_args::DEFAULT_NETWORK_BUFFER_SIZE, server::handle_connection, statistics::StatisticsEvent,
-    test_helpers::mock_tcp_stream::MockTcpStream,
+    cli_args::{DEFAULT_NETWORK_BUFFER_SIZE, DEFAULT_RESPONSE_BUFFER_SIZE},
+    server::{
+        bind_listener, connection_worker, connections_per_ip_denied_text, handle_connection,
+        ConnectionLimits, ConnectionOptions, ParserOptions, Server, TcpOptions,
+        CONNECTION_DENIED_TEXT, CONNECTION_LIMIT_HIT_TEXT,
+    },
+    statistics::{BytesReadCounter, BytesReadCounters, IpMap, StatisticsEvent},
+    test_helpers::{captured_output::CapturedOutput, mock_tcp_stream::MockTcpStream},
 };
 
 #[fixture]
@@ -19,6 +38,12 @@ fn ip() -> IpAddr {
     IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
 }
 
+/// Address of the client, the statistics only use its [`ip`]
+#[fixture]
+fn peer_addr() -> SocketAddr {
+    SocketAddr::new(ip(), 54321)
+}
+
 #[fixture]
 fn fb() -> Arc<SimpleFrameBuffer> {
     // We keep the framebuffer so small, so that we can easily test all pixels in a test run
@@ -33,6 +58,19 @@ fn statistics_channel() -> (
     mpsc::channel(10000)
 }
 
+/// The parsers that support the binary commands, so that we can check they all behave the same
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    not(any(feature = "binary-set-pixel", feature = "binary-sync-pixels")),
+    allow(dead_code)
+)]
+enum ParserKind {
+    Original,
+    Memchr,
+    #[cfg_attr(not(feature = "binary-sync-pixels"), allow(dead_code))]
+    Refactored,
+}
+
 #[rstest]
 #[timeout(std::time::Duration::from_secs(1))]
 #[case("", "")]
@@ -68,6 +106,9 @@ async fn test_correct_responses_to_general_commands(#[case] input: &str, #[case]
 // 0xab = 171, 0x88 = 136
 // (171 * 136) / 255 = 91 = 0x5b
 #[case("PX 0 0 abcdef88\nPX 0 0\n", if cfg!(feature = "alpha") {"PX 0 0 5b6d7f\n"} else {"PX 0 0 abcdef\n"})]
+// Blending on top of a non-black pixel, every channel must be blended with the same channel of the current pixel
+// red: (255 * 127) / 255 = 127 = 0x7f, blue: (255 * 128) / 255 = 128 = 0x80
+#[case("PX 0 0 ff0000\nPX 0 0 0000ff80\nPX 0 0\n", if cfg!(feature = "alpha") {"PX 0 0 7f0080\n"} else {"PX 0 0 0000ff\n"})]
 // Short commands
 #[case("PX 0 0 00\nPX 0 0\n", "PX 0 0 000000\n")]
 #[case("PX 0 0 ff\nPX 0 0\n", "PX 0 0 ffffff\n")]
@@ -100,27 +141,14 @@ async fn test_setting_pixel(#[case] input: &str, #[case] expected: &str) {
 #[case("PX 0 0 aaaaaa\n")]
 #[case("PX 0 0 aa\n")]
 #[tokio::test]
-async fn test_safe<FB: FrameBuffer>(
-    #[case] input: &str,
-    ip: IpAddr,
-    fb: Arc<FB>,
-    statistics_channel: (
-        mpsc::Sender<StatisticsEvent>,
-        mpsc::Receiver<StatisticsEvent>,
-    ),
-) {
-    let mut stream = MockTcpStream::from_string(input);
-    handle_connection(
-        &mut stream,
-        ip,
+async fn test_safe<FB: FrameBuffer>(#[case] input: &str, fb: Arc<FB>) {
+    run_connection(
+        ParserKind::Original,
         fb.clone(),
-        statistics_channel.0,
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
+        input.as_bytes(),
+        ConnectionLimits::default(),
     )
-    .await
-    .unwrap();
+    .await;
 
     // Test if it panics
     assert_eq!(fb.get(0, 0).unwrap() & 0x00ff_ffff, 0xaaaaaa);
@@ -148,12 +176,7 @@ async fn test_drawing_rect<FB: FrameBuffer>(
     #[case] height: usize,
     #[case] offset_x: usize,
     #[case] offset_y: usize,
-    ip: IpAddr,
     fb: Arc<FB>,
-    statistics_channel: (
-        mpsc::Sender<StatisticsEvent>,
-        mpsc::Receiver<StatisticsEvent>,
-    ),
 ) {
     let mut color: u32 = 0;
     let mut fill_commands = String::new();
@@ -184,63 +207,43 @@ async fn test_drawing_rect<FB: FrameBuffer>(
     }
 
     // Color the pixels
-    let mut stream = MockTcpStream::from_string(&fill_commands);
-    handle_connection(
-        &mut stream,
-        ip,
+    let stream = run_connection(
+        ParserKind::Original,
         Arc::clone(&fb),
-        statistics_channel.0.clone(),
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
+        fill_commands.as_bytes(),
+        ConnectionLimits::default(),
     )
-    .await
-    .unwrap();
+    .await;
     assert_eq!("", stream.get_output());
 
     // Read the pixels again
-    let mut stream = MockTcpStream::from_string(&read_commands);
-    handle_connection(
-        &mut stream,
-        ip,
+    let stream = run_connection(
+        ParserKind::Original,
         Arc::clone(&fb),
-        statistics_channel.0.clone(),
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
+        read_commands.as_bytes(),
+        ConnectionLimits::default(),
     )
-    .await
-    .unwrap();
+    .await;
     assert_eq!(fill_commands, stream.get_output());
 
     // We can also do coloring and reading in a single connection
-    let mut stream = MockTcpStream::from_string(&combined_commands);
-    handle_connection(
-        &mut stream,
-        ip,
+    let stream = run_connection(
+        ParserKind::Original,
         Arc::clone(&fb),
-        statistics_channel.0.clone(),
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
+        combined_commands.as_bytes(),
+        ConnectionLimits::default(),
     )
-    .await
-    .unwrap();
+    .await;
     assert_eq!(combined_commands_expected, stream.get_output());
 
     // Check that nothing else was colored
-    let mut stream = MockTcpStream::from_string(&read_other_pixels_commands);
-    handle_connection(
-        &mut stream,
-        ip,
+    let stream = run_connection(
+        ParserKind::Original,
         Arc::clone(&fb),
-        statistics_channel.0.clone(),
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
+        read_other_pixels_commands.as_bytes(),
+        ConnectionLimits::default(),
     )
-    .await
-    .unwrap();
+    .await;
     assert_eq!(read_other_pixels_commands_expected, stream.get_output());
 }
 
@@ -261,37 +264,44 @@ async fn test_drawing_rect<FB: FrameBuffer>(
     "PX 0 0 000000\nPX 0 0 313233\n"
 )]
 #[tokio::test]
-async fn test_binary_set_pixel<FB: FrameBuffer>(
+async fn test_binary_set_pixel(
     #[case] input: &str,
     #[case] expected: &str,
-    ip: IpAddr,
-    fb: Arc<FB>,
-    statistics_channel: (
-        mpsc::Sender<StatisticsEvent>,
-        mpsc::Receiver<StatisticsEvent>,
-    ),
+    #[values(ParserKind::Original, ParserKind::Memchr)] parser: ParserKind,
 ) {
-    let mut stream = MockTcpStream::from_string(input);
-    handle_connection(
-        &mut stream,
-        ip,
-        fb,
-        statistics_channel.0,
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
-        None,
-    )
-    .await
-    .unwrap();
+    assert_returns_with_parser(parser, input.as_bytes(), expected).await;
+}
 
-    assert_eq!(expected, stream.get_output());
+#[cfg(feature = "binary-set-pixel")]
+#[rstest]
+// Set with PB and read back with PG, the response has no newline. The alpha is always ff, as the canvas is opaque.
+#[case(b"PB\x01\x00\x02\x00\xab\xcd\xef\xffPG\x01\x00\x02\x00", &[0xab, 0xcd, 0xef, 0xff])]
+#[case(b"PG\x00\x00\x00\x00PG\x7f\x02\xdf\x01\n", &[0, 0, 0, 0xff, 0, 0, 0, 0xff])]
+#[case(
+    b"PB\x00\x00\x00\x00\x12\x34\x56\x00\nPG\x00\x00\x00\x00PX 0 0\n",
+    b"\x12\x34\x56\xffPX 0 0 123456\n"
+)]
+// Outside of the canvas
+#[case(b"PG\x80\x02\x00\x00PG\x00\x00\xe0\x01", &[])]
+#[tokio::test]
+async fn test_binary_get_pixel(
+    fb: Arc<SimpleFrameBuffer>,
+    #[case] input: &[u8],
+    #[case] expected: &[u8],
+) {
+    let stream = run_connection(ParserKind::Original, fb, input, ConnectionLimits::default()).await;
+
+    assert_eq!(stream.get_output_bytes(), expected);
 }
 
 #[cfg(feature = "binary-sync-pixels")]
+#[rstest]
 #[tokio::test]
-async fn test_binary_sync_pixels() {
+async fn test_binary_sync_pixels(
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
     // Test byte conversion works
-    assert_returns("PX 0 0 42\nPX 0 0\n".as_bytes(), "PX 0 0 424242\n").await;
+    assert_returns_with_parser(parser, "PX 0 0 42\nPX 0 0\n".as_bytes(), "PX 0 0 424242\n").await;
 
     // Don't set any pixels
     let mut input = Vec::new();
@@ -302,7 +312,7 @@ async fn test_binary_sync_pixels() {
         0, 0, 0, 0, /* length */
     ]);
     input.extend("PX 0 0\n".as_bytes());
-    assert_returns(&input, "PX 0 0 000000\n").await;
+    assert_returns_with_parser(parser, &input, "PX 0 0 000000\n").await;
 
     // Set first 10 pixels
     let mut input = Vec::new();
@@ -311,21 +321,159 @@ async fn test_binary_sync_pixels() {
     input.extend(0_u16.to_le_bytes()); // y
     input.extend(10_u32.to_le_bytes()); // length
     for pixel in 0..10_u32 {
-        // Some alpha stuff going on (which I don't fully understand)
-        input.extend((pixel << 8).to_be_bytes());
+        // The pixels are sent as rgba. They are opaque, so that they are drawn the same with and without the alpha
+        // feature
+        input.extend(((pixel << 8) | 0xff).to_be_bytes());
     }
     input.extend(
         "PX 0 0\nPX 1 0\nPX 2 0\nPX 3 0\nPX 4 0\nPX 5 0\nPX 6 0\nPX 7 0\nPX 8 0\nPX 9 0\n"
             .as_bytes(),
     );
-    assert_returns(&input, "PX 0 0 000000\nPX 1 0 000001\nPX 2 0 000002\nPX 3 0 000003\nPX 4 0 000004\nPX 5 0 000005\nPX 6 0 000006\nPX 7 0 000007\nPX 8 0 000008\nPX 9 0 000009\n").await;
+    assert_returns_with_parser(parser, &input, "PX 0 0 000000\nPX 1 0 000001\nPX 2 0 000002\nPX 3 0 000003\nPX 4 0 000004\nPX 5 0 000005\nPX 6 0 000006\nPX 7 0 000007\nPX 8 0 000008\nPX 9 0 000009\n").await;
+}
+
+#[cfg(feature = "binary-sync-pixels")]
+#[rstest]
+#[tokio::test]
+/// Pixels written using `PXMULTI` must end up exactly like the same pixels drawn using `PX x y rrggbbaa`: Without the
+/// alpha feature both ignore the alpha byte, with it both blend the pixel onto the canvas
+async fn test_binary_sync_pixels_matches_px(
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
+    let colors = [0x123456_u32, 0xffeedd, 0x000001];
+    let px_fb = fb();
+    let pxmulti_fb = fb();
+
+    let mut px_input = String::new();
+    let mut pxmulti_input = Vec::new();
+    pxmulti_input.extend("PXMULTI".as_bytes());
+    pxmulti_input.extend(0_u16.to_le_bytes()); // x
+    pxmulti_input.extend(0_u16.to_le_bytes()); // y
+    pxmulti_input.extend((colors.len() as u32).to_le_bytes()); // length
+    for (x, (rgb, alpha)) in colors.iter().zip([0x00, 0x42, 0xff]).enumerate() {
+        px_input += &format!("PX {x} 0 {rgb:06x}{alpha:02x}\n");
+        pxmulti_input.extend(((rgb << 8) | alpha).to_be_bytes());
+    }
+
+    for (fb, input) in [
+        (&px_fb, px_input.into_bytes()),
+        (&pxmulti_fb, pxmulti_input),
+    ] {
+        run_connection(parser, fb.clone(), &input, ConnectionLimits::default()).await;
+    }
+
+    assert_eq!(px_fb.as_bytes(), pxmulti_fb.as_bytes());
+}
+
+#[cfg(feature = "binary-sync-pixels")]
+#[rstest]
+#[tokio::test]
+/// Exporting the canvas using `as_bytes` and importing it on another server using `PXMULTI` is lossless. With the alpha
+/// feature `PXMULTI` blends the pixels, so the export needs to carry an alpha of `ff`, the same as `PG` and `GETRECT`
+/// respond with.
+async fn test_binary_sync_pixels_round_trip(
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
+    let exporting_fb = fb();
+    let importing_fb = fb();
+
+    let mut px_input = String::new();
+    for (x, rgb) in [0x123456_u32, 0xffeedd, 0x000001, 0xabcdef]
+        .iter()
+        .enumerate()
+    {
+        px_input += &format!("PX {x} 1 {rgb:06x}\n");
+    }
+    run_connection(
+        parser,
+        exporting_fb.clone(),
+        px_input.as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+    // Gets overwritten by the import
+    run_connection(
+        parser,
+        importing_fb.clone(),
+        b"PX 0 1 ffffff\n",
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    // The first two rows are enough, the rest of both canvases is black anyway
+    let mut exported = exporting_fb.as_bytes()[..2 * exporting_fb.get_width() * 4].to_vec();
+    if cfg!(feature = "alpha") {
+        for pixel in exported.chunks_exact_mut(4) {
+            pixel[3] = 0xff;
+        }
+    }
+    let mut pxmulti_input = Vec::new();
+    pxmulti_input.extend("PXMULTI".as_bytes());
+    pxmulti_input.extend(0_u16.to_le_bytes()); // x
+    pxmulti_input.extend(0_u16.to_le_bytes()); // y
+    pxmulti_input.extend((exported.len() as u32 / 4).to_le_bytes()); // length
+    pxmulti_input.extend(exported);
+    run_connection(
+        parser,
+        importing_fb.clone(),
+        &pxmulti_input,
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    assert_eq!(importing_fb.as_bytes(), exporting_fb.as_bytes());
+}
+
+#[cfg(all(feature = "binary-sync-pixels", feature = "alpha"))]
+#[rstest]
+#[tokio::test]
+/// With the alpha feature the pixels written using `PXMULTI` are blended onto the canvas, just like `PX x y rrggbbaa`
+async fn test_binary_sync_pixels_alpha(
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
+    let background = [0x000000_u32, 0xffffff, 0x123456, 0x0000ff, 0xabcdef];
+    let pixels = [
+        0xff000080_u32,
+        0x00ff0042,
+        0xffffff00,
+        0x654321ff,
+        0x80808080,
+    ];
+
+    let mut input = Vec::new();
+    for (x, rgb) in background.iter().enumerate() {
+        input.extend(format!("PX {x} 0 {rgb:06x}\n").as_bytes());
+    }
+    input.extend("PXMULTI".as_bytes());
+    input.extend(0_u16.to_le_bytes()); // x
+    input.extend(0_u16.to_le_bytes()); // y
+    input.extend((pixels.len() as u32).to_le_bytes()); // length
+    for rgba in pixels {
+        input.extend(rgba.to_be_bytes());
+    }
+
+    let mut expected = String::new();
+    for (x, (rgb, rgba)) in background.iter().zip(pixels).enumerate() {
+        input.extend(format!("PX {x} 0\n").as_bytes());
+        // The framebuffer stores the colors as 0x00bbggrr, the PX command uses rrggbb(aa)
+        let current = u32::from_be_bytes([0, *rgb as u8, (rgb >> 8) as u8, (rgb >> 16) as u8]);
+        let blended = breakwater_parser::alpha_blend(current, rgba.swap_bytes());
+        expected += &format!("PX {x} 0 {:06x}\n", blended.swap_bytes() >> 8);
+    }
+    // Fully transparent pixels don't change anything, opaque ones replace the pixel
+    assert!(expected.starts_with("PX 0 0 800000\nPX 1 0 bdffbd\nPX 2 0 123456\nPX 3 0 654321\n"));
+
+    assert_returns_with_parser(parser, &input, &expected).await;
 }
 
 #[cfg(feature = "binary-sync-pixels")]
 #[rstest]
 #[tokio::test]
 /// Try painting the very last pixel of the screen. There is only space for a single pixel left.
-async fn test_binary_sync_pixels_last_pixel<FB: FrameBuffer>(fb: Arc<FB>) {
+async fn test_binary_sync_pixels_last_pixel<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
     let mut input = Vec::new();
     let x = fb.get_width() as u16 - 1;
     let y = fb.get_height() as u16 - 1;
@@ -333,10 +481,11 @@ async fn test_binary_sync_pixels_last_pixel<FB: FrameBuffer>(fb: Arc<FB>) {
     input.extend(x.to_le_bytes()); // x
     input.extend(y.to_le_bytes()); // y
     input.extend(1_u32.to_le_bytes()); // length
-    input.extend(0x12345678_u32.to_be_bytes());
+    input.extend(0x123456ff_u32.to_be_bytes());
 
     input.extend(format!("PX 0 0\nPX {} {y}\nPX {x} {y}\n", x - 1).as_bytes());
-    assert_returns(
+    assert_returns_with_parser(
+        parser,
         &input,
         &format!(
             "PX 0 0 000000\nPX {} {y} 000000\nPX {x} {y} 123456\n",
@@ -350,7 +499,10 @@ async fn test_binary_sync_pixels_last_pixel<FB: FrameBuffer>(fb: Arc<FB>) {
 #[rstest]
 #[tokio::test]
 /// Try painting some pixels in the middle of the screen
-async fn test_binary_sync_pixels_in_the_middle<FB: FrameBuffer>(fb: Arc<FB>) {
+async fn test_binary_sync_pixels_in_the_middle<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
     let mut input = Vec::new();
     let mut expected = String::new();
 
@@ -363,7 +515,7 @@ async fn test_binary_sync_pixels_in_the_middle<FB: FrameBuffer>(fb: Arc<FB>) {
     input.extend(num_pixels.to_le_bytes()); // length
 
     for rgba in 0..num_pixels {
-        input.extend((rgba << 8).to_be_bytes());
+        input.extend(((rgba << 8) | 0xff).to_be_bytes());
     }
 
     let mut rgba = 0_u32;
@@ -382,14 +534,17 @@ async fn test_binary_sync_pixels_in_the_middle<FB: FrameBuffer>(fb: Arc<FB>) {
     input.extend("PX 52 14\n".as_bytes());
     expected += "PX 52 14 000000\n";
 
-    assert_returns(&input, &expected).await;
+    assert_returns_with_parser(parser, &input, &expected).await;
 }
 
 #[cfg(feature = "binary-sync-pixels")]
 #[rstest]
 #[tokio::test]
 /// Try painting too much pixels, so it overflows the framebuffer.
-async fn test_binary_sync_pixels_exceeding_screen<FB: FrameBuffer>(fb: Arc<FB>) {
+async fn test_binary_sync_pixels_exceeding_screen<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
     let mut input = Vec::new();
     let x = fb.get_width() as u16 - 1;
     let y = fb.get_height() as u16 - 1;
@@ -402,7 +557,7 @@ async fn test_binary_sync_pixels_exceeding_screen<FB: FrameBuffer>(fb: Arc<FB>)
 
     input.extend(format!("PX {x} {y}\n").as_bytes());
     // As we exceeded the screen nothing should have been set
-    assert_returns(&input, &format!("PX {x} {y} 000000\n")).await;
+    assert_returns_with_parser(parser, &input, &format!("PX {x} {y} 000000\n")).await;
 }
 
 #[cfg(feature = "binary-sync-pixels")]
@@ -410,7 +565,10 @@ async fn test_binary_sync_pixels_exceeding_screen<FB: FrameBuffer>(fb: Arc<FB>)
 #[tokio::test]
 /// Try painting more pixels that fit in the buffer. This checks if the parse correctly keeps track of the command
 /// across multiple parse calls as the pixel screen send is bigger than the buffer.
-async fn test_binary_sync_pixels_larger_than_buffer<FB: FrameBuffer>(fb: Arc<FB>) {
+async fn test_binary_sync_pixels_larger_than_buffer<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
     // let fb = Arc::new(FrameBuffer::new(50, 30)); // For testing
 
     let num_pixels = (fb.get_width() * fb.get_height()) as u32;
@@ -430,7 +588,7 @@ async fn test_binary_sync_pixels_larger_than_buffer<FB: FrameBuffer>(fb: Arc<FB>
     input.extend(num_pixels.to_le_bytes()); // length
 
     for rgba in 0..num_pixels {
-        input.extend((rgba << 8).to_be_bytes());
+        input.extend(((rgba << 8) | 0xff).to_be_bytes());
         // input.extend((0xdeadbeef_u32).to_be_bytes()); // For testing
     }
 
@@ -445,35 +603,1782 @@ async fn test_binary_sync_pixels_larger_than_buffer<FB: FrameBuffer>(fb: Arc<FB>
         }
     }
 
-    let mut stream = MockTcpStream::from_bytes(input.to_owned());
+    assert_returns_with_parser(parser, &input, &expected).await;
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_max_pixels_per_connection<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    mut statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let input = (0..10)
+        .map(|x| format!("PX {x} 0 ffffff\n"))
+        .collect::<String>();
+    let parser = OriginalParser::new(fb.clone());
+    // The network buffer only has space for exactly 5 commands (in addition to the parser lookahead), so that the
+    // connection reads 5 commands at a time
+    let network_buffer_size = parser.parser_lookahead() + 5 * "PX 0 0 ffffff\n".len();
+
+    let mut stream = MockTcpStream::from_string(&input);
     handle_connection(
         &mut stream,
-        ip(),
-        fb,
-        statistics_channel().0,
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
+        peer_addr(),
+        parser,
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            network_buffer_size,
+            limits: ConnectionLimits {
+                max_pixels: Some(5),
+                max_bytes: None,
+                idle_timeout: None,
+                warm_up: None,
+            },
+            ..connection_options()
+        },
         None,
     )
     .await
     .unwrap();
 
-    assert_eq!(expected, stream.get_output());
+    assert_eq!(
+        std::str::from_utf8(CONNECTION_LIMIT_HIT_TEXT).unwrap(),
+        stream.get_output()
+    );
+    for x in 0..5 {
+        assert_eq!(fb.get(x, 0), Some(0xffffff), "Pixel {x} should be drawn");
+    }
+    for x in 5..10 {
+        assert_eq!(fb.get(x, 0), Some(0), "Pixel {x} should not be drawn");
+    }
+
+    let mut limit_hit_events = 0;
+    while let Ok(event) = statistics_channel.1.try_recv() {
+        if matches!(event, StatisticsEvent::ConnectionLimitHit { .. }) {
+            limit_hit_events += 1;
+        }
+    }
+    assert_eq!(limit_hit_events, 1);
 }
 
-async fn assert_returns(input: &[u8], expected: &str) {
-    let mut stream = MockTcpStream::from_bytes(input.to_owned());
+#[rstest]
+#[tokio::test]
+async fn test_max_bytes_per_connection<FB: FrameBuffer>(
+    fb: Arc<FB>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let input = (0..10)
+        .map(|x| format!("PX {x} 0 ffffff\nPX {x} 0\n"))
+        .collect::<String>();
+
+    let mut stream = MockTcpStream::from_string(&input);
     handle_connection(
         &mut stream,
-        ip(),
-        fb(),
-        statistics_channel().0,
-        DEFAULT_NETWORK_BUFFER_SIZE,
-        page_size::get(),
+        peer_addr(),
+        OriginalParser::new(fb.clone()),
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            limits: ConnectionLimits {
+                max_pixels: None,
+                // Exactly 3 draw and read commands
+                max_bytes: Some(3 * "PX 0 0 ffffff\nPX 0 0\n".len() as u64),
+                idle_timeout: None,
+                warm_up: None,
+            },
+            ..connection_options()
+        },
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        format!(
+            "PX 0 0 ffffff\nPX 1 0 ffffff\nPX 2 0 ffffff\n{}",
+            std::str::from_utf8(CONNECTION_LIMIT_HIT_TEXT).unwrap()
+        ),
+        stream.get_output()
+    );
+    for x in 3..10 {
+        assert_eq!(fb.get(x, 0), Some(0), "Pixel {x} should not be drawn");
+    }
+}
+
+#[rstest]
+#[case::valid("PX 0 0 ffffff\nPX 0 0\nOFFSET 1 1\nSIZE\n", 0)]
+#[case::malformed_px("PX abc\nPX 1\nPX 1 2 zzzz\nPX 1 2 abcdef 12\n", 4)]
+#[case::malformed_offset("OFFSET 1\nOFFSET 1 x\n", 2)]
+#[case::mixed(&"PX 0 0 ffffff\nPX 1\n".repeat(1_000), 1_000)]
+#[tokio::test]
+async fn test_malformed_commands(
+    #[case] input: &str,
+    #[case] expected: u64,
+    ip: IpAddr,
+    fb: Arc<SimpleFrameBuffer>,
+    mut statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut stream = MockTcpStream::from_string(input);
+    handle_connection(
+        &mut stream,
+        peer_addr(),
+        OriginalParser::new(fb),
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        connection_options(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    let mut malformed_commands = 0;
+    while let Ok(event) = statistics_channel.1.try_recv() {
+        if let StatisticsEvent::MalformedCommands {
+            ip: event_ip,
+            commands,
+        } = event
+        {
+            assert_eq!(event_ip, ip);
+            malformed_commands += commands;
+        }
+    }
+    assert_eq!(malformed_commands, expected);
+}
+
+#[rstest]
+// Data that never forms a complete command can not be kept forever
+#[case::garbage(&"x".repeat(100_000), true)]
+#[case::commands(&"PX 0 0 ffffff\n".repeat(10_000), false)]
+#[tokio::test]
+async fn test_discarded_bytes<FB: FrameBuffer>(
+    #[case] input: &str,
+    #[case] expect_discarded: bool,
+    ip: IpAddr,
+    fb: Arc<FB>,
+    mut statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let parser = OriginalParser::new(fb);
+    let parser_lookahead = parser.parser_lookahead();
+    let mut stream = MockTcpStream::from_string(input);
+    handle_connection(
+        &mut stream,
+        peer_addr(),
+        parser,
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            ..connection_options()
+        },
         None,
     )
     .await
     .unwrap();
 
+    let mut discarded_bytes = 0;
+    while let Ok(event) = statistics_channel.1.try_recv() {
+        if let StatisticsEvent::BytesDiscarded {
+            ip: event_ip,
+            bytes,
+        } = event
+        {
+            assert_eq!(event_ip, ip);
+            discarded_bytes += bytes;
+        }
+    }
+    if expect_discarded {
+        // Everything except for the bytes that could still be the start of a command is discarded
+        let kept_bytes = input.len() - discarded_bytes as usize;
+        assert!(
+            kept_bytes <= parser_lookahead + 1,
+            "kept {kept_bytes} bytes"
+        );
+    } else {
+        assert_eq!(discarded_bytes, 0);
+    }
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_connection_warm_up(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let commands = |y| {
+        (0..10)
+            .map(|x| format!("PX {x} {y} ffffff\nPX {x} {y}\n"))
+            .collect::<String>()
+    };
+    let (mut client, server) = tokio::io::duplex(4096);
+    let connection = tokio::spawn(handle_connection(
+        server,
+        peer_addr(),
+        OriginalParser::new(fb.clone()),
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            limits: ConnectionLimits {
+                // Exactly 3 draw and read commands
+                max_bytes: Some(3 * "PX 0 0 ffffff\nPX 0 0\n".len() as u64),
+                warm_up: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..connection_options()
+        },
+        None,
+    ));
+
+    // The initial burst exceeds the byte limit, but is sent during the warm-up
+    client.write_all(commands(0).as_bytes()).await.unwrap();
+    let expected = (0..10)
+        .map(|x| format!("PX {x} 0 ffffff\n"))
+        .collect::<String>();
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected.as_bytes(), response);
+
+    // Afterwards the byte limit is enforced
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    client.write_all(commands(1).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert_eq!(
+        format!(
+            "PX 0 1 ffffff\nPX 1 1 ffffff\nPX 2 1 ffffff\n{}",
+            std::str::from_utf8(CONNECTION_LIMIT_HIT_TEXT).unwrap()
+        ),
+        response
+    );
+    connection.await.unwrap().unwrap();
+    assert_eq!(fb.get(3, 1), Some(0));
+}
+
+#[rstest]
+#[case("PX abc\n", "ERROR: Invalid PX command, expected `PX x y rrggbb`, `PX x y rrggbbaa`, `PX x y gg` or `PX x y`\n")]
+#[case("PX 1\n", "ERROR: Invalid PX command, expected `PX x y rrggbb`, `PX x y rrggbbaa`, `PX x y gg` or `PX x y`\n")]
+#[case("PX 1 2 zzzz\nPX 1 2\n", "ERROR: Invalid PX command, expected `PX x y rrggbb`, `PX x y rrggbbaa`, `PX x y gg` or `PX x y`\nPX 1 2 000000\n")]
+#[case("OFFSET 1\n", "ERROR: Invalid OFFSET command, expected `OFFSET x y`\n")]
+// The command following an invalid one is not swallowed
+#[case("PX 1\nPX 1 2 ff\nPX 1 2\n", "ERROR: Invalid PX command, expected `PX x y rrggbb`, `PX x y rrggbbaa`, `PX x y gg` or `PX x y`\nPX 1 2 ffffff\n")]
+// Valid commands must still work unchanged
+#[case("PX 0 0 ffffff\nPX 0 0\n", "PX 0 0 ffffff\n")]
+#[case("OFFSET 10 10\nPX 0 0 ff\nPX 0 0\n", "PX 0 0 ffffff\n")]
+#[case("SIZE\n", "SIZE 640 480\n")]
+// Unknown commands are still skipped, only commands looking like a known command are reported
+#[case("FOO\n", "")]
+#[tokio::test]
+async fn test_strict_mode(#[case] input: &str, #[case] expected: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_strict(true),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
     assert_eq!(expected, stream.get_output());
 }
+
+#[cfg(not(feature = "alpha"))]
+#[rstest]
+#[case(
+    "PX 1 2 abcdefff\nPX 1 2\n",
+    "ERROR: Alpha is not supported by this server, use `PX x y rrggbb` instead\nPX 1 2 000000\n"
+)]
+#[case(
+    "PX 1 2 abcdef00\n",
+    "ERROR: Alpha is not supported by this server, use `PX x y rrggbb` instead\n"
+)]
+// Plain rgb and gray must still work
+#[case("PX 1 2 abcdef\nPX 1 2\n", "PX 1 2 abcdef\n")]
+#[case("PX 1 2 ab\nPX 1 2\n", "PX 1 2 ababab\n")]
+#[tokio::test]
+async fn test_reject_alpha(#[case] input: &str, #[case] expected: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_reject_alpha(true),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[cfg(feature = "alpha")]
+#[rstest]
+#[case("PX 1 2 abcdef\nPX 1 2\n", "PX 1 2 abcdefff\n")]
+#[case("PX 1 2\n", "PX 1 2 000000ff\n")]
+// Alpha is blended into the canvas, so the pixel read back is opaque
+#[case("PX 1 2 ffffff80\nPX 1 2\n", "PX 1 2 808080ff\n")]
+#[case("OFFSET 10 10\nPX 1 2 abcdef\nPX 1 2\n", "PX 1 2 abcdefff\n")]
+#[tokio::test]
+async fn test_rgba_reads(#[case] input: &str, #[case] expected: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_rgba_reads(true),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[case(b"PX 1 2 abcdef\n", "ECHO PX 1 2 abcdef\n")]
+#[case(b"PX 1 2 ab\n", "ECHO PX 1 2 ababab\n")]
+#[case(b"PX 1 2 abcdefff\n", "ECHO PX 1 2 abcdef\n")]
+#[case(
+    b"PX 0 0 ffffff\nPX 1 0 000000\nPX 0 0\n",
+    "ECHO PX 0 0 ffffff\nECHO PX 1 0 000000\nPX 0 0 ffffff\n"
+)]
+// The echo contains the coordinates as sent by the client
+#[case(b"OFFSET 10 10\nPX 1 2 abcdef\n", "ECHO PX 1 2 abcdef\n")]
+// Pixels that are not drawn are not echoed
+#[case(b"PX 640 480 abcdef\n", "")]
+#[cfg_attr(
+    feature = "binary-set-pixel",
+    case(b"PB\x01\x00\x02\x00\xab\xcd\xef\xff", "ECHO PX 1 2 abcdef\n")
+)]
+#[tokio::test]
+async fn test_pixel_command_echo(#[case] input: &[u8], #[case] expected: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_pixel_command_echo(true),
+        input,
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[tokio::test]
+async fn test_connection_logs_contain_port() {
+    // Other tests might have installed it already, which is fine
+    let _ = tracing_log::LogTracer::init();
+    let output = CapturedOutput::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(output.clone())
+        .finish();
+    // The test runs on a single thread, so everything the connection logs ends up in our subscriber
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    // IPv4 clients often show up embedded inside an IPv6 address
+    let peer_addr: SocketAddr = "[::ffff:10.0.0.1]:54321".parse().unwrap();
+    let (statistics_tx, mut statistics_rx) = statistics_channel();
+    let mut stream = MockTcpStream::from_string("PX 0 0 ffffff\n");
+    handle_connection(
+        &mut stream,
+        peer_addr,
+        OriginalParser::new(fb()),
+        statistics_tx,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            ..connection_options()
+        },
+        None,
+    )
+    .await
+    .unwrap();
+
+    let output = output.get_output();
+    assert!(
+        output.contains("Handling connection from [::ffff:10.0.0.1]:54321"),
+        "The log must contain the port: {output}"
+    );
+
+    // The statistics only use the canonical IP, so that the number of entries stays bounded
+    let Some(StatisticsEvent::ConnectionCreated { ip }) = statistics_rx.recv().await else {
+        panic!("The first statistics event must be the created connection");
+    };
+    assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_strict_mode_command_split_across_reads(fb: Arc<SimpleFrameBuffer>) {
+    // The network buffer is so small, that the commands are cut off at the end of the reads
+    let input = (0..20)
+        .map(|x| format!("PX {x} 0 ffffff\n"))
+        .collect::<String>()
+        + "PX 5 0\n";
+    let parser = OriginalParser::new(fb.clone()).with_strict(true);
+    let network_buffer_size = parser.parser_lookahead() + 30;
+
+    let stream = run_connection_with_parser(
+        parser,
+        input.as_bytes(),
+        ConnectionOptions {
+            network_buffer_size,
+            ..connection_options()
+        },
+    )
+    .await;
+
+    // Cut off commands must not be reported as invalid
+    assert_eq!("PX 5 0 ffffff\n", stream.get_output());
+}
+
+#[rstest]
+#[case("PX  0   0  ffffff\nPX 0 0\n", "PX 0 0 ffffff\n")]
+#[case("PX 0 0  abcdef\nPX 0 0\n", "PX 0 0 abcdef\n")]
+#[case("PX 0 0 ff  \nPX 0 0\n", "PX 0 0 ffffff\n")]
+#[case("PX   1   2\n", "PX 1 2 000000\n")]
+#[case(
+    "OFFSET 10 10\nPX  0  0  ffffff\nPX 10 10\nPX 0 0\n",
+    "PX 10 10 000000\nPX 0 0 ffffff\n"
+)]
+// Single spaces still work the same
+#[case("PX 0 0 ffffff\nPX 0 0\n", "PX 0 0 ffffff\n")]
+#[tokio::test]
+async fn test_lenient_whitespace(#[case] input: &str, #[case] expected: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_lenient_whitespace(true),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[case("PX  0   0  ffffff\nPX 0 0\n")]
+#[case("PX 0 0  abcdef\nPX 0 0\n")]
+#[case("PX   1   2\nPX 0 0\n")]
+#[tokio::test]
+async fn test_multiple_spaces_rejected_in_strict_mode(#[case] input: &str) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_strict(true),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    // Nothing got drawn and the client got told about it
+    assert_eq!(
+        format!(
+            "{}PX 0 0 000000\n",
+            String::from_utf8_lossy(INVALID_PX_COMMAND_TEXT)
+        ),
+        stream.get_output()
+    );
+}
+
+/// Offsets are clamped to the size of the canvas, so reads and draws with a huge offset are simply outside of the canvas
+#[rstest]
+#[case(
+    "OFFSET 9999 9999\nPX 0 0\nPX 0 0 ffffff\nOFFSET 0 0\nPX 639 479\n",
+    "PX 639 479 000000\n"
+)]
+#[case(
+    "OFFSET 639 9999\nPX 0 0\nOFFSET 639 479\nPX 0 0 ffffff\nPX 0 0\n",
+    "PX 0 0 ffffff\n"
+)]
+#[case(
+    "OFFSET 640 0\nPX 0 0 ffffff\nPX 0 0\nOFFSET 0 0\nPX 639 0\nPX 0 1\n",
+    "PX 639 0 000000\nPX 0 1 000000\n"
+)]
+#[tokio::test]
+async fn test_large_offset(
+    #[case] input: &str,
+    #[case] expected: &str,
+    #[values(ParserKind::Original, ParserKind::Memchr, ParserKind::Refactored)] parser: ParserKind,
+) {
+    assert_returns_with_parser(parser, input.as_bytes(), expected).await;
+}
+
+/// Offsets are clamped to the size of the rotated canvas, which is taller than the framebuffer
+#[rstest]
+#[case(CanvasRotation::Clockwise90)]
+#[case(CanvasRotation::Clockwise270)]
+#[tokio::test]
+async fn test_large_offset_on_rotated_canvas(#[case] rotation: CanvasRotation) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb()).with_canvas_rotation(rotation),
+        "OFFSET 0 600\nPX 0 0 ffffff\nPX 0 0\nOFFSET 0 9999\nPX 0 0\nOFFSET 0 0\nPX 0 600\n"
+            .as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!("PX 0 0 ffffff\nPX 0 600 ffffff\n", stream.get_output());
+}
+
+/// The memchr parser does not limit the length of the coordinates, so they must not overflow when adding the offset
+#[rstest]
+#[tokio::test]
+async fn test_memchr_parser_offset_does_not_overflow() {
+    let input = format!(
+        "OFFSET {max} {max}\nPX {max} {max}\nPX {max} {max} ffffff\nOFFSET 0 0\nPX 0 0\n",
+        max = usize::MAX
+    );
+    assert_returns_with_parser(ParserKind::Memchr, input.as_bytes(), "PX 0 0 000000\n").await;
+}
+
+/// Every draw command and every read must respect the connection offset in the same way. After drawing we read the
+/// pixel relative to the offset, the absolute pixel and the pixel at the relative coordinates without offset.
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case(b"PX 1 2 abcdef\n")]
+#[case(b"PX 1 2 abcdefff\n")]
+#[case(b"PX 1 2 ab\n")]
+#[cfg_attr(
+    feature = "binary-set-pixel",
+    case(b"PB\x01\x00\x02\x00\xab\xcd\xef\xff")
+)]
+#[cfg_attr(
+    feature = "binary-sync-pixels",
+    case(b"PXMULTI\x01\x00\x02\x00\x01\x00\x00\x00\xab\xcd\xef\xff")
+)]
+#[tokio::test]
+async fn test_offset_applies_to_all_commands(
+    #[case] draw_command: &[u8],
+    #[values(ParserKind::Original, ParserKind::Memchr)] parser: ParserKind,
+) {
+    let mut input = b"OFFSET 10 20\n".to_vec();
+    input.extend_from_slice(draw_command);
+    input.extend_from_slice(b"PX 1 2\nOFFSET 0 0\nPX 11 22\nPX 1 2\n");
+
+    let color = if draw_command == b"PX 1 2 ab\n" {
+        "ababab"
+    } else {
+        "abcdef"
+    };
+    assert_returns_with_parser(
+        parser,
+        &input,
+        &format!("PX 1 2 {color}\nPX 11 22 {color}\nPX 1 2 000000\n"),
+    )
+    .await;
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case(b"PX 0 470 ffffff\nPX 0 470\n", "PX 0 470 000000\n")]
+#[case(b"PX 639 479 ffffffff\nPX 639 479\n", "PX 639 479 000000\n")]
+#[case(b"PX 100 444 42\nPX 100 444\n", "PX 100 444 000000\n")]
+#[case(b"PX 0 443 ffffff\nPX 0 443\n", "PX 0 443 ffffff\n")]
+// The offset is applied before checking the region
+#[case(b"OFFSET 0 400\nPX 0 70 ffffff\nPX 0 70\n", "PX 0 70 000000\n")]
+#[cfg_attr(
+    feature = "binary-set-pixel",
+    case(b"PB\0\0\x01\x011234PX 0 257\n", "PX 0 257 313233\n")
+)]
+// y = 0x01bc = 444
+#[cfg_attr(
+    feature = "binary-set-pixel",
+    case(b"PB\0\0\xbc\x011234PX 0 444\n", "PX 0 444 000000\n")
+)]
+#[tokio::test]
+async fn test_write_protected_region(
+    #[case] input: &[u8],
+    #[case] expected: &str,
+    fb: Arc<SimpleFrameBuffer>,
+) {
+    // Same as the strip the VNC sink renders the statistics into
+    let stats_region = WriteProtectedRegion {
+        x: 0,
+        y: 444,
+        width: 640,
+        height: 36,
+    };
+
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb).with_write_protected_regions(vec![stats_region]),
+        input,
+        connection_options(),
+    )
+    .await;
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case(CanvasRotation::None, "SIZE 640 480\n", (0, 0), (10, 20))]
+#[case(CanvasRotation::Clockwise90, "SIZE 480 640\n", (639, 0), (619, 10))]
+#[case(CanvasRotation::Clockwise180, "SIZE 640 480\n", (639, 479), (629, 459))]
+#[case(CanvasRotation::Clockwise270, "SIZE 480 640\n", (0, 479), (20, 469))]
+#[tokio::test]
+async fn test_canvas_rotation(
+    #[case] rotation: CanvasRotation,
+    #[case] expected_size: &str,
+    #[case] expected_origin: (usize, usize),
+    #[case] expected_offset_pixel: (usize, usize),
+    fb: Arc<SimpleFrameBuffer>,
+) {
+    let stream = run_connection_with_parser(
+        OriginalParser::new(fb.clone()).with_canvas_rotation(rotation),
+        "SIZE\nPX 0 0 ffffff\nPX 0 0\nOFFSET 5 10\nPX 5 10 123456\nPX 5 10\nPX 1000 1000 ffffff\n"
+            .as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    // Clients only see the rotated canvas
+    assert_eq!(
+        format!("{expected_size}PX 0 0 ffffff\nPX 5 10 123456\n"),
+        stream.get_output()
+    );
+    // But the pixels end up rotated in the framebuffer
+    assert_eq!(
+        fb.get(expected_origin.0, expected_origin.1),
+        Some(0x00ff_ffff)
+    );
+    assert_eq!(
+        fb.get(expected_offset_pixel.0, expected_offset_pixel.1),
+        Some(0x0056_3412)
+    );
+    assert_eq!(
+        fb.as_pixels().iter().filter(|&&pixel| pixel != 0).count(),
+        2
+    );
+}
+
+#[cfg(feature = "flip-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case(
+    "FLIP 10 10 3 2 h\n",
+    "PX 10 10 333333\nPX 11 10 222222\nPX 12 10 111111\nPX 10 11 666666\nPX 11 11 555555\nPX 12 11 444444\n"
+)]
+#[case(
+    "FLIP 10 10 3 2 v\n",
+    "PX 10 10 444444\nPX 11 10 555555\nPX 12 10 666666\nPX 10 11 111111\nPX 11 11 222222\nPX 12 11 333333\n"
+)]
+// Only the first two columns get mirrored
+#[case(
+    "FLIP 10 10 2 2 h\n",
+    "PX 10 10 222222\nPX 11 10 111111\nPX 12 10 333333\nPX 10 11 555555\nPX 11 11 444444\nPX 12 11 666666\n"
+)]
+#[case(
+    "OFFSET 10 10\nFLIP 0 0 3 2 h\nOFFSET 0 0\n",
+    "PX 10 10 333333\nPX 11 10 222222\nPX 12 10 111111\nPX 10 11 666666\nPX 11 11 555555\nPX 12 11 444444\n"
+)]
+// Too large, so nothing happens
+#[case(
+    "FLIP 0 0 640 480 h\n",
+    "PX 10 10 111111\nPX 11 10 222222\nPX 12 10 333333\nPX 10 11 444444\nPX 11 11 555555\nPX 12 11 666666\n"
+)]
+// Invalid direction, so nothing happens
+#[case(
+    "FLIP 10 10 3 2 x\n",
+    "PX 10 10 111111\nPX 11 10 222222\nPX 12 10 333333\nPX 10 11 444444\nPX 11 11 555555\nPX 12 11 666666\n"
+)]
+#[tokio::test]
+async fn test_flip(#[case] flip: &str, #[case] expected: &str) {
+    // An asymmetric pattern of 3x2 pixels
+    let draw = "PX 10 10 111111\nPX 11 10 222222\nPX 12 10 333333\nPX 10 11 444444\nPX 11 11 555555\nPX 12 11 666666\n";
+    let read = "PX 10 10\nPX 11 10\nPX 12 10\nPX 10 11\nPX 11 11\nPX 12 11\n";
+
+    assert_returns(format!("{draw}{flip}{read}").as_bytes(), expected).await;
+}
+
+#[cfg(feature = "flip-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[tokio::test]
+async fn test_flip_is_clipped_to_canvas() {
+    assert_returns(
+        b"PX 638 0 aaaaaa\nPX 639 0 bbbbbb\nFLIP 638 0 5 1 h\nPX 638 0\nPX 639 0\n",
+        "PX 638 0 bbbbbb\nPX 639 0 aaaaaa\n",
+    )
+    .await;
+}
+
+#[cfg(feature = "cas-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case::matches("PX 1 2 123456\nPXCAS 1 2 123456 abcdef\nPX 1 2\n", "PX 1 2 abcdef\n")]
+#[case::does_not_match("PX 1 2 123456\nPXCAS 1 2 000000 abcdef\nPX 1 2\n", "PX 1 2 123456\n")]
+#[case::untouched_pixel("PXCAS 1 2 000000 abcdef\nPX 1 2\n", "PX 1 2 abcdef\n")]
+#[case::second_cas_loses(
+    "PXCAS 1 2 000000 ff0000\nPXCAS 1 2 000000 00ff00\nPX 1 2\n",
+    "PX 1 2 ff0000\n"
+)]
+#[case::chained(
+    "PXCAS 1 2 000000 ff0000\nPXCAS 1 2 ff0000 00ff00\nPX 1 2\n",
+    "PX 1 2 00ff00\n"
+)]
+#[case::offset(
+    "OFFSET 10 10\nPXCAS 1 2 000000 abcdef\nOFFSET 0 0\nPX 11 12\nPX 1 2\n",
+    "PX 11 12 abcdef\nPX 1 2 000000\n"
+)]
+#[case::outside_of_screen("PXCAS 640 0 000000 abcdef\nPX 639 0\n", "PX 639 0 000000\n")]
+// Invalid commands are skipped
+#[case::missing_color("PXCAS 1 2 000000\nPX 1 2\n", "PX 1 2 000000\n")]
+#[case::rgba("PXCAS 1 2 000000ff abcdef\nPX 1 2\n", "PX 1 2 000000\n")]
+#[tokio::test]
+async fn test_pxcas(#[case] input: &str, #[case] expected: &str) {
+    assert_returns(input.as_bytes(), expected).await;
+}
+
+#[cfg(feature = "text-command")]
+#[rstest]
+#[case::text("TEXT 100 100 ffffff Hi\n", true, true)]
+#[case::offset("OFFSET 50 50\nTEXT 50 50 ffffff Hi\n", true, true)]
+#[case::no_font("TEXT 100 100 ffffff Hi\n", false, false)]
+#[case::too_long(
+    &format!("TEXT 100 100 ffffff {}\n", "H".repeat(breakwater_parser::MAX_TEXT_LENGTH + 1)),
+    true,
+    false
+)]
+#[case::missing_newline("TEXT 100 100 ffffff Hi", true, false)]
+#[tokio::test]
+async fn test_text(#[case] input: &str, #[case] with_font: bool, #[case] expect_drawn: bool) {
+    let font = with_font
+        .then(|| crate::font::load_embedded_font(crate::cli_args::EmbeddedFont::Arial).unwrap());
+    let fb = fb();
+    run_connection_with_parser(
+        OriginalParser::new(fb.clone()).with_font(font),
+        input.as_bytes(),
+        connection_options(),
+    )
+    .await;
+
+    let drawn: Vec<_> = (0..fb.get_height())
+        .flat_map(|y| (0..fb.get_width()).map(move |x| (x, y)))
+        .filter(|&(x, y)| fb.get(x, y) != Some(0))
+        .collect();
+    assert_eq!(!drawn.is_empty(), expect_drawn, "drawn pixels: {drawn:?}");
+    // The text starts at the given position (top left corner) and is drawn in the given color
+    for (x, y) in drawn {
+        assert!(
+            (100..200).contains(&x) && (100..150).contains(&y),
+            "pixel ({x}, {y})"
+        );
+        assert_eq!(fb.get(x, y), Some(0xffffff));
+    }
+}
+
+#[cfg(feature = "sprites")]
+#[rstest]
+#[case::no_offset(0)]
+#[case::offset(5)]
+#[tokio::test]
+async fn test_sprites(#[case] offset: usize) {
+    // A 3x2 sprite
+    let pixels = [
+        0x0000_00ff_u32,
+        0x0000_ff00,
+        0x00ff_0000,
+        0x00ff_ffff,
+        0x0012_3456,
+        0x00ab_cdef,
+    ];
+    let positions = [(10, 20), (100, 200)];
+
+    let mut input = format!("OFFSET {offset} {offset}\nSPRITE define 42 3 2 ").into_bytes();
+    input.extend(pixels.iter().flat_map(|rgba| rgba.to_le_bytes()));
+    for (x, y) in positions {
+        input.extend_from_slice(
+            format!("SPRITE blit 42 {} {}\n", x - offset, y - offset).as_bytes(),
+        );
+    }
+    // Unknown sprites are ignored, sprites are clipped at the border of the canvas
+    input.extend_from_slice(b"OFFSET 0 0\nSPRITE blit 7 0 0\nSPRITE blit 42 638 479\n");
+
+    let fb = fb();
+    run_connection(
+        ParserKind::Original,
+        fb.clone(),
+        &input,
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    for (x, y) in positions {
+        for (i, rgba) in pixels.iter().enumerate() {
+            assert_eq!(
+                fb.get(x + i % 3, y + i / 3),
+                Some(*rgba),
+                "pixel {i} at ({x}, {y})"
+            );
+        }
+        // Nothing is drawn around the sprite
+        assert_eq!(fb.get(x + 3, y), Some(0));
+        assert_eq!(fb.get(x, y + 2), Some(0));
+    }
+    assert_eq!(fb.get(0, 0), Some(0));
+    assert_eq!(fb.get(638, 479), Some(0x0000_00ff));
+    assert_eq!(fb.get(639, 479), Some(0x0000_ff00));
+}
+
+#[cfg(feature = "circle-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case("CIRCLE 10 10 2 ffffff\n")]
+#[case("OFFSET 5 5\nCIRCLE 5 5 2 ffffff\nOFFSET 0 0\n")]
+#[tokio::test]
+async fn test_circle(#[case] circle: &str) {
+    // Within the radius (including the border)
+    let inside = [
+        (10, 10),
+        (10, 8),
+        (10, 12),
+        (8, 10),
+        (12, 10),
+        (9, 9),
+        (11, 11),
+        (9, 11),
+    ];
+    // Just outside of the radius
+    let outside = [
+        (10, 7),
+        (10, 13),
+        (7, 10),
+        (13, 10),
+        (12, 12),
+        (8, 8),
+        (11, 8),
+        (8, 11),
+    ];
+
+    let read: String = inside
+        .iter()
+        .chain(outside.iter())
+        .map(|(x, y)| format!("PX {x} {y}\n"))
+        .collect();
+    let expected: String = inside
+        .iter()
+        .map(|(x, y)| format!("PX {x} {y} ffffff\n"))
+        .chain(outside.iter().map(|(x, y)| format!("PX {x} {y} 000000\n")))
+        .collect();
+
+    assert_returns(format!("{circle}{read}").as_bytes(), &expected).await;
+}
+
+#[cfg(feature = "circle-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case::clipped_at_the_top_left("CIRCLE 0 0 2 ffffff\n", 6)]
+#[case::clipped_at_the_bottom_right("CIRCLE 639 479 2 ffffff\n", 6)]
+#[case::center_outside_of_canvas("CIRCLE 641 10 2 ffffff\n", 1)]
+// Larger than MAX_CIRCLE_RADIUS
+#[case::too_large("CIRCLE 10 10 257 ffffff\n", 0)]
+#[case::transparent("CIRCLE 10 10 2 ffffff00\n", if cfg!(feature = "alpha") { 0 } else { 13 })]
+#[case::rgba("CIRCLE 10 10 2 ffffffff\n", 13)]
+#[case::invalid_color("CIRCLE 10 10 2 fffff\n", 0)]
+#[tokio::test]
+async fn test_circle_pixel_count(
+    #[case] circle: &str,
+    #[case] expected_pixels: usize,
+    fb: Arc<SimpleFrameBuffer>,
+) {
+    run_connection(
+        ParserKind::Original,
+        fb.clone(),
+        circle.as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    assert_eq!(
+        fb.as_pixels().iter().filter(|&&pixel| pixel != 0).count(),
+        expected_pixels
+    );
+}
+
+#[cfg(feature = "circle-command")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[case::no_width(0, 480)]
+#[case::no_height(640, 0)]
+#[tokio::test]
+async fn test_circle_on_empty_canvas(#[case] width: usize, #[case] height: usize) {
+    let fb = Arc::new(SimpleFrameBuffer::new(width, height));
+    let stream = run_connection(
+        ParserKind::Original,
+        fb,
+        b"CIRCLE 0 0 2 ffffff\nSIZE\n",
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    assert_eq!(stream.get_output(), format!("SIZE {width} {height}\n"));
+}
+
+#[cfg(feature = "hash-command")]
+#[rstest]
+#[case::same_content(
+    "PX 10 10 ff0000\nPX 639 479 123456\n",
+    "PX 639 479 123456\nPX 10 10 ff0000\n",
+    true
+)]
+#[case::both_empty("", "", true)]
+#[case::different_color("PX 10 10 ff0000\n", "PX 10 10 ff0001\n", false)]
+#[case::different_pixel("PX 10 10 ff0000\n", "PX 10 11 ff0000\n", false)]
+#[tokio::test]
+async fn test_hash_command(
+    #[case] first_input: &str,
+    #[case] second_input: &str,
+    #[case] expect_equal: bool,
+) {
+    // Two independent servers, e.g. the screens of a Pixelflut wall synced using PXMULTI
+    let mut hashes = Vec::new();
+    for input in [first_input, second_input] {
+        let stream = run_connection(
+            ParserKind::Original,
+            fb(),
+            format!("{input}HASH\n").as_bytes(),
+            ConnectionLimits::default(),
+        )
+        .await;
+
+        let output = stream.get_output();
+        assert_eq!(output.len(), "HASH 0123456789abcdef\n".len(), "{output}");
+        assert!(output.starts_with("HASH "), "{output}");
+        hashes.push(output);
+    }
+
+    assert_eq!(hashes[0] == hashes[1], expect_equal, "{hashes:?}");
+}
+
+/// Commands processing the whole canvas are only answered a few times per read, the rest is rejected
+#[cfg(feature = "hash-command")]
+#[rstest]
+#[tokio::test]
+async fn test_canvas_commands_per_read_are_limited() {
+    let stream = run_connection(
+        ParserKind::Original,
+        fb(),
+        "HASH\n"
+            .repeat(MAX_CANVAS_COMMANDS_PER_PARSE + 2)
+            .as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    let output = stream.get_output();
+    let hash = &output[.."HASH 0123456789abcdef\n".len()];
+    assert_eq!(
+        output,
+        format!(
+            "{}{}",
+            hash.repeat(MAX_CANVAS_COMMANDS_PER_PARSE),
+            std::str::from_utf8(TOO_MANY_CANVAS_COMMANDS_TEXT)
+                .unwrap()
+                .repeat(2)
+        )
+    );
+
+    // The limit applies per read, so commands spread across multiple reads are answered more often
+    let commands = connection_options().network_buffer_size / "HASH\n".len() + 1;
+    let stream = run_connection(
+        ParserKind::Original,
+        fb(),
+        "HASH\n".repeat(commands).as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    let output = stream.get_output();
+    let answered = output.matches(hash).count();
+    assert!(answered > MAX_CANVAS_COMMANDS_PER_PARSE, "{answered}");
+    assert_eq!(
+        output
+            .matches(std::str::from_utf8(TOO_MANY_CANVAS_COMMANDS_TEXT).unwrap())
+            .count(),
+        commands - answered
+    );
+}
+
+#[cfg(feature = "qoi")]
+#[rstest]
+#[tokio::test]
+async fn test_qoi_snapshot(fb: Arc<SimpleFrameBuffer>) {
+    let stream = run_connection(
+        ParserKind::Original,
+        fb.clone(),
+        "PX 0 0 ff0000\nPX 1 0 00ff00\nPX 639 479 123456\nQOI\nPX 2 0 0000ff\n".as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    let output = stream.get_output_bytes();
+    let header_end = output.iter().position(|&b| b == b'\n').unwrap();
+    let header = std::str::from_utf8(&output[..header_end]).unwrap();
+    let image = &output[header_end + 1..];
+    assert_eq!(header, format!("QOI {}", image.len()));
+
+    let (header, pixels) = qoi::decode_to_vec(image).unwrap();
+    assert_eq!((header.width, header.height), (640, 480));
+    let pixel = |x: usize, y: usize| {
+        let index = (x + y * 640) * 3;
+        [pixels[index], pixels[index + 1], pixels[index + 2]]
+    };
+    assert_eq!(pixel(0, 0), [0xff, 0x00, 0x00]);
+    assert_eq!(pixel(1, 0), [0x00, 0xff, 0x00]);
+    assert_eq!(pixel(639, 479), [0x12, 0x34, 0x56]);
+    assert_eq!(pixel(42, 42), [0x00, 0x00, 0x00]);
+    // The snapshot was taken before this pixel got drawn
+    assert_eq!(pixel(2, 0), [0x00, 0x00, 0x00]);
+    assert_eq!(fb.get(2, 0), Some(0x00ff_0000));
+}
+
+#[cfg(feature = "screenshot-command")]
+#[rstest]
+#[case::uncompressed("SCREENSHOT", 0)]
+#[case::zstd("SCREENSHOT ZSTD", 1)]
+#[tokio::test]
+async fn test_screenshot(
+    #[case] command: &str,
+    #[case] expected_compression: u8,
+    fb: Arc<SimpleFrameBuffer>,
+) {
+    let stream = run_connection(
+        ParserKind::Original,
+        fb.clone(),
+        format!("PX 0 0 ff0000\nPX 1 0 00ff00\nPX 639 479 123456\n{command}\nPX 2 0 0000ff\n")
+            .as_bytes(),
+        ConnectionLimits::default(),
+    )
+    .await;
+
+    let output = stream.get_output_bytes();
+    let header_end = output.iter().position(|&b| b == b'\n').unwrap();
+    let header = std::str::from_utf8(&output[..header_end]).unwrap();
+    let data = &output[header_end + 1..];
+    assert_eq!(
+        header,
+        format!("SCREENSHOT 640 480 {expected_compression} {}", data.len())
+    );
+
+    let pixels = if expected_compression == 1 {
+        let pixels = zstd::decode_all(data).unwrap();
+        // The mostly black canvas compresses well
+        assert!(data.len() < pixels.len() / 100);
+        pixels
+    } else {
+        data.to_vec()
+    };
+    assert_eq!(pixels.len(), 640 * 480 * 4);
+    let pixel = |x: usize, y: usize| {
+        let index = (x + y * 640) * 4;
+        &pixels[index..index + 4]
+    };
+    assert_eq!(pixel(0, 0), [0xff, 0x00, 0x00, 0xff]);
+    assert_eq!(pixel(1, 0), [0x00, 0xff, 0x00, 0xff]);
+    assert_eq!(pixel(639, 479), [0x12, 0x34, 0x56, 0xff]);
+    assert_eq!(pixel(42, 42), [0x00, 0x00, 0x00, 0xff]);
+    // The screenshot was taken before this pixel got drawn
+    assert_eq!(pixel(2, 0), [0x00, 0x00, 0x00, 0xff]);
+    assert_eq!(fb.get(2, 0), Some(0x00ff_0000));
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(1))]
+#[tokio::test]
+async fn test_connection_idle_timeout(
+    fb: Arc<SimpleFrameBuffer>,
+    mut statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    // The client sends a command and afterwards stays silent forever
+    let mut stream = MockTcpStream::from_string("PX 0 0 ffffff\n").never_closing();
+    handle_connection(
+        &mut stream,
+        peer_addr(),
+        OriginalParser::new(fb.clone()),
+        statistics_channel.0,
+        BytesReadCounter::default(),
+        ConnectionOptions {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            limits: ConnectionLimits {
+                idle_timeout: Some(std::time::Duration::from_millis(50)),
+                ..Default::default()
+            },
+            ..connection_options()
+        },
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(fb.get(0, 0), Some(0xffffff));
+    let mut connections_closed = 0;
+    while let Ok(event) = statistics_channel.1.try_recv() {
+        if matches!(event, StatisticsEvent::ConnectionClosed { .. }) {
+            connections_closed += 1;
+        }
+    }
+    assert_eq!(connections_closed, 1);
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+/// All connections are kept open at the same time, so this only finishes in case the worker serves them concurrently
+async fn test_connection_worker_handles_many_connections(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let (connections_tx, connections_rx) = mpsc::unbounded_channel();
+    let worker = tokio::spawn(connection_worker(connections_rx));
+
+    // One connection per column of the screen. The network buffer is kept small, as we have many connections
+    let mut clients = Vec::new();
+    for _ in 0..fb.get_width() {
+        let (client, server) = tokio::io::duplex(1024);
+        connections_tx
+            .send(handle_connection(
+                server,
+                peer_addr(),
+                OriginalParser::new(fb.clone()),
+                statistics_channel.0.clone(),
+                BytesReadCounter::default(),
+                ConnectionOptions {
+                    network_buffer_size: 4096,
+                    ..connection_options()
+                },
+                None,
+            ))
+            .unwrap();
+        clients.push(client);
+    }
+
+    for (x, client) in clients.iter_mut().enumerate() {
+        client
+            .write_all(format!("PX {x} 0 {x:06x}\nPX {x} 0\n").as_bytes())
+            .await
+            .unwrap();
+    }
+    for (x, client) in clients.iter_mut().enumerate() {
+        let expected = format!("PX {x} 0 {x:06x}\n");
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(expected.as_bytes(), response);
+    }
+
+    // The worker finishes once all connections are closed and no new connections can arrive
+    drop(clients);
+    drop(connections_tx);
+    worker.await.unwrap();
+
+    let mut statistics_rx = statistics_channel.1;
+    let mut connections_closed = 0;
+    while let Ok(event) = statistics_rx.try_recv() {
+        if matches!(event, StatisticsEvent::ConnectionClosed { .. }) {
+            connections_closed += 1;
+        }
+    }
+    assert_eq!(connections_closed, fb.get_width());
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+/// The statistics task drains the counters while the connections are still sending, no bytes must get lost
+async fn test_bytes_read_counters_match_sent_bytes(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let bytes_read_counters = Arc::new(BytesReadCounters::new(3));
+    let mut expected_bytes_for_ip = IpMap::default();
+
+    let mut connections = Vec::new();
+    for connection in 0..64_u8 {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, connection % 5));
+        let input = format!("PX {connection} 0 ffffff\n").repeat(1000 + connection as usize);
+        *expected_bytes_for_ip.entry(ip).or_insert(0) += input.len() as u64;
+
+        connections.push(tokio::spawn(handle_connection(
+            MockTcpStream::from_string(&input),
+            SocketAddr::new(ip, 54321),
+            OriginalParser::new(fb.clone()),
+            statistics_channel.0.clone(),
+            bytes_read_counters.register(ip),
+            ConnectionOptions {
+                // Small buffer, so that every connection needs multiple reads
+                network_buffer_size: 4096,
+                ..connection_options()
+            },
+            None,
+        )));
+    }
+
+    let mut bytes_for_ip = IpMap::default();
+    while connections
+        .iter()
+        .any(|connection| !connection.is_finished())
+    {
+        bytes_read_counters.drain_into(&mut bytes_for_ip);
+        tokio::task::yield_now().await;
+    }
+    for connection in connections {
+        connection.await.unwrap().unwrap();
+    }
+    bytes_read_counters.drain_into(&mut bytes_for_ip);
+
+    assert_eq!(bytes_for_ip, expected_bytes_for_ip);
+}
+
+#[rstest]
+#[case::some_digits("1".repeat(1000))]
+// Spans multiple reads
+#[case::megabytes_of_digits("1".repeat(4 * DEFAULT_NETWORK_BUFFER_SIZE))]
+// Commands hidden in the garbage must not be picked up
+#[case::hidden_command(format!("{}PX 0 0 ff0000", "0".repeat(100)))]
+#[tokio::test]
+async fn test_overlong_commands_are_skipped(#[case] garbage: String) {
+    assert_returns(
+        format!("PX {garbage}\nPX 1 0 abcdef\nPX 0 0\nPX 1 0\n").as_bytes(),
+        "PX 0 0 000000\nPX 1 0 abcdef\n",
+    )
+    .await;
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+/// With port 0 the operating system picks a free port, which the server needs to report
+async fn test_server_reports_bound_port(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned()],
+        1,
+        fb,
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        None,
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap();
+    let local_addr = server.local_addrs()[0];
+    assert_ne!(local_addr.port(), 0);
+
+    // The reported port is the one we can actually connect to
+    let server = tokio::spawn(async move { server.start().await });
+    let mut client = TcpStream::connect(local_addr).await.unwrap();
+    client.write_all(b"SIZE\n").await.unwrap();
+    let expected = b"SIZE 640 480\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+
+    server.abort();
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_server_with_resizable_framebuffer(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let resizable_fb = Arc::new(ResizableFrameBuffer::from_arc(fb.clone()));
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned()],
+        1,
+        fb,
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        None,
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap()
+    .with_resizable_framebuffer(Arc::clone(&resizable_fb));
+    let local_addr = server.local_addrs()[0];
+    let server = tokio::spawn(async move { server.start().await });
+
+    let mut open_client = TcpStream::connect(local_addr).await.unwrap();
+    open_client
+        .write_all(b"PX 1 1 abcdef\nSIZE\n")
+        .await
+        .unwrap();
+    let expected = b"SIZE 640 480\n";
+    let mut response = vec![0; expected.len()];
+    open_client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+
+    let resized = resizable_fb.resize(800, 600);
+
+    // Connections that were already open follow the resize with their next read
+    open_client
+        .write_all(b"SIZE\nPX 700 500 fedcba\n")
+        .await
+        .unwrap();
+    let expected = b"SIZE 800 600\n";
+    let mut response = vec![0; expected.len()];
+    open_client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+
+    // Connections opened after the resize draw on the resized framebuffer as well
+    let mut client = TcpStream::connect(local_addr).await.unwrap();
+    client
+        .write_all(b"SIZE\nPX 799 599 123456\nPX 1 1\n")
+        .await
+        .unwrap();
+    let expected = b"SIZE 800 600\nPX 1 1 abcdef\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+    assert_eq!(resized.get(799, 599), Some(0x563412));
+    assert_eq!(resized.get(700, 500), Some(0xbadcfe));
+
+    server.abort();
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_server_with_multiple_listen_addresses(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned(), "[::1]:0".to_owned()],
+        1,
+        fb.clone(),
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        None,
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap();
+    let local_addrs = server.local_addrs().to_vec();
+    assert_eq!(local_addrs.len(), 2);
+    assert_ne!(local_addrs[0].port(), local_addrs[1].port());
+
+    let server = tokio::spawn(async move { server.start().await });
+
+    // Both listeners draw on the same framebuffer, so every client sees the pixels drawn through the other one
+    for (x, local_addr, color) in [(0, local_addrs[0], "abcdef"), (1, local_addrs[1], "123456")] {
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client
+            .write_all(format!("PX {x} 0 {color}\nPX 0 0\n").as_bytes())
+            .await
+            .unwrap();
+        let expected = "PX 0 0 abcdef\n";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, expected.as_bytes());
+    }
+    assert_eq!(fb.get(1, 0), Some(0x56_34_12));
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_bind_listeners_with_reuse_port() {
+    let first = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+    let local_addr = first.local_addr().unwrap();
+    let second = bind_listener(local_addr, true).unwrap();
+    assert_eq!(second.local_addr().unwrap(), local_addr);
+
+    // Without SO_REUSEPORT the port is taken
+    assert!(bind_listener(local_addr, false).is_err());
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_server_with_multiple_acceptors(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned()],
+        4,
+        fb,
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        None,
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap();
+    // All acceptors share the same port
+    let local_addrs = server.local_addrs().to_vec();
+    assert_eq!(local_addrs.len(), 1);
+
+    let server = tokio::spawn(async move { server.start().await });
+    for _ in 0..16 {
+        let mut client = TcpStream::connect(local_addrs[0]).await.unwrap();
+        client.write_all(b"SIZE\n").await.unwrap();
+        let expected = b"SIZE 640 480\n";
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+    }
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_tcp_options() {
+    let listener = bind_listener("127.0.0.1:0".parse().unwrap(), false).unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    assert!(!socket.nodelay().unwrap());
+
+    TcpOptions {
+        nodelay: true,
+        keepalive: Some(std::time::Duration::from_secs(42)),
+    }
+    .apply(&socket)
+    .unwrap();
+
+    assert!(socket.nodelay().unwrap());
+    let socket = socket2::SockRef::from(&socket);
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(
+        socket.keepalive_time().unwrap(),
+        std::time::Duration::from_secs(42)
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_max_total_connections(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned()],
+        1,
+        fb,
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        None,
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap()
+    .with_max_total_connections(Some(2));
+    let ips = (1..=3).map(|i| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)));
+    let ips = ips.collect::<Vec<_>>();
+
+    // The limit applies across all IPs
+    let mut stream = MockTcpStream::default();
+    let first = server.admit_connection(&mut stream, ips[0]).await.unwrap();
+    let second = server.admit_connection(&mut stream, ips[1]).await.unwrap();
+    assert!(first.is_some() && second.is_some());
+    assert!(server
+        .admit_connection(&mut stream, ips[2])
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        stream.get_output().as_bytes(),
+        CONNECTION_DENIED_TEXT,
+        "Only the denied connection should get a message"
+    );
+
+    // Closing a connection frees its slot
+    drop(first);
+    let mut stream = MockTcpStream::default();
+    assert!(server
+        .admit_connection(&mut stream, ips[2])
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(stream.get_output(), "");
+}
+
+#[rstest]
+// Large enough for the parser to stop early multiple times, see RESPONSE_FLUSH_THRESHOLD
+#[case::reads_only("PX 1 2\n".repeat(50_000), "PX 1 2 000000\n".repeat(50_000))]
+#[case::mixed(
+    "PX 1 2 ffffff\nPX 1 2\nPX 3 4\n".repeat(20_000),
+    "PX 1 2 ffffff\nPX 3 4 000000\n".repeat(20_000)
+)]
+#[tokio::test]
+async fn test_many_reads(#[case] input: String, #[case] expected: String) {
+    assert_returns(input.as_bytes(), &expected).await;
+}
+
+/// Counts the [`Parser::parse`] calls that had to grow the response buffer
+struct ResponseGrowthCounter<P: Parser> {
+    parser: P,
+    growths: Arc<AtomicUsize>,
+}
+
+impl<P: Parser> Parser for ResponseGrowthCounter<P> {
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        let capacity = response.capacity();
+        let parsed = self.parser.parse(buffer, response);
+        if response.capacity() != capacity {
+            self.growths.fetch_add(1, Ordering::Relaxed);
+        }
+        parsed
+    }
+
+    fn parser_lookahead(&self) -> usize {
+        self.parser.parser_lookahead()
+    }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.parser.pixels_drawn()
+    }
+}
+
+#[rstest]
+#[case::grow_on_demand(DEFAULT_RESPONSE_BUFFER_SIZE, 1)]
+// The parser stops once the response reaches the threshold, so the response exceeds it by at most a single response
+#[case::pre_allocated(RESPONSE_FLUSH_THRESHOLD + 1024, 0)]
+#[tokio::test]
+async fn test_response_buffer_size(
+    #[case] response_buffer_size: usize,
+    #[case] expected_growths: usize,
+) {
+    let growths = Arc::new(AtomicUsize::new(0));
+    let parser = ResponseGrowthCounter {
+        parser: OriginalParser::new(fb()),
+        growths: Arc::clone(&growths),
+    };
+    // Large enough for the parser to stop early multiple times
+    let stream = run_connection_with_parser(
+        parser,
+        "PX 1 2\n".repeat(50_000).as_bytes(),
+        ConnectionOptions {
+            network_buffer_size: DEFAULT_NETWORK_BUFFER_SIZE,
+            response_buffer_size,
+            ..connection_options()
+        },
+    )
+    .await;
+
+    // The buffer is reused after every write, so even without pre-allocation it only grows for the first response
+    assert_eq!(growths.load(Ordering::Relaxed), expected_growths);
+    assert_eq!(stream.get_output(), "PX 1 2 000000\n".repeat(50_000));
+}
+
+/// Records the largest response a single [`Parser::parse`] call produced
+#[cfg_attr(
+    not(any(feature = "getrect", feature = "screenshot-command")),
+    allow(dead_code)
+)]
+struct LargestResponseRecorder<P: Parser> {
+    parser: P,
+    largest_response: Arc<AtomicUsize>,
+}
+
+impl<P: Parser> Parser for LargestResponseRecorder<P> {
+    fn parse(&mut self, buffer: &[u8], response: &mut Vec<u8>) -> usize {
+        let parsed = self.parser.parse(buffer, response);
+        self.largest_response
+            .fetch_max(response.len(), Ordering::Relaxed);
+        parsed
+    }
+
+    fn parser_lookahead(&self) -> usize {
+        self.parser.parser_lookahead()
+    }
+
+    fn pixels_drawn(&self) -> u64 {
+        self.parser.pixels_drawn()
+    }
+}
+
+/// Sends a command responding with more than [`RESPONSE_FLUSH_THRESHOLD`] bytes multiple times within a single read.
+/// The parser has to stop after every one of them, otherwise the response grows with the number of commands.
+#[cfg_attr(
+    not(any(feature = "getrect", feature = "screenshot-command")),
+    allow(dead_code)
+)]
+async fn assert_large_responses_are_flushed(command: &str, repetitions: usize) {
+    let input = command.repeat(repetitions);
+    // Otherwise the commands would be split across multiple reads anyway
+    assert!(input.len() < connection_options().network_buffer_size);
+
+    let largest_response = Arc::new(AtomicUsize::new(0));
+    let parser = LargestResponseRecorder {
+        parser: OriginalParser::new(fb()),
+        largest_response: Arc::clone(&largest_response),
+    };
+    let stream = run_connection_with_parser(parser, input.as_bytes(), connection_options()).await;
+
+    // All responses have the same size, as the canvas is empty
+    let output = stream.get_output_bytes();
+    let response_len = output.len() / repetitions;
+    assert!(response_len > RESPONSE_FLUSH_THRESHOLD);
+    assert_eq!(output.len(), response_len * repetitions);
+    assert_eq!(largest_response.load(Ordering::Relaxed), response_len);
+}
+
+#[cfg(feature = "getrect")]
+#[rstest]
+#[tokio::test]
+async fn test_getrect_responses_are_flushed() {
+    assert_large_responses_are_flushed("GETRECT 0 0 512 512\n", 100).await;
+}
+
+#[cfg(feature = "screenshot-command")]
+#[rstest]
+#[tokio::test]
+async fn test_screenshot_responses_are_flushed() {
+    assert_large_responses_are_flushed("SCREENSHOT\n", 3).await;
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_max_connections_per_ip(
+    fb: Arc<SimpleFrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut server = Server::new(
+        &["127.0.0.1:0".to_owned()],
+        1,
+        fb,
+        statistics_channel.0,
+        Arc::new(BytesReadCounters::default()),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+        Some(2),
+        ConnectionLimits::default(),
+        ParserOptions::default(),
+    )
+    .await
+    .unwrap();
+    let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    let mut stream = MockTcpStream::default();
+    for _ in 0..2 {
+        assert!(server
+            .admit_connection(&mut stream, ip)
+            .await
+            .unwrap()
+            .is_some());
+    }
+    assert!(server
+        .admit_connection(&mut stream, ip)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(stream.get_output(), connections_per_ip_denied_text(2));
+}
+
+#[rstest]
+#[case(1, "Connection denied as the limit of 1 connections per IP address is reached, please close one of your other connections or retry in a few seconds\n")]
+#[case(42, "Connection denied as the limit of 42 connections per IP address is reached, please close one of your other connections or retry in a few seconds\n")]
+fn test_connections_per_ip_denied_text(#[case] connections_per_ip: u64, #[case] expected: &str) {
+    assert_eq!(connections_per_ip_denied_text(connections_per_ip), expected);
+}
+
+async fn assert_returns(input: &[u8], expected: &str) {
+    assert_returns_with_parser(ParserKind::Original, input, expected).await;
+}
+
+async fn assert_returns_with_parser(parser: ParserKind, input: &[u8], expected: &str) {
+    let stream = run_connection(parser, fb(), input, ConnectionLimits::default()).await;
+    assert_eq!(expected, stream.get_output());
+}
+
+/// Options for the connections of the tests. The network buffer is only a page, so that larger inputs need multiple
+/// reads.
+fn connection_options() -> ConnectionOptions {
+    ConnectionOptions {
+        network_buffer_size: page_size::get(),
+        ..Default::default()
+    }
+}
+
+/// Sends `input` over a connection handled by a parser of the given kind drawing on `fb`. Returns the stream, which
+/// contains the response.
+async fn run_connection<FB: FrameBuffer>(
+    parser: ParserKind,
+    fb: Arc<FB>,
+    input: &[u8],
+    limits: ConnectionLimits,
+) -> MockTcpStream {
+    let options = ConnectionOptions {
+        limits,
+        ..connection_options()
+    };
+    match parser {
+        ParserKind::Original => {
+            run_connection_with_parser(OriginalParser::new(fb), input, options).await
+        }
+        ParserKind::Memchr => {
+            run_connection_with_parser(MemchrParser::new(fb), input, options).await
+        }
+        ParserKind::Refactored => {
+            run_connection_with_parser(RefactoredParser::new(fb), input, options).await
+        }
+    }
+}
+
+/// Same as [`run_connection`], but using a parser configured by the test
+async fn run_connection_with_parser(
+    parser: impl Parser,
+    input: &[u8],
+    options: ConnectionOptions,
+) -> MockTcpStream {
+    let mut stream = MockTcpStream::from_bytes(input.to_owned());
+    let (statistics_tx, _statistics_rx) = statistics_channel();
+    handle_connection(
+        &mut stream,
+        peer_addr(),
+        parser,
+        statistics_tx,
+        BytesReadCounter::default(),
+        options,
+        None,
+    )
+    .await
+    .unwrap();
+
+    stream
+}